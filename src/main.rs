@@ -1,8 +1,18 @@
-use std::fs;
+use std::{fs, path::PathBuf};
+#[cfg(target_os = "linux")]
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+};
 
 use smolvm::{HvError, SmolVmT};
 
-use crate::smolvm::GpaSpan;
+use crate::smolvm::{
+    cpuid::{CpuidOverride, CpuidPolicy, CpuidRegister, CpuidTemplate},
+    numa::NumaNode,
+    topology::CpuTopology,
+    DebugConOutput, GpaSpan, HugePageSize, PmemOptions, RamBacking, RamOptions, VmOptions,
+};
 
 #[macro_use]
 extern crate clap;
@@ -18,6 +28,27 @@ fn main() -> Result<(), HvError> {
         (@arg KERNEL_PATH: -k --kernel +takes_value "Path to the ELF binary (Linux kernel perhaps)")
         (@arg KERNEL_CMD_LINE: -c --cmd_line +takes_value "Kernel command line")
         (@arg DTB_PATH: -d --dtb +takes_value "Path to the Device Tree Blob")
+        (@arg SHARE_DIR: --share_dir +takes_value "Host directory shared with the guest via virtio-9p")
+        (@arg SHARE_TAG: --share_tag +takes_value "Mount tag of the shared directory, \"share\" by default")
+        (@arg SHARE_READ_ONLY: --share_read_only "Do not let the guest modify the shared directory")
+        (@arg PMEM: --pmem +takes_value ... "Host file mapped into the guest as a virtio-pmem device")
+        (@arg PMEM_READ_ONLY: --pmem_read_only "Do not let the guest modify the persistent memory")
+        (@arg BALLOON: --balloon +takes_value "Add a virtio-balloon device and ask the guest to give that many MiB back")
+        (@arg VHOST_USER: --vhost_user +takes_value ... "Device run by a vhost-user backend as <type>:<socket>, the type is one of net, blk, rng, 9p, fs")
+        (@arg VHOST_USER_BACKEND: --vhost_user_backend +takes_value "Serve the shared directory to a vhost-user frontend at the socket rather than running a VM")
+        (@arg RTC_EPOCH: --rtc_epoch +takes_value "Start the guest real-time clock at that many seconds past 1970-01-01 rather than at the host time")
+        (@arg DEBUGCON: --debugcon +takes_value "Where the output of the debug console ports 0xe9 and 0x402 (x86_64) goes: log (default), stdout, stderr or a file path")
+        (@arg PCI: --pci "Put the virtio devices on the PCI bus rather than virtio-mmio")
+        (@arg DEBUG_EXIT: --debug_exit "Let the guest end the run with an exit status through the debug exit device")
+        (@arg EXIT_ON_PANIC: --exit_on_panic "Dump the vCPU state and exit with status 2 when the guest kernel panics")
+        (@arg EXIT_ON_REBOOT: --exit_on_reboot "Exit when the guest reboots rather than resetting the VM")
+        (@arg WATCHDOG: --watchdog +takes_value "Add the SP805 watchdog (aarch64), its expiry does one of reset, dump (the vCPU state and run on), exit (with status 4)")
+        (@arg CPUID_TEMPLATE: --cpuid_template +takes_value "Features the CPUID reports at most on x86_64: host (default), x86-64-v2 or x86-64-v3")
+        (@arg CPUID: --cpuid +takes_value ... "CPUID register to override on x86_64 as <leaf>[.<sub-leaf>]:<register>=<value>[/<mask>], e.g. 0x7:ebx=0/0x20")
+        (@arg PRINT_CPUID: --print_cpuid "Print the CPUID the guest sees on x86_64 and exit")
+        (@arg TOPOLOGY: --topology +takes_value "CPU topology as sockets=<n>,dies=<n>,cores=<n>,threads=<n>, a missing level has 1")
+        (@arg RAM: --ram +takes_value ... "Backing of a RAM region, one per NUMA node or a single one without NUMA nodes, as <backing>[,prefault][,mlock][,mergeable], the backing is one of memfd (default), sealed-memfd, anonymous, hugepages-2M, hugepages-1G, file=<path>")
+        (@arg NUMA: --numa +takes_value ... "NUMA node with an equal part of the RAM as cpus=<first>[-<last>][,host_node=<n>][,distances=<d>/<d>/...]")
         (@arg LOG_LEVEL: -l --log_level +takes_value ... "Sets the level of debugging information")
     )
     .get_matches();
@@ -31,13 +62,97 @@ fn main() -> Result<(), HvError> {
     ))
     .init();
 
+    let shared_dir = matches.value_of("SHARE_DIR").map(|path| SharedDir {
+        path,
+        tag: matches.value_of("SHARE_TAG").unwrap_or("share"),
+        read_only: matches.is_present("SHARE_READ_ONLY"),
+    });
+
+    if let Some(socket_path) = matches.value_of("VHOST_USER_BACKEND") {
+        return serve_vhost_user(socket_path, shared_dir);
+    }
+
+    if matches.is_present("PRINT_CPUID") {
+        return print_cpuid(VmOptions {
+            cpuid: cpuid_policy(&matches),
+            topology: cpu_topology(&matches),
+            ..Default::default()
+        });
+    }
+
     if let Some(kernel_path) = matches.value_of("KERNEL_PATH") {
         log::info!("Kernel path {}", kernel_path);
 
-        let command_line = matches.value_of("KERNEL_CMD_LINE");
-        let dtb_path = matches.value_of("DTB_PATH");
+        let kernel = Kernel {
+            path: kernel_path,
+            command_line: matches.value_of("KERNEL_CMD_LINE"),
+            dtb_path: matches.value_of("DTB_PATH"),
+            exit_on_reboot: matches.is_present("EXIT_ON_REBOOT"),
+            exit_on_panic: matches.is_present("EXIT_ON_PANIC"),
+            watchdog: matches.value_of("WATCHDOG").map(|action| match action {
+                "reset" => WatchdogAction::Reset,
+                "dump" => WatchdogAction::Dump,
+                "exit" => WatchdogAction::Exit,
+                _ => panic!("Watchdog action must be one of reset, dump, exit"),
+            }),
+        };
+        let balloon_size = matches
+            .value_of("BALLOON")
+            .map(|size| size.parse::<u64>().expect("Balloon size must be a number") << 20);
+        let options = VmOptions {
+            irqchip: true,
+            pmem: matches
+                .values_of("PMEM")
+                .into_iter()
+                .flatten()
+                .map(|path| PmemOptions {
+                    path: PathBuf::from(path),
+                    read_only: matches.is_present("PMEM_READ_ONLY"),
+                })
+                .collect(),
+            pci: matches.is_present("PCI"),
+            debug_exit: matches.is_present("DEBUG_EXIT"),
+            rtc_epoch: matches
+                .value_of("RTC_EPOCH")
+                .map(|epoch| epoch.parse::<u64>().expect("RTC epoch must be a number")),
+            watchdog: kernel.watchdog.is_some(),
+            debugcon: match matches.value_of("DEBUGCON") {
+                None | Some("log") => DebugConOutput::Log,
+                Some("stdout") => DebugConOutput::Stdout,
+                Some("stderr") => DebugConOutput::Stderr,
+                Some(path) => DebugConOutput::File(PathBuf::from(path)),
+            },
+            cpuid: cpuid_policy(&matches),
+            topology: cpu_topology(&matches),
+            numa: matches
+                .values_of("NUMA")
+                .into_iter()
+                .flatten()
+                .enumerate()
+                .map(|(node, numa)| parse_numa_node(node, numa))
+                .collect(),
+            ram: matches
+                .values_of("RAM")
+                .into_iter()
+                .flatten()
+                .map(parse_ram_options)
+                .collect(),
+        };
 
-        run_kernel(kernel_path, command_line, dtb_path)?;
+        let status = run_kernel(
+            &kernel,
+            &options,
+            shared_dir,
+            balloon_size,
+            matches
+                .values_of("VHOST_USER")
+                .into_iter()
+                .flatten()
+                .collect(),
+        )?;
+        if status != 0 {
+            std::process::exit(status);
+        }
     } else {
         log::info!("Path to the kernel was not specified, running a smol test");
         run_until_halt()?;
@@ -46,14 +161,48 @@ fn main() -> Result<(), HvError> {
     Ok(())
 }
 
+struct SharedDir<'a> {
+    path: &'a str,
+    tag: &'a str,
+    read_only: bool,
+}
+
+/// The kernel to boot, and boot again when the guest reboots
+struct Kernel<'a> {
+    path: &'a str,
+    command_line: Option<&'a str>,
+    dtb_path: Option<&'a str>,
+    exit_on_reboot: bool,
+    exit_on_panic: bool,
+    watchdog: Option<WatchdogAction>,
+}
+
+/// What to do when the guest stops petting the watchdog
+#[derive(Clone, Copy)]
+enum WatchdogAction {
+    Reset,
+    Dump,
+    Exit,
+}
+
+/// Even, unlike the statuses the debug exit device produces
+const PANIC_EXIT_STATUS: i32 = 2;
+const WATCHDOG_EXIT_STATUS: i32 = 4;
+
+/// The NUMA nodes get their RAM in huge pages
+const RAM_REGION_ALIGNMENT: usize = 2 * 1024 * 1024;
+
+/// Returns the exit status the guest has asked for, 0 when it has powered off
 fn run_kernel(
-    kernel_path: &str,
-    command_line: Option<&str>,
-    dtb_path: Option<&str>,
-) -> Result<(), HvError> {
-    log::info!("Opening {}", kernel_path);
+    kernel: &Kernel,
+    options: &VmOptions,
+    shared_dir: Option<SharedDir>,
+    balloon_size: Option<u64>,
+    vhost_user: Vec<&str>,
+) -> Result<i32, HvError> {
+    log::info!("Opening {}", kernel.path);
 
-    let file = fs::File::open(&kernel_path).unwrap();
+    let file = fs::File::open(kernel.path).unwrap();
     let file = unsafe { memmap2::Mmap::map(&file).unwrap() };
 
     #[cfg(target_arch = "x86_64")]
@@ -61,12 +210,475 @@ fn run_kernel(
     #[cfg(target_arch = "aarch64")]
     let gpa_start = 0x4000_0000;
 
-    let mut vm = smolvm::create_vm(&[GpaSpan {
-        start: gpa_start,
-        size: 512 * 1024 * 1024,
-    }])?;
-    vm.load_kernel_elf(&*file, command_line, dtb_path);
-    vm.run()?;
+    // The RAM is split between the NUMA nodes, the last region takes
+    // what the others leave
+    let ram_size = 512 * 1024 * 1024;
+    let region_count = options.numa.len().max(1);
+    let region_size = (ram_size / region_count) & !(RAM_REGION_ALIGNMENT - 1);
+    let gpa_map = (0..region_count)
+        .map(|region| GpaSpan {
+            start: gpa_start + (region * region_size) as u64,
+            size: if region + 1 == region_count {
+                ram_size - region * region_size
+            } else {
+                region_size
+            },
+        })
+        .collect::<Vec<_>>();
+
+    if !options.ram.is_empty() && options.ram.len() != region_count {
+        panic!(
+            "{} RAM backings given for {} RAM regions, one per NUMA node or a single one without NUMA nodes",
+            options.ram.len(),
+            region_count
+        );
+    }
+    // The backends map the RAM through the files
+    if !vhost_user.is_empty()
+        && options.ram.iter().any(|ram| {
+            matches!(
+                ram.backing,
+                RamBacking::Anonymous | RamBacking::HugePages(_)
+            )
+        })
+    {
+        panic!("The vhost-user devices need the RAM backed by a memfd or a file");
+    }
+
+    // The VM starts the threads of the timers
+    #[cfg(target_os = "linux")]
+    let sigterm = block_sigterm()?;
+
+    let mut vm = smolvm::create_vm_with_options(&gpa_map, options)?;
+
+    #[cfg(target_os = "linux")]
+    handle_sigterm(sigterm, vm.get_power_button());
+
+    let fs_tag = shared_dir
+        .as_ref()
+        .map_or("share", |shared_dir| shared_dir.tag);
+
+    if let Some(shared_dir) = shared_dir {
+        #[cfg(target_os = "linux")]
+        {
+            let device = smolvm::virtio::p9::Virtio9p::new(
+                shared_dir.tag,
+                Path::new(shared_dir.path),
+                shared_dir.read_only,
+            )?;
+            vm.add_virtio_device(Arc::new(Mutex::new(device)));
+        }
+
+        #[cfg(not(target_os = "linux"))]
+        log::warn!(
+            "Sharing {} is supported only on Linux, ignoring",
+            shared_dir.path
+        );
+    }
+
+    if let Some(balloon_size) = balloon_size {
+        #[cfg(target_os = "linux")]
+        {
+            use smolvm::virtio::balloon::{BalloonControl, VirtioBalloon};
+
+            let balloon = Arc::new(Mutex::new(VirtioBalloon::new()));
+            let transport = vm.add_virtio_device(balloon.clone());
+            BalloonControl::new(balloon, transport).set_target_size(balloon_size);
+        }
+
+        #[cfg(not(target_os = "linux"))]
+        log::warn!(
+            "Balloon of {} bytes is supported only on Linux, ignoring",
+            balloon_size
+        );
+    }
+
+    for device in vhost_user {
+        #[cfg(target_os = "linux")]
+        add_vhost_user_device(&mut vm, device, fs_tag)?;
+
+        #[cfg(not(target_os = "linux"))]
+        log::warn!(
+            "vhost-user device {} is supported only on Linux, ignoring",
+            device
+        );
+    }
+
+    vm.load_kernel_elf(&file, kernel.command_line, kernel.dtb_path);
+
+    let status = run_until_exit(&mut vm, kernel, &file);
+    log_unknown_msrs(&vm);
+
+    status
+}
+
+/// Runs the guest booted from the kernel ELF until it asks to end the run
+fn run_until_exit(vm: &mut smolvm::SmolVm, kernel: &Kernel, file: &[u8]) -> Result<i32, HvError> {
+    loop {
+        let exit_reason = match vm.run() {
+            Ok(exit_reason) => exit_reason,
+            Err(err) => {
+                log_post_codes(vm);
+                return Err(err);
+            }
+        };
+
+        match exit_reason {
+            smolvm::CpuExitReason::Shutdown => log::info!("Guest has powered off"),
+            smolvm::CpuExitReason::Reset if !kernel.exit_on_reboot => {
+                log::info!("Guest has rebooted, resetting the VM");
+                vm.reset()?;
+                vm.load_kernel_elf(file, kernel.command_line, kernel.dtb_path);
+                continue;
+            }
+            smolvm::CpuExitReason::Reset => {
+                log::info!("Guest has rebooted");
+                log_post_codes(vm);
+            }
+            smolvm::CpuExitReason::Exit(status) => return Ok(status),
+            smolvm::CpuExitReason::Panic(_) if kernel.exit_on_panic => {
+                dump_state(vm);
+                return Ok(PANIC_EXIT_STATUS);
+            }
+            // Up to the guest what comes next, e.g. the crash kernel or
+            // a reboot with `panic=N`
+            smolvm::CpuExitReason::Panic(_) => continue,
+            smolvm::CpuExitReason::Watchdog => match kernel.watchdog {
+                Some(WatchdogAction::Reset) => {
+                    log::error!("Guest watchdog has expired, resetting the VM");
+                    vm.reset()?;
+                    vm.load_kernel_elf(file, kernel.command_line, kernel.dtb_path);
+                    continue;
+                }
+                Some(WatchdogAction::Dump) => {
+                    log::error!("Guest watchdog has expired");
+                    dump_state(vm);
+                    continue;
+                }
+                _ => {
+                    log::error!("Guest watchdog has expired");
+                    dump_state(vm);
+                    return Ok(WATCHDOG_EXIT_STATUS);
+                }
+            },
+            _ => {}
+        }
+
+        return Ok(0);
+    }
+}
+
+/// The vCPU state and the POST codes, e.g. when the guest has crashed
+fn dump_state(vm: &smolvm::SmolVm) {
+    vm.get_cpu().lock().unwrap().dump_state();
+    log_post_codes(vm);
+}
+
+/// The MSRs the guest has accessed in vain, e.g. the features it expects
+/// and the VMM lacks
+fn log_unknown_msrs(vm: &smolvm::SmolVm) {
+    let bus = vm.get_bus();
+    let bus = bus.lock().unwrap();
+    for (index, accesses) in bus.unknown_msrs() {
+        log::warn!(
+            "Guest has accessed the unknown MSR {:#x}: {} reads, {} writes",
+            index,
+            accesses.reads,
+            accesses.writes
+        );
+    }
+}
+
+fn log_post_codes(vm: &smolvm::SmolVm) {
+    let codes = vm
+        .get_post_codes()
+        .map(|post_codes| post_codes.last())
+        .unwrap_or_default();
+    if !codes.is_empty() {
+        log::error!("Last POST codes, oldest first: {:02x?}", codes);
+    }
+}
+
+/// Must be called before spawning any threads so that they all have the
+/// signal blocked and `handle_sigterm` is the only one to get it.
+#[cfg(target_os = "linux")]
+fn block_sigterm() -> Result<nix::sys::signal::SigSet, HvError> {
+    use nix::sys::signal::{SigSet, Signal};
+
+    let mut signals = SigSet::empty();
+    signals.add(Signal::SIGTERM);
+    signals.thread_block()?;
+
+    Ok(signals)
+}
+
+/// The first SIGTERM presses the power button to let the guest shut down
+/// cleanly, the next one ends the process right away.
+#[cfg(target_os = "linux")]
+fn handle_sigterm(signals: nix::sys::signal::SigSet, power_button: Option<smolvm::PowerButton>) {
+    use nix::sys::signal::Signal;
+
+    std::thread::spawn(move || {
+        if let Some(power_button) = power_button {
+            if signals.wait().is_ok() {
+                log::info!("SIGTERM received, pressing the power button");
+                power_button();
+            }
+        }
+
+        if signals.wait().is_ok() {
+            log::info!("SIGTERM received, exiting");
+        }
+        std::process::exit(128 + Signal::SIGTERM as i32);
+    });
+}
+
+/// Adds the device described as `<type>:<socket>` with the virtqueues
+/// processed by the vhost-user backend listening at the socket
+#[cfg(target_os = "linux")]
+fn add_vhost_user_device(
+    vm: &mut smolvm::SmolVm,
+    device: &str,
+    fs_tag: &str,
+) -> Result<(), HvError> {
+    use smolvm::virtio::{
+        vhost_user::VhostUserDevice, VIRTIO_ID_9P, VIRTIO_ID_BLOCK, VIRTIO_ID_FS, VIRTIO_ID_NET,
+        VIRTIO_ID_RNG,
+    };
+
+    let (device_type, socket_path) = device
+        .split_once(':')
+        .expect("vhost-user device must be given as <type>:<socket>");
+    let (device_type, queue_count, config) = match device_type {
+        "net" => (VIRTIO_ID_NET, 2, Vec::new()),
+        "blk" => (VIRTIO_ID_BLOCK, 1, Vec::new()),
+        "rng" => (VIRTIO_ID_RNG, 1, Vec::new()),
+        "9p" => (VIRTIO_ID_9P, 1, Vec::new()),
+        "fs" => {
+            // struct virtio_fs_config { u8 tag[36]; le32 num_request_queues; },
+            // the high priority queue and a request queue
+            let mut config = vec![0_u8; 40];
+            let tag_len = fs_tag.len().min(36);
+            config[..tag_len].copy_from_slice(&fs_tag.as_bytes()[..tag_len]);
+            config[36..].copy_from_slice(&1_u32.to_le_bytes());
+            (VIRTIO_ID_FS, 2, config)
+        }
+        _ => panic!("Unknown vhost-user device type {}", device_type),
+    };
+
+    let device = Arc::new(Mutex::new(VhostUserDevice::connect(
+        Path::new(socket_path),
+        device_type,
+        queue_count,
+        config,
+    )?));
+    let transport = vm.add_virtio_device(device.clone());
+    device.lock().unwrap().forward_interrupts(transport);
+
+    Ok(())
+}
+
+fn cpuid_policy(matches: &clap::ArgMatches) -> CpuidPolicy {
+    CpuidPolicy {
+        template: match matches.value_of("CPUID_TEMPLATE") {
+            None | Some("host") => CpuidTemplate::Host,
+            Some("x86-64-v2") => CpuidTemplate::X86_64V2,
+            Some("x86-64-v3") => CpuidTemplate::X86_64V3,
+            Some(template) => panic!("Unknown CPUID template {}", template),
+        },
+        overrides: matches
+            .values_of("CPUID")
+            .into_iter()
+            .flatten()
+            .map(parse_cpuid_override)
+            .collect(),
+    }
+}
+
+/// `<leaf>[.<sub-leaf>]:<register>=<value>[/<mask>]`, the numbers in hex
+/// with `0x` or in decimal
+fn parse_cpuid_override(cpuid_override: &str) -> CpuidOverride {
+    let number = |number: &str| {
+        match number.strip_prefix("0x") {
+            Some(hex) => u32::from_str_radix(hex, 16),
+            None => number.parse(),
+        }
+        .unwrap_or_else(|_| panic!("Invalid number {} in CPUID override", number))
+    };
+
+    let (leaf, assignment) = cpuid_override
+        .split_once(':')
+        .expect("CPUID override must be given as <leaf>[.<sub-leaf>]:<register>=<value>[/<mask>]");
+    let (function, index) = match leaf.split_once('.') {
+        Some((function, index)) => (number(function), number(index)),
+        None => (number(leaf), 0),
+    };
+    let (register, value) = assignment
+        .split_once('=')
+        .expect("CPUID override must assign a register");
+    let (value, mask) = match value.split_once('/') {
+        Some((value, mask)) => (number(value), number(mask)),
+        None => (number(value), u32::MAX),
+    };
+
+    CpuidOverride {
+        function,
+        index,
+        register: match register {
+            "eax" => CpuidRegister::Eax,
+            "ebx" => CpuidRegister::Ebx,
+            "ecx" => CpuidRegister::Ecx,
+            "edx" => CpuidRegister::Edx,
+            _ => panic!("CPUID register must be one of eax, ebx, ecx, edx"),
+        },
+        value,
+        mask,
+    }
+}
+
+/// `sockets=<n>,dies=<n>,cores=<n>,threads=<n>` in any order
+fn cpu_topology(matches: &clap::ArgMatches) -> CpuTopology {
+    let mut topology = CpuTopology::default();
+    if let Some(levels) = matches.value_of("TOPOLOGY") {
+        for level in levels.split(',') {
+            let (name, count) = level
+                .split_once('=')
+                .expect("CPU topology level must be given as <level>=<count>");
+            let count = count
+                .parse::<u32>()
+                .unwrap_or_else(|_| panic!("Invalid count {} in CPU topology", count));
+            match name {
+                "sockets" => topology.sockets = count,
+                "dies" => topology.dies = count,
+                "cores" => topology.cores = count,
+                "threads" => topology.threads = count,
+                _ => panic!("CPU topology level must be one of sockets, dies, cores, threads"),
+            }
+        }
+    }
+
+    topology
+}
+
+/// `cpus=<first>[-<last>][,host_node=<n>][,distances=<d>/<d>/...]`, the
+/// node has the RAM region of its index
+fn parse_numa_node(node: usize, numa: &str) -> NumaNode {
+    let number = |number: &str| {
+        number
+            .parse::<u32>()
+            .unwrap_or_else(|_| panic!("Invalid number {} in NUMA node", number))
+    };
+
+    let mut numa_node = NumaNode {
+        memory: vec![node],
+        ..Default::default()
+    };
+    for setting in numa.split(',') {
+        let (name, value) = setting
+            .split_once('=')
+            .expect("NUMA node setting must be given as <name>=<value>");
+        match name {
+            "cpus" => {
+                numa_node.cpus = match value.split_once('-') {
+                    Some((first, last)) => (number(first)..=number(last)).collect(),
+                    None => vec![number(value)],
+                }
+            }
+            "host_node" => numa_node.host_node = Some(number(value)),
+            "distances" => {
+                numa_node.distances = value
+                    .split('/')
+                    .map(|distance| {
+                        distance
+                            .parse::<u8>()
+                            .unwrap_or_else(|_| panic!("Invalid NUMA distance {}", distance))
+                    })
+                    .collect()
+            }
+            _ => panic!("NUMA node setting must be one of cpus, host_node, distances"),
+        }
+    }
+
+    numa_node
+}
+
+/// `<backing>[,prefault][,mlock][,mergeable]`
+fn parse_ram_options(ram: &str) -> RamOptions {
+    let mut settings = ram.split(',');
+    let mut options = RamOptions {
+        backing: match settings.next().unwrap() {
+            "memfd" => RamBacking::Memfd,
+            "sealed-memfd" => RamBacking::SealedMemfd,
+            "anonymous" => RamBacking::Anonymous,
+            "hugepages-2M" => RamBacking::HugePages(HugePageSize::Size2M),
+            "hugepages-1G" => RamBacking::HugePages(HugePageSize::Size1G),
+            backing => match backing.strip_prefix("file=") {
+                Some(path) => RamBacking::File(PathBuf::from(path)),
+                None => panic!(
+                    "RAM backing must be one of memfd, sealed-memfd, anonymous, hugepages-2M, hugepages-1G, file=<path>"
+                ),
+            },
+        },
+        ..Default::default()
+    };
+    for setting in settings {
+        match setting {
+            "prefault" => options.prefault = true,
+            "mlock" => options.mlock = true,
+            "mergeable" => options.mergeable = true,
+            _ => panic!("RAM option must be one of prefault, mlock, mergeable"),
+        }
+    }
+
+    options
+}
+
+fn print_cpuid(options: VmOptions) -> Result<(), HvError> {
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    {
+        let vm = smolvm::create_vm_with_options(
+            &[GpaSpan {
+                start: 0,
+                size: 2 * 1024 * 1024,
+            }],
+            &options,
+        )?;
+        for entry in vm.get_cpu().lock().unwrap().cpuid()? {
+            println!("{}", entry);
+        }
+    }
+
+    #[cfg(not(all(target_os = "linux", target_arch = "x86_64")))]
+    log::warn!(
+        "CPUID of the {:?} template is supported only on Linux on x86_64",
+        options.cpuid.template
+    );
+
+    Ok(())
+}
+
+fn serve_vhost_user(socket_path: &str, shared_dir: Option<SharedDir>) -> Result<(), HvError> {
+    let shared_dir = shared_dir.expect("The vhost-user backend serves the shared directory");
+
+    #[cfg(target_os = "linux")]
+    {
+        let device = smolvm::virtio::p9::Virtio9p::new(
+            shared_dir.tag,
+            Path::new(shared_dir.path),
+            shared_dir.read_only,
+        )?;
+        smolvm::virtio::vhost_user::VhostUserBackend::serve(
+            Path::new(socket_path),
+            Arc::new(Mutex::new(device)),
+        )?;
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    log::warn!(
+        "Serving {} at {} is supported only on Linux",
+        shared_dir.path,
+        socket_path
+    );
 
     Ok(())
 }
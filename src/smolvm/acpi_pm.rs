@@ -0,0 +1,82 @@
+//! The power management registers of the hardware-reduced ACPI, one byte
+//! each at consecutive ports:
+//!
+//!            80h      40h      20h      10h      08h      04h      02h      01h
+//!  Register  Bit 7    Bit 6    Bit 5    Bit 4    Bit 3    Bit 2    Bit 1    Bit 0
+//!  -------------------------------------------------------------------------------
+//!  Control     0        0      SLP_EN   -------- SLP_TYPx --------   0        0
+//!  Status    WAK_STS    0        0        0        0        0        0        0
+//!  Reset     ------------------------ RESET_VALUE -----------------------------
+//!
+//! The guest takes SLP_TYPx for a sleep state from the `_Sx` objects of the
+//! DSDT and writes it together with SLP_EN. Only S5 (soft off) is supported.
+//! Writing RESET_VALUE from the FADT to the reset register reboots.
+
+use super::bus::{ExitRequest, ExitRequester, IoDevice};
+
+/// The SLP_TYPx of S5 as declared by `\_S5_`
+pub const ACPI_SLP_TYP_S5: u8 = 5;
+
+pub const ACPI_RESET_VALUE: u8 = 1;
+
+const SLP_TYP_SHIFT: u8 = 2;
+const SLP_TYP_MASK: u8 = 0x7;
+const SLP_EN: u8 = 1 << 5;
+
+pub const ACPI_PM_SLEEP_CONTROL_OFFSET: u16 = 0;
+pub const ACPI_PM_SLEEP_STATUS_OFFSET: u16 = 1;
+pub const ACPI_PM_RESET_OFFSET: u16 = 2;
+
+pub struct AcpiPm {
+    base_addr: u16,
+    exit_requester: ExitRequester,
+}
+
+impl AcpiPm {
+    pub const PORT_COUNT: u16 = 3;
+
+    pub fn new(base_addr: u16, exit_requester: ExitRequester) -> Self {
+        Self {
+            base_addr,
+            exit_requester,
+        }
+    }
+
+    fn write_sleep_control(&mut self, value: u8) {
+        if value & SLP_EN == 0 {
+            return;
+        }
+
+        match (value >> SLP_TYP_SHIFT) & SLP_TYP_MASK {
+            ACPI_SLP_TYP_S5 => {
+                log::info!("Guest has entered S5, shutting down");
+                self.exit_requester.request(ExitRequest::Shutdown);
+            }
+            slp_typ => log::warn!("Sleep type {} is not supported", slp_typ),
+        }
+    }
+}
+
+impl IoDevice for AcpiPm {
+    fn io_in(&mut self, port: u16, data: &mut [u8]) {
+        // Nothing to report: the guest never wakes up as it never sleeps
+        data.fill(0);
+
+        if data.len() != 1 {
+            log::warn!("Reading {} bytes from {:#x}", data.len(), port);
+        }
+    }
+
+    fn io_out(&mut self, port: u16, data: &[u8]) {
+        match (port - self.base_addr, data) {
+            (ACPI_PM_SLEEP_CONTROL_OFFSET, [value]) => self.write_sleep_control(*value),
+            // Clearing WAK_STS
+            (ACPI_PM_SLEEP_STATUS_OFFSET, [_]) => {}
+            (ACPI_PM_RESET_OFFSET, [ACPI_RESET_VALUE]) => {
+                log::info!("Guest has asked for a reset");
+                self.exit_requester.request(ExitRequest::Reset);
+            }
+            _ => log::warn!("Writing {:x?} to {:#x}", data, port),
+        }
+    }
+}
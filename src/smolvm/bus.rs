@@ -0,0 +1,446 @@
+//! Routes the port I/O and MMIO exits to the device models.
+//!
+//! The devices are registered for a range of ports or guest physical
+//! addresses and receive the absolute port number or address together
+//! with the data of the access, so the same model can be placed at
+//! different bases (e.g. the 8250 UART at COM1..COM4).
+//!
+//! The hypercalls are routed by their number to the handlers the same way,
+//! and so are the MSR accesses KVM leaves to the VMM on x86_64. The MSRs
+//! nobody handles are counted for the report at the end of the run.
+//!
+//! A device that stops the whole VM (e.g. the guest powering off) posts an
+//! `ExitRequest`, the vCPU loop returns it once the access has been handled.
+//! A request posted from another thread (e.g. a device timer) kicks the vCPU
+//! out of the guest to have it noticed.
+
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+use super::{fdt::FdtNode, Hypercall, IoType, MmIoType, MsrType};
+
+pub trait IoDevice: Send {
+    fn io_in(&mut self, port: u16, data: &mut [u8]);
+    fn io_out(&mut self, port: u16, data: &[u8]);
+
+    /// Back to the power-on state when the VM is reset
+    fn reset(&mut self) {}
+}
+
+pub trait MmIoDevice: Send {
+    fn mmio_read(&mut self, addr: u64, data: &mut [u8]);
+    fn mmio_write(&mut self, addr: u64, data: &[u8]);
+
+    /// Back to the power-on state when the VM is reset
+    fn reset(&mut self) {}
+}
+
+/// Answers the hypercalls of the number it has been added for
+pub trait HypercallHandler: Send {
+    /// `ret` holds the "not supported" error of the platform, the values
+    /// written to it go to the guest registers
+    fn hypercall(&mut self, nr: u64, args: &[u64; 6], ret: &mut [u64]);
+
+    /// Back to the power-on state when the VM is reset
+    fn reset(&mut self) {}
+}
+
+/// Emulates the MSRs it has been added for on x86_64
+pub trait MsrHandler: Send {
+    /// `None` has the guest take a #GP
+    fn rdmsr(&mut self, index: u32) -> Option<u64>;
+    /// `false` has the guest take a #GP
+    fn wrmsr(&mut self, index: u32, value: u64) -> bool;
+
+    /// Back to the power-on state when the VM is reset
+    fn reset(&mut self) {}
+}
+
+/// How often the guest has accessed an MSR neither KVM nor a handler knows
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct UnknownMsr {
+    pub reads: u64,
+    pub writes: u64,
+}
+
+/// What the devices ask the vCPU loop to do on behalf of the guest
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExitRequest {
+    /// The guest has powered the machine off
+    Shutdown,
+    /// The guest has asked for a reboot
+    Reset,
+    /// The guest has asked to end the run with the exit status
+    Exit(i32),
+    /// The guest kernel has reported the pvpanic events
+    Panic(u8),
+    /// The guest has stopped petting the watchdog
+    Watchdog,
+}
+
+/// Makes the vCPU leave the guest, called from the threads other than the
+/// one running the vCPU
+pub type VcpuKick = Arc<dyn Fn() + Send + Sync>;
+
+/// Given to the devices that can stop the VM, the clones share the request
+#[derive(Clone, Default)]
+pub struct ExitRequester {
+    request: Arc<Mutex<Option<ExitRequest>>>,
+    kick: Arc<Mutex<Option<VcpuKick>>>,
+}
+
+impl ExitRequester {
+    pub fn request(&self, request: ExitRequest) {
+        *self.request.lock().unwrap() = Some(request);
+
+        let kick = self.kick.lock().unwrap().clone();
+        if let Some(kick) = kick {
+            kick();
+        }
+    }
+
+    pub fn take(&self) -> Option<ExitRequest> {
+        self.request.lock().unwrap().take()
+    }
+
+    /// Set by the vCPU loop while the vCPU runs
+    pub fn set_vcpu_kick(&self, kick: Option<VcpuKick>) {
+        *self.kick.lock().unwrap() = kick;
+    }
+}
+
+struct IoRange {
+    start: u16,
+    size: u16,
+    device: Arc<Mutex<dyn IoDevice>>,
+}
+
+struct MmIoRange {
+    start: u64,
+    size: u64,
+    device: Arc<Mutex<dyn MmIoDevice>>,
+}
+
+struct HypercallEntry {
+    nr: u64,
+    handler: Arc<Mutex<dyn HypercallHandler>>,
+}
+
+struct MsrEntry {
+    index: u32,
+    handler: Arc<Mutex<dyn MsrHandler>>,
+}
+
+#[derive(Default)]
+pub struct Bus {
+    io_ranges: Vec<IoRange>,
+    mmio_ranges: Vec<MmIoRange>,
+    hypercalls: Vec<HypercallEntry>,
+    msrs: Vec<MsrEntry>,
+    /// By the MSR index, kept across the resets
+    unknown_msrs: BTreeMap<u32, UnknownMsr>,
+    cmd_line_extras: Vec<String>,
+    fdt_nodes: Vec<FdtNode>,
+    exit_requester: ExitRequester,
+}
+
+impl Bus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_io_device(&mut self, start: u16, size: u16, device: Arc<Mutex<dyn IoDevice>>) {
+        let end = start as u32 + size as u32;
+        for range in &self.io_ranges {
+            if (start as u32) < range.start as u32 + range.size as u32 && (range.start as u32) < end
+            {
+                panic!(
+                    "Port range [{:#x}; {:#x}) overlaps with [{:#x}; {:#x})",
+                    start,
+                    end,
+                    range.start,
+                    range.start as u32 + range.size as u32
+                );
+            }
+        }
+
+        self.io_ranges.push(IoRange {
+            start,
+            size,
+            device,
+        });
+    }
+
+    pub fn add_mmio_device(&mut self, start: u64, size: u64, device: Arc<Mutex<dyn MmIoDevice>>) {
+        let end = start + size;
+        for range in &self.mmio_ranges {
+            if start < range.start + range.size && range.start < end {
+                panic!(
+                    "MMIO range [{:#x}; {:#x}) overlaps with [{:#x}; {:#x})",
+                    start,
+                    end,
+                    range.start,
+                    range.start + range.size
+                );
+            }
+        }
+
+        self.mmio_ranges.push(MmIoRange {
+            start,
+            size,
+            device,
+        });
+    }
+
+    pub fn add_hypercall_handler(&mut self, nr: u64, handler: Arc<Mutex<dyn HypercallHandler>>) {
+        if self.hypercalls.iter().any(|entry| entry.nr == nr) {
+            panic!("Hypercall {:#x} has a handler already", nr);
+        }
+
+        self.hypercalls.push(HypercallEntry { nr, handler });
+    }
+
+    /// The numbers of the hypercalls with a handler
+    pub fn hypercall_numbers(&self) -> Vec<u64> {
+        self.hypercalls.iter().map(|entry| entry.nr).collect()
+    }
+
+    pub fn add_msr_handler(&mut self, index: u32, handler: Arc<Mutex<dyn MsrHandler>>) {
+        if self.msrs.iter().any(|entry| entry.index == index) {
+            panic!("MSR {:#x} has a handler already", index);
+        }
+
+        self.msrs.push(MsrEntry { index, handler });
+    }
+
+    /// The indices of the MSRs with a handler
+    pub fn msr_indices(&self) -> Vec<u32> {
+        self.msrs.iter().map(|entry| entry.index).collect()
+    }
+
+    /// The MSRs the guest has accessed in vain since the VM was created
+    pub fn unknown_msrs(&self) -> &BTreeMap<u32, UnknownMsr> {
+        &self.unknown_msrs
+    }
+
+    /// Parameters the devices need the guest kernel to see on its command line,
+    /// e.g. the virtio-mmio transports on x86_64 where there is no device tree.
+    pub fn add_cmd_line_extra(&mut self, extra: String) {
+        self.cmd_line_extras.push(extra);
+    }
+
+    pub fn cmd_line_extras(&self) -> &[String] {
+        &self.cmd_line_extras
+    }
+
+    /// Nodes of the devices to add to the device tree on aarch64, the ones
+    /// the `virt` machine of `qemu` does not have
+    pub fn add_fdt_node(&mut self, node: FdtNode) {
+        self.fdt_nodes.push(node);
+    }
+
+    pub fn fdt_nodes(&self) -> &[FdtNode] {
+        &self.fdt_nodes
+    }
+
+    pub fn exit_requester(&self) -> ExitRequester {
+        self.exit_requester.clone()
+    }
+
+    /// Resets every device once, even the one registered at several ranges
+    /// or as both port I/O and MMIO device, and drops the pending request
+    pub fn reset(&mut self) {
+        let mut reset_devices: Vec<*const ()> = Vec::new();
+        let mut first_time = |device: *const ()| {
+            let first = !reset_devices.contains(&device);
+            reset_devices.push(device);
+            first
+        };
+
+        for range in &self.io_ranges {
+            if first_time(Arc::as_ptr(&range.device) as *const ()) {
+                range.device.lock().unwrap().reset();
+            }
+        }
+
+        for range in &self.mmio_ranges {
+            if first_time(Arc::as_ptr(&range.device) as *const ()) {
+                range.device.lock().unwrap().reset();
+            }
+        }
+
+        for entry in &self.hypercalls {
+            if first_time(Arc::as_ptr(&entry.handler) as *const ()) {
+                entry.handler.lock().unwrap().reset();
+            }
+        }
+
+        for entry in &self.msrs {
+            if first_time(Arc::as_ptr(&entry.handler) as *const ()) {
+                entry.handler.lock().unwrap().reset();
+            }
+        }
+
+        // E.g. the timer of a device that has fired again meanwhile
+        self.exit_requester.take();
+    }
+
+    pub fn handle_io(&mut self, io_type: IoType) {
+        match io_type {
+            IoType::ByteIn(port, data) => self.io_in(port, std::slice::from_mut(data)),
+            IoType::ByteOut(port, data) => self.io_out(port, &[data]),
+            IoType::WordIn(port, data) => {
+                let mut bytes = data.to_le_bytes();
+                self.io_in(port, &mut bytes);
+                *data = u16::from_le_bytes(bytes);
+            }
+            IoType::WordOut(port, data) => self.io_out(port, &data.to_le_bytes()),
+            IoType::DoubleWordIn(port, data) => {
+                let mut bytes = data.to_le_bytes();
+                self.io_in(port, &mut bytes);
+                *data = u32::from_le_bytes(bytes);
+            }
+            IoType::DoubleWordOut(port, data) => self.io_out(port, &data.to_le_bytes()),
+            IoType::StringIn(port, size, data) => {
+                for element in data.chunks_mut(size as usize) {
+                    self.io_in(port, element);
+                }
+            }
+            IoType::StringOut(port, size, data) => {
+                for element in data.chunks(size as usize) {
+                    self.io_out(port, element);
+                }
+            }
+        }
+    }
+
+    pub fn handle_mmio(&mut self, mmio_type: MmIoType) {
+        match mmio_type {
+            MmIoType::ByteIn(addr, data) => self.mmio_read(addr, std::slice::from_mut(data)),
+            MmIoType::ByteOut(addr, data) => self.mmio_write(addr, &[data]),
+            MmIoType::WordIn(addr, data) => {
+                let mut bytes = data.to_le_bytes();
+                self.mmio_read(addr, &mut bytes);
+                *data = u16::from_le_bytes(bytes);
+            }
+            MmIoType::WordOut(addr, data) => self.mmio_write(addr, &data.to_le_bytes()),
+            MmIoType::DoubleWordIn(addr, data) => {
+                let mut bytes = data.to_le_bytes();
+                self.mmio_read(addr, &mut bytes);
+                *data = u32::from_le_bytes(bytes);
+            }
+            MmIoType::DoubleWordOut(addr, data) => self.mmio_write(addr, &data.to_le_bytes()),
+            MmIoType::QuadWordIn(addr, data) => {
+                let mut bytes = data.to_le_bytes();
+                self.mmio_read(addr, &mut bytes);
+                *data = u64::from_le_bytes(bytes);
+            }
+            MmIoType::QuadWordOut(addr, data) => self.mmio_write(addr, &data.to_le_bytes()),
+        }
+    }
+
+    pub fn handle_hypercall(&mut self, hypercall: Hypercall) {
+        match self
+            .hypercalls
+            .iter()
+            .find(|entry| entry.nr == hypercall.nr)
+        {
+            Some(entry) => entry.handler.lock().unwrap().hypercall(
+                hypercall.nr,
+                &hypercall.args,
+                hypercall.ret,
+            ),
+            None => log::warn!("Unhandled hypercall {:#x}", hypercall.nr),
+        }
+    }
+
+    /// The guest takes a #GP unless the handler accepts the access
+    pub fn handle_msr(&mut self, msr_type: MsrType) {
+        match msr_type {
+            MsrType::Read(index, data, error) => {
+                let value = match self.find_msr_handler(index) {
+                    Some(handler) => handler.lock().unwrap().rdmsr(index),
+                    None => {
+                        log::debug!("Reading from the unknown MSR {:#x}", index);
+                        self.unknown_msrs.entry(index).or_default().reads += 1;
+                        None
+                    }
+                };
+
+                *error = value.is_none();
+                *data = value.unwrap_or_default();
+            }
+            MsrType::Write(index, data, error) => {
+                let accepted = match self.find_msr_handler(index) {
+                    Some(handler) => handler.lock().unwrap().wrmsr(index, data),
+                    None => {
+                        log::debug!("Writing {:#x} to the unknown MSR {:#x}", data, index);
+                        self.unknown_msrs.entry(index).or_default().writes += 1;
+                        false
+                    }
+                };
+
+                *error = !accepted;
+            }
+        }
+    }
+
+    pub fn io_in(&mut self, port: u16, data: &mut [u8]) {
+        if let Some(device) = self.find_io_device(port) {
+            device.lock().unwrap().io_in(port, data);
+        } else {
+            log::warn!("Reading from port {:#x}, no device", port);
+        }
+    }
+
+    pub fn io_out(&mut self, port: u16, data: &[u8]) {
+        if let Some(device) = self.find_io_device(port) {
+            device.lock().unwrap().io_out(port, data);
+        } else {
+            log::warn!("Writing {:x?} to port {:#x}, no device", data, port);
+        }
+    }
+
+    pub fn mmio_read(&mut self, addr: u64, data: &mut [u8]) {
+        if let Some(device) = self.find_mmio_device(addr) {
+            device.lock().unwrap().mmio_read(addr, data);
+        } else {
+            log::warn!("Unknown MMIO read from 0x{:x}", addr);
+        }
+    }
+
+    pub fn mmio_write(&mut self, addr: u64, data: &[u8]) {
+        if let Some(device) = self.find_mmio_device(addr) {
+            device.lock().unwrap().mmio_write(addr, data);
+        } else {
+            log::warn!("Unknown MMIO write to 0x{:x}", addr);
+        }
+    }
+
+    pub fn has_mmio_device(&self, addr: u64) -> bool {
+        self.find_mmio_device(addr).is_some()
+    }
+
+    fn find_io_device(&self, port: u16) -> Option<Arc<Mutex<dyn IoDevice>>> {
+        self.io_ranges
+            .iter()
+            .find(|range| range.start <= port && (port - range.start) < range.size)
+            .map(|range| range.device.clone())
+    }
+
+    fn find_mmio_device(&self, addr: u64) -> Option<Arc<Mutex<dyn MmIoDevice>>> {
+        self.mmio_ranges
+            .iter()
+            .find(|range| range.start <= addr && (addr - range.start) < range.size)
+            .map(|range| range.device.clone())
+    }
+
+    fn find_msr_handler(&self, index: u32) -> Option<Arc<Mutex<dyn MsrHandler>>> {
+        self.msrs
+            .iter()
+            .find(|entry| entry.index == index)
+            .map(|entry| entry.handler.clone())
+    }
+}
@@ -0,0 +1,584 @@
+//! The MC146818 real-time clock with its CMOS memory, the wall clock of
+//! the PC. The guest selects the register through the index port and
+//! accesses it through the data port:
+//!
+//!   70h  index (bit 7 masks the NMI, ignored)
+//!   71h  data
+//!
+//!   00h seconds  01h seconds alarm  02h minutes  03h minutes alarm
+//!   04h hours    05h hours alarm    06h weekday  07h day  08h month
+//!   09h year     0Ah A  UIP, divider, rate of the periodic interrupt
+//!                0Bh B  SET, PIE, AIE, UIE, binary, 24 hours
+//!                0Ch C  IRQF, PF, AF, UF, cleared on read
+//!                0Dh D  VRT, the battery is fine
+//!   32h century (the FADT points there)
+//!
+//! The time runs from the host clock or from a fixed epoch so runs can be
+//! reproduced. The rest of the 128 bytes is the NVRAM, the firmware way
+//! of reporting the memory size is filled in:
+//!
+//!   15h-16h  base memory in KiB        17h-18h, 30h-31h  1MiB..64MiB in KiB
+//!   34h-35h  16MiB..4GiB in 64KiB      5Bh-5Dh           above 4GiB in 64KiB
+//!
+//! With an irqchip, a timer thread raises IRQ 8 for the periodic, alarm
+//! and update-ended interrupts the guest has enabled, those set their flags
+//! in register C too.
+
+use std::{
+    sync::{Arc, Condvar, Mutex, Weak},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use super::{bus::IoDevice, GpaSpan, IrqChip};
+
+pub const CMOS_PORT: u16 = 0x70;
+pub const CMOS_PORT_COUNT: u16 = 2;
+pub const CMOS_IRQ: u32 = 8;
+
+pub const RTC_CENTURY: u8 = 0x32;
+
+const RTC_SECONDS: u8 = 0x00;
+const RTC_SECONDS_ALARM: u8 = 0x01;
+const RTC_MINUTES: u8 = 0x02;
+const RTC_MINUTES_ALARM: u8 = 0x03;
+const RTC_HOURS: u8 = 0x04;
+const RTC_HOURS_ALARM: u8 = 0x05;
+const RTC_DAY_OF_WEEK: u8 = 0x06;
+const RTC_DAY_OF_MONTH: u8 = 0x07;
+const RTC_MONTH: u8 = 0x08;
+const RTC_YEAR: u8 = 0x09;
+const RTC_REG_A: u8 = 0x0a;
+const RTC_REG_B: u8 = 0x0b;
+const RTC_REG_C: u8 = 0x0c;
+const RTC_REG_D: u8 = 0x0d;
+
+const RTC_TIME_REGISTERS: [u8; 8] = [
+    RTC_SECONDS,
+    RTC_MINUTES,
+    RTC_HOURS,
+    RTC_DAY_OF_WEEK,
+    RTC_DAY_OF_MONTH,
+    RTC_MONTH,
+    RTC_YEAR,
+    RTC_CENTURY,
+];
+
+const REG_A_UIP: u8 = 0x80;
+const REG_A_RATE_MASK: u8 = 0x0f;
+/// The 32.768kHz time base, 1024Hz periodic rate
+const REG_A_DEFAULT: u8 = 0x26;
+
+const REG_B_SET: u8 = 0x80;
+const REG_B_PIE: u8 = 0x40;
+const REG_B_AIE: u8 = 0x20;
+const REG_B_UIE: u8 = 0x10;
+const REG_B_SQWE: u8 = 0x08;
+const REG_B_DM_BINARY: u8 = 0x04;
+const REG_B_24H: u8 = 0x02;
+
+const REG_C_IRQF: u8 = 0x80;
+const REG_C_PF: u8 = 0x40;
+const REG_C_AF: u8 = 0x20;
+const REG_C_UF: u8 = 0x10;
+
+const REG_D_VRT: u8 = 0x80;
+
+/// An alarm register matching any value
+const ALARM_DONT_CARE: u8 = 0xc0;
+
+const HOURS_PM: u8 = 0x80;
+
+/// UIP is set that long before the update and stays set during it
+const UPDATE_SETUP: Duration = Duration::from_micros(244);
+const UPDATE_CYCLE: Duration = Duration::from_micros(1984);
+
+/// Checks on the VM going away at least that often
+const TIMER_MAX_WAIT: Duration = Duration::from_secs(1);
+
+const NVRAM_SIZE: usize = 128;
+
+const KIB: u64 = 1024;
+const MIB: u64 = 1024 * KIB;
+const GIB: u64 = 1024 * MIB;
+
+fn to_bcd(value: u8) -> u8 {
+    ((value / 10) << 4) | (value % 10)
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0f)
+}
+
+/// Days since 1970-01-01 of the date in the proleptic Gregorian calendar
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * if month > 2 { month - 3 } else { month + 9 } + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+    era * 146097 + day_of_era - 719468
+}
+
+/// The year, the month and the day of the days since 1970-01-01
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = if days >= 0 { days } else { days - 146096 } / 146097;
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day)
+}
+
+struct RtcState {
+    index: u8,
+    nvram: [u8; NVRAM_SIZE],
+    /// The guest time since the epoch at `clock_start`
+    clock_base: Duration,
+    clock_start: Instant,
+    /// The periodic interrupt counts from there
+    periodic_start: Instant,
+    periodic_ticks: u64,
+    /// The second the last update-ended event was for
+    last_update: u64,
+}
+
+impl RtcState {
+    fn now(&self) -> Duration {
+        self.clock_base + self.clock_start.elapsed()
+    }
+
+    fn is_set(&self) -> bool {
+        self.nvram[RTC_REG_B as usize] & REG_B_SET != 0
+    }
+
+    fn encode(&self, value: u8) -> u8 {
+        if self.nvram[RTC_REG_B as usize] & REG_B_DM_BINARY != 0 {
+            value
+        } else {
+            to_bcd(value)
+        }
+    }
+
+    fn decode(&self, value: u8) -> u8 {
+        if self.nvram[RTC_REG_B as usize] & REG_B_DM_BINARY != 0 {
+            value
+        } else {
+            from_bcd(value)
+        }
+    }
+
+    fn encode_hours(&self, hours: u8) -> u8 {
+        if self.nvram[RTC_REG_B as usize] & REG_B_24H != 0 {
+            self.encode(hours)
+        } else {
+            let pm = if hours >= 12 { HOURS_PM } else { 0 };
+            let hours = match hours % 12 {
+                0 => 12,
+                hours => hours,
+            };
+            self.encode(hours) | pm
+        }
+    }
+
+    fn decode_hours(&self, hours: u8) -> u8 {
+        if self.nvram[RTC_REG_B as usize] & REG_B_24H != 0 {
+            self.decode(hours)
+        } else {
+            let pm = if hours & HOURS_PM != 0 { 12 } else { 0 };
+            self.decode(hours & !HOURS_PM) % 12 + pm
+        }
+    }
+
+    /// Brings the time registers up to date unless the guest is setting them
+    fn refresh(&mut self) {
+        if self.is_set() {
+            return;
+        }
+
+        let seconds = self.now().as_secs() as i64;
+        let days = seconds / 86400;
+        let time_of_day = seconds % 86400;
+        let (year, month, day) = civil_from_days(days);
+
+        let fields = [
+            (RTC_SECONDS, self.encode((time_of_day % 60) as u8)),
+            (RTC_MINUTES, self.encode((time_of_day / 60 % 60) as u8)),
+            (RTC_HOURS, self.encode_hours((time_of_day / 3600) as u8)),
+            // Sunday is 1, the epoch was a Thursday
+            (RTC_DAY_OF_WEEK, self.encode(((days + 4) % 7 + 1) as u8)),
+            (RTC_DAY_OF_MONTH, self.encode(day as u8)),
+            (RTC_MONTH, self.encode(month as u8)),
+            (RTC_YEAR, self.encode((year % 100) as u8)),
+            (RTC_CENTURY, self.encode((year / 100) as u8)),
+        ];
+        for (index, value) in fields {
+            self.nvram[index as usize] = value;
+        }
+    }
+
+    /// Sets the clock to the time the guest has written to the registers
+    fn set_clock(&mut self) {
+        let field = |index: u8| self.decode(self.nvram[index as usize]) as i64;
+
+        let year = field(RTC_CENTURY) * 100 + field(RTC_YEAR);
+        let days = days_from_civil(year, field(RTC_MONTH), field(RTC_DAY_OF_MONTH));
+        let hours = self.decode_hours(self.nvram[RTC_HOURS as usize]) as i64;
+        let seconds = days * 86400 + hours * 3600 + field(RTC_MINUTES) * 60 + field(RTC_SECONDS);
+
+        log::info!(
+            "Guest has set the RTC to {} seconds past the epoch",
+            seconds
+        );
+
+        self.clock_base = Duration::from_secs(seconds.max(0) as u64);
+        self.clock_start = Instant::now();
+        self.last_update = self.clock_base.as_secs();
+    }
+
+    fn update_in_progress(&self) -> bool {
+        if self.is_set() {
+            return false;
+        }
+
+        let into_second = Duration::from_nanos(self.now().subsec_nanos() as u64);
+        into_second < UPDATE_CYCLE || into_second >= Duration::from_secs(1) - UPDATE_SETUP
+    }
+
+    /// `None` when the periodic interrupt is off
+    fn periodic_period(&self) -> Option<Duration> {
+        let rate = self.nvram[RTC_REG_A as usize] & REG_A_RATE_MASK;
+        let shift = match rate {
+            0 => return None,
+            // 256Hz and 128Hz rather than 8kHz and 4kHz as the table goes
+            1 | 2 => rate + 6,
+            _ => rate - 1,
+        };
+
+        Some(Duration::from_nanos((1_000_000_000_u64 << shift) / 32768))
+    }
+
+    fn restart_periodic(&mut self) {
+        self.periodic_start = Instant::now();
+        self.periodic_ticks = 0;
+    }
+
+    /// How long until the next interrupt the guest has enabled
+    fn next_event(&self) -> Option<Duration> {
+        let enabled = self.nvram[RTC_REG_B as usize];
+        let mut next: Option<Duration> = None;
+
+        if enabled & REG_B_PIE != 0 {
+            if let Some(period) = self.periodic_period() {
+                let tick = self.periodic_start
+                    + Duration::from_nanos(period.as_nanos() as u64 * (self.periodic_ticks + 1));
+                next = Some(tick.saturating_duration_since(Instant::now()));
+            }
+        }
+
+        if enabled & (REG_B_AIE | REG_B_UIE) != 0 && !self.is_set() {
+            let into_second = Duration::from_nanos(self.now().subsec_nanos() as u64);
+            let update = Duration::from_secs(1) - into_second;
+            next = Some(next.map_or(update, |next| next.min(update)));
+        }
+
+        next
+    }
+
+    /// Sets the flags of the events that have happened, true when that
+    /// raises the interrupt
+    fn tick(&mut self) -> bool {
+        let enabled = self.nvram[RTC_REG_B as usize];
+        let mut flags = 0;
+
+        if enabled & REG_B_PIE != 0 {
+            if let Some(period) = self.periodic_period() {
+                let ticks = (self.periodic_start.elapsed().as_nanos() / period.as_nanos()) as u64;
+                if ticks > self.periodic_ticks {
+                    self.periodic_ticks = ticks;
+                    flags |= REG_C_PF;
+                }
+            }
+        }
+
+        if enabled & (REG_B_AIE | REG_B_UIE) != 0 && !self.is_set() {
+            let second = self.now().as_secs();
+            if second != self.last_update {
+                self.last_update = second;
+                flags |= REG_C_UF;
+
+                self.refresh();
+                let alarm = [
+                    (RTC_SECONDS_ALARM, RTC_SECONDS),
+                    (RTC_MINUTES_ALARM, RTC_MINUTES),
+                    (RTC_HOURS_ALARM, RTC_HOURS),
+                ]
+                .iter()
+                .all(|(alarm, time)| {
+                    let alarm = self.nvram[*alarm as usize];
+                    alarm & ALARM_DONT_CARE == ALARM_DONT_CARE
+                        || alarm == self.nvram[*time as usize]
+                });
+                if alarm {
+                    flags |= REG_C_AF;
+                }
+            }
+        }
+
+        let reg_c = &mut self.nvram[RTC_REG_C as usize];
+        *reg_c |= flags;
+
+        // The line stays asserted until the guest reads register C
+        let pending = *reg_c & enabled & (REG_C_PF | REG_C_AF | REG_C_UF) != 0;
+        if pending && *reg_c & REG_C_IRQF == 0 {
+            *reg_c |= REG_C_IRQF;
+            return true;
+        }
+
+        false
+    }
+
+    fn read(&mut self, index: u8) -> u8 {
+        match index {
+            RTC_REG_A => {
+                let uip = if self.update_in_progress() {
+                    REG_A_UIP
+                } else {
+                    0
+                };
+                self.nvram[RTC_REG_A as usize] & !REG_A_UIP | uip
+            }
+            RTC_REG_C => std::mem::take(&mut self.nvram[RTC_REG_C as usize]),
+            _ => {
+                if RTC_TIME_REGISTERS.contains(&index) {
+                    self.refresh();
+                }
+                self.nvram[index as usize]
+            }
+        }
+    }
+
+    fn write(&mut self, index: u8, data: u8) {
+        match index {
+            RTC_REG_A => {
+                self.nvram[RTC_REG_A as usize] = data & !REG_A_UIP;
+                self.restart_periodic();
+            }
+            RTC_REG_B => {
+                let was_set = self.is_set();
+                // The registers keep the time they have had when the
+                // guest started setting them
+                if data & REG_B_SET != 0 && !was_set {
+                    self.refresh();
+                }
+
+                let data = if data & REG_B_SET != 0 {
+                    data & !REG_B_UIE
+                } else {
+                    data
+                };
+                let old = std::mem::replace(&mut self.nvram[RTC_REG_B as usize], data);
+
+                if data & REG_B_SET == 0 && was_set {
+                    self.set_clock();
+                }
+                if (old ^ data) & REG_B_PIE != 0 {
+                    self.restart_periodic();
+                }
+            }
+            RTC_REG_C | RTC_REG_D => {}
+            _ if RTC_TIME_REGISTERS.contains(&index) => {
+                if self.is_set() {
+                    self.nvram[index as usize] = data;
+                } else {
+                    self.refresh();
+                    self.nvram[index as usize] = data;
+                    self.set_clock();
+                }
+            }
+            _ => self.nvram[index as usize] = data,
+        }
+    }
+}
+
+struct RtcShared {
+    state: Mutex<RtcState>,
+    /// Wakes the timer thread up when the guest changes the interrupts
+    changed: Condvar,
+}
+
+pub struct CmosRtc {
+    shared: Arc<RtcShared>,
+}
+
+impl CmosRtc {
+    /// The clock starts at `epoch` seconds past 1970-01-01 if given, at the
+    /// host time otherwise. Without the irqchip there are no interrupts.
+    pub fn new(ram: &[GpaSpan], epoch: Option<u64>, irq_chip: Option<Arc<dyn IrqChip>>) -> Self {
+        let clock_base = match epoch {
+            Some(epoch) => Duration::from_secs(epoch),
+            None => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default(),
+        };
+
+        let mut nvram = [0_u8; NVRAM_SIZE];
+        nvram[RTC_REG_A as usize] = REG_A_DEFAULT;
+        nvram[RTC_REG_B as usize] = REG_B_24H;
+        nvram[RTC_REG_D as usize] = REG_D_VRT;
+
+        let ram_between = |start: u64, end: u64| {
+            ram.iter()
+                .map(|span| {
+                    let span_end = span.start + span.size as u64;
+                    span_end.min(end).saturating_sub(span.start.max(start))
+                })
+                .sum::<u64>()
+        };
+        let mut set_u16 = |index: usize, value: u64| {
+            let value = value.min(0xffff) as u16;
+            nvram[index..index + 2].copy_from_slice(&value.to_le_bytes());
+        };
+
+        set_u16(0x15, 640);
+        let extended = ram_between(MIB, 64 * MIB) / KIB;
+        set_u16(0x17, extended);
+        set_u16(0x30, extended);
+        set_u16(0x34, ram_between(16 * MIB, 4 * GIB) / (64 * KIB));
+        let high = (ram_between(4 * GIB, u64::MAX) / (64 * KIB)).min(0xff_ffff) as u32;
+        nvram[0x5b..0x5e].copy_from_slice(&high.to_le_bytes()[..3]);
+
+        let now = Instant::now();
+        let shared = Arc::new(RtcShared {
+            state: Mutex::new(RtcState {
+                index: 0,
+                nvram,
+                clock_base,
+                clock_start: now,
+                periodic_start: now,
+                periodic_ticks: 0,
+                last_update: clock_base.as_secs(),
+            }),
+            changed: Condvar::new(),
+        });
+
+        if let Some(irq_chip) = irq_chip {
+            let shared = Arc::downgrade(&shared);
+            std::thread::spawn(move || Self::run_timer(shared, irq_chip));
+        }
+
+        Self { shared }
+    }
+
+    fn run_timer(shared: Weak<RtcShared>, irq_chip: Arc<dyn IrqChip>) {
+        while let Some(shared) = shared.upgrade() {
+            let state = shared.state.lock().unwrap();
+            let wait = state
+                .next_event()
+                .map_or(TIMER_MAX_WAIT, |next| next.min(TIMER_MAX_WAIT));
+
+            let (mut state, _) = shared.changed.wait_timeout(state, wait).unwrap();
+            if state.tick() {
+                irq_chip.pulse_irq(CMOS_IRQ);
+            }
+        }
+    }
+}
+
+impl IoDevice for CmosRtc {
+    fn io_in(&mut self, port: u16, data: &mut [u8]) {
+        let mut state = self.shared.state.lock().unwrap();
+        data.fill(0);
+        data[0] = if port == CMOS_PORT {
+            state.index
+        } else {
+            let index = state.index;
+            state.read(index)
+        };
+    }
+
+    fn io_out(&mut self, port: u16, data: &[u8]) {
+        let mut state = self.shared.state.lock().unwrap();
+        if port == CMOS_PORT {
+            state.index = data[0] & (NVRAM_SIZE as u8 - 1);
+        } else {
+            let index = state.index;
+            state.write(index, data[0]);
+            self.shared.changed.notify_one();
+        }
+    }
+
+    /// The clock and the NVRAM are battery-backed, only the interrupts are
+    /// turned off
+    fn reset(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.index = 0;
+        state.nvram[RTC_REG_B as usize] &= !(REG_B_PIE | REG_B_AIE | REG_B_UIE | REG_B_SQWE);
+        state.nvram[RTC_REG_C as usize] = 0;
+        self.shared.changed.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_civil_days() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(days_from_civil(2000, 3, 1), 11017);
+        assert_eq!(civil_from_days(11017), (2000, 3, 1));
+        assert_eq!(civil_from_days(days_from_civil(2024, 2, 29)), (2024, 2, 29));
+    }
+
+    #[test]
+    fn test_fixed_epoch() {
+        // 2021-09-01 12:34:56, a Wednesday
+        let mut rtc = CmosRtc::new(&[], Some(1_630_499_696), None);
+        let mut read = |index: u8| {
+            let mut data = [0_u8];
+            rtc.io_out(CMOS_PORT, &[index]);
+            rtc.io_in(CMOS_PORT + 1, &mut data);
+            data[0]
+        };
+
+        assert_eq!(read(RTC_HOURS), 0x12);
+        assert_eq!(read(RTC_MINUTES), 0x34);
+        assert_eq!(read(RTC_DAY_OF_WEEK), 4);
+        assert_eq!(read(RTC_DAY_OF_MONTH), 0x01);
+        assert_eq!(read(RTC_MONTH), 0x09);
+        assert_eq!(read(RTC_YEAR), 0x21);
+        assert_eq!(read(RTC_CENTURY), 0x20);
+        assert_eq!(read(RTC_REG_D), REG_D_VRT);
+    }
+
+    #[test]
+    fn test_periodic_period() {
+        let rtc = CmosRtc::new(&[], None, None);
+        let mut state = rtc.shared.state.lock().unwrap();
+        let mut period = |rate: u8| {
+            state.nvram[RTC_REG_A as usize] = (REG_A_DEFAULT & !REG_A_RATE_MASK) | rate;
+            state.periodic_period()
+        };
+
+        assert_eq!(period(0), None);
+        for (rate, hz) in [(1, 256), (2, 128), (3, 8192), (6, 1024), (15, 2)].iter() {
+            assert_eq!(
+                period(*rate),
+                Some(Duration::from_nanos(1_000_000_000 / hz)),
+                "Rate {}",
+                rate
+            );
+        }
+    }
+}
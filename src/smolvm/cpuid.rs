@@ -0,0 +1,523 @@
+//! The CPUID the guest sees on x86_64. KVM reports everything it can
+//! virtualize on the host, which differs from one host to the other, the
+//! policy narrows that down:
+//!
+//! - the template keeps the ISA features of an x86-64 microarchitecture
+//!   level of the psABI, the system features (e.g. SMEP, the speculation
+//!   controls) pass through when the host has them;
+//! - the hypervisor leaves of KVM move from 0x40000000 to 0x40000100 to
+//!   make room for the signature of smolvm, both KVM and the Linux guest
+//!   look for theirs at every multiple of 0x100;
+//! - the APIC ID and the topology leaves describe the vCPU, see `topology`;
+//! - the overrides come last, they can add the leaves KVM does not have.
+
+use std::{convert::TryInto, fmt};
+
+use super::topology::CpuTopology;
+
+/// `KVM_CPUID_FLAG_SIGNIFCANT_INDEX`
+pub const CPUID_FLAG_SIGNIFICANT_INDEX: u32 = 1;
+
+/// The function 0x40000000 answers with
+pub const HYPERVISOR_SIGNATURE: &[u8; 12] = b"SmolVMSmolVM";
+
+const HYPERVISOR_BASE: u32 = 0x4000_0000;
+/// Where the leaves of KVM move to
+const KVM_HYPERVISOR_BASE: u32 = 0x4000_0100;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CpuidEntry {
+    pub function: u32,
+    pub index: u32,
+    /// `CPUID_FLAG_SIGNIFICANT_INDEX` if the function has sub-leaves
+    pub flags: u32,
+    pub eax: u32,
+    pub ebx: u32,
+    pub ecx: u32,
+    pub edx: u32,
+}
+
+impl CpuidEntry {
+    fn register(&mut self, register: CpuidRegister) -> &mut u32 {
+        match register {
+            CpuidRegister::Eax => &mut self.eax,
+            CpuidRegister::Ebx => &mut self.ebx,
+            CpuidRegister::Ecx => &mut self.ecx,
+            CpuidRegister::Edx => &mut self.edx,
+        }
+    }
+}
+
+impl fmt::Display for CpuidEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:#010x}.{:<2} eax={:08x} ebx={:08x} ecx={:08x} edx={:08x}",
+            self.function, self.index, self.eax, self.ebx, self.ecx, self.edx
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CpuidRegister {
+    Eax,
+    Ebx,
+    Ecx,
+    Edx,
+}
+
+/// The features the guest gets at most
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum CpuidTemplate {
+    /// Everything KVM supports on the host
+    #[default]
+    Host,
+    /// x86-64-v2: up to SSE4.2 and POPCNT
+    X86_64V2,
+    /// x86-64-v3: up to AVX2, BMI2 and FMA
+    X86_64V3,
+}
+
+/// Replaces the bits of the mask in the register of the leaf
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CpuidOverride {
+    pub function: u32,
+    pub index: u32,
+    pub register: CpuidRegister,
+    pub value: u32,
+    pub mask: u32,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct CpuidPolicy {
+    pub template: CpuidTemplate,
+    pub overrides: Vec<CpuidOverride>,
+}
+
+struct Feature {
+    name: &'static str,
+    function: u32,
+    register: CpuidRegister,
+    bit: u8,
+}
+
+const fn feature(name: &'static str, function: u32, register: CpuidRegister, bit: u8) -> Feature {
+    Feature {
+        name,
+        function,
+        register,
+        bit,
+    }
+}
+
+/// The registers of the feature flags the templates filter, all at the
+/// index 0
+const FEATURE_REGISTERS: [(u32, CpuidRegister); 7] = [
+    (0x1, CpuidRegister::Ecx),
+    (0x1, CpuidRegister::Edx),
+    (0x7, CpuidRegister::Ebx),
+    (0x7, CpuidRegister::Ecx),
+    (0x7, CpuidRegister::Edx),
+    (0x8000_0001, CpuidRegister::Ecx),
+    (0x8000_0001, CpuidRegister::Edx),
+];
+
+/// x86-64 itself and the system features, kept if the host has them
+const BASE_FEATURES: &[Feature] = &[
+    feature("fpu", 0x1, CpuidRegister::Edx, 0),
+    feature("vme", 0x1, CpuidRegister::Edx, 1),
+    feature("de", 0x1, CpuidRegister::Edx, 2),
+    feature("pse", 0x1, CpuidRegister::Edx, 3),
+    feature("tsc", 0x1, CpuidRegister::Edx, 4),
+    feature("msr", 0x1, CpuidRegister::Edx, 5),
+    feature("pae", 0x1, CpuidRegister::Edx, 6),
+    feature("mce", 0x1, CpuidRegister::Edx, 7),
+    feature("cx8", 0x1, CpuidRegister::Edx, 8),
+    feature("apic", 0x1, CpuidRegister::Edx, 9),
+    feature("sep", 0x1, CpuidRegister::Edx, 11),
+    feature("mtrr", 0x1, CpuidRegister::Edx, 12),
+    feature("pge", 0x1, CpuidRegister::Edx, 13),
+    feature("mca", 0x1, CpuidRegister::Edx, 14),
+    feature("cmov", 0x1, CpuidRegister::Edx, 15),
+    feature("pat", 0x1, CpuidRegister::Edx, 16),
+    feature("pse36", 0x1, CpuidRegister::Edx, 17),
+    feature("clflush", 0x1, CpuidRegister::Edx, 19),
+    feature("mmx", 0x1, CpuidRegister::Edx, 23),
+    feature("fxsr", 0x1, CpuidRegister::Edx, 24),
+    feature("sse", 0x1, CpuidRegister::Edx, 25),
+    feature("sse2", 0x1, CpuidRegister::Edx, 26),
+    feature("ht", 0x1, CpuidRegister::Edx, 28),
+    feature("pcid", 0x1, CpuidRegister::Ecx, 17),
+    feature("x2apic", 0x1, CpuidRegister::Ecx, 21),
+    feature("tsc_deadline_timer", 0x1, CpuidRegister::Ecx, 24),
+    feature("xsave", 0x1, CpuidRegister::Ecx, 26),
+    // KVM follows CR4.OSXSAVE of the guest
+    feature("osxsave", 0x1, CpuidRegister::Ecx, 27),
+    feature("hypervisor", 0x1, CpuidRegister::Ecx, 31),
+    feature("fsgsbase", 0x7, CpuidRegister::Ebx, 0),
+    feature("smep", 0x7, CpuidRegister::Ebx, 7),
+    feature("erms", 0x7, CpuidRegister::Ebx, 9),
+    feature("invpcid", 0x7, CpuidRegister::Ebx, 10),
+    feature("smap", 0x7, CpuidRegister::Ebx, 20),
+    feature("md_clear", 0x7, CpuidRegister::Edx, 10),
+    feature("spec_ctrl", 0x7, CpuidRegister::Edx, 26),
+    feature("intel_stibp", 0x7, CpuidRegister::Edx, 27),
+    feature("flush_l1d", 0x7, CpuidRegister::Edx, 28),
+    feature("arch_capabilities", 0x7, CpuidRegister::Edx, 29),
+    feature("spec_ctrl_ssbd", 0x7, CpuidRegister::Edx, 31),
+    feature("syscall", 0x8000_0001, CpuidRegister::Edx, 11),
+    feature("nx", 0x8000_0001, CpuidRegister::Edx, 20),
+    feature("pdpe1gb", 0x8000_0001, CpuidRegister::Edx, 26),
+    feature("rdtscp", 0x8000_0001, CpuidRegister::Edx, 27),
+    feature("lm", 0x8000_0001, CpuidRegister::Edx, 29),
+];
+
+const X86_64_V2_FEATURES: &[Feature] = &[
+    feature("pni", 0x1, CpuidRegister::Ecx, 0),
+    feature("ssse3", 0x1, CpuidRegister::Ecx, 9),
+    feature("cx16", 0x1, CpuidRegister::Ecx, 13),
+    feature("sse4_1", 0x1, CpuidRegister::Ecx, 19),
+    feature("sse4_2", 0x1, CpuidRegister::Ecx, 20),
+    feature("popcnt", 0x1, CpuidRegister::Ecx, 23),
+    feature("lahf_lm", 0x8000_0001, CpuidRegister::Ecx, 0),
+];
+
+const X86_64_V3_FEATURES: &[Feature] = &[
+    feature("fma", 0x1, CpuidRegister::Ecx, 12),
+    feature("movbe", 0x1, CpuidRegister::Ecx, 22),
+    feature("avx", 0x1, CpuidRegister::Ecx, 28),
+    feature("f16c", 0x1, CpuidRegister::Ecx, 29),
+    feature("bmi1", 0x7, CpuidRegister::Ebx, 3),
+    feature("avx2", 0x7, CpuidRegister::Ebx, 5),
+    feature("bmi2", 0x7, CpuidRegister::Ebx, 8),
+    feature("abm", 0x8000_0001, CpuidRegister::Ecx, 5),
+];
+
+/// x87, SSE
+const XSAVE_COMPONENTS_V2: u32 = 0b11;
+/// x87, SSE, AVX
+const XSAVE_COMPONENTS_V3: u32 = 0b111;
+/// XSAVEOPT in the function 0xd index 1, the compacted and the supervisor
+/// forms depend on the host
+const XSAVE_FEATURES: u32 = 1 << 0;
+
+impl CpuidTemplate {
+    /// The features the host must have for the template
+    fn required(&self) -> Vec<&'static Feature> {
+        let levels: &[&[Feature]] = match self {
+            CpuidTemplate::Host => &[],
+            CpuidTemplate::X86_64V2 => &[X86_64_V2_FEATURES],
+            CpuidTemplate::X86_64V3 => &[X86_64_V2_FEATURES, X86_64_V3_FEATURES],
+        };
+        levels.iter().flat_map(|level| level.iter()).collect()
+    }
+
+    /// Keeps the features of the template only, or returns the names of
+    /// the ones the host lacks
+    fn apply(&self, entries: &mut Vec<CpuidEntry>) -> Result<(), Vec<&'static str>> {
+        if *self == CpuidTemplate::Host {
+            return Ok(());
+        }
+
+        let required = self.required();
+        let missing = required
+            .iter()
+            .filter(|feature| {
+                let value = find(entries, feature.function, 0)
+                    .map_or(0, |mut entry| *entry.register(feature.register));
+                value & (1 << feature.bit) == 0 && feature.name != "osxsave"
+            })
+            .map(|feature| feature.name)
+            .collect::<Vec<_>>();
+        if !missing.is_empty() {
+            return Err(missing);
+        }
+
+        for (function, register) in FEATURE_REGISTERS {
+            let allowed = BASE_FEATURES
+                .iter()
+                .chain(required.iter().copied())
+                .filter(|feature| feature.function == function && feature.register == register)
+                .fold(0_u32, |allowed, feature| allowed | (1 << feature.bit));
+
+            if let Some(entry) = find_mut(entries, function, 0) {
+                *entry.register(register) &= allowed;
+            }
+        }
+
+        // The sub-leaves of the function 7 describe the newer features
+        entries.retain(|entry| entry.function != 0x7 || entry.index == 0);
+        if let Some(entry) = find_mut(entries, 0x7, 0) {
+            entry.eax = 0;
+        }
+
+        let components = match self {
+            CpuidTemplate::X86_64V3 => XSAVE_COMPONENTS_V3,
+            _ => XSAVE_COMPONENTS_V2,
+        };
+        entries.retain(|entry| {
+            entry.function != 0xd || entry.index < 2 || components & (1 << entry.index) != 0
+        });
+        if let Some(entry) = find_mut(entries, 0xd, 0) {
+            entry.eax &= components;
+            entry.edx = 0;
+        }
+        if let Some(entry) = find_mut(entries, 0xd, 1) {
+            entry.eax &= XSAVE_FEATURES;
+            entry.ecx = 0;
+            entry.edx = 0;
+        }
+
+        Ok(())
+    }
+}
+
+impl CpuidPolicy {
+    /// What the vCPU of the index in the topology sees out of what KVM
+    /// supports, or the names of the features of the template the host lacks
+    pub fn apply(
+        &self,
+        supported: &[CpuidEntry],
+        topology: &CpuTopology,
+        cpu: u32,
+    ) -> Result<Vec<CpuidEntry>, Vec<&'static str>> {
+        let mut entries = supported.to_vec();
+
+        self.template.apply(&mut entries)?;
+        add_hypervisor_signature(&mut entries);
+        set_topology(&mut entries, topology, cpu);
+
+        for cpuid_override in &self.overrides {
+            let entry = match find_mut(&mut entries, cpuid_override.function, cpuid_override.index)
+            {
+                Some(entry) => entry,
+                None => {
+                    entries.push(CpuidEntry {
+                        function: cpuid_override.function,
+                        index: cpuid_override.index,
+                        flags: if cpuid_override.index != 0 {
+                            CPUID_FLAG_SIGNIFICANT_INDEX
+                        } else {
+                            0
+                        },
+                        ..Default::default()
+                    });
+                    entries.last_mut().unwrap()
+                }
+            };
+
+            let register = entry.register(cpuid_override.register);
+            *register =
+                (*register & !cpuid_override.mask) | (cpuid_override.value & cpuid_override.mask);
+        }
+
+        entries.sort_by_key(|entry| (entry.function, entry.index));
+        Ok(entries)
+    }
+}
+
+fn find(entries: &[CpuidEntry], function: u32, index: u32) -> Option<CpuidEntry> {
+    entries
+        .iter()
+        .find(|entry| {
+            entry.function == function
+                && (entry.index == index || entry.flags & CPUID_FLAG_SIGNIFICANT_INDEX == 0)
+        })
+        .copied()
+}
+
+fn find_mut(entries: &mut [CpuidEntry], function: u32, index: u32) -> Option<&mut CpuidEntry> {
+    entries.iter_mut().find(|entry| {
+        entry.function == function
+            && (entry.index == index || entry.flags & CPUID_FLAG_SIGNIFICANT_INDEX == 0)
+    })
+}
+
+/// Moves the leaves of KVM up and answers 0x40000000 with the signature
+fn add_hypervisor_signature(entries: &mut Vec<CpuidEntry>) {
+    let range = HYPERVISOR_BASE..KVM_HYPERVISOR_BASE;
+    for entry in entries
+        .iter_mut()
+        .filter(|entry| range.contains(&entry.function))
+    {
+        if entry.function == HYPERVISOR_BASE {
+            // The last function of the range
+            entry.eax += KVM_HYPERVISOR_BASE - HYPERVISOR_BASE;
+        }
+        entry.function += KVM_HYPERVISOR_BASE - HYPERVISOR_BASE;
+    }
+
+    let signature = |offset: usize| {
+        u32::from_le_bytes(HYPERVISOR_SIGNATURE[offset..offset + 4].try_into().unwrap())
+    };
+    entries.push(CpuidEntry {
+        function: HYPERVISOR_BASE,
+        eax: HYPERVISOR_BASE,
+        ebx: signature(0),
+        ecx: signature(4),
+        edx: signature(8),
+        ..Default::default()
+    });
+}
+
+/// The APIC ID of the vCPU and the levels of the topology above it
+fn set_topology(entries: &mut Vec<CpuidEntry>, topology: &CpuTopology, cpu: u32) {
+    let apic_id = topology.apic_id(cpu);
+    let location = topology.location(cpu);
+    let package_cpus = topology.dies * topology.cores * topology.threads;
+
+    for entry in entries.iter_mut() {
+        match entry.function {
+            // The initial APIC ID, the addressable logical processors
+            0x1 => {
+                let addressable = (1 << topology.die_width()).min(0xff);
+                entry.ebx = (entry.ebx & 0xffff) | (addressable << 16) | (apic_id << 24);
+                if package_cpus > 1 {
+                    entry.edx |= 1 << 28;
+                }
+            }
+            // The cores of the package, the threads sharing the cache: the
+            // ones of the core up to L2, the whole package for L3
+            0x4 => {
+                let sharing = match (entry.eax >> 5) & 7 {
+                    1 | 2 => topology.thread_width(),
+                    _ => topology.die_width(),
+                };
+                let cores = topology.die_width() - topology.thread_width();
+                entry.eax = (entry.eax & 0x3fff)
+                    | ((((1 << cores) - 1) & 0x3f) << 26)
+                    | ((((1 << sharing) - 1) & 0xfff) << 14);
+            }
+            // The cores of the package on AMD
+            0x8000_0008 => {
+                entry.ecx = (entry.ecx & !0xf0ff)
+                    | (topology.die_width() << 12)
+                    | ((package_cpus - 1) & 0xff);
+            }
+            // The extended APIC ID, the core and the node on AMD
+            0x8000_001e => {
+                entry.eax = apic_id;
+                entry.ebx = ((topology.threads - 1) << 8)
+                    | ((location.die * topology.cores + location.core) & 0xff);
+                entry.ecx = ((topology.dies - 1) << 8)
+                    | ((location.socket * topology.dies + location.die) & 0xff);
+            }
+            _ => {}
+        }
+    }
+
+    // The extended topology: the level type, the APIC ID bits below the
+    // next level and the logical processors of the level, ending with an
+    // invalid level
+    let smt = (1, topology.thread_width(), topology.threads);
+    let core = (2, topology.core_width(), topology.cores * topology.threads);
+    let die = (5, topology.die_width(), package_cpus);
+    let package_core = (2, topology.die_width(), package_cpus);
+    for (function, levels) in [(0xb, vec![smt, package_core]), (0x1f, vec![smt, core, die])] {
+        if find(entries, function, 0).is_none() {
+            continue;
+        }
+
+        entries.retain(|entry| entry.function != function);
+        for (index, (level_type, width, count)) in levels
+            .into_iter()
+            .chain(std::iter::once((0, 0, 0)))
+            .enumerate()
+        {
+            entries.push(CpuidEntry {
+                function,
+                index: index as u32,
+                flags: CPUID_FLAG_SIGNIFICANT_INDEX,
+                eax: width,
+                ebx: count,
+                ecx: (level_type << 8) | index as u32,
+                edx: apic_id,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn host() -> Vec<CpuidEntry> {
+        vec![
+            CpuidEntry {
+                function: 0x1,
+                ebx: 0x0f00_0800,
+                ecx: 0xffff_ffff,
+                edx: 0xffff_ffff,
+                ..Default::default()
+            },
+            CpuidEntry {
+                function: 0x7,
+                flags: CPUID_FLAG_SIGNIFICANT_INDEX,
+                eax: 1,
+                ebx: 0xffff_ffff,
+                ..Default::default()
+            },
+            CpuidEntry {
+                function: 0x7,
+                index: 1,
+                flags: CPUID_FLAG_SIGNIFICANT_INDEX,
+                eax: 0xffff_ffff,
+                ..Default::default()
+            },
+            CpuidEntry {
+                function: 0x4000_0000,
+                eax: 0x4000_0001,
+                ebx: 0x4b4d_564b,
+                ..Default::default()
+            },
+            CpuidEntry {
+                function: 0x8000_0001,
+                ecx: 0xffff_ffff,
+                edx: 0xffff_ffff,
+                ..Default::default()
+            },
+        ]
+    }
+
+    #[test]
+    fn test_template() {
+        let policy = CpuidPolicy {
+            template: CpuidTemplate::X86_64V2,
+            overrides: vec![CpuidOverride {
+                function: 0x7,
+                index: 0,
+                register: CpuidRegister::Ebx,
+                value: 1 << 5,
+                mask: 1 << 5,
+            }],
+        };
+        let topology = CpuTopology {
+            cores: 2,
+            threads: 2,
+            ..Default::default()
+        };
+        let entries = policy.apply(&host(), &topology, 3).unwrap();
+
+        let leaf_1 = find(&entries, 0x1, 0).unwrap();
+        assert_eq!(leaf_1.ebx, 0x0304_0800);
+        assert_ne!(leaf_1.ecx & (1 << 20), 0, "sse4_2");
+        assert_eq!(leaf_1.ecx & (1 << 28), 0, "avx");
+
+        // AVX2 comes from the override only
+        assert_eq!(find(&entries, 0x7, 0).unwrap().ebx, 0x0010_0681 | (1 << 5));
+        assert_eq!(find(&entries, 0x7, 1), None);
+
+        assert_eq!(find(&entries, 0x4000_0000, 0).unwrap().ebx, 0x6c6f_6d53);
+        assert_eq!(find(&entries, 0x4000_0100, 0).unwrap().eax, 0x4000_0101);
+
+        let mut old_host = host();
+        old_host[0].ecx &= !(1 << 28);
+        let missing = CpuidPolicy {
+            template: CpuidTemplate::X86_64V3,
+            overrides: Vec::new(),
+        }
+        .apply(&old_host, &CpuTopology::default(), 0)
+        .unwrap_err();
+        assert_eq!(missing, ["avx"]);
+    }
+}
@@ -31,10 +31,19 @@ const SCTLR_INITIAL_VALUE: u64 = SCTLR_RESERVED_MUST_BE_1
 // https://developer.arm.com/documentation/ddi0595/2021-09/AArch64-Registers/MIDR-EL1--Main-ID-Register?lang=en
 pub const MIDR_EL1_INITIAL_VALUE: u64 = 0x00000000410fd034;
 
+/// The load from MMIO to finish before running the vCPU again
+struct MmioLoad {
+    register: Register,
+    /// In bytes
+    access_size: u32,
+    sign_extend: bool,
+    register_is_64_bit: bool,
+}
+
 pub struct Cpu {
     vcpu: VirtualCpu,
     mmio: u64,
-    mmio_register_load: Option<Register>,
+    mmio_load: Option<MmioLoad>,
     sctlr_e1: u64,
 }
 
@@ -43,7 +52,7 @@ impl Cpu {
         Ok(Self {
             vcpu,
             mmio: 0,
-            mmio_register_load: None,
+            mmio_load: None,
             sctlr_e1: SCTLR_INITIAL_VALUE,
         })
     }
@@ -67,10 +76,17 @@ impl Cpu {
     }
 
     pub fn run(&mut self) -> Result<CpuExitReason, HypervisorError> {
-        // Finish pending MMIO if any
-        if let Some(register) = self.mmio_register_load {
-            self.set_register(register, self.mmio)?;
-            self.mmio_register_load = None;
+        // Finish pending MMIO if any, extending the value to the register
+        if let Some(load) = self.mmio_load.take() {
+            let unused_bits = 64 - load.access_size * 8;
+            let mut value = self.mmio;
+            if load.sign_extend {
+                value = (((value << unused_bits) as i64) >> unused_bits) as u64;
+            }
+            if !load.register_is_64_bit {
+                value &= 0xffff_ffff;
+            }
+            self.set_register(load.register, value)?;
         }
 
         let vcpu_exit = self.vcpu.run()?;
@@ -143,10 +159,21 @@ impl Cpu {
                             let writing_to_memory = ((syndrome >> 6) & 0x1) == 1;
                             let data_fault_status_code = syndrome & 0x1f;
 
-                            if is_instr_32_bit && !register_is_64_bit {
+                            if is_instr_32_bit {
                                 if !writing_to_memory {
-                                    self.mmio_register_load =
-                                        Some(Self::get_register_by_index(register_transfer));
+                                    // Zero-extended unless the syndrome says otherwise
+                                    self.mmio = 0;
+                                    // The zero register discards the value
+                                    if register_transfer != 0x1f {
+                                        self.mmio_load = Some(MmioLoad {
+                                            register: Self::get_register_by_index(
+                                                register_transfer,
+                                            ),
+                                            access_size: 1 << access_size,
+                                            sign_extend,
+                                            register_is_64_bit,
+                                        });
+                                    }
                                     match access_size {
                                         // 8 bit
                                         0b00 => CpuExitReason::MmIo(MmIoType::ByteIn(pa, unsafe {
@@ -163,7 +190,11 @@ impl Cpu {
                                                 &mut *(&mut self.mmio as *const _ as *mut u32)
                                             },
                                         )),
-                                        _ => CpuExitReason::NotSupported,
+                                        // 64 bit
+                                        _ => CpuExitReason::MmIo(MmIoType::QuadWordIn(
+                                            pa,
+                                            &mut self.mmio,
+                                        )),
                                     }
                                 } else {
                                     let value = if register_transfer != 0x1f {
@@ -188,7 +219,8 @@ impl Cpu {
                                             pa,
                                             value as u32,
                                         )),
-                                        _ => CpuExitReason::NotSupported,
+                                        // 64 bit
+                                        _ => CpuExitReason::MmIo(MmIoType::QuadWordOut(pa, value)),
                                     }
                                 }
                             } else {
@@ -215,6 +247,32 @@ impl Cpu {
         Ok(exit)
     }
 
+    /// Logs the registers, e.g. when the guest has crashed
+    pub fn dump_state(&mut self) {
+        match self.vcpu.get_register(Register::PC) {
+            Ok(pc) => log::error!("PC {:#018x}", pc),
+            Err(_) => {
+                log::error!("Cannot read the vCPU registers");
+                return;
+            }
+        }
+
+        for index in (0..31).step_by(4) {
+            let regs = (index..(index + 4).min(31))
+                .map(|index| {
+                    format!(
+                        "X{:<2} {:#018x}",
+                        index,
+                        self.vcpu
+                            .get_register(Self::get_register_by_index(index))
+                            .unwrap_or_default()
+                    )
+                })
+                .collect::<Vec<_>>();
+            log::error!("{}", regs.join(" "));
+        }
+    }
+
     pub fn set_instruction_pointer(&mut self, ip: u64) -> Result<(), HypervisorError> {
         self.vcpu.set_register(Register::PC, ip)
     }
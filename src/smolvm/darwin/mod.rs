@@ -13,16 +13,38 @@ use ahv::{MemoryPermission, VirtualMachine};
 
 #[cfg(target_arch = "aarch64")]
 pub use self::aarch64::Cpu;
-use super::{GpaSpan, MappedGpa, Memory};
+use super::{
+    bus::{Bus, HypercallHandler, MsrHandler},
+    debug_port::PostCodes,
+    pci::PciRoot,
+    GpaSpan, IrqChip, MappedGpa, Memory, PowerButton, VmOptions,
+};
 
 pub struct SmolVm {
     cpu: Arc<Mutex<Cpu>>,
     memory: Arc<Mutex<Memory>>,
+    bus: Arc<Mutex<Bus>>,
     vm: VirtualMachine,
 }
 
 impl SmolVm {
-    pub fn new(memory_map: &[GpaSpan]) -> Result<Self, HvError> {
+    pub fn new(memory_map: &[GpaSpan], options: &VmOptions) -> Result<Self, HvError> {
+        if !options.pmem.is_empty() {
+            log::warn!("Persistent memory is not supported, ignoring");
+        }
+        if options.pci {
+            log::warn!("PCI is not supported, ignoring");
+        }
+        if options.watchdog {
+            log::warn!("The watchdog is not supported, ignoring");
+        }
+        if !options.ram.is_empty() {
+            log::warn!("RAM backing options are not supported, ignoring");
+        }
+        if options.numa.iter().any(|node| node.host_node.is_some()) {
+            log::warn!("Binding the NUMA nodes to host nodes is not supported, ignoring");
+        }
+
         let mut vm = VirtualMachine::new(None)?;
         let memory = {
             let mut memory_spans = Vec::new();
@@ -38,6 +60,7 @@ impl SmolVm {
                     memory: base,
                     gpa: gpa_span.start,
                     size,
+                    file: None,
                 });
             }
 
@@ -52,6 +75,7 @@ impl SmolVm {
             vm,
             cpu: Arc::new(Mutex::new(cpu)),
             memory: Arc::new(Mutex::new(memory)),
+            bus: Arc::new(Mutex::new(super::create_bus(memory_map, options))),
         })
     }
 }
@@ -64,4 +88,47 @@ impl crate::smolvm::SmolVmT for SmolVm {
     fn get_cpu(&self) -> std::sync::Arc<std::sync::Mutex<Cpu>> {
         self.cpu.clone()
     }
+
+    fn get_bus(&self) -> Arc<Mutex<Bus>> {
+        self.bus.clone()
+    }
+
+    fn get_irq_chip(&self) -> Option<Arc<dyn IrqChip>> {
+        None
+    }
+
+    fn get_pci(&self) -> Option<Arc<Mutex<PciRoot>>> {
+        None
+    }
+
+    fn get_power_button(&self) -> Option<PowerButton> {
+        None
+    }
+
+    fn get_post_codes(&self) -> Option<PostCodes> {
+        None
+    }
+
+    fn add_hypercall_handler(
+        &mut self,
+        _nr: u64,
+        _handler: Arc<Mutex<dyn HypercallHandler>>,
+    ) -> Result<(), HvError> {
+        log::error!("Hypercalls are not supported");
+        Err(HvError::Unsupported)
+    }
+
+    fn add_msr_handler(
+        &mut self,
+        _index: u32,
+        _handler: Arc<Mutex<dyn MsrHandler>>,
+    ) -> Result<(), HvError> {
+        log::error!("MSR handlers are not supported");
+        Err(HvError::Unsupported)
+    }
+
+    fn reset(&mut self) -> Result<(), HvError> {
+        log::error!("Reset is not supported");
+        Err(HvError::Unsupported)
+    }
 }
@@ -16,6 +16,8 @@ impl Cpu {
         Ok(CpuExit {})
     }
 
+    pub fn dump_state(&self) {}
+
     pub fn set_instruction_pointer(&mut self, ip: u64) -> Result<(), std::io::Error> {
         Ok(())
     }
@@ -0,0 +1,60 @@
+//! Lets a test running in the guest end the run with its verdict, compatible
+//! with the `isa-debug-exit` device of `qemu`: writing `value` makes smolvm
+//! exit with the status `(value << 1) | 1`, so no value looks like success.
+//!
+//! The device is a port on x86_64 and an MMIO register on aarch64, it takes
+//! writes of any width.
+
+use super::bus::{ExitRequest, ExitRequester, IoDevice, MmIoDevice};
+
+#[cfg(target_arch = "x86_64")]
+pub const DEBUG_EXIT_PORT: u16 = 0xf4;
+#[cfg(target_arch = "x86_64")]
+pub const DEBUG_EXIT_PORT_COUNT: u16 = 4;
+
+/// Past the PL011 and the other peripherals of the `virt` machine of `qemu`
+#[cfg(target_arch = "aarch64")]
+pub const DEBUG_EXIT_BASE: u64 = 0x0910_0000;
+#[cfg(target_arch = "aarch64")]
+pub const DEBUG_EXIT_MMIO_SIZE: u64 = 0x1000;
+
+pub struct DebugExit {
+    exit_requester: ExitRequester,
+}
+
+impl DebugExit {
+    pub fn new(exit_requester: ExitRequester) -> Self {
+        Self { exit_requester }
+    }
+
+    fn write(&mut self, data: &[u8]) {
+        let mut value = [0_u8; 4];
+        let len = data.len().min(4);
+        value[..len].copy_from_slice(&data[..len]);
+        let value = u32::from_le_bytes(value);
+
+        let status = ((value << 1) | 1) as i32;
+        log::info!("Guest has written {:#x}, exiting with {}", value, status);
+        self.exit_requester.request(ExitRequest::Exit(status));
+    }
+}
+
+impl IoDevice for DebugExit {
+    fn io_in(&mut self, _port: u16, data: &mut [u8]) {
+        data.fill(0);
+    }
+
+    fn io_out(&mut self, _port: u16, data: &[u8]) {
+        self.write(data);
+    }
+}
+
+impl MmIoDevice for DebugExit {
+    fn mmio_read(&mut self, _addr: u64, data: &mut [u8]) {
+        data.fill(0);
+    }
+
+    fn mmio_write(&mut self, _addr: u64, data: &[u8]) {
+        self.write(data);
+    }
+}
@@ -0,0 +1,195 @@
+//! The debug ports the firmware and the bare-metal code write to on x86_64
+//! before there is a console:
+//!
+//!   0xE9   debugcon of Bochs and `qemu`, a character per byte
+//!   0x402  debug log of OVMF, the same as debugcon
+//!   0x80   POST codes, the progress of the firmware
+//!
+//! Reading 0xE9 gives 0xE9 back for the guest to detect the port. The POST
+//! codes are kept, also across the resets, for the host to print when the
+//! VM dies. Linux writes to 0x80 to delay the slow port I/O, so the codes
+//! tell little past the early boot of the kernel.
+
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::Write,
+    sync::{Arc, Mutex},
+};
+
+use super::{bus::IoDevice, DebugConOutput};
+
+pub const DEBUGCON_PORT: u16 = 0xe9;
+pub const FIRMWARE_LOG_PORT: u16 = 0x402;
+pub const POST_CODE_PORT: u16 = 0x80;
+
+const DEBUGCON_READBACK: u8 = 0xe9;
+
+/// A guest never writing a newline gets its output cut into lines this long
+const DEBUGCON_LINE_SIZE: usize = 512;
+
+/// How many of the last POST codes are kept
+const POST_CODE_HISTORY: usize = 32;
+
+enum DebugConSink {
+    Log,
+    Writer(Box<dyn Write + Send>),
+}
+
+/// Sends the output line by line to the sink
+pub struct DebugCon {
+    sink: DebugConSink,
+    line: Vec<u8>,
+}
+
+impl DebugCon {
+    pub fn new(output: &DebugConOutput) -> Result<Self, std::io::Error> {
+        let sink = match output {
+            DebugConOutput::Log => DebugConSink::Log,
+            DebugConOutput::Stdout => DebugConSink::Writer(Box::new(std::io::stdout())),
+            DebugConOutput::Stderr => DebugConSink::Writer(Box::new(std::io::stderr())),
+            DebugConOutput::File(path) => DebugConSink::Writer(Box::new(File::create(path)?)),
+        };
+
+        Ok(Self {
+            sink,
+            line: Vec::with_capacity(DEBUGCON_LINE_SIZE),
+        })
+    }
+
+    fn write_byte(&mut self, byte: u8) {
+        self.line.push(byte);
+        if byte == b'\n' || self.line.len() == DEBUGCON_LINE_SIZE {
+            self.flush();
+        }
+    }
+
+    fn flush(&mut self) {
+        if self.line.is_empty() {
+            return;
+        }
+
+        match &mut self.sink {
+            DebugConSink::Log => {
+                log::info!(
+                    "debugcon: {}",
+                    String::from_utf8_lossy(&self.line).trim_end()
+                )
+            }
+            DebugConSink::Writer(writer) => {
+                if let Err(err) = writer.write_all(&self.line).and_then(|_| writer.flush()) {
+                    log::warn!("Cannot write the debug console output: {}", err);
+                }
+            }
+        }
+        self.line.clear();
+    }
+}
+
+impl Drop for DebugCon {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+impl IoDevice for DebugCon {
+    fn io_in(&mut self, _port: u16, data: &mut [u8]) {
+        data.fill(0);
+        if let Some(byte) = data.first_mut() {
+            *byte = DEBUGCON_READBACK;
+        }
+    }
+
+    fn io_out(&mut self, _port: u16, data: &[u8]) {
+        for byte in data {
+            self.write_byte(*byte);
+        }
+    }
+}
+
+/// The last POST codes, shared with the host
+#[derive(Clone, Default)]
+pub struct PostCodes(Arc<Mutex<VecDeque<u8>>>);
+
+impl PostCodes {
+    fn push(&self, code: u8) {
+        let mut codes = self.0.lock().unwrap();
+        if codes.len() == POST_CODE_HISTORY {
+            codes.pop_front();
+        }
+        codes.push_back(code);
+    }
+
+    /// Oldest first
+    pub fn last(&self) -> Vec<u8> {
+        self.0.lock().unwrap().iter().copied().collect()
+    }
+}
+
+pub struct PostCodePort {
+    codes: PostCodes,
+}
+
+impl PostCodePort {
+    pub fn new(codes: PostCodes) -> Self {
+        Self { codes }
+    }
+}
+
+impl IoDevice for PostCodePort {
+    fn io_in(&mut self, _port: u16, data: &mut [u8]) {
+        let last = self.codes.0.lock().unwrap().back().copied();
+
+        data.fill(0);
+        if let Some(byte) = data.first_mut() {
+            *byte = last.unwrap_or_default();
+        }
+    }
+
+    fn io_out(&mut self, _port: u16, data: &[u8]) {
+        if let Some(code) = data.first() {
+            self.codes.push(*code);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Collects what the debug console writes
+    #[derive(Clone, Default)]
+    struct Output(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_debugcon_lines() {
+        let output = Output::default();
+        let mut debugcon = DebugCon {
+            sink: DebugConSink::Writer(Box::new(output.clone())),
+            line: Vec::new(),
+        };
+
+        debugcon.io_out(DEBUGCON_PORT, b"line\n");
+        assert_eq!(*output.0.lock().unwrap(), b"line\n");
+
+        // No newline, the full buffer is written out anyway
+        debugcon.io_out(DEBUGCON_PORT, &[b'x'; DEBUGCON_LINE_SIZE + 10]);
+        assert_eq!(output.0.lock().unwrap().len(), 5 + DEBUGCON_LINE_SIZE);
+        assert_eq!(debugcon.line.len(), 10);
+
+        // The rest is not lost with the device
+        drop(debugcon);
+        assert_eq!(output.0.lock().unwrap().len(), 5 + DEBUGCON_LINE_SIZE + 10);
+    }
+}
@@ -0,0 +1,405 @@
+//! Adds the nodes of the devices emulated by smolvm to the flattened device
+//! tree the guest is booted with. The blob is expected to come from the
+//! `virt` machine of `qemu`, so the root has `#address-cells` and
+//! `#size-cells` of 2 and its `interrupt-parent` is the GIC.
+//!
+//!   Header (big-endian u32s):
+//!     +0  magic 0xd00dfeed   +4  totalsize
+//!     +8  off_dt_struct      +12 off_dt_strings
+//!     +16 off_mem_rsvmap     +20 version
+//!     +24 last_comp_version  +28 boot_cpuid_phys
+//!     +32 size_dt_strings    +36 size_dt_struct
+//!
+//! The structure block is a sequence of tokens: BEGIN_NODE with the name,
+//! PROP with the length, the offset of the name in the strings block and
+//! the value, END_NODE, NOP and END, everything padded to 4 bytes.
+//! The nodes are appended to the root, a node the blob already has under
+//! the same name (e.g. `pl031@9010000`) is left as it is unless the new one
+//! is to replace it (e.g. `cpus`).
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_HEADER_SIZE: usize = 40;
+const FDT_VERSION: u32 = 17;
+const FDT_LAST_COMP_VERSION: u32 = 16;
+
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+const FDT_END: u32 = 9;
+
+/// The interrupt specifier of the GIC: SPI, the number, the flags
+pub const GIC_FDT_IRQ_TYPE_SPI: u32 = 0;
+pub const IRQ_TYPE_EDGE_RISING: u32 = 1;
+pub const IRQ_TYPE_LEVEL_HIGH: u32 = 4;
+
+/// The clock of the AMBA peripherals, picked far from what `dtc` and `qemu`
+/// hand out
+pub const APB_PCLK_PHANDLE: u32 = 0x534d_0001;
+pub const APB_PCLK_FREQUENCY: u32 = 24_000_000;
+pub const PL061_PHANDLE: u32 = 0x534d_0002;
+/// Of the CPU 0, the others follow
+pub const CPU_PHANDLE_BASE: u32 = 0x534d_0100;
+
+#[derive(Clone)]
+pub struct FdtNode {
+    name: String,
+    properties: Vec<(String, Vec<u8>)>,
+    children: Vec<FdtNode>,
+    replace: bool,
+}
+
+impl FdtNode {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            properties: Vec::new(),
+            children: Vec::new(),
+            replace: false,
+        }
+    }
+
+    /// Takes the place of the node of the same name the blob has
+    pub fn replace(mut self) -> Self {
+        self.replace = true;
+        self
+    }
+
+    pub fn property(mut self, name: &str, value: &[u8]) -> Self {
+        self.properties.push((name.to_string(), value.to_vec()));
+        self
+    }
+
+    pub fn property_empty(self, name: &str) -> Self {
+        self.property(name, &[])
+    }
+
+    pub fn property_string(self, name: &str, value: &str) -> Self {
+        let mut data = value.as_bytes().to_vec();
+        data.push(0);
+        self.property(name, &data)
+    }
+
+    /// The strings of a string list, e.g. the `compatible` with the fallbacks
+    pub fn property_strings(self, name: &str, values: &[&str]) -> Self {
+        let mut data = Vec::new();
+        for value in values {
+            data.extend_from_slice(value.as_bytes());
+            data.push(0);
+        }
+        self.property(name, &data)
+    }
+
+    pub fn property_u32(self, name: &str, value: u32) -> Self {
+        self.property_cells(name, &[value])
+    }
+
+    pub fn property_cells(self, name: &str, cells: &[u32]) -> Self {
+        let data = cells
+            .iter()
+            .flat_map(|cell| cell.to_be_bytes())
+            .collect::<Vec<_>>();
+        self.property(name, &data)
+    }
+
+    /// `reg` of a single region with 2 address and 2 size cells
+    pub fn reg(self, base: u64, size: u64) -> Self {
+        self.property_cells(
+            "reg",
+            &[
+                (base >> 32) as u32,
+                base as u32,
+                (size >> 32) as u32,
+                size as u32,
+            ],
+        )
+    }
+
+    /// `interrupts` of a single SPI of the GIC
+    pub fn interrupt_spi(self, spi: u32, flags: u32) -> Self {
+        self.property_cells("interrupts", &[GIC_FDT_IRQ_TYPE_SPI, spi, flags])
+    }
+
+    /// The AMBA peripheral bits: `arm,primecell` and the APB clock
+    pub fn primecell(self, compatible: &str) -> Self {
+        self.property_strings("compatible", &[compatible, "arm,primecell"])
+            .property_u32("clocks", APB_PCLK_PHANDLE)
+            .property_string("clock-names", "apb_pclk")
+    }
+
+    pub fn child(mut self, child: FdtNode) -> Self {
+        self.children.push(child);
+        self
+    }
+
+    fn serialize(&self, structure: &mut Vec<u8>, strings: &mut Vec<u8>) {
+        structure.extend_from_slice(&FDT_BEGIN_NODE.to_be_bytes());
+        structure.extend_from_slice(self.name.as_bytes());
+        structure.push(0);
+        pad(structure);
+
+        for (name, value) in &self.properties {
+            structure.extend_from_slice(&FDT_PROP.to_be_bytes());
+            structure.extend_from_slice(&(value.len() as u32).to_be_bytes());
+            structure.extend_from_slice(&string_offset(strings, name).to_be_bytes());
+            structure.extend_from_slice(value);
+            pad(structure);
+        }
+
+        for child in &self.children {
+            child.serialize(structure, strings);
+        }
+
+        structure.extend_from_slice(&FDT_END_NODE.to_be_bytes());
+    }
+}
+
+fn invalid_data(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+fn pad(data: &mut Vec<u8>) {
+    data.resize((data.len() + 3) & !3, 0);
+}
+
+/// The offset of the name in the strings block, appended if not there yet
+fn string_offset(strings: &mut Vec<u8>, name: &str) -> u32 {
+    let mut offset = 0;
+    for string in strings.split(|byte| *byte == 0) {
+        if string == name.as_bytes() && offset < strings.len() {
+            return offset as u32;
+        }
+        offset += string.len() + 1;
+    }
+
+    let offset = strings.len();
+    strings.extend_from_slice(name.as_bytes());
+    strings.push(0);
+    offset as u32
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, std::io::Error> {
+    data.get(offset..offset + 4)
+        .map(|bytes| u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        .ok_or_else(|| invalid_data("Truncated device tree"))
+}
+
+/// A copy of `dtb` with the `nodes` added to the root
+pub fn add_nodes(dtb: &[u8], nodes: &[FdtNode]) -> Result<Vec<u8>, std::io::Error> {
+    if read_u32(dtb, 0)? != FDT_MAGIC {
+        return Err(invalid_data("Not a device tree blob"));
+    }
+    if read_u32(dtb, 24)? > FDT_VERSION {
+        return Err(invalid_data("Unsupported device tree version"));
+    }
+
+    let struct_offset = read_u32(dtb, 8)? as usize;
+    let strings_offset = read_u32(dtb, 12)? as usize;
+    let rsvmap_offset = read_u32(dtb, 16)? as usize;
+    let strings_size = read_u32(dtb, 32)? as usize;
+    let struct_size = read_u32(dtb, 36)? as usize;
+
+    let structure = dtb
+        .get(struct_offset..struct_offset + struct_size)
+        .ok_or_else(|| invalid_data("Truncated device tree"))?;
+    let mut strings = dtb
+        .get(strings_offset..strings_offset + strings_size)
+        .ok_or_else(|| invalid_data("Truncated device tree"))?
+        .to_vec();
+
+    // The reservation map ends with an all-zero entry
+    let mut rsvmap_size = 0;
+    loop {
+        let entry = dtb
+            .get(rsvmap_offset + rsvmap_size..rsvmap_offset + rsvmap_size + 16)
+            .ok_or_else(|| invalid_data("Truncated device tree"))?;
+        rsvmap_size += 16;
+        if entry.iter().all(|byte| *byte == 0) {
+            break;
+        }
+    }
+
+    // Walk the tokens to find the end of the root and its children: the
+    // name, the offsets of the first token and past the last one
+    let mut root_end = None;
+    let mut root_children = Vec::new();
+    let mut child_start = 0;
+    let mut depth = 0;
+    let mut offset = 0;
+    while root_end.is_none() {
+        let token_offset = offset;
+        let token = read_u32(structure, offset)?;
+        offset += 4;
+
+        match token {
+            FDT_BEGIN_NODE => {
+                let name_len = structure[offset..]
+                    .iter()
+                    .position(|byte| *byte == 0)
+                    .ok_or_else(|| invalid_data("Unterminated node name"))?;
+                if depth == 1 {
+                    root_children.push((structure[offset..offset + name_len].to_vec(), 0, 0));
+                    child_start = token_offset;
+                }
+                offset = (offset + name_len + 1 + 3) & !3;
+                depth += 1;
+            }
+            FDT_END_NODE => {
+                depth -= 1;
+                if depth == 1 {
+                    let child = root_children.last_mut().unwrap();
+                    child.1 = child_start;
+                    child.2 = offset;
+                }
+                if depth == 0 {
+                    root_end = Some(offset - 4);
+                }
+            }
+            FDT_PROP => {
+                let len = read_u32(structure, offset)? as usize;
+                offset = (offset + 8 + len + 3) & !3;
+            }
+            FDT_NOP => {}
+            FDT_END => return Err(invalid_data("Device tree has no root node")),
+            _ => return Err(invalid_data("Unknown device tree token")),
+        }
+    }
+    let root_end = root_end.unwrap();
+
+    let mut new_structure = Vec::new();
+    let mut copied = 0;
+    for (name, start, end) in &root_children {
+        if nodes
+            .iter()
+            .any(|node| node.replace && node.name.as_bytes() == name.as_slice())
+        {
+            new_structure.extend_from_slice(&structure[copied..*start]);
+            copied = *end;
+        }
+    }
+    new_structure.extend_from_slice(&structure[copied..root_end]);
+
+    for node in nodes {
+        let present = root_children
+            .iter()
+            .any(|(name, _, _)| name.as_slice() == node.name.as_bytes());
+        if present && !node.replace {
+            log::info!("Device tree already has {}", node.name);
+            continue;
+        }
+        node.serialize(&mut new_structure, &mut strings);
+    }
+    new_structure.extend_from_slice(&structure[root_end..]);
+
+    let new_rsvmap_offset = FDT_HEADER_SIZE;
+    let new_struct_offset = new_rsvmap_offset + rsvmap_size;
+    let new_strings_offset = new_struct_offset + new_structure.len();
+    let total_size = new_strings_offset + strings.len();
+
+    let mut blob = Vec::with_capacity(total_size);
+    for field in [
+        FDT_MAGIC,
+        total_size as u32,
+        new_struct_offset as u32,
+        new_strings_offset as u32,
+        new_rsvmap_offset as u32,
+        FDT_VERSION,
+        FDT_LAST_COMP_VERSION,
+        read_u32(dtb, 28)?,
+        strings.len() as u32,
+        new_structure.len() as u32,
+    ] {
+        blob.extend_from_slice(&field.to_be_bytes());
+    }
+    blob.extend_from_slice(&dtb[rsvmap_offset..rsvmap_offset + rsvmap_size]);
+    blob.extend_from_slice(&new_structure);
+    blob.extend_from_slice(&strings);
+
+    Ok(blob)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn root_children(dtb: &[u8]) -> Vec<String> {
+        let struct_offset = read_u32(dtb, 8).unwrap() as usize;
+        let struct_size = read_u32(dtb, 36).unwrap() as usize;
+        let structure = &dtb[struct_offset..struct_offset + struct_size];
+
+        let mut children = Vec::new();
+        let mut depth = 0;
+        let mut offset = 0;
+        loop {
+            let token = read_u32(structure, offset).unwrap();
+            offset += 4;
+            match token {
+                FDT_BEGIN_NODE => {
+                    let name_len = structure[offset..].iter().position(|b| *b == 0).unwrap();
+                    if depth == 1 {
+                        children.push(
+                            String::from_utf8(structure[offset..offset + name_len].to_vec())
+                                .unwrap(),
+                        );
+                    }
+                    offset = (offset + name_len + 1 + 3) & !3;
+                    depth += 1;
+                }
+                FDT_END_NODE => depth -= 1,
+                FDT_PROP => {
+                    let len = read_u32(structure, offset).unwrap() as usize;
+                    offset = (offset + 8 + len + 3) & !3;
+                }
+                FDT_END => return children,
+                _ => {}
+            }
+        }
+    }
+
+    #[test]
+    fn test_add_nodes() {
+        let mut structure = Vec::new();
+        let mut strings = Vec::new();
+        FdtNode::new("")
+            .property_u32("#address-cells", 2)
+            .child(FdtNode::new("pl011@9000000").reg(0x900_0000, 0x1000))
+            .serialize(&mut structure, &mut strings);
+        structure.extend_from_slice(&FDT_END.to_be_bytes());
+
+        let mut dtb = Vec::new();
+        for field in [
+            FDT_MAGIC,
+            (FDT_HEADER_SIZE + 16 + structure.len() + strings.len()) as u32,
+            (FDT_HEADER_SIZE + 16) as u32,
+            (FDT_HEADER_SIZE + 16 + structure.len()) as u32,
+            FDT_HEADER_SIZE as u32,
+            FDT_VERSION,
+            FDT_LAST_COMP_VERSION,
+            0,
+            strings.len() as u32,
+            structure.len() as u32,
+        ] {
+            dtb.extend_from_slice(&field.to_be_bytes());
+        }
+        dtb.extend_from_slice(&[0; 16]);
+        dtb.extend_from_slice(&structure);
+        dtb.extend_from_slice(&strings);
+
+        let dtb = add_nodes(
+            &dtb,
+            &[
+                FdtNode::new("pl011@9000000"),
+                FdtNode::new("pvpanic@9110000")
+                    .property_string("compatible", "qemu,pvpanic-mmio")
+                    .reg(0x911_0000, 2),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(read_u32(&dtb, 4).unwrap() as usize, dtb.len());
+        assert_eq!(
+            root_children(&dtb),
+            vec!["pl011@9000000".to_string(), "pvpanic@9110000".to_string()]
+        );
+    }
+}
@@ -0,0 +1,204 @@
+//! The keyboard controller of the PC (i8042) with neither the keyboard nor
+//! the mouse attached. The guest probes it and reboots through it:
+//!
+//!   Port  Read               Write
+//!   ----------------------------------------------------------------
+//!   0x60  Output buffer      Data: the argument of the command, or a byte
+//!                            for the keyboard
+//!   0x64  Status register    Command
+//!
+//! The self-test passes, the keyboard does not answer the bytes sent to it,
+//! and there is no AUX (mouse) port. Pulsing the reset line, e.g. the 0xFE
+//! command Linux uses with `reboot=k`, resets the VM.
+
+use std::sync::Arc;
+
+use super::{
+    bus::{ExitRequest, ExitRequester, IoDevice},
+    IrqChip,
+};
+
+pub const I8042_DATA_PORT: u16 = 0x60;
+pub const I8042_COMMAND_PORT: u16 = 0x64;
+pub const I8042_KBD_IRQ: u32 = 1;
+
+// Status register
+const STR_OBF: u8 = 1 << 0;
+const STR_SYSFLAG: u8 = 1 << 2;
+const STR_CMDDAT: u8 = 1 << 3;
+/// The keyboard is not locked
+const STR_KEYLOCK: u8 = 1 << 4;
+const STR_TIMEOUT: u8 = 1 << 6;
+
+// Command byte
+const CTR_KBDINT: u8 = 1 << 0;
+const CTR_SYSFLAG: u8 = 1 << 2;
+const CTR_KBDDIS: u8 = 1 << 4;
+const CTR_AUXDIS: u8 = 1 << 5;
+const CTR_XLATE: u8 = 1 << 6;
+
+// Commands
+const CMD_CTL_RCTR: u8 = 0x20;
+const CMD_CTL_WCTR: u8 = 0x60;
+const CMD_AUX_DISABLE: u8 = 0xA7;
+const CMD_AUX_ENABLE: u8 = 0xA8;
+const CMD_AUX_TEST: u8 = 0xA9;
+const CMD_CTL_TEST: u8 = 0xAA;
+const CMD_KBD_TEST: u8 = 0xAB;
+const CMD_KBD_DISABLE: u8 = 0xAD;
+const CMD_KBD_ENABLE: u8 = 0xAE;
+const CMD_READ_OUTPUT_PORT: u8 = 0xD0;
+const CMD_WRITE_OUTPUT_PORT: u8 = 0xD1;
+const CMD_KBD_LOOP: u8 = 0xD2;
+const CMD_AUX_LOOP: u8 = 0xD3;
+const CMD_AUX_SEND: u8 = 0xD4;
+/// 0xF0..=0xFF pulse the output port lines the low bits are clear for
+const CMD_PULSE_OUTPUT_PORT: u8 = 0xF0;
+
+const CTL_TEST_PASSED: u8 = 0x55;
+const KBD_TEST_PASSED: u8 = 0x00;
+/// The clock line is stuck high, nothing is connected
+const AUX_TEST_NO_DEVICE: u8 = 0x02;
+/// What the keyboard would answer, with the timeout in the status
+const KBD_NO_DEVICE: u8 = 0xFE;
+
+/// The reset line is active low, the A20 gate is always enabled
+const OUTPUT_PORT_RESET: u8 = 1 << 0;
+const OUTPUT_PORT_A20: u8 = 1 << 1;
+
+const CTR_RESET: u8 = CTR_XLATE | CTR_AUXDIS | CTR_SYSFLAG | CTR_KBDINT;
+
+pub struct I8042 {
+    status: u8,
+    ctr: u8,
+    output: u8,
+    /// The command waiting for its argument at the data port
+    pending_command: Option<u8>,
+    exit_requester: ExitRequester,
+    irq_chip: Option<Arc<dyn IrqChip>>,
+}
+
+impl I8042 {
+    /// Without the irqchip the guest has to poll the output buffer
+    pub fn new(exit_requester: ExitRequester, irq_chip: Option<Arc<dyn IrqChip>>) -> Self {
+        Self {
+            status: STR_SYSFLAG | STR_KEYLOCK,
+            ctr: CTR_RESET,
+            output: 0,
+            pending_command: None,
+            exit_requester,
+            irq_chip,
+        }
+    }
+
+    fn push_output(&mut self, value: u8) {
+        self.output = value;
+        self.status |= STR_OBF;
+
+        if self.ctr & CTR_KBDINT != 0 {
+            if let Some(irq_chip) = &self.irq_chip {
+                irq_chip.pulse_irq(I8042_KBD_IRQ);
+            }
+        }
+    }
+
+    fn read_data(&mut self) -> u8 {
+        self.status &= !(STR_OBF | STR_TIMEOUT);
+        self.output
+    }
+
+    fn request_reset(&mut self) {
+        log::info!("Guest has pulsed the reset line of the keyboard controller");
+        self.exit_requester.request(ExitRequest::Reset);
+    }
+
+    fn write_command(&mut self, command: u8) {
+        self.status |= STR_CMDDAT;
+        self.pending_command = None;
+
+        match command {
+            CMD_CTL_RCTR => self.push_output(self.ctr),
+            CMD_CTL_WCTR | CMD_WRITE_OUTPUT_PORT | CMD_KBD_LOOP | CMD_AUX_LOOP | CMD_AUX_SEND => {
+                self.pending_command = Some(command)
+            }
+            // No AUX port to switch on or off
+            CMD_AUX_DISABLE | CMD_AUX_ENABLE => {}
+            CMD_AUX_TEST => self.push_output(AUX_TEST_NO_DEVICE),
+            CMD_CTL_TEST => {
+                self.status |= STR_SYSFLAG;
+                self.push_output(CTL_TEST_PASSED);
+            }
+            CMD_KBD_TEST => self.push_output(KBD_TEST_PASSED),
+            CMD_KBD_DISABLE => self.ctr |= CTR_KBDDIS,
+            CMD_KBD_ENABLE => self.ctr &= !CTR_KBDDIS,
+            CMD_READ_OUTPUT_PORT => self.push_output(OUTPUT_PORT_RESET | OUTPUT_PORT_A20),
+            CMD_PULSE_OUTPUT_PORT..=0xFF => {
+                if command & OUTPUT_PORT_RESET == 0 {
+                    self.request_reset();
+                }
+            }
+            _ => log::warn!(
+                "Keyboard controller command {:#x} is not supported",
+                command
+            ),
+        }
+    }
+
+    fn write_data(&mut self, value: u8) {
+        self.status &= !STR_CMDDAT;
+
+        match self.pending_command.take() {
+            Some(CMD_CTL_WCTR) => {
+                self.ctr = value;
+                self.status = (self.status & !STR_SYSFLAG) | (value & CTR_SYSFLAG);
+            }
+            Some(CMD_WRITE_OUTPUT_PORT) => {
+                if value & OUTPUT_PORT_RESET == 0 {
+                    self.request_reset();
+                }
+            }
+            // Both looped back as if from the keyboard, the guest finds no
+            // AUX port when the byte comes without the AUX flag
+            Some(CMD_KBD_LOOP) | Some(CMD_AUX_LOOP) => self.push_output(value),
+            Some(CMD_AUX_SEND) => {}
+            Some(command) => unreachable!("Command {:#x} takes no argument", command),
+            None => {
+                self.status |= STR_TIMEOUT;
+                self.push_output(KBD_NO_DEVICE);
+            }
+        }
+    }
+}
+
+impl IoDevice for I8042 {
+    fn io_in(&mut self, port: u16, data: &mut [u8]) {
+        let value = match port {
+            I8042_DATA_PORT => self.read_data(),
+            _ => self.status,
+        };
+
+        data.fill(0);
+        if let Some(byte) = data.first_mut() {
+            *byte = value;
+        }
+    }
+
+    fn io_out(&mut self, port: u16, data: &[u8]) {
+        let value = match data {
+            [value] => *value,
+            _ => {
+                log::warn!("Writing {:x?} to {:#x}", data, port);
+                return;
+            }
+        };
+
+        match port {
+            I8042_DATA_PORT => self.write_data(value),
+            _ => self.write_command(value),
+        }
+    }
+
+    fn reset(&mut self) {
+        *self = Self::new(self.exit_requester.clone(), self.irq_chip.take());
+    }
+}
@@ -128,6 +128,7 @@ pub const SYS_MPIDR_EL1: u64 = sys_reg(3, 0, 0, 0, 5);
 pub const SYS_SCTLR_EL1: u64 = sys_reg(3, 0, 1, 0, 0);
 pub const SYS_TTBR0_EL1: u64 = sys_reg(3, 0, 2, 0, 0);
 pub const SYS_TTBR1_EL1: u64 = sys_reg(3, 0, 2, 0, 1);
+pub const SYS_TCR_EL1: u64 = sys_reg(3, 0, 2, 0, 2);
 pub const SYS_ESR_EL1: u64 = sys_reg(3, 0, 5, 2, 0);
 pub const SYS_SPSR_EL1: u64 = sys_reg(3, 0, 4, 0, 0);
 pub const SYS_MAIR_EL1: u64 = sys_reg(3, 0, 10, 2, 0);
@@ -10,17 +10,25 @@ use std::{
 };
 
 use kvm_bindings::{
-    kvm_one_reg, kvm_reg_list, kvm_regs, kvm_run, kvm_vcpu_init, KVMIO, KVM_ARM_VCPU_PSCI_0_2,
-    KVM_SYSTEM_EVENT_SHUTDOWN,
+    kvm_create_device, kvm_device_attr, kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_ITS,
+    kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_V3, kvm_irq_routing_entry, kvm_one_reg, kvm_reg_list,
+    kvm_regs, kvm_run, kvm_vcpu_init, KVMIO, KVM_ARM_IRQ_TYPE_SHIFT, KVM_ARM_IRQ_TYPE_SPI,
+    KVM_ARM_VCPU_PSCI_0_2, KVM_CAP_ARM_NISV_TO_USER, KVM_DEV_ARM_VGIC_CTRL_INIT,
+    KVM_DEV_ARM_VGIC_GRP_ADDR, KVM_DEV_ARM_VGIC_GRP_CTRL, KVM_EXIT_ARM_NISV, KVM_EXIT_HYPERCALL,
+    KVM_EXIT_MMIO, KVM_EXIT_SYSTEM_EVENT, KVM_MSI_VALID_DEVID, KVM_SYSTEM_EVENT_RESET,
+    KVM_SYSTEM_EVENT_SHUTDOWN, KVM_VGIC_ITS_ADDR_TYPE, KVM_VGIC_V3_ADDR_TYPE_DIST,
+    KVM_VGIC_V3_ADDR_TYPE_REDIST,
 };
-use nix::{ioctl_read, ioctl_write_ptr};
+use nix::{ioctl_read, ioctl_readwrite, ioctl_write_ptr};
 
 use super::Memory;
-use crate::smolvm::{CpuExitReason, MmIoType};
+use crate::smolvm::{bus::VcpuKick, CpuExitReason, Hypercall, MmIoType};
 
 mod cpu;
+mod nisv;
 
 use cpu::*;
+use nisv::{Address, Gpr, GprOperand, LoadStore, TranslationRegs};
 
 #[repr(u64)]
 #[allow(dead_code)]
@@ -64,6 +72,7 @@ pub enum CpuRegister {
     SCTLR_EL1 = SYS_SCTLR_EL1 + (REG_ARM64_SYSREG_BASE | REG_SIZE_U64),
     TTBR0_EL1 = SYS_TTBR0_EL1 + (REG_ARM64_SYSREG_BASE | REG_SIZE_U64),
     TTBR1_EL1 = SYS_TTBR1_EL1 + (REG_ARM64_SYSREG_BASE | REG_SIZE_U64),
+    TCR_EL1 = SYS_TCR_EL1 + (REG_ARM64_SYSREG_BASE | REG_SIZE_U64),
     ESR_EL1 = SYS_ESR_EL1 + (REG_ARM64_SYSREG_BASE | REG_SIZE_U64),
     SPSR_EL1 = SYS_SPSR_EL1 + (REG_ARM64_SYSREG_BASE | REG_SIZE_U64),
     MAIR_EL1 = SYS_MAIR_EL1 + (REG_ARM64_SYSREG_BASE | REG_SIZE_U64),
@@ -74,19 +83,200 @@ ioctl_write_ptr!(kvm_set_one_reg, KVMIO, 0xac, kvm_one_reg);
 ioctl_write_ptr!(kvm_arm_vcpu_init, KVMIO, 0xae, kvm_vcpu_init);
 ioctl_read!(kvm_arm_preferred_target, KVMIO, 0xaf, kvm_vcpu_init);
 ioctl_write_ptr!(kvm_get_reg_list, KVMIO, 0xb0, kvm_reg_list);
+ioctl_readwrite!(kvm_create_device, KVMIO, 0xe0, kvm_create_device);
+ioctl_write_ptr!(kvm_set_device_attr, KVMIO, 0xe1, kvm_device_attr);
+
+// The same layout as the qemu `virt` machine
+pub const GIC_DIST_BASE: u64 = 0x0800_0000;
+pub const GIC_DIST_SIZE: u64 = 0x1_0000;
+pub const GIC_REDIST_BASE: u64 = 0x080a_0000;
+pub const GIC_REDIST_SIZE: u64 = 0x2_0000; // per vCPU
+pub const GIC_ITS_BASE: u64 = 0x0808_0000;
+pub const GIC_ITS_SIZE: u64 = 0x2_0000;
+
+/// The ITS translates the MSIs by the requester id
+pub const MSI_ROUTING_FLAGS: u32 = KVM_MSI_VALID_DEVID;
+
+// SPIs start after the 16 SGIs and the 16 PPIs
+const GIC_SPI_BASE: u32 = 32;
+
+// The core registers past X0..X30 by their index in `kvm_regs`
+const CORE_REG_SP_EL0: u64 = 31;
+const CORE_REG_PC: u64 = 32;
+const CORE_REG_PSTATE: u64 = 33;
+const CORE_REG_SP_EL1: u64 = 34;
+
+/// Lets the VM emulate the data aborts without the instruction syndrome,
+/// see `nisv`
+pub fn enable_nisv_to_user(vm_fd: RawFd) -> Result<(), std::io::Error> {
+    super::enable_vm_cap(vm_fd, KVM_CAP_ARM_NISV_TO_USER, [0; 4])
+}
+
+// The SMCCC filter, not in the bindings
+const KVM_ARM_VM_SMCCC_CTRL: u32 = 0;
+const KVM_ARM_VM_SMCCC_FILTER: u64 = 0;
+const KVM_SMCCC_FILTER_FWD_TO_USER: u8 = 2;
+
+#[repr(C)]
+struct KvmSmcccFilter {
+    base: u32,
+    nr_functions: u32,
+    action: u8,
+    pad: [u8; 15],
+}
+
+/// What the guest gets for the SMCCC calls nobody answers
+const SMCCC_RET_NOT_SUPPORTED: u64 = -1_i64 as u64;
+
+/// Has KVM exit on the SMCCC calls of the function ID rather than answering
+/// them itself. KVM takes the filter only before the vCPU first runs and
+/// refuses the IDs it reserves, e.g. PSCI.
+pub fn forward_smccc_call(vm_fd: RawFd, function_id: u64) -> Result<(), std::io::Error> {
+    let filter = KvmSmcccFilter {
+        base: u32::try_from(function_id)
+            .map_err(|_| std::io::Error::from_raw_os_error(libc::EINVAL))?,
+        nr_functions: 1,
+        action: KVM_SMCCC_FILTER_FWD_TO_USER,
+        pad: [0; 15],
+    };
+    let device_attr = kvm_device_attr {
+        flags: 0,
+        group: KVM_ARM_VM_SMCCC_CTRL,
+        attr: KVM_ARM_VM_SMCCC_FILTER,
+        addr: &filter as *const _ as u64,
+    };
+    unsafe { kvm_set_device_attr(vm_fd, &device_attr as *const _) }?;
+
+    Ok(())
+}
+
+fn create_vgic_device(
+    vm_fd: RawFd,
+    type_: u32,
+    addrs: &[(u32, u64)],
+) -> Result<RawFd, std::io::Error> {
+    let mut create_device = kvm_create_device {
+        type_,
+        fd: 0,
+        flags: 0,
+    };
+    unsafe { kvm_create_device(vm_fd, &mut create_device as *mut _) }?;
+
+    let device_fd = create_device.fd as RawFd;
+
+    for (attr, addr) in addrs {
+        let device_attr = kvm_device_attr {
+            flags: 0,
+            group: KVM_DEV_ARM_VGIC_GRP_ADDR,
+            attr: *attr as u64,
+            addr: addr as *const u64 as u64,
+        };
+        unsafe { kvm_set_device_attr(device_fd, &device_attr as *const _) }?;
+    }
+
+    Ok(device_fd)
+}
+
+/// Creates the in-kernel GICv3 with the ITS and places its distributor,
+/// redistributors and the ITS into the guest physical address space.
+pub fn create_irqchip(vm_fd: RawFd) -> Result<Vec<RawFd>, std::io::Error> {
+    let vgic_fd = create_vgic_device(
+        vm_fd,
+        kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_V3,
+        &[
+            (KVM_VGIC_V3_ADDR_TYPE_DIST, GIC_DIST_BASE),
+            (KVM_VGIC_V3_ADDR_TYPE_REDIST, GIC_REDIST_BASE),
+        ],
+    )?;
+    let its_fd = create_vgic_device(
+        vm_fd,
+        kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_ITS,
+        &[(KVM_VGIC_ITS_ADDR_TYPE, GIC_ITS_BASE)],
+    )?;
+
+    Ok(vec![vgic_fd, its_fd])
+}
+
+/// Initializes the GICv3 and then the ITS once all vCPUs exist
+pub fn finalize_irqchip(device_fds: &[RawFd]) -> Result<(), std::io::Error> {
+    for device_fd in device_fds {
+        let device_attr = kvm_device_attr {
+            flags: 0,
+            group: KVM_DEV_ARM_VGIC_GRP_CTRL,
+            attr: KVM_DEV_ARM_VGIC_CTRL_INIT as u64,
+            addr: 0,
+        };
+        unsafe { kvm_set_device_attr(*device_fd, &device_attr as *const _) }?;
+    }
+
+    Ok(())
+}
+
+/// The guest brings the GIC to a known state when it boots, nothing to
+/// restore when the VM is reset
+pub struct IrqChipState;
+
+pub fn save_irqchip(_vm_fd: RawFd) -> Result<IrqChipState, std::io::Error> {
+    Ok(IrqChipState)
+}
+
+pub fn restore_irqchip(_vm_fd: RawFd, _state: &IrqChipState) -> Result<(), std::io::Error> {
+    Ok(())
+}
+
+/// `KVM_IRQ_LINE` does not go through the routing table on aarch64,
+/// only the MSIs need routes.
+pub fn irqchip_routes() -> Vec<kvm_irq_routing_entry> {
+    Vec::new()
+}
+
+/// Encodes an SPI for `KVM_IRQ_LINE`
+pub fn spi_irq_line(spi: u32) -> u32 {
+    (KVM_ARM_IRQ_TYPE_SPI << KVM_ARM_IRQ_TYPE_SHIFT) | (spi + GIC_SPI_BASE)
+}
+
+/// A register the data abort without the syndrome loads or stores
+#[derive(Clone, Copy)]
+struct NisvTransfer {
+    ipa: u64,
+    register: GprOperand,
+    /// Of the register to store
+    value: u64,
+}
+
+/// The access to finish before running the vCPU again, one transfer per
+/// MMIO exit
+struct NisvAccess {
+    is_load: bool,
+    /// In bytes
+    access_size: u8,
+    sign_extend: bool,
+    transfers: Vec<NisvTransfer>,
+    next: usize,
+    /// The base register updated by the pre- and post-indexed accesses
+    writeback: Option<(Gpr, u64)>,
+}
 
 pub struct Cpu {
     vcpu_fd: RawFd,
     vcpu_run: *mut kvm_run,
     vcpu_mmap_size: i32,
-    _memory: Arc<Mutex<Memory>>,
+    memory: Arc<Mutex<Memory>>,
+    /// Initializing the vCPU again resets it
+    vcpu_init: kvm_vcpu_init,
+    mmio: u64,
+    nisv: Option<NisvAccess>,
+    /// X0..X3 to set before running the vCPU again
+    hypercall_ret: Option<[u64; 4]>,
+    /// Set again on reset
+    mpidr: Option<u64>,
 }
 
 impl Cpu {
     pub fn new(
         kvm_fd: RawFd,
         vm_fd: RawFd,
-        _memory: Arc<Mutex<Memory>>,
+        memory: Arc<Mutex<Memory>>,
     ) -> Result<Self, std::io::Error> {
         let vcpu_fd = unsafe { super::kvm_create_vcpu(vm_fd, 0)? };
 
@@ -108,14 +298,20 @@ impl Cpu {
 
         let mut kvi = kvm_bindings::kvm_vcpu_init::default();
         unsafe { kvm_arm_preferred_target(vm_fd, &mut kvi)? };
-        //kvi.features[0] |= 1 << KVM_ARM_VCPU_PSCI_0_2;
+        // SYSTEM_OFF and SYSTEM_RESET came with PSCI 0.2
+        kvi.features[0] |= 1 << KVM_ARM_VCPU_PSCI_0_2;
         unsafe { kvm_arm_vcpu_init(vcpu_fd, &mut kvi)? };
 
         Ok(Self {
             vcpu_fd,
             vcpu_run,
             vcpu_mmap_size,
-            _memory,
+            memory,
+            vcpu_init: kvi,
+            mmio: 0,
+            nisv: None,
+            hypercall_ret: None,
+            mpidr: None,
         })
     }
 
@@ -138,17 +334,68 @@ impl Cpu {
         Ok(())
     }
 
+    /// Places the vCPU in the topology, the affinity routing of the GIC
+    /// follows. Must be called before the vCPU first runs.
+    pub fn set_mpidr(&mut self, mpidr: u64) -> Result<(), std::io::Error> {
+        self.set_one_reg(CpuRegister::MPIDR_EL1, mpidr)?;
+        self.mpidr = Some(mpidr);
+
+        Ok(())
+    }
+
+    /// Brings the vCPU back to the state `init` and `set_mpidr` have left
+    /// it in
+    pub fn reset(&mut self) -> Result<(), std::io::Error> {
+        self.nisv = None;
+        self.hypercall_ret = None;
+        unsafe { kvm_arm_vcpu_init(self.vcpu_fd, &self.vcpu_init)? };
+
+        // KVM_ARM_VCPU_INIT has put back the one KVM picks
+        if let Some(mpidr) = self.mpidr {
+            self.set_one_reg(CpuRegister::MPIDR_EL1, mpidr)?;
+        }
+
+        self.init()
+    }
+
+    /// Lets the other threads interrupt `run` on the calling thread
+    pub fn kick(&self) -> VcpuKick {
+        super::vcpu_kick(self.vcpu_run)
+    }
+
     pub fn run(&mut self) -> Result<CpuExitReason, std::io::Error> {
         let run = &mut unsafe { std::slice::from_raw_parts_mut(self.vcpu_run, 1) }[0];
 
-        unsafe { super::kvm_run(self.vcpu_fd, 0)? };
+        if let Some(ret) = self.hypercall_ret.take() {
+            for (index, value) in ret.iter().enumerate() {
+                self.set_core_reg(index as u64, *value)?;
+            }
+        }
 
-        let exit_reason = match run.exit_reason {
-            KVM_SYSTEM_EVENT_SHUTDOWN => {
-                log::error!("Shutdown at {:#x}", self.get_instruction_pointer()?);
+        if self.finish_nisv_transfer()? {
+            return Ok(self.nisv_exit());
+        }
 
-                CpuExitReason::NotSupported
-            }
+        if !unsafe { super::enter_guest(self.vcpu_fd, self.vcpu_run)? } {
+            return Ok(CpuExitReason::Continue);
+        }
+
+        let exit_reason = match run.exit_reason {
+            // PSCI SYSTEM_OFF and SYSTEM_RESET
+            KVM_EXIT_SYSTEM_EVENT => match unsafe { run.__bindgen_anon_1.system_event.type_ } {
+                KVM_SYSTEM_EVENT_SHUTDOWN => CpuExitReason::Shutdown,
+                KVM_SYSTEM_EVENT_RESET => CpuExitReason::Reset,
+                type_ => {
+                    log::error!(
+                        "System event {} at {:#x}",
+                        type_,
+                        self.get_instruction_pointer()?
+                    );
+
+                    CpuExitReason::NotSupported
+                }
+            },
+            // KVM sign-extends the value read if the syndrome says so
             KVM_EXIT_MMIO => unsafe {
                 let mmio = &mut run.__bindgen_anon_1.mmio;
                 let pa = mmio.phys_addr;
@@ -172,6 +419,11 @@ impl Cpu {
                             pa,
                             &mut *(&mut data[0] as *const _ as *mut u32),
                         )),
+                        // 64 bit
+                        8 => CpuExitReason::MmIo(MmIoType::QuadWordIn(
+                            pa,
+                            &mut *(&mut data[0] as *const _ as *mut u64),
+                        )),
                         _ => CpuExitReason::NotSupported,
                     }
                 } else {
@@ -185,15 +437,258 @@ impl Cpu {
                             pa,
                             *(&mut data[0] as *const _ as *mut u32),
                         )),
+                        8 => CpuExitReason::MmIo(MmIoType::QuadWordOut(
+                            pa,
+                            *(&mut data[0] as *const _ as *mut u64),
+                        )),
                         _ => CpuExitReason::NotSupported,
                     }
                 }
             },
+            // The calls `forward_smccc_call` has enabled, KVM has stepped
+            // over the `hvc`/`smc` already
+            KVM_EXIT_HYPERCALL => {
+                let nr = unsafe { run.__bindgen_anon_1.hypercall.nr };
+                let mut args = [0; 6];
+                for (index, arg) in args.iter_mut().enumerate() {
+                    *arg = self.get_core_reg(index as u64 + 1)?;
+                }
+
+                let ret = self
+                    .hypercall_ret
+                    .insert([SMCCC_RET_NOT_SUPPORTED, 0, 0, 0]);
+                CpuExitReason::Hypercall(Hypercall { nr, args, ret })
+            }
+            // A load/store KVM has no syndrome for, e.g. `ldp` or `str x0, [x1], #8`
+            KVM_EXIT_ARM_NISV => {
+                let fault_ipa = unsafe { run.__bindgen_anon_1.arm_nisv.fault_ipa };
+
+                match self.decode_nisv(fault_ipa)? {
+                    Some(access) => {
+                        self.nisv = Some(access);
+                        self.nisv_exit()
+                    }
+                    None => CpuExitReason::NotSupported,
+                }
+            }
+            _ => {
+                log::error!(
+                    "Exit {:#x} at {:#x}",
+                    run.exit_reason,
+                    self.get_instruction_pointer()?
+                );
+
+                CpuExitReason::NotSupported
+            }
         };
 
         Ok(exit_reason)
     }
 
+    /// Fetches and decodes the instruction that has aborted accessing the
+    /// IPA, reads the registers the access needs
+    fn decode_nisv(&mut self, fault_ipa: u64) -> Result<Option<NisvAccess>, std::io::Error> {
+        let pc = self.get_core_reg(CORE_REG_PC)?;
+        let regs = TranslationRegs {
+            sctlr: self.get_one_reg(CpuRegister::SCTLR_EL1)?,
+            tcr: self.get_one_reg(CpuRegister::TCR_EL1)?,
+            ttbr0: self.get_one_reg(CpuRegister::TTBR0_EL1)?,
+            ttbr1: self.get_one_reg(CpuRegister::TTBR1_EL1)?,
+        };
+
+        let opcode = {
+            let memory = self.memory.lock().unwrap();
+            match nisv::translate(&memory, &regs, pc) {
+                Some(gpa) if memory.find_span(gpa).is_some() => memory.read_obj::<u32>(gpa),
+                _ => {
+                    log::error!("Cannot fetch the instruction at {:#x}", pc);
+                    return Ok(None);
+                }
+            }
+        };
+
+        let load_store = match bad64::decode(opcode, pc) {
+            Ok(instruction) => match LoadStore::decode(&instruction) {
+                Some(load_store) => load_store,
+                None => {
+                    log::error!(
+                        "Cannot emulate `{}` at {:#x} accessing {:#x}",
+                        instruction,
+                        pc,
+                        fault_ipa
+                    );
+                    return Ok(None);
+                }
+            },
+            Err(err) => {
+                log::error!("Cannot decode {:#010x} at {:#x}: {}", opcode, pc, err);
+                return Ok(None);
+            }
+        };
+
+        let va = match load_store.address {
+            Address::Offset { base, offset } | Address::PreIndex { base, offset } => {
+                self.get_gpr(base)?.wrapping_add(offset as u64)
+            }
+            Address::PostIndex { base, .. } => self.get_gpr(base)?,
+            Address::Register { base, index, shift } => self
+                .get_gpr(base)?
+                .wrapping_add(nisv::extend_index(self.get_gpr(index)?, shift)),
+            Address::Literal(address) => address,
+        };
+        // The page offset is the same for the virtual and the physical address
+        let ipa = (fault_ipa & !0xfff) | (va & 0xfff);
+
+        let mut transfers = Vec::with_capacity(load_store.registers.len());
+        for (index, register) in load_store.registers.iter().enumerate() {
+            transfers.push(NisvTransfer {
+                ipa: ipa + index as u64 * load_store.access_size as u64,
+                register: *register,
+                value: if load_store.is_load {
+                    0
+                } else {
+                    self.get_gpr(register.reg)?
+                },
+            });
+        }
+
+        Ok(Some(NisvAccess {
+            is_load: load_store.is_load,
+            access_size: load_store.access_size,
+            sign_extend: load_store.sign_extend,
+            transfers,
+            next: 0,
+            writeback: load_store.address.writeback(va),
+        }))
+    }
+
+    /// The MMIO exit for the next transfer of the pending access
+    fn nisv_exit(&mut self) -> CpuExitReason {
+        let (access_size, transfer) = match &self.nisv {
+            Some(access) if !access.is_load => {
+                let transfer = access.transfers[access.next];
+                return match access.access_size {
+                    1 => CpuExitReason::MmIo(MmIoType::ByteOut(transfer.ipa, transfer.value as u8)),
+                    2 => {
+                        CpuExitReason::MmIo(MmIoType::WordOut(transfer.ipa, transfer.value as u16))
+                    }
+                    4 => CpuExitReason::MmIo(MmIoType::DoubleWordOut(
+                        transfer.ipa,
+                        transfer.value as u32,
+                    )),
+                    _ => CpuExitReason::MmIo(MmIoType::QuadWordOut(transfer.ipa, transfer.value)),
+                };
+            }
+            Some(access) => (access.access_size, access.transfers[access.next]),
+            None => return CpuExitReason::NotSupported,
+        };
+
+        // Zero-extended unless the instruction says otherwise
+        self.mmio = 0;
+        match access_size {
+            1 => CpuExitReason::MmIo(MmIoType::ByteIn(transfer.ipa, unsafe {
+                &mut *(&mut self.mmio as *const _ as *mut u8)
+            })),
+            2 => CpuExitReason::MmIo(MmIoType::WordIn(transfer.ipa, unsafe {
+                &mut *(&mut self.mmio as *const _ as *mut u16)
+            })),
+            4 => CpuExitReason::MmIo(MmIoType::DoubleWordIn(transfer.ipa, unsafe {
+                &mut *(&mut self.mmio as *const _ as *mut u32)
+            })),
+            _ => CpuExitReason::MmIo(MmIoType::QuadWordIn(transfer.ipa, &mut self.mmio)),
+        }
+    }
+
+    /// Completes the transfer the bus has handled, `true` if the access has
+    /// more of them. Updates the base register and steps over the
+    /// instruction after the last one.
+    fn finish_nisv_transfer(&mut self) -> Result<bool, std::io::Error> {
+        let mut access = match self.nisv.take() {
+            Some(access) => access,
+            None => return Ok(false),
+        };
+
+        let transfer = access.transfers[access.next];
+        if access.is_load {
+            let unused_bits = 64 - access.access_size as u32 * 8;
+            let mut value = self.mmio;
+            if access.sign_extend {
+                value = (((value << unused_bits) as i64) >> unused_bits) as u64;
+            }
+            if !transfer.register.is_64_bit {
+                value &= 0xffff_ffff;
+            }
+            self.set_gpr(transfer.register.reg, value)?;
+        }
+
+        access.next += 1;
+        if access.next < access.transfers.len() {
+            self.nisv = Some(access);
+            return Ok(true);
+        }
+
+        if let Some((base, value)) = access.writeback {
+            self.set_gpr(base, value)?;
+        }
+        let pc = self.get_core_reg(CORE_REG_PC)?;
+        self.set_core_reg(CORE_REG_PC, pc + 4)?;
+
+        Ok(false)
+    }
+
+    /// The stack pointer of EL1h or the one of EL0 and EL1t
+    fn sp_index(&self) -> Result<u64, std::io::Error> {
+        let pstate = self.get_core_reg(CORE_REG_PSTATE)?;
+
+        Ok(if pstate as u32 & PSR_MODE_MASK == PSR_MODE_EL1h {
+            CORE_REG_SP_EL1
+        } else {
+            CORE_REG_SP_EL0
+        })
+    }
+
+    fn get_gpr(&self, reg: Gpr) -> Result<u64, std::io::Error> {
+        match reg {
+            Gpr::X(index) => self.get_core_reg(index as u64),
+            Gpr::Zero => Ok(0),
+            Gpr::Sp => self.get_core_reg(self.sp_index()?),
+        }
+    }
+
+    fn set_gpr(&mut self, reg: Gpr, value: u64) -> Result<(), std::io::Error> {
+        match reg {
+            Gpr::X(index) => self.set_core_reg(index as u64, value),
+            Gpr::Zero => Ok(()),
+            Gpr::Sp => self.set_core_reg(self.sp_index()?, value),
+        }
+    }
+
+    /// The core registers are indexed by their offset in `kvm_regs` in
+    /// 64-bit units
+    fn get_core_reg(&self, index: u64) -> Result<u64, std::io::Error> {
+        let mut reg_value: u64 = 0;
+        let mut reg = kvm_one_reg {
+            id: index * 2 + (REG_ARM64_CORE_BASE | REG_SIZE_U64),
+            addr: &mut reg_value as *mut u64 as u64,
+        };
+
+        unsafe { kvm_get_one_reg(self.vcpu_fd, &mut reg)? };
+
+        Ok(reg_value)
+    }
+
+    fn set_core_reg(&mut self, index: u64, value: u64) -> Result<(), std::io::Error> {
+        let mut reg_value = value;
+        let mut reg = kvm_one_reg {
+            id: index * 2 + (REG_ARM64_CORE_BASE | REG_SIZE_U64),
+            addr: &mut reg_value as *mut u64 as u64,
+        };
+
+        unsafe { kvm_set_one_reg(self.vcpu_fd, &mut reg)? };
+
+        Ok(())
+    }
+
     fn set_one_reg(&mut self, reg_id: CpuRegister, reg_value: u64) -> Result<(), std::io::Error> {
         let mut reg_value = reg_value;
         let mut reg = kvm_one_reg {
@@ -219,6 +714,55 @@ impl Cpu {
         Ok(reg_value)
     }
 
+    /// Logs the registers, e.g. when the guest has crashed
+    pub fn dump_state(&self) {
+        // The core registers are indexed by their offset in `kvm_regs` in
+        // 32-bit units: X0..X30, SP, PC, PSTATE
+        let core_reg = |index: u64| {
+            let mut reg_value: u64 = 0;
+            let mut reg = kvm_one_reg {
+                id: index * 2 + (REG_ARM64_CORE_BASE | REG_SIZE_U64),
+                addr: &mut reg_value as *mut u64 as u64,
+            };
+
+            unsafe { kvm_get_one_reg(self.vcpu_fd, &mut reg) }.map(|_| reg_value)
+        };
+
+        match (core_reg(32), core_reg(31), core_reg(33)) {
+            (Ok(pc), Ok(sp), Ok(pstate)) => {
+                log::error!("PC {:#018x} SP {:#018x} PSTATE {:#010x}", pc, sp, pstate)
+            }
+            _ => {
+                log::error!("Cannot read the vCPU registers");
+                return;
+            }
+        }
+
+        for index in (0..31).step_by(4) {
+            let regs = (index..(index + 4).min(31))
+                .map(|index| {
+                    format!(
+                        "X{:<2} {:#018x}",
+                        index,
+                        core_reg(index).unwrap_or_default()
+                    )
+                })
+                .collect::<Vec<_>>();
+            log::error!("{}", regs.join(" "));
+        }
+
+        for (name, register) in [
+            ("ESR_EL1", CpuRegister::ESR_EL1),
+            ("SCTLR_EL1", CpuRegister::SCTLR_EL1),
+            ("TTBR0_EL1", CpuRegister::TTBR0_EL1),
+            ("TTBR1_EL1", CpuRegister::TTBR1_EL1),
+        ] {
+            if let Ok(value) = self.get_one_reg(register) {
+                log::error!("{} {:#018x}", name, value);
+            }
+        }
+    }
+
     pub fn set_instruction_pointer(&mut self, ip: u64) -> Result<(), std::io::Error> {
         self.set_one_reg(CpuRegister::PC, ip)
     }
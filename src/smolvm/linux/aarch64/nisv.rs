@@ -0,0 +1,429 @@
+//! The data aborts KVM cannot emulate as the syndrome does not describe the
+//! instruction (ISV=0): the load/store pairs, the pre- and post-indexed and
+//! the register offset accesses. `KVM_CAP_ARM_NISV_TO_USER` makes KVM exit
+//! with `KVM_EXIT_ARM_NISV` instead of injecting an external abort, the
+//! instruction is then fetched from the guest memory and decoded here.
+
+use bad64::{Imm, Instruction, Op, Operand, Reg, Shift};
+
+use crate::smolvm::Memory;
+
+/// A general purpose register as the instruction names it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Gpr {
+    /// X0..X30 by the index
+    X(u8),
+    /// XZR or WZR
+    Zero,
+    /// SP or WSP, the one of the current exception level
+    Sp,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct GprOperand {
+    pub reg: Gpr,
+    /// Writing the W register clears the upper half
+    pub is_64_bit: bool,
+}
+
+impl GprOperand {
+    fn new(reg: Reg) -> Option<Self> {
+        let (first, is_64_bit) = match reg as u32 {
+            n if (Reg::W0 as u32..=Reg::WSP as u32).contains(&n) => (Reg::W0, false),
+            n if (Reg::X0 as u32..=Reg::SP as u32).contains(&n) => (Reg::X0, true),
+            _ => return None,
+        };
+
+        let reg = match reg as u32 - first as u32 {
+            31 => Gpr::Zero,
+            32 => Gpr::Sp,
+            index => Gpr::X(index as u8),
+        };
+
+        Some(Self { reg, is_64_bit })
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Address {
+    /// `[base, #offset]`
+    Offset { base: Gpr, offset: i64 },
+    /// `[base, #offset]!`, the base is updated before the access
+    PreIndex { base: Gpr, offset: i64 },
+    /// `[base], #offset`, the base is updated after the access
+    PostIndex { base: Gpr, offset: i64 },
+    /// `[base, index, extend #amount]`
+    Register { base: Gpr, index: Gpr, shift: Shift },
+    /// PC-relative
+    Literal(u64),
+}
+
+/// A load or a store of one or two registers
+#[derive(Debug)]
+pub struct LoadStore {
+    pub is_load: bool,
+    pub registers: Vec<GprOperand>,
+    /// Of each element in bytes
+    pub access_size: u8,
+    pub sign_extend: bool,
+    pub address: Address,
+}
+
+impl Address {
+    fn new(operand: &Operand) -> Option<Self> {
+        let base = |reg: &Reg| GprOperand::new(*reg).map(|operand| operand.reg);
+
+        let address = match operand {
+            Operand::MemReg(reg) => Address::Offset {
+                base: base(reg)?,
+                offset: 0,
+            },
+            Operand::MemOffset { reg, offset, .. } => Address::Offset {
+                base: base(reg)?,
+                offset: imm_value(offset),
+            },
+            Operand::MemPreIdx { reg, imm } => Address::PreIndex {
+                base: base(reg)?,
+                offset: imm_value(imm),
+            },
+            Operand::MemPostIdxImm { reg, imm } => Address::PostIndex {
+                base: base(reg)?,
+                offset: imm_value(imm),
+            },
+            Operand::MemExt { regs, shift, .. } => Address::Register {
+                base: base(&regs[0])?,
+                index: base(&regs[1])?,
+                shift: shift.unwrap_or(Shift::LSL(0)),
+            },
+            Operand::Label(imm) => Address::Literal(imm_value(imm) as u64),
+            _ => return None,
+        };
+
+        Some(address)
+    }
+
+    /// The base register and its value past the access if it is updated
+    pub fn writeback(&self, address: u64) -> Option<(Gpr, u64)> {
+        match *self {
+            Address::PreIndex { base, .. } => Some((base, address)),
+            Address::PostIndex { base, offset } => {
+                Some((base, address.wrapping_add(offset as u64)))
+            }
+            _ => None,
+        }
+    }
+}
+
+fn imm_value(imm: &Imm) -> i64 {
+    match *imm {
+        Imm::Signed(imm) => imm,
+        Imm::Unsigned(imm) => imm as i64,
+    }
+}
+
+/// Extends and shifts the index register of the register offset
+pub fn extend_index(index: u64, shift: Shift) -> u64 {
+    match shift {
+        Shift::UXTW(amount) => ((index as u32) as u64) << amount,
+        Shift::SXTW(amount) => ((index as i32) as i64 as u64) << amount,
+        Shift::LSL(amount) | Shift::UXTX(amount) | Shift::SXTX(amount) => index << amount,
+        _ => index,
+    }
+}
+
+impl LoadStore {
+    /// The loads and the stores of the general purpose registers, not of
+    /// the SIMD ones nor the atomics
+    pub fn decode(instruction: &Instruction) -> Option<Self> {
+        // Loads the register size unless the opcode says otherwise
+        let (is_load, access_size, sign_extend) = match instruction.op() {
+            Op::LDR | Op::LDUR | Op::LDP | Op::LDNP => (true, None, false),
+            Op::LDRB | Op::LDURB => (true, Some(1), false),
+            Op::LDRH | Op::LDURH => (true, Some(2), false),
+            Op::LDRSB | Op::LDURSB => (true, Some(1), true),
+            Op::LDRSH | Op::LDURSH => (true, Some(2), true),
+            Op::LDRSW | Op::LDURSW | Op::LDPSW => (true, Some(4), true),
+            Op::STR | Op::STUR | Op::STP | Op::STNP => (false, None, false),
+            Op::STRB | Op::STURB => (false, Some(1), false),
+            Op::STRH | Op::STURH => (false, Some(2), false),
+            _ => return None,
+        };
+
+        let (address, registers) = instruction.operands().split_last()?;
+        let registers = registers
+            .iter()
+            .map(|operand| match operand {
+                Operand::Reg { reg, arrspec: None } => GprOperand::new(*reg),
+                _ => None,
+            })
+            .collect::<Option<Vec<_>>>()?;
+
+        let first = registers.first()?;
+        if registers.len() > 2 || first.reg == Gpr::Sp {
+            return None;
+        }
+
+        Some(Self {
+            is_load,
+            access_size: access_size.unwrap_or(if first.is_64_bit { 8 } else { 4 }),
+            sign_extend,
+            address: Address::new(address)?,
+            registers,
+        })
+    }
+}
+
+/// What the guest has set up for the translation at EL1
+pub struct TranslationRegs {
+    pub sctlr: u64,
+    pub tcr: u64,
+    pub ttbr0: u64,
+    pub ttbr1: u64,
+}
+
+const SCTLR_M: u64 = 1 << 0;
+
+const TCR_EPD0: u64 = 1 << 7;
+const TCR_EPD1: u64 = 1 << 23;
+/// A 48-bit range
+const MIN_TXSZ: u64 = 16;
+/// With FEAT_TTST
+const MAX_TXSZ: u64 = 48;
+
+const TTBR_BADDR_MASK: u64 = 0x0000_ffff_ffff_fffe;
+/// Bits [47:12] of the next table or the output address
+const DESC_ADDR_MASK: u64 = 0x0000_ffff_ffff_f000;
+const DESC_VALID: u64 = 1 << 0;
+/// A table rather than a block above the level 3, a page at the level 3
+const DESC_TABLE: u64 = 1 << 1;
+
+/// Walks the stage 1 tables for the guest virtual address, the 52-bit
+/// addresses are not supported
+pub fn translate(memory: &Memory, regs: &TranslationRegs, va: u64) -> Option<u64> {
+    if regs.sctlr & SCTLR_M == 0 {
+        return Some(va);
+    }
+
+    // Bit 55 selects the upper or the lower range
+    let (tsz, granule_bits, ttbr) = if va & (1 << 55) != 0 {
+        if regs.tcr & TCR_EPD1 != 0 {
+            return None;
+        }
+        let granule_bits = match (regs.tcr >> 30) & 3 {
+            0b01 => 14,
+            0b11 => 16,
+            _ => 12,
+        };
+        ((regs.tcr >> 16) & 0x3f, granule_bits, regs.ttbr1)
+    } else {
+        if regs.tcr & TCR_EPD0 != 0 {
+            return None;
+        }
+        let granule_bits = match (regs.tcr >> 14) & 3 {
+            0b01 => 16,
+            0b10 => 14,
+            _ => 12,
+        };
+        (regs.tcr & 0x3f, granule_bits, regs.ttbr0)
+    };
+
+    // Without FEAT_LVA the range is 48 bits at most, and at least one level
+    // of table is needed
+    if !(MIN_TXSZ..=MAX_TXSZ).contains(&tsz) || 64 - tsz as u32 <= granule_bits {
+        return None;
+    }
+
+    let va_bits = 64 - tsz as u32;
+    // Each table resolves that many bits
+    let stride = granule_bits - 3;
+    let levels = (va_bits - granule_bits + stride - 1) / stride;
+
+    let mut table = ttbr & TTBR_BADDR_MASK;
+    for level in (4 - levels)..4 {
+        let shift = granule_bits + stride * (3 - level);
+        let index_bits = (va_bits - shift).min(stride);
+        let index = (va >> shift) & ((1 << index_bits) - 1);
+
+        let desc_gpa = table + index * 8;
+        memory.find_span(desc_gpa)?;
+        let desc: u64 = memory.read_obj(desc_gpa);
+
+        if desc & DESC_VALID == 0 {
+            return None;
+        }
+
+        let output = desc & DESC_ADDR_MASK & !((1 << granule_bits) - 1);
+        if level < 3 && desc & DESC_TABLE != 0 {
+            table = output;
+            continue;
+        }
+        if level == 3 && desc & DESC_TABLE == 0 {
+            return None;
+        }
+
+        let offset_mask = (1 << shift) - 1;
+        return Some((output & !offset_mask) | (va & offset_mask));
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::smolvm::test_memory;
+
+    fn decode(opcode: u32) -> Option<LoadStore> {
+        LoadStore::decode(&bad64::decode(opcode, 0).unwrap())
+    }
+
+    #[test]
+    fn test_decode() {
+        // ldp w1, w2, [x3]
+        let load_store = decode(0x2940_0861).unwrap();
+        assert!(load_store.is_load);
+        assert_eq!(load_store.access_size, 4);
+        assert_eq!(load_store.registers.len(), 2);
+        assert_eq!(load_store.registers[1].reg, Gpr::X(2));
+        assert!(!load_store.registers[0].is_64_bit);
+        assert!(load_store.address.writeback(0x1000).is_none());
+
+        // stp x1, x2, [sp, #-16]!
+        let load_store = decode(0xa9bf_0be1).unwrap();
+        assert!(!load_store.is_load);
+        assert_eq!(load_store.access_size, 8);
+        assert!(load_store.registers[0].is_64_bit);
+        assert!(matches!(
+            load_store.address,
+            Address::PreIndex {
+                base: Gpr::Sp,
+                offset: -16
+            }
+        ));
+        assert_eq!(load_store.address.writeback(0xff0), Some((Gpr::Sp, 0xff0)));
+
+        // ldp x1, x2, [x3], #16
+        let load_store = decode(0xa8c1_0861).unwrap();
+        assert_eq!(
+            load_store.address.writeback(0x1000),
+            Some((Gpr::X(3), 0x1010))
+        );
+
+        // ldr w1, [x2, #8]!
+        let load_store = decode(0xb840_8c41).unwrap();
+        assert_eq!(load_store.access_size, 4);
+        assert_eq!(
+            load_store.address.writeback(0x1008),
+            Some((Gpr::X(2), 0x1008))
+        );
+
+        // ldrsw x1, [x2, #4]
+        let load_store = decode(0xb980_0441).unwrap();
+        assert_eq!(load_store.access_size, 4);
+        assert!(load_store.sign_extend);
+        assert!(load_store.registers[0].is_64_bit);
+        assert!(matches!(
+            load_store.address,
+            Address::Offset {
+                base: Gpr::X(2),
+                offset: 4
+            }
+        ));
+
+        // ldrsb w1, [x2]
+        let load_store = decode(0x39c0_0041).unwrap();
+        assert_eq!(load_store.access_size, 1);
+        assert!(load_store.sign_extend);
+        assert!(!load_store.registers[0].is_64_bit);
+
+        // ldr x1, [x2, w3, sxtw #3]
+        let load_store = decode(0xf863_d841).unwrap();
+        assert!(matches!(
+            load_store.address,
+            Address::Register {
+                base: Gpr::X(2),
+                index: Gpr::X(3),
+                shift: Shift::SXTW(3)
+            }
+        ));
+
+        // ldr q0, [x1], a SIMD register
+        assert!(decode(0x3dc0_0020).is_none());
+    }
+
+    #[test]
+    fn test_extend_index() {
+        assert_eq!(
+            extend_index(0xffff_ffff_8000_0001, Shift::UXTW(2)),
+            0x2_0000_0004
+        );
+        assert_eq!(
+            extend_index(0xffff_fff0, Shift::SXTW(3)),
+            0xffff_ffff_ffff_ff80
+        );
+        assert_eq!(extend_index(5, Shift::LSL(3)), 40);
+        assert_eq!(extend_index(0xffff_fff0, Shift::SXTX(0)), 0xffff_fff0);
+    }
+
+    const TABLE_VALID: u64 = DESC_VALID | DESC_TABLE;
+
+    #[test]
+    fn test_translate_4k() {
+        let mut memory = test_memory(0, 0x40_0000);
+        memory.write_obj(0x1000 + 8, &(0x2000 | TABLE_VALID));
+        memory.write_obj(0x2000 + 2 * 8, &(0x3000 | TABLE_VALID));
+        memory.write_obj(0x3000 + 3 * 8, &(0x4000 | TABLE_VALID));
+        memory.write_obj(0x4000 + 4 * 8, &(0x8000 | TABLE_VALID));
+        // A 2M block at the level 2
+        memory.write_obj(0x3000 + 5 * 8, &(0x20_0000 | DESC_VALID));
+
+        // 48 bits, 4K granule
+        let mut regs = TranslationRegs {
+            sctlr: SCTLR_M,
+            tcr: 16,
+            ttbr0: 0x1000,
+            ttbr1: 0,
+        };
+        let va = (1 << 39) | (2 << 30);
+        assert_eq!(
+            translate(&memory, &regs, va | (3 << 21) | (4 << 12) | 0x123),
+            Some(0x8123)
+        );
+        assert_eq!(
+            translate(&memory, &regs, va | (5 << 21) | 0x1_2345),
+            Some(0x21_2345)
+        );
+        assert_eq!(translate(&memory, &regs, va | (3 << 21) | (6 << 12)), None);
+
+        // Past the 48-bit range, no table level left
+        regs.tcr = 15;
+        assert_eq!(translate(&memory, &regs, 0x123), None);
+        regs.tcr = 53;
+        assert_eq!(translate(&memory, &regs, 0x123), None);
+
+        regs.sctlr = 0;
+        assert_eq!(translate(&memory, &regs, 0x123), Some(0x123));
+    }
+
+    #[test]
+    fn test_translate_64k() {
+        let mut memory = test_memory(0, 0x40_0000);
+        memory.write_obj(0x1_0000 + 5 * 8, &(0x2_0000 | TABLE_VALID));
+        memory.write_obj(0x2_0000 + 7 * 8, &(0x3_0000 | TABLE_VALID));
+
+        // 42 bits, 64K granule: the levels 2 and 3
+        let regs = TranslationRegs {
+            sctlr: SCTLR_M,
+            tcr: 22 | (0b01 << 14),
+            ttbr0: 0x1_0000,
+            ttbr1: 0,
+        };
+        assert_eq!(
+            translate(&memory, &regs, (5 << 29) | (7 << 16) | 0x1234),
+            Some(0x3_1234)
+        );
+        assert_eq!(translate(&memory, &regs, (5 << 29) | (8 << 16)), None);
+        // The upper range is not set up
+        assert_eq!(translate(&memory, &regs, 0xffff_0000_0000_0000), None);
+    }
+}
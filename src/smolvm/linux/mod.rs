@@ -1,14 +1,20 @@
 use std::{
-    os::unix::prelude::RawFd,
-    sync::{Arc, Mutex},
+    fs::{File, OpenOptions},
+    os::unix::prelude::{AsRawFd, RawFd},
+    sync::{Arc, Mutex, Once},
 };
 
-use kvm_bindings::{kvm_fpu, kvm_guest_debug, kvm_userspace_memory_region};
+use kvm_bindings::{
+    kvm_fpu, kvm_guest_debug, kvm_irq_level, kvm_irq_level__bindgen_ty_1, kvm_irq_routing,
+    kvm_irq_routing_entry, kvm_irq_routing_entry__bindgen_ty_1, kvm_irq_routing_msi,
+    kvm_irq_routing_msi__bindgen_ty_1, kvm_irqfd, kvm_run, kvm_userspace_memory_region,
+    KVM_IRQ_ROUTING_MSI, KVM_MEM_READONLY,
+};
 
 #[cfg(target_arch = "x86_64")]
 mod x86_64;
 #[cfg(target_arch = "x86_64")]
-pub use self::x86_64::{Cpu, CpuRegister};
+pub use self::x86_64::{set_command_line, Cpu, CpuRegister};
 
 #[cfg(target_arch = "aarch64")]
 mod aarch64;
@@ -18,7 +24,17 @@ use nix::{ioctl_read, ioctl_write_int_bad, ioctl_write_ptr, request_code_none};
 
 #[cfg(target_arch = "aarch64")]
 pub use self::aarch64::{Cpu, CpuRegister};
-use super::{GpaSpan, MappedGpa, Memory};
+use super::{
+    bus::{Bus, HypercallHandler, MsrHandler, VcpuKick},
+    debug_port::PostCodes,
+    host_page_size,
+    numa::{self, NumaNode},
+    pci::PciRoot,
+    topology::CpuTopology,
+    virtio::pmem::{VirtioPmem, PMEM_ALIGNMENT},
+    GpaSpan, HugePageSize, IrqChip, MappedGpa, Memory, PowerButton, RamBacking, RamOptions,
+    SmolVmT, VmOptions,
+};
 
 pub fn last_os_error() -> std::io::Error {
     std::io::Error::from_raw_os_error(nix::errno::errno())
@@ -26,6 +42,19 @@ pub fn last_os_error() -> std::io::Error {
 
 const KVMIO: u8 = 0xae;
 
+/// The persistent memory goes above the RAM and the 32-bit MMIO hole
+const PMEM_GPA_BASE: u64 = 0x1_0000_0000;
+
+/// Since Linux 5.14, newer than the libc crate
+const MADV_POPULATE_WRITE: libc::c_int = 23;
+
+// mbind(2)
+const MPOL_BIND: libc::c_ulong = 2;
+const MPOL_MF_STRICT: libc::c_uint = 1 << 0;
+const MPOL_MF_MOVE: libc::c_uint = 1 << 1;
+/// The host nodes the node mask has room for
+const MAX_HOST_NODES: u32 = 1024;
+
 ioctl_write_int_bad!(kvm_create_vm, request_code_none!(KVMIO, 0x1));
 ioctl_write_int_bad!(kvm_get_vcpu_mmap_size, request_code_none!(KVMIO, 0x04));
 ioctl_write_int_bad!(kvm_create_vcpu, request_code_none!(KVMIO, 0x41));
@@ -35,6 +64,10 @@ ioctl_write_ptr!(
     0x46,
     kvm_userspace_memory_region
 );
+ioctl_write_ptr!(kvm_irq_line, KVMIO, 0x61, kvm_irq_level);
+ioctl_write_ptr!(kvm_set_gsi_routing, KVMIO, 0x6a, kvm_irq_routing);
+ioctl_write_ptr!(kvm_irqfd, KVMIO, 0x76, kvm_irqfd);
+ioctl_write_ptr!(kvm_enable_cap, KVMIO, 0xa3, kvm_bindings::kvm_enable_cap);
 ioctl_write_int_bad!(kvm_run, request_code_none!(KVMIO, 0x80));
 ioctl_read!(kvm_get_fpu, KVMIO, 0x8c, kvm_fpu);
 ioctl_write_ptr!(kvm_set_fpu, KVMIO, 0x8d, kvm_fpu);
@@ -55,16 +88,547 @@ fn open_kvm() -> std::io::Result<RawFd> {
     }
 }
 
+/// KVM_ENABLE_CAP on the VM
+fn enable_vm_cap(vm_fd: RawFd, cap: u32, args: [u64; 4]) -> Result<(), std::io::Error> {
+    let enable_cap = kvm_bindings::kvm_enable_cap {
+        cap,
+        flags: 0,
+        args,
+        pad: [0; 64],
+    };
+    unsafe { kvm_enable_cap(vm_fd, &enable_cap) }?;
+
+    Ok(())
+}
+
+/// Allocates the pages of the mapping from the host node only, the pages
+/// already there are moved
+fn bind_to_host_node(addr: *mut u8, size: usize, host_node: u32) -> Result<(), std::io::Error> {
+    if host_node >= MAX_HOST_NODES {
+        log::error!("Host node {} is past {}", host_node, MAX_HOST_NODES - 1);
+        return Err(std::io::Error::from_raw_os_error(libc::EINVAL));
+    }
+
+    let mut node_mask = [0_u64; MAX_HOST_NODES as usize / 64];
+    node_mask[host_node as usize / 64] |= 1 << (host_node % 64);
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_mbind,
+            addr,
+            size,
+            MPOL_BIND,
+            node_mask.as_ptr(),
+            // The kernel takes one bit less than given
+            MAX_HOST_NODES as libc::c_ulong + 1,
+            MPOL_MF_STRICT | MPOL_MF_MOVE,
+        )
+    };
+    if result < 0 {
+        let err = last_os_error();
+        log::error!("Cannot bind the RAM to host node {}: {}", host_node, err);
+        return Err(err);
+    }
+
+    Ok(())
+}
+
+/// Maps a RAM region with its backing, bound to the host node if given
+/// before any page is allocated
+pub(super) fn map_ram(
+    span: &GpaSpan,
+    options: &RamOptions,
+    host_node: Option<u32>,
+) -> Result<MappedGpa, std::io::Error> {
+    use nix::sys::memfd::{memfd_create, MemFdCreateFlag};
+    use std::{ffi::CStr, os::unix::prelude::FromRawFd, ptr::null_mut};
+
+    // KSM only scans the private anonymous pages, MADV_MERGEABLE does
+    // nothing on the others
+    if options.mergeable && !matches!(options.backing, RamBacking::Anonymous) {
+        log::error!(
+            "KSM cannot merge the RAM backed as {:?}, only the anonymous one",
+            options.backing
+        );
+        return Err(std::io::Error::from_raw_os_error(libc::EINVAL));
+    }
+
+    // The memfds rather than anonymous memory by default so that the
+    // out-of-process device backends can map the RAM, too
+    let file = match &options.backing {
+        RamBacking::Memfd | RamBacking::SealedMemfd => {
+            let sealed = matches!(options.backing, RamBacking::SealedMemfd);
+            let fd = memfd_create(
+                CStr::from_bytes_with_nul(b"smolvm-ram\0").unwrap(),
+                if sealed {
+                    MemFdCreateFlag::MFD_CLOEXEC | MemFdCreateFlag::MFD_ALLOW_SEALING
+                } else {
+                    MemFdCreateFlag::MFD_CLOEXEC
+                },
+            )?;
+            let file = unsafe { File::from_raw_fd(fd) };
+            file.set_len(span.size as u64)?;
+            if sealed {
+                let seals = libc::F_SEAL_SHRINK | libc::F_SEAL_GROW | libc::F_SEAL_SEAL;
+                if unsafe { libc::fcntl(fd, libc::F_ADD_SEALS, seals) } < 0 {
+                    return Err(last_os_error());
+                }
+            }
+            Some(file)
+        }
+        RamBacking::File(path) => {
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(path)?;
+            if file.metadata()?.len() < span.size as u64 {
+                file.set_len(span.size as u64)?;
+            }
+            Some(file)
+        }
+        RamBacking::Anonymous | RamBacking::HugePages(_) => None,
+    };
+
+    // A file on hugetlbfs has the huge page size as the block size
+    let page_size = match (&options.backing, &file) {
+        (RamBacking::HugePages(size), _) => size.bytes(),
+        (RamBacking::File(_), Some(file)) => {
+            let mut statfs = unsafe { std::mem::zeroed::<libc::statfs>() };
+            if unsafe { libc::fstatfs(file.as_raw_fd(), &mut statfs) } < 0 {
+                return Err(last_os_error());
+            }
+            (statfs.f_bsize as u64).max(host_page_size())
+        }
+        _ => host_page_size(),
+    };
+    if (span.start | span.size as u64) & (page_size - 1) != 0 {
+        log::error!(
+            "RAM at {:#x}, {:#x} bytes is not aligned to its {:#x}-byte pages",
+            span.start,
+            span.size,
+            page_size
+        );
+        return Err(std::io::Error::from_raw_os_error(libc::EINVAL));
+    }
+
+    let (mut flags, fd) = match (&options.backing, &file) {
+        (RamBacking::HugePages(size), _) => (
+            libc::MAP_PRIVATE
+                | libc::MAP_ANONYMOUS
+                | libc::MAP_HUGETLB
+                | match size {
+                    HugePageSize::Size2M => libc::MAP_HUGE_2MB,
+                    HugePageSize::Size1G => libc::MAP_HUGE_1GB,
+                },
+            -1,
+        ),
+        (_, Some(file)) => (libc::MAP_SHARED, file.as_raw_fd()),
+        (_, None) => (libc::MAP_PRIVATE | libc::MAP_ANONYMOUS, -1),
+    };
+    // The huge pages are reserved up front, the mapping fails rather than
+    // the process being killed by SIGBUS when the pool runs out
+    if page_size == host_page_size() {
+        flags |= libc::MAP_NORESERVE;
+    }
+    let addr = unsafe {
+        libc::mmap(
+            null_mut(),
+            span.size,
+            libc::PROT_READ | libc::PROT_WRITE,
+            flags,
+            fd,
+            0,
+        )
+    };
+    if addr == libc::MAP_FAILED {
+        let err = last_os_error();
+        log::error!("Cannot map the RAM as {:?}: {}", options.backing, err);
+        return Err(err);
+    }
+
+    let mapped_gpa = MappedGpa {
+        memory: addr as *mut u8,
+        gpa: span.start,
+        size: span.size,
+        file: file.map(Arc::new),
+        page_size,
+        locked: options.mlock,
+    };
+
+    if let Some(host_node) = host_node {
+        bind_to_host_node(mapped_gpa.memory, span.size, host_node)?;
+    }
+
+    if options.mergeable && unsafe { libc::madvise(addr, span.size, libc::MADV_MERGEABLE) } < 0 {
+        let err = last_os_error();
+        log::error!("Cannot let KSM merge the RAM: {}", err);
+        return Err(err);
+    }
+
+    if options.mlock {
+        if unsafe { libc::mlock(addr, span.size) } < 0 {
+            let err = last_os_error();
+            log::error!("Cannot lock the RAM: {}", err);
+            return Err(err);
+        }
+    } else if options.prefault && unsafe { libc::madvise(addr, span.size, MADV_POPULATE_WRITE) } < 0
+    {
+        let err = last_os_error();
+        log::error!("Cannot prefault the RAM: {}", err);
+        return Err(err);
+    }
+
+    Ok(mapped_gpa)
+}
+
+/// KVM_RUN, `false` if another thread has kicked the vCPU out of the guest
+/// with `vcpu_kick` rather than the guest exiting
+unsafe fn enter_guest(vcpu_fd: RawFd, vcpu_run: *mut kvm_run) -> Result<bool, std::io::Error> {
+    match kvm_run(vcpu_fd, 0) {
+        Ok(_) => Ok(true),
+        Err(nix::errno::Errno::EINTR) => {
+            (*vcpu_run).immediate_exit = 0;
+            Ok(false)
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Interrupts KVM_RUN of the vCPU on the calling thread: the signal makes it
+/// return with EINTR, `immediate_exit` does the same if the signal lands
+/// right before entering the guest.
+fn vcpu_kick(vcpu_run: *mut kvm_run) -> VcpuKick {
+    static HANDLER: Once = Once::new();
+
+    extern "C" fn handle_kick(_signal: libc::c_int) {}
+
+    HANDLER.call_once(|| unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = handle_kick as extern "C" fn(libc::c_int) as usize;
+        libc::sigaction(libc::SIGRTMIN(), &action, std::ptr::null_mut());
+    });
+
+    let thread = unsafe { libc::pthread_self() };
+    // The mapping lives as long as the vCPU
+    let vcpu_run = vcpu_run as usize;
+
+    Arc::new(move || unsafe {
+        if libc::pthread_self() == thread {
+            return;
+        }
+
+        let vcpu_run = vcpu_run as *mut kvm_run;
+        std::ptr::write_volatile(&mut (*vcpu_run).immediate_exit, 1);
+        libc::pthread_kill(thread, libc::SIGRTMIN());
+    })
+}
+
+/// The MSIs get the GSIs past the IOAPIC pins
+const FIRST_MSI_GSI: u32 = 24;
+
+struct MsiRoute {
+    entry: kvm_irq_routing_entry,
+    eventfd: RawFd,
+}
+
+/// The in-kernel interrupt controller: PIC, IOAPIC and LAPIC on x86_64,
+/// GICv3 with the ITS on aarch64.
+///
+/// The MSIs are routed through the KVM irq routing table, each one has
+/// an eventfd attached with `KVM_IRQFD`, signalling the MSI is writing
+/// to the eventfd.
+pub struct KvmIrqChip {
+    vm_fd: RawFd,
+    device_fds: Vec<RawFd>,
+    msi_routes: Mutex<Vec<MsiRoute>>,
+    #[cfg(target_arch = "x86_64")]
+    initial_state: self::x86_64::IrqChipState,
+    #[cfg(target_arch = "aarch64")]
+    initial_state: self::aarch64::IrqChipState,
+}
+
+impl KvmIrqChip {
+    /// Must be called before creating the vCPUs
+    fn new(vm_fd: RawFd) -> Result<Self, std::io::Error> {
+        #[cfg(target_arch = "x86_64")]
+        let (device_fds, initial_state) = (
+            self::x86_64::create_irqchip(vm_fd)?,
+            self::x86_64::save_irqchip(vm_fd)?,
+        );
+        #[cfg(target_arch = "aarch64")]
+        let (device_fds, initial_state) = (
+            self::aarch64::create_irqchip(vm_fd)?,
+            self::aarch64::save_irqchip(vm_fd)?,
+        );
+
+        Ok(Self {
+            vm_fd,
+            device_fds,
+            msi_routes: Mutex::new(Vec::new()),
+            initial_state,
+        })
+    }
+
+    /// The interrupt controllers go back to the state they were created
+    /// in, the MSI routes stay as the devices keep their interrupts
+    fn reset(&self) -> Result<(), std::io::Error> {
+        #[cfg(target_arch = "x86_64")]
+        self::x86_64::restore_irqchip(self.vm_fd, &self.initial_state)?;
+        #[cfg(target_arch = "aarch64")]
+        self::aarch64::restore_irqchip(self.vm_fd, &self.initial_state)?;
+
+        Ok(())
+    }
+
+    /// Must be called after all vCPUs have been created
+    fn finalize(&self) -> Result<(), std::io::Error> {
+        #[cfg(target_arch = "x86_64")]
+        self::x86_64::finalize_irqchip(&self.device_fds)?;
+        #[cfg(target_arch = "aarch64")]
+        self::aarch64::finalize_irqchip(&self.device_fds)?;
+
+        Ok(())
+    }
+
+    fn set_gsi_routing(&self, msi_routes: &[MsiRoute]) -> Result<(), std::io::Error> {
+        #[cfg(target_arch = "x86_64")]
+        let mut entries = self::x86_64::irqchip_routes();
+        #[cfg(target_arch = "aarch64")]
+        let mut entries = self::aarch64::irqchip_routes();
+
+        entries.extend(msi_routes.iter().map(|route| route.entry));
+
+        // struct kvm_irq_routing is followed by the entries, u64 keeps
+        // the buffer aligned for both
+        let header_size = std::mem::size_of::<kvm_irq_routing>();
+        let entries_size = entries.len() * std::mem::size_of::<kvm_irq_routing_entry>();
+        let mut buffer = vec![0_u64; (header_size + entries_size).div_ceil(8)];
+
+        unsafe {
+            let routing = buffer.as_mut_ptr() as *mut kvm_irq_routing;
+            (*routing).nr = entries.len() as u32;
+            (*routing).flags = 0;
+            std::ptr::copy_nonoverlapping(
+                entries.as_ptr(),
+                (*routing).entries.as_mut_ptr(),
+                entries.len(),
+            );
+
+            kvm_set_gsi_routing(self.vm_fd, routing)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl IrqChip for KvmIrqChip {
+    fn set_irq_line(&self, irq: u32, level: bool) {
+        #[cfg(target_arch = "x86_64")]
+        let irq_line = irq;
+        #[cfg(target_arch = "aarch64")]
+        let irq_line = self::aarch64::spi_irq_line(irq);
+
+        let irq_level = kvm_irq_level {
+            __bindgen_anon_1: kvm_irq_level__bindgen_ty_1 { irq: irq_line },
+            level: level as u32,
+        };
+
+        if let Err(e) = unsafe { kvm_irq_line(self.vm_fd, &irq_level as *const _) } {
+            log::error!("Cannot set the level of irq {} to {}: {}", irq, level, e);
+        }
+    }
+
+    fn allocate_msi(&self, requester_id: u32) -> Option<u32> {
+        let mut msi_routes = self.msi_routes.lock().unwrap();
+        let gsi = FIRST_MSI_GSI + msi_routes.len() as u32;
+
+        let eventfd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+        if eventfd < 0 {
+            log::error!("Cannot create the eventfd for MSI: {}", last_os_error());
+            return None;
+        }
+
+        #[cfg(target_arch = "x86_64")]
+        let flags = self::x86_64::MSI_ROUTING_FLAGS;
+        #[cfg(target_arch = "aarch64")]
+        let flags = self::aarch64::MSI_ROUTING_FLAGS;
+
+        msi_routes.push(MsiRoute {
+            entry: kvm_irq_routing_entry {
+                gsi,
+                type_: KVM_IRQ_ROUTING_MSI,
+                flags,
+                pad: 0,
+                u: kvm_irq_routing_entry__bindgen_ty_1 {
+                    msi: kvm_irq_routing_msi {
+                        address_lo: 0,
+                        address_hi: 0,
+                        data: 0,
+                        __bindgen_anon_1: kvm_irq_routing_msi__bindgen_ty_1 {
+                            devid: requester_id,
+                        },
+                    },
+                },
+            },
+            eventfd,
+        });
+
+        let irqfd = kvm_irqfd {
+            fd: eventfd as u32,
+            gsi,
+            ..Default::default()
+        };
+
+        if let Err(e) = self
+            .set_gsi_routing(&msi_routes)
+            .and_then(|_| unsafe { kvm_irqfd(self.vm_fd, &irqfd as *const _) }.map_err(Into::into))
+        {
+            log::error!("Cannot route MSI {}: {}", gsi, e);
+            msi_routes.pop();
+            unsafe { libc::close(eventfd) };
+            return None;
+        }
+
+        Some(gsi)
+    }
+
+    fn set_msi_route(&self, gsi: u32, address: u64, data: u32) {
+        let mut msi_routes = self.msi_routes.lock().unwrap();
+
+        if let Some(route) = msi_routes.get_mut(gsi.wrapping_sub(FIRST_MSI_GSI) as usize) {
+            route.entry.u.msi.address_lo = address as u32;
+            route.entry.u.msi.address_hi = (address >> 32) as u32;
+            route.entry.u.msi.data = data;
+        } else {
+            log::error!("MSI {} has not been allocated", gsi);
+            return;
+        }
+
+        if let Err(e) = self.set_gsi_routing(&msi_routes) {
+            log::error!("Cannot route MSI {}: {}", gsi, e);
+        }
+    }
+
+    fn signal_msi(&self, gsi: u32) {
+        let msi_routes = self.msi_routes.lock().unwrap();
+
+        if let Some(route) = msi_routes.get(gsi.wrapping_sub(FIRST_MSI_GSI) as usize) {
+            let value = 1_u64;
+            let result = unsafe {
+                libc::write(
+                    route.eventfd,
+                    &value as *const u64 as *const libc::c_void,
+                    std::mem::size_of::<u64>(),
+                )
+            };
+            if result < 0 {
+                log::error!("Cannot signal MSI {}: {}", gsi, last_os_error());
+            }
+        } else {
+            log::error!("MSI {} has not been allocated", gsi);
+        }
+    }
+}
+
+/// The zero page the kernel boots with, and the ACPI tables it points to
+#[cfg(target_arch = "x86_64")]
+fn write_boot_params(
+    memory: &mut Memory,
+    ram: &[GpaSpan],
+    topology: &CpuTopology,
+    numa: &[NumaNode],
+    acpi: bool,
+    pci: bool,
+) {
+    use self::x86_64::{
+        create_acpi_tables, BootE820Entry, BootParams, E820MemoryType, ACPI_RSDP_GPA,
+        ACPI_TABLES_GPA, ACPI_TABLES_SIZE, BOOT_PARAMS_GPA,
+    };
+
+    let mut params = BootParams::default();
+
+    // The ACPI tables are carved out of the RAM
+    let reserved = if acpi {
+        Some((ACPI_TABLES_GPA, ACPI_TABLES_SIZE))
+    } else {
+        None
+    };
+    let mut e820 = Vec::new();
+    for span in ram {
+        let (start, end) = (span.start, span.start + span.size as u64);
+        match reserved {
+            Some((gpa, size)) if start <= gpa && gpa + size <= end => {
+                e820.push((start, gpa - start, E820MemoryType::E820TypeRam));
+                e820.push((gpa, size, E820MemoryType::E820TypeReserved));
+                e820.push((gpa + size, end - gpa - size, E820MemoryType::E820TypeRam));
+            }
+            _ => e820.push((start, end - start, E820MemoryType::E820TypeRam)),
+        }
+    }
+
+    for (addr, size, type_) in e820.into_iter().filter(|(_, size, _)| *size != 0) {
+        params.e820_table[params.e820_entries as usize] = BootE820Entry {
+            addr,
+            size: size as usize,
+            type_,
+        };
+        params.e820_entries += 1;
+    }
+
+    if acpi {
+        params.acpi_rsdp_addr = ACPI_RSDP_GPA;
+    }
+
+    params.setup_header.boot_flag = 0xaa55;
+    params.setup_header.header = 0x53726448;
+    params.setup_header.version = 0x20c;
+    params.setup_header.type_of_loader = 0xff;
+    params.setup_header.initrd_addr_max = 0x7fffffff;
+    params.setup_header.kernel_alignment = 0x200000;
+    params.setup_header.relocatable_kernel = 0x0;
+    // params.setup_header.cmd_line_ptr = 0x90000;
+    // params.setup_header.cmdline_size = 0x7ff;
+    params.setup_header.pref_address = 0x2000000;
+    params.setup_header.min_alignment = 0x15;
+
+    memory.write(BOOT_PARAMS_GPA, unsafe {
+        std::slice::from_raw_parts(
+            (&params as *const BootParams) as *const u8,
+            std::mem::size_of::<BootParams>(),
+        )
+    });
+
+    if acpi {
+        memory.write(
+            ACPI_TABLES_GPA,
+            &create_acpi_tables(topology, super::VCPU_COUNT, ram, numa, pci),
+        );
+    }
+}
+
 pub struct SmolVm {
     cpu: Arc<Mutex<Cpu>>,
     memory: Arc<Mutex<Memory>>,
-    _vm_fd: RawFd,
+    /// Cleared when the VM is reset, unlike the persistent memory
+    ram: Vec<GpaSpan>,
+    topology: CpuTopology,
+    numa: Vec<NumaNode>,
+    bus: Arc<Mutex<Bus>>,
+    irq_chip: Option<Arc<KvmIrqChip>>,
+    pci: Option<Arc<Mutex<PciRoot>>>,
+    power_button: Option<PowerButton>,
+    post_codes: Option<PostCodes>,
+    vm_fd: RawFd,
     _kvm_fd: RawFd,
 }
 
 impl SmolVm {
-    pub fn new(gpa_map: &[GpaSpan]) -> Result<Self, std::io::Error> {
-        let map_anonymous = |size: usize| -> *mut u8 {
+    pub fn new(gpa_map: &[GpaSpan], options: &VmOptions) -> Result<Self, std::io::Error> {
+        // The read-only files are mapped privately so that a device writing
+        // to such memory on behalf of the guest does not crash the process,
+        // the guest itself cannot write to the read-only memory slot.
+        let map_file = |file: &File, size: usize, read_only: bool| -> std::io::Result<*mut u8> {
             use std::ptr::null_mut;
 
             let addr = unsafe {
@@ -72,30 +636,91 @@ impl SmolVm {
                     null_mut(),
                     size,
                     libc::PROT_READ | libc::PROT_WRITE,
-                    libc::MAP_ANONYMOUS | libc::MAP_SHARED | libc::MAP_NORESERVE,
-                    -1,
+                    if read_only {
+                        libc::MAP_PRIVATE
+                    } else {
+                        libc::MAP_SHARED
+                    },
+                    file.as_raw_fd(),
                     0,
                 )
             };
             if addr == libc::MAP_FAILED {
-                panic!("mmap failed.");
+                return Err(std::io::Error::last_os_error());
             }
 
-            addr as *mut u8
+            Ok(addr as *mut u8)
         };
 
+        if !options.topology.is_valid() {
+            log::error!("Invalid CPU topology {:?}", options.topology);
+            return Err(std::io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        if options.topology.cpu_count() > super::VCPU_COUNT {
+            log::warn!(
+                "The topology has {} CPUs, only the first {} run",
+                options.topology.cpu_count(),
+                super::VCPU_COUNT
+            );
+        }
+
+        if !options.numa.is_empty()
+            && !numa::is_valid(&options.numa, gpa_map.len(), options.topology.cpu_count())
+        {
+            log::error!(
+                "Every RAM region and every CPU must be in one NUMA node, the distances given for every node: {:?}",
+                options.numa
+            );
+            return Err(std::io::Error::from_raw_os_error(libc::EINVAL));
+        }
+
+        if options.ram.len() > gpa_map.len() {
+            log::error!(
+                "{} RAM backings for {} RAM regions",
+                options.ram.len(),
+                gpa_map.len()
+            );
+            return Err(std::io::Error::from_raw_os_error(libc::EINVAL));
+        }
+
+        // The virtio-pmem devices interrupt the guest through the irqchip
+        if !options.pmem.is_empty() && !options.irqchip {
+            log::error!("The persistent memory requires the irqchip");
+            return Err(std::io::Error::from_raw_os_error(libc::EINVAL));
+        }
+
         let kvm_fd = open_kvm()?;
-        let vm_fd = unsafe {
-            kvm_create_vm(kvm_fd, 36 /*vm type (PA bits = 32..36) */)
-        }?;
+        #[cfg(target_arch = "x86_64")]
+        let vm_type = 0;
+        #[cfg(target_arch = "aarch64")]
+        let vm_type = 36; /* PA bits = 32..36 */
+
+        let vm_fd = unsafe { kvm_create_vm(kvm_fd, vm_type) }?;
+
+        // Without it the guest gets an external abort instead
+        #[cfg(target_arch = "aarch64")]
+        if let Err(err) = self::aarch64::enable_nisv_to_user(vm_fd) {
+            log::warn!(
+                "Cannot emulate the MMIO accesses without the syndrome: {}",
+                err
+            );
+        }
+
+        // Without it the MSRs KVM does not know go unnoticed
+        #[cfg(target_arch = "x86_64")]
+        if let Err(err) = self::x86_64::enable_user_space_msr(vm_fd) {
+            log::warn!("Cannot handle the MSR accesses in userspace: {}", err);
+        }
 
         let mut spans = Vec::new();
         for (index, span) in gpa_map.iter().enumerate() {
-            let mapped_gpa = MappedGpa {
-                memory: map_anonymous(span.size),
-                gpa: span.start,
-                size: span.size,
-            };
+            let host_node = numa::node_of_memory(&options.numa, index)
+                .and_then(|node| options.numa[node as usize].host_node);
+            let mapped_gpa = map_ram(
+                span,
+                &options.ram.get(index).cloned().unwrap_or_default(),
+                host_node,
+            )?;
 
             unsafe {
                 kvm_userspace_memory_region(
@@ -113,79 +738,331 @@ impl SmolVm {
             spans.push(mapped_gpa);
         }
 
-        let params_page: u64;
-
-        #[cfg(target_arch = "x86_64")]
-        let params_slice = {
-            use self::x86_64::{BootE820Entry, BootParams, E820MemoryType};
-
-            let params_slice = unsafe {
-                std::slice::from_raw_parts(
-                    {
-                        let mut params = BootParams {
-                            e820_entries: spans.len() as u8,
-                            ..Default::default()
-                        };
-
-                        for i in 0..params.e820_entries {
-                            params.e820_table[i as usize] = BootE820Entry {
-                                addr: spans[i as usize].gpa,
-                                size: spans[i as usize].size,
-                                type_: E820MemoryType::E820TypeRam,
-                            }
-                        }
-
-                        params.setup_header.boot_flag = 0xaa55;
-                        params.setup_header.header = 0x53726448;
-                        params.setup_header.version = 0x20c;
-                        params.setup_header.type_of_loader = 0xff;
-                        params.setup_header.initrd_addr_max = 0x7fffffff;
-                        params.setup_header.kernel_alignment = 0x200000;
-                        params.setup_header.relocatable_kernel = 0x0;
-                        // params.setup_header.cmd_line_ptr = 0x90000;
-                        // params.setup_header.cmdline_size = 0x7ff;
-                        params.setup_header.pref_address = 0x2000000;
-                        params.setup_header.min_alignment = 0x15;
-
-                        (&params as *const _) as *const u8
-                    },
-                    std::mem::size_of::<BootParams>(),
-                )
+        let mut pmem_devices = Vec::new();
+        let mut pmem_gpa = gpa_map
+            .iter()
+            .map(|span| span.start + span.size as u64)
+            .max()
+            .unwrap_or(0)
+            .max(PMEM_GPA_BASE);
+        for pmem in &options.pmem {
+            let file = OpenOptions::new()
+                .read(true)
+                .write(!pmem.read_only)
+                .open(&pmem.path)?;
+            let size = file.metadata()?.len();
+            if size == 0 || size % PMEM_ALIGNMENT != 0 {
+                log::error!(
+                    "Size of {} must be a non-zero multiple of {:#x} bytes",
+                    pmem.path.display(),
+                    PMEM_ALIGNMENT
+                );
+                return Err(std::io::Error::from_raw_os_error(libc::EINVAL));
+            }
+
+            pmem_gpa = (pmem_gpa + PMEM_ALIGNMENT - 1) & !(PMEM_ALIGNMENT - 1);
+
+            let file = Arc::new(file);
+            let mapped_gpa = MappedGpa {
+                memory: map_file(&file, size as usize, pmem.read_only)?,
+                gpa: pmem_gpa,
+                size: size as usize,
+                // The private mapping of a read-only file cannot be shared
+                file: if pmem.read_only {
+                    None
+                } else {
+                    Some(file.clone())
+                },
+                page_size: host_page_size(),
+                locked: false,
             };
 
-            params_slice
-        };
+            unsafe {
+                kvm_userspace_memory_region(
+                    vm_fd,
+                    &kvm_userspace_memory_region {
+                        slot: spans.len() as u32,
+                        guest_phys_addr: pmem_gpa,
+                        memory_size: size,
+                        userspace_addr: mapped_gpa.memory as u64,
+                        flags: if pmem.read_only { KVM_MEM_READONLY } else { 0 },
+                    } as *const _,
+                )?;
+            }
 
-        let memory = Memory::new(spans);
+            log::info!(
+                "Mapped {} at {:#x}, {:#x} bytes{}",
+                pmem.path.display(),
+                pmem_gpa,
+                size,
+                if pmem.read_only { ", read-only" } else { "" }
+            );
 
-        #[cfg(target_arch = "x86_64")]
-        {
-            params_page = 0x10000;
-            memory.write(params_page, params_slice);
+            pmem_devices.push(VirtioPmem::new(file, pmem_gpa, size));
+            spans.push(mapped_gpa);
+            pmem_gpa += size;
         }
 
+        let mut memory = Memory::new(spans);
+
+        // The ACPI tables describe the in-kernel interrupt controllers
+        #[cfg(target_arch = "x86_64")]
+        write_boot_params(
+            &mut memory,
+            gpa_map,
+            &options.topology,
+            &options.numa,
+            options.irqchip,
+            options.pci,
+        );
+
         let memory = Arc::new(Mutex::new(memory));
 
+        let irq_chip = if options.irqchip {
+            Some(Arc::new(KvmIrqChip::new(vm_fd)?))
+        } else {
+            None
+        };
+
         let mut cpu = Cpu::new(kvm_fd, vm_fd, memory.clone())?;
-        cpu.init()?;
+        #[cfg(target_arch = "x86_64")]
+        cpu.init(&options.cpuid, &options.topology)?;
+        #[cfg(target_arch = "aarch64")]
+        {
+            cpu.init()?;
+            cpu.set_mpidr(options.topology.mpidr(0))?;
+        }
+
+        if let Some(irq_chip) = &irq_chip {
+            irq_chip.finalize()?;
+        }
 
         #[cfg(target_arch = "x86_64")]
         {
-            cpu.set_gp_register(self::x86_64::CpuRegister::Rsi, params_page)?;
+            cpu.set_gp_register(
+                self::x86_64::CpuRegister::Rsi,
+                self::x86_64::BOOT_PARAMS_GPA,
+            )?;
         }
 
         let cpu = Arc::new(Mutex::new(cpu));
 
-        Ok(Self {
+        let mut bus = super::create_bus(gpa_map, options);
+        let pci = if options.pci {
+            Some(PciRoot::attach(&mut bus))
+        } else {
+            None
+        };
+
+        #[cfg(target_arch = "x86_64")]
+        let post_codes = {
+            use super::debug_port::{
+                DebugCon, PostCodePort, DEBUGCON_PORT, FIRMWARE_LOG_PORT, POST_CODE_PORT,
+            };
+
+            let debugcon = Arc::new(Mutex::new(DebugCon::new(&options.debugcon)?));
+            bus.add_io_device(DEBUGCON_PORT, 1, debugcon.clone());
+            bus.add_io_device(FIRMWARE_LOG_PORT, 1, debugcon);
+
+            let post_codes = PostCodes::default();
+            bus.add_io_device(
+                POST_CODE_PORT,
+                1,
+                Arc::new(Mutex::new(PostCodePort::new(post_codes.clone()))),
+            );
+
+            Some(post_codes)
+        };
+        #[cfg(target_arch = "aarch64")]
+        let post_codes = None;
+
+        #[cfg(target_arch = "x86_64")]
+        {
+            use super::i8042::{I8042, I8042_COMMAND_PORT, I8042_DATA_PORT};
+
+            let i8042 = Arc::new(Mutex::new(I8042::new(
+                bus.exit_requester(),
+                irq_chip
+                    .clone()
+                    .map(|irq_chip| irq_chip as Arc<dyn IrqChip>),
+            )));
+            bus.add_io_device(I8042_DATA_PORT, 1, i8042.clone());
+            bus.add_io_device(I8042_COMMAND_PORT, 1, i8042);
+        }
+
+        #[cfg(target_arch = "x86_64")]
+        {
+            use super::cmos::{CmosRtc, CMOS_PORT, CMOS_PORT_COUNT};
+
+            bus.add_io_device(
+                CMOS_PORT,
+                CMOS_PORT_COUNT,
+                Arc::new(Mutex::new(CmosRtc::new(
+                    gpa_map,
+                    options.rtc_epoch,
+                    irq_chip
+                        .clone()
+                        .map(|irq_chip| irq_chip as Arc<dyn IrqChip>),
+                ))),
+            );
+        }
+
+        #[cfg(target_arch = "aarch64")]
+        {
+            use super::{
+                fdt::{FdtNode, IRQ_TYPE_LEVEL_HIGH},
+                pl031::{RtcPl031, PL031_BASE, PL031_MMIO_SIZE, PL031_SPI},
+            };
+
+            bus.add_mmio_device(
+                PL031_BASE,
+                PL031_MMIO_SIZE,
+                Arc::new(Mutex::new(RtcPl031::new(
+                    PL031_BASE,
+                    options.rtc_epoch,
+                    irq_chip
+                        .clone()
+                        .map(|irq_chip| irq_chip as Arc<dyn IrqChip>),
+                ))),
+            );
+            bus.add_fdt_node(
+                FdtNode::new(&format!("pl031@{:x}", PL031_BASE))
+                    .primecell("arm,pl031")
+                    .reg(PL031_BASE, PL031_MMIO_SIZE)
+                    .interrupt_spi(PL031_SPI, IRQ_TYPE_LEVEL_HIGH),
+            );
+        }
+
+        #[cfg(target_arch = "x86_64")]
+        if options.watchdog {
+            log::warn!("The watchdog is supported only on aarch64, ignoring");
+        }
+        #[cfg(target_arch = "aarch64")]
+        if options.watchdog {
+            use super::{
+                fdt::{FdtNode, APB_PCLK_PHANDLE, IRQ_TYPE_LEVEL_HIGH},
+                sp805::{WatchdogSp805, SP805_BASE, SP805_MMIO_SIZE, SP805_SPI},
+            };
+
+            let watchdog = WatchdogSp805::new(
+                SP805_BASE,
+                bus.exit_requester(),
+                irq_chip
+                    .clone()
+                    .map(|irq_chip| irq_chip as Arc<dyn IrqChip>),
+            );
+            bus.add_mmio_device(SP805_BASE, SP805_MMIO_SIZE, Arc::new(Mutex::new(watchdog)));
+            // The counter runs at the APB clock as well
+            bus.add_fdt_node(
+                FdtNode::new(&format!("watchdog@{:x}", SP805_BASE))
+                    .property_strings("compatible", &["arm,sp805", "arm,primecell"])
+                    .reg(SP805_BASE, SP805_MMIO_SIZE)
+                    .interrupt_spi(SP805_SPI, IRQ_TYPE_LEVEL_HIGH)
+                    .property_cells("clocks", &[APB_PCLK_PHANDLE, APB_PCLK_PHANDLE])
+                    .property_strings("clock-names", &["wdog_clk", "apb_pclk"]),
+            );
+        }
+
+        // The guest powers off and learns of the power button through ACPI,
+        // the tables come with the irqchip
+        #[cfg(target_arch = "x86_64")]
+        let power_button = match &irq_chip {
+            Some(irq_chip) => {
+                use self::x86_64::{ACPI_GED_IRQ, ACPI_PM_PORT};
+                use super::acpi_pm::AcpiPm;
+
+                bus.add_io_device(
+                    ACPI_PM_PORT,
+                    AcpiPm::PORT_COUNT,
+                    Arc::new(Mutex::new(AcpiPm::new(ACPI_PM_PORT, bus.exit_requester()))),
+                );
+
+                let irq_chip = irq_chip.clone();
+                Some(Arc::new(move || irq_chip.pulse_irq(ACPI_GED_IRQ)) as PowerButton)
+            }
+            None => None,
+        };
+        // The power button is a `gpio-keys` line, PSCI powers off
+        #[cfg(target_arch = "aarch64")]
+        let power_button = match &irq_chip {
+            Some(irq_chip) => {
+                use super::{
+                    fdt::{FdtNode, IRQ_TYPE_LEVEL_HIGH, PL061_PHANDLE},
+                    pl061::{
+                        GpioPl061, KEY_POWER, PL061_BASE, PL061_KEY_PRESS, PL061_MMIO_SIZE,
+                        PL061_POWER_BUTTON_LINE, PL061_SPI,
+                    },
+                };
+
+                let gpio = Arc::new(Mutex::new(GpioPl061::new(
+                    PL061_BASE,
+                    Some(irq_chip.clone() as Arc<dyn IrqChip>),
+                )));
+                bus.add_mmio_device(PL061_BASE, PL061_MMIO_SIZE, gpio.clone());
+                bus.add_fdt_node(
+                    FdtNode::new(&format!("pl061@{:x}", PL061_BASE))
+                        .primecell("arm,pl061")
+                        .reg(PL061_BASE, PL061_MMIO_SIZE)
+                        .interrupt_spi(PL061_SPI, IRQ_TYPE_LEVEL_HIGH)
+                        .property_empty("gpio-controller")
+                        .property_u32("#gpio-cells", 2)
+                        .property_u32("phandle", PL061_PHANDLE),
+                );
+                bus.add_fdt_node(
+                    FdtNode::new("gpio-keys")
+                        .property_string("compatible", "gpio-keys")
+                        .child(
+                            FdtNode::new("poweroff")
+                                .property_string("label", "GPIO Key Poweroff")
+                                .property_u32("linux,code", KEY_POWER)
+                                .property_cells(
+                                    "gpios",
+                                    &[PL061_PHANDLE, PL061_POWER_BUTTON_LINE as u32, 0],
+                                ),
+                        ),
+                );
+
+                Some(Arc::new(move || {
+                    gpio.lock()
+                        .unwrap()
+                        .set_input(PL061_POWER_BUTTON_LINE, true);
+
+                    let gpio = gpio.clone();
+                    std::thread::spawn(move || {
+                        std::thread::sleep(PL061_KEY_PRESS);
+                        gpio.lock()
+                            .unwrap()
+                            .set_input(PL061_POWER_BUTTON_LINE, false);
+                    });
+                }) as PowerButton)
+            }
+            None => None,
+        };
+
+        let mut vm = Self {
             cpu,
             memory,
-            _vm_fd: vm_fd,
+            ram: gpa_map.to_vec(),
+            topology: options.topology,
+            numa: options.numa.clone(),
+            bus: Arc::new(Mutex::new(bus)),
+            irq_chip,
+            pci,
+            power_button,
+            post_codes,
+            vm_fd,
             _kvm_fd: kvm_fd,
-        })
+        };
+
+        for device in pmem_devices {
+            vm.add_virtio_device(Arc::new(Mutex::new(device)));
+        }
+
+        Ok(vm)
     }
 }
 
-impl crate::SmolVmT for SmolVm {
+impl SmolVmT for SmolVm {
     fn get_memory(&self) -> std::sync::Arc<std::sync::Mutex<Memory>> {
         self.memory.clone()
     }
@@ -193,4 +1070,115 @@ impl crate::SmolVmT for SmolVm {
     fn get_cpu(&self) -> std::sync::Arc<std::sync::Mutex<Cpu>> {
         self.cpu.clone()
     }
+
+    fn get_bus(&self) -> Arc<Mutex<Bus>> {
+        self.bus.clone()
+    }
+
+    fn get_irq_chip(&self) -> Option<Arc<dyn IrqChip>> {
+        self.irq_chip
+            .clone()
+            .map(|irq_chip| irq_chip as Arc<dyn IrqChip>)
+    }
+
+    fn get_pci(&self) -> Option<Arc<Mutex<PciRoot>>> {
+        self.pci.clone()
+    }
+
+    fn get_power_button(&self) -> Option<PowerButton> {
+        self.power_button.clone()
+    }
+
+    fn get_post_codes(&self) -> Option<PostCodes> {
+        self.post_codes.clone()
+    }
+
+    fn vcpu_kick(&self) -> Option<VcpuKick> {
+        Some(self.cpu.lock().unwrap().kick())
+    }
+
+    fn add_hypercall_handler(
+        &mut self,
+        nr: u64,
+        handler: Arc<Mutex<dyn HypercallHandler>>,
+    ) -> Result<(), HvError> {
+        let mut bus = self.bus.lock().unwrap();
+
+        // The guest makes the others through `HYPERCALL_PORT`
+        #[cfg(target_arch = "x86_64")]
+        if self::x86_64::is_hypercall_forwarded(nr) {
+            let mut numbers = bus.hypercall_numbers();
+            numbers.retain(|nr| self::x86_64::is_hypercall_forwarded(*nr));
+            numbers.push(nr);
+            self::x86_64::enable_hypercall_exits(self.vm_fd, &numbers)?;
+        }
+        #[cfg(target_arch = "aarch64")]
+        self::aarch64::forward_smccc_call(self.vm_fd, nr)?;
+
+        bus.add_hypercall_handler(nr, handler);
+
+        Ok(())
+    }
+
+    fn add_msr_handler(
+        &mut self,
+        index: u32,
+        handler: Arc<Mutex<dyn MsrHandler>>,
+    ) -> Result<(), HvError> {
+        #[cfg(target_arch = "x86_64")]
+        {
+            let mut bus = self.bus.lock().unwrap();
+            let mut indices = bus.msr_indices();
+            indices.push(index);
+            self::x86_64::set_msr_filter(self.vm_fd, &indices)?;
+            bus.add_msr_handler(index, handler);
+
+            Ok(())
+        }
+
+        #[cfg(target_arch = "aarch64")]
+        {
+            let _ = (index, handler);
+            log::error!("MSRs are x86_64 only");
+            Err(std::io::Error::from_raw_os_error(libc::EINVAL))
+        }
+    }
+
+    fn reset(&mut self) -> Result<(), HvError> {
+        {
+            let mut memory = self.memory.lock().unwrap();
+            for span in &self.ram {
+                memory.discard(span.start, span.size)?;
+            }
+
+            #[cfg(target_arch = "x86_64")]
+            write_boot_params(
+                &mut memory,
+                &self.ram,
+                &self.topology,
+                &self.numa,
+                self.irq_chip.is_some(),
+                self.pci.is_some(),
+            );
+        }
+
+        if let Some(irq_chip) = &self.irq_chip {
+            irq_chip.reset()?;
+        }
+
+        {
+            let mut cpu = self.cpu.lock().unwrap();
+            cpu.reset()?;
+
+            #[cfg(target_arch = "x86_64")]
+            cpu.set_gp_register(
+                self::x86_64::CpuRegister::Rsi,
+                self::x86_64::BOOT_PARAMS_GPA,
+            )?;
+        }
+
+        self.bus.lock().unwrap().reset();
+
+        Ok(())
+    }
 }
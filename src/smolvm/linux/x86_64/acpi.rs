@@ -0,0 +1,596 @@
+//! ACPI tables describing the platform to the guest. The tables are placed
+//! into the reserved memory below 1MiB where the guest also finds the RSDP
+//! by scanning:
+//!
+//!   RSDP -> XSDT -> FADT -> DSDT
+//!                -> MADT
+//!                -> SRAT, SLIT with the NUMA nodes
+//!
+//! The FADT declares the hardware-reduced ACPI: no PM timer, no GPEs, no
+//! fixed-feature registers, so there is no SCI either. The guest powers off
+//! through the sleep control register, reboots through the reset register,
+//! and the power button is signalled by
+//! the Generic Event Device (GED) notifying the button device:
+//!
+//!   \_S5_           SLP_TYPx to write to the sleep control register for S5
+//!   \_SB_.PWRB      PNP0C0C control method power button
+//!   \_SB_.GED_      ACPI0013, its `_EVT` runs on the interrupt
+//!
+//! The pvpanic port is found by the guest as the QEMU0001 device, the
+//! real-time clock as PNP0B00 and the keyboard controller as PNP0303 since
+//! the legacy ones would come without the interrupts on the hardware-reduced
+//! ACPI.
+//!
+//! With the PCI bus, the guest enumerates it only below the host bridge
+//! device `\_SB_.PCI0` decoding bus 0, the configuration ports and the
+//! MMIO window of the BARs.
+
+use super::aml;
+use crate::smolvm::{
+    acpi_pm::{
+        ACPI_PM_RESET_OFFSET, ACPI_PM_SLEEP_CONTROL_OFFSET, ACPI_PM_SLEEP_STATUS_OFFSET,
+        ACPI_RESET_VALUE, ACPI_SLP_TYP_S5,
+    },
+    cmos::{CMOS_IRQ, CMOS_PORT, CMOS_PORT_COUNT, RTC_CENTURY},
+    i8042::{I8042_COMMAND_PORT, I8042_DATA_PORT, I8042_KBD_IRQ},
+    numa::{self, NumaNode},
+    pci::{PCI_CONFIG_ADDRESS_PORT, PCI_CONFIG_PORT_COUNT, PCI_MMIO_BASE, PCI_MMIO_SIZE},
+    pvpanic::{PVPANIC_PORT, PVPANIC_PORT_COUNT},
+    topology::CpuTopology,
+    GpaSpan,
+};
+
+pub const ACPI_TABLES_GPA: u64 = 0x000e_0000;
+pub const ACPI_TABLES_SIZE: u64 = 0x2_0000;
+
+/// The RSDP comes first
+pub const ACPI_RSDP_GPA: u64 = ACPI_TABLES_GPA;
+
+/// The sleep control, sleep status and reset registers
+pub const ACPI_PM_PORT: u16 = 0x600;
+
+/// Past the ISA interrupts and the virtio-mmio ones
+pub const ACPI_GED_IRQ: u32 = 16;
+
+const ACPI_OEM_ID: &[u8; 6] = b"SMOLVM";
+const ACPI_OEM_TABLE_ID: &[u8; 8] = b"SMOLVM  ";
+const ACPI_CREATOR_ID: &[u8; 4] = b"SMOL";
+
+const ACPI_TABLE_HEADER_SIZE: usize = 36;
+const ACPI_TABLE_CHECKSUM_OFFSET: usize = 9;
+const ACPI_RSDP_SIZE: usize = 36;
+
+const LOCAL_APIC_ADDRESS: u32 = 0xfee0_0000;
+const IO_APIC_ADDRESS: u32 = 0xfec0_0000;
+
+// MADT
+const MADT_PCAT_COMPAT: u32 = 1;
+const MADT_LOCAL_APIC: u8 = 0;
+const MADT_IO_APIC: u8 = 1;
+const MADT_INTERRUPT_OVERRIDE: u8 = 2;
+const MADT_LOCAL_APIC_NMI: u8 = 4;
+const MADT_LOCAL_APIC_ENABLED: u32 = 1;
+
+// SRAT
+const SRAT_LOCAL_APIC_AFFINITY: u8 = 0;
+const SRAT_MEMORY_AFFINITY: u8 = 1;
+const SRAT_ENABLED: u32 = 1;
+
+// FADT
+const FADT_SIZE: usize = 276;
+const FADT_REVISION: u8 = 6;
+const FADT_MINOR_REVISION: u8 = 5;
+const FADT_F_PWR_BUTTON: u32 = 1 << 4;
+const FADT_F_SLP_BUTTON: u32 = 1 << 5;
+const FADT_F_RESET_REG_SUP: u32 = 1 << 10;
+const FADT_F_HW_REDUCED_ACPI: u32 = 1 << 20;
+const FADT_IAPC_BOOT_ARCH_8042: u16 = 1 << 1;
+const FADT_IAPC_BOOT_ARCH_VGA_NOT_PRESENT: u16 = 1 << 2;
+
+const GAS_SYSTEM_IO: u8 = 1;
+const GAS_ACCESS_BYTE: u8 = 1;
+
+/// The notification the button devices receive when pressed
+const ACPI_NOTIFY_BUTTON_PRESSED: u64 = 0x80;
+
+const COM1_PORT: u16 = 0x3f8;
+const COM1_IRQ: u8 = 4;
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter()
+        .fold(0_u8, |sum, byte| sum.wrapping_add(*byte))
+        .wrapping_neg()
+}
+
+/// A table with the standard header, the length and the checksum are filled
+/// in when the table is finished
+struct AcpiTable {
+    data: Vec<u8>,
+}
+
+impl AcpiTable {
+    fn new(signature: &[u8; 4], revision: u8) -> Self {
+        let mut data = Vec::with_capacity(ACPI_TABLE_HEADER_SIZE);
+        data.extend_from_slice(signature);
+        data.extend_from_slice(&0_u32.to_le_bytes());
+        data.push(revision);
+        data.push(0);
+        data.extend_from_slice(ACPI_OEM_ID);
+        data.extend_from_slice(ACPI_OEM_TABLE_ID);
+        data.extend_from_slice(&1_u32.to_le_bytes());
+        data.extend_from_slice(ACPI_CREATOR_ID);
+        data.extend_from_slice(&1_u32.to_le_bytes());
+
+        Self { data }
+    }
+
+    fn append(&mut self, data: &[u8]) {
+        self.data.extend_from_slice(data);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        let length = self.data.len() as u32;
+        self.data[4..8].copy_from_slice(&length.to_le_bytes());
+        self.data[ACPI_TABLE_CHECKSUM_OFFSET] = checksum(&self.data);
+
+        self.data
+    }
+}
+
+/// Generic Address Structure of a byte-wide I/O port
+fn gas_io_byte(port: u16) -> [u8; 12] {
+    let mut gas = [0_u8; 12];
+    gas[..4].copy_from_slice(&[GAS_SYSTEM_IO, 8 /* bit width */, 0, GAS_ACCESS_BYTE]);
+    gas[4..].copy_from_slice(&(port as u64).to_le_bytes());
+
+    gas
+}
+
+fn create_fadt(dsdt_gpa: u64) -> Vec<u8> {
+    let mut fadt = vec![0_u8; FADT_SIZE - ACPI_TABLE_HEADER_SIZE];
+    let mut field = |offset: usize, data: &[u8]| {
+        let offset = offset - ACPI_TABLE_HEADER_SIZE;
+        fadt[offset..offset + data.len()].copy_from_slice(data);
+    };
+
+    field(108, &[RTC_CENTURY]);
+    field(
+        109,
+        &(FADT_IAPC_BOOT_ARCH_8042 | FADT_IAPC_BOOT_ARCH_VGA_NOT_PRESENT).to_le_bytes(),
+    );
+    field(
+        112,
+        &(FADT_F_HW_REDUCED_ACPI | FADT_F_PWR_BUTTON | FADT_F_SLP_BUTTON | FADT_F_RESET_REG_SUP)
+            .to_le_bytes(),
+    );
+    field(116, &gas_io_byte(ACPI_PM_PORT + ACPI_PM_RESET_OFFSET));
+    field(128, &[ACPI_RESET_VALUE]);
+    field(131, &[FADT_MINOR_REVISION]);
+    field(140, &dsdt_gpa.to_le_bytes());
+    field(
+        244,
+        &gas_io_byte(ACPI_PM_PORT + ACPI_PM_SLEEP_CONTROL_OFFSET),
+    );
+    field(
+        256,
+        &gas_io_byte(ACPI_PM_PORT + ACPI_PM_SLEEP_STATUS_OFFSET),
+    );
+    field(268, b"SMOLVM\0\0");
+
+    let mut table = AcpiTable::new(b"FACP", FADT_REVISION);
+    table.append(&fadt);
+    table.finish()
+}
+
+/// Every CPU of the topology in the order of the index, the ones past the
+/// `cpu_count` running are disabled
+fn create_madt(topology: &CpuTopology, cpu_count: u32) -> Vec<u8> {
+    let mut table = AcpiTable::new(b"APIC", 5);
+    table.append(&LOCAL_APIC_ADDRESS.to_le_bytes());
+    table.append(&MADT_PCAT_COMPAT.to_le_bytes());
+
+    for cpu in 0..topology.cpu_count() {
+        table.append(&[
+            MADT_LOCAL_APIC,
+            8,
+            cpu as u8,                   /* UID */
+            topology.apic_id(cpu) as u8, /* APIC ID */
+        ]);
+        let flags = if cpu < cpu_count {
+            MADT_LOCAL_APIC_ENABLED
+        } else {
+            0
+        };
+        table.append(&flags.to_le_bytes());
+    }
+
+    table.append(&[MADT_IO_APIC, 12, 0 /* ID */, 0]);
+    table.append(&IO_APIC_ADDRESS.to_le_bytes());
+    table.append(&0_u32.to_le_bytes()); // GSI base
+
+    // The PIT is wired to the IOAPIC pin 2
+    table.append(&[
+        MADT_INTERRUPT_OVERRIDE,
+        10,
+        0, /* ISA */
+        0, /* IRQ */
+    ]);
+    table.append(&2_u32.to_le_bytes());
+    table.append(&0_u16.to_le_bytes()); // Conforms to the bus
+
+    // LINT1 of all processors is the NMI
+    table.append(&[MADT_LOCAL_APIC_NMI, 6, 0xff, 0, 0, 1]);
+
+    table.finish()
+}
+
+/// The node of every CPU of the topology and of every RAM region, the
+/// proximity domains are the indices of the nodes
+fn create_srat(topology: &CpuTopology, ram: &[GpaSpan], nodes: &[NumaNode]) -> Vec<u8> {
+    let mut table = AcpiTable::new(b"SRAT", 3);
+    table.append(&1_u32.to_le_bytes()); // Reserved, 1 for compatibility
+    table.append(&0_u64.to_le_bytes());
+
+    for cpu in 0..topology.cpu_count() {
+        let domain = numa::node_of_cpu(nodes, cpu).unwrap_or(0);
+        table.append(&[
+            SRAT_LOCAL_APIC_AFFINITY,
+            16,
+            domain as u8,
+            topology.apic_id(cpu) as u8,
+        ]);
+        table.append(&SRAT_ENABLED.to_le_bytes());
+        table.append(&[0 /* SAPIC EID */]);
+        table.append(&domain.to_le_bytes()[1..]);
+        table.append(&0_u32.to_le_bytes()); // Clock domain
+    }
+
+    for (region, span) in ram.iter().enumerate() {
+        let domain = numa::node_of_memory(nodes, region).unwrap_or(0);
+        table.append(&[SRAT_MEMORY_AFFINITY, 40]);
+        table.append(&domain.to_le_bytes());
+        table.append(&0_u16.to_le_bytes());
+        table.append(&span.start.to_le_bytes());
+        table.append(&(span.size as u64).to_le_bytes());
+        table.append(&0_u32.to_le_bytes());
+        table.append(&SRAT_ENABLED.to_le_bytes());
+        table.append(&0_u64.to_le_bytes());
+    }
+
+    table.finish()
+}
+
+/// The distances between the nodes
+fn create_slit(nodes: &[NumaNode]) -> Vec<u8> {
+    let mut table = AcpiTable::new(b"SLIT", 1);
+    table.append(&(nodes.len() as u64).to_le_bytes());
+    for from in 0..nodes.len() {
+        for to in 0..nodes.len() {
+            table.append(&[numa::distance(nodes, from, to)]);
+        }
+    }
+
+    table.finish()
+}
+
+fn create_pci_host_bridge() -> Vec<u8> {
+    aml::device(
+        "PCI0",
+        &[
+            aml::name("_HID", &aml::eisa_id("PNP0A08")),
+            aml::name("_CID", &aml::eisa_id("PNP0A03")),
+            aml::name("_SEG", &aml::integer(0)),
+            aml::name("_BBN", &aml::integer(0)),
+            aml::name("_UID", &aml::integer(0)),
+            aml::name(
+                "_CRS",
+                &aml::resource_template(&[
+                    aml::word_bus_number(0, 0),
+                    aml::io_port(PCI_CONFIG_ADDRESS_PORT, PCI_CONFIG_PORT_COUNT as u8),
+                    aml::dword_memory(PCI_MMIO_BASE as u32, PCI_MMIO_SIZE as u32),
+                ]),
+            ),
+        ],
+    )
+}
+
+fn create_dsdt(pci: bool) -> Vec<u8> {
+    let com1 = aml::device(
+        "COM1",
+        &[
+            aml::name("_HID", &aml::eisa_id("PNP0501")),
+            aml::name("_UID", &aml::integer(0)),
+            aml::name(
+                "_CRS",
+                &aml::resource_template(&[aml::io_port(COM1_PORT, 8), aml::irq_no_flags(COM1_IRQ)]),
+            ),
+        ],
+    );
+
+    let power_button = aml::device(
+        "PWRB",
+        &[
+            aml::name("_HID", &aml::eisa_id("PNP0C0C")),
+            aml::name("_UID", &aml::integer(0)),
+        ],
+    );
+
+    // The only event is the power button, no need to look at the argument
+    let ged = aml::device(
+        "GED_",
+        &[
+            aml::name("_HID", &aml::string("ACPI0013")),
+            aml::name("_UID", &aml::integer(0)),
+            aml::name(
+                "_CRS",
+                &aml::resource_template(&[aml::interrupt(ACPI_GED_IRQ)]),
+            ),
+            aml::method(
+                "_EVT",
+                1,
+                &[aml::notify("\\_SB_.PWRB", ACPI_NOTIFY_BUTTON_PRESSED)],
+            ),
+        ],
+    );
+
+    let rtc = aml::device(
+        "RTC_",
+        &[
+            aml::name("_HID", &aml::eisa_id("PNP0B00")),
+            aml::name(
+                "_CRS",
+                &aml::resource_template(&[
+                    aml::io_port(CMOS_PORT, CMOS_PORT_COUNT as u8),
+                    aml::irq_no_flags(CMOS_IRQ as u8),
+                ]),
+            ),
+        ],
+    );
+
+    // No PNP0F13 for the mouse, the controller has no AUX port
+    let keyboard = aml::device(
+        "PS2K",
+        &[
+            aml::name("_HID", &aml::eisa_id("PNP0303")),
+            aml::name(
+                "_CRS",
+                &aml::resource_template(&[
+                    aml::io_port(I8042_DATA_PORT, 1),
+                    aml::io_port(I8042_COMMAND_PORT, 1),
+                    aml::irq_no_flags(I8042_KBD_IRQ as u8),
+                ]),
+            ),
+        ],
+    );
+
+    let pvpanic = aml::device(
+        "PEVT",
+        &[
+            aml::name("_HID", &aml::string("QEMU0001")),
+            aml::name(
+                "_CRS",
+                &aml::resource_template(&[aml::io_port(PVPANIC_PORT, PVPANIC_PORT_COUNT as u8)]),
+            ),
+        ],
+    );
+
+    let s5 = aml::name(
+        "_S5_",
+        &aml::package(&[
+            aml::integer(ACPI_SLP_TYP_S5 as u64),
+            aml::integer(ACPI_SLP_TYP_S5 as u64),
+        ]),
+    );
+
+    let mut devices = vec![com1, rtc, keyboard, power_button, ged, pvpanic];
+    if pci {
+        devices.push(create_pci_host_bridge());
+    }
+
+    let mut table = AcpiTable::new(b"DSDT", 2);
+    table.append(&s5);
+    table.append(&aml::scope("\\_SB_", &devices));
+    table.finish()
+}
+
+fn place(tables: &mut Vec<u8>, table: &[u8]) -> u64 {
+    let offset = (tables.len() + 15) & !15;
+    tables.resize(offset, 0);
+    tables.extend_from_slice(table);
+
+    ACPI_TABLES_GPA + offset as u64
+}
+
+/// The tables for the first `cpu_count` processors of the topology, the
+/// NUMA nodes if any and the PCI host bridge if `pci`, to be placed at
+/// `ACPI_TABLES_GPA`
+pub fn create_acpi_tables(
+    topology: &CpuTopology,
+    cpu_count: u32,
+    ram: &[GpaSpan],
+    numa: &[NumaNode],
+    pci: bool,
+) -> Vec<u8> {
+    // The xAPIC entries of the MADT and the SRAT hold the APIC IDs in 8
+    // bits, the topology keeps them below the broadcast one
+    assert!(topology.is_valid());
+
+    let mut tables = vec![0_u8; ACPI_RSDP_SIZE];
+
+    let dsdt_gpa = place(&mut tables, &create_dsdt(pci));
+    let fadt_gpa = place(&mut tables, &create_fadt(dsdt_gpa));
+    let madt_gpa = place(&mut tables, &create_madt(topology, cpu_count));
+
+    let mut xsdt = AcpiTable::new(b"XSDT", 1);
+    xsdt.append(&fadt_gpa.to_le_bytes());
+    xsdt.append(&madt_gpa.to_le_bytes());
+    if !numa.is_empty() {
+        let srat_gpa = place(&mut tables, &create_srat(topology, ram, numa));
+        let slit_gpa = place(&mut tables, &create_slit(numa));
+        xsdt.append(&srat_gpa.to_le_bytes());
+        xsdt.append(&slit_gpa.to_le_bytes());
+    }
+    let xsdt_gpa = place(&mut tables, &xsdt.finish());
+
+    let rsdp = &mut tables[..ACPI_RSDP_SIZE];
+    rsdp[0..8].copy_from_slice(b"RSD PTR ");
+    rsdp[9..15].copy_from_slice(ACPI_OEM_ID);
+    rsdp[15] = 2; // Revision
+    rsdp[20..24].copy_from_slice(&(ACPI_RSDP_SIZE as u32).to_le_bytes());
+    rsdp[24..32].copy_from_slice(&xsdt_gpa.to_le_bytes());
+    rsdp[8] = checksum(&rsdp[..20]);
+    rsdp[32] = checksum(rsdp);
+
+    assert!(tables.len() as u64 <= ACPI_TABLES_SIZE);
+
+    tables
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The table at the address with its signature, the length and the
+    /// checksum checked
+    fn table<'a>(tables: &'a [u8], gpa: u64, signature: &[u8; 4]) -> &'a [u8] {
+        let offset = (gpa - ACPI_TABLES_GPA) as usize;
+        let header = &tables[offset..offset + ACPI_TABLE_HEADER_SIZE];
+        let length = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+
+        let table = &tables[offset..offset + length];
+        assert_eq!(&table[..4], signature);
+        assert_eq!(checksum(table), 0, "{:?}", signature);
+
+        table
+    }
+
+    fn u64_at(data: &[u8], offset: usize) -> u64 {
+        let mut bytes = [0_u8; 8];
+        bytes.copy_from_slice(&data[offset..offset + 8]);
+        u64::from_le_bytes(bytes)
+    }
+
+    #[test]
+    fn test_tables() {
+        let topology = CpuTopology {
+            sockets: 2,
+            dies: 1,
+            cores: 3,
+            threads: 2,
+        };
+        let ram = [
+            GpaSpan {
+                start: 0,
+                size: 0x1000_0000,
+            },
+            GpaSpan {
+                start: 0x1000_0000,
+                size: 0x1000_0000,
+            },
+        ];
+        let numa = [
+            NumaNode {
+                memory: vec![0],
+                cpus: (0..6).collect(),
+                ..Default::default()
+            },
+            NumaNode {
+                memory: vec![1],
+                cpus: (6..12).collect(),
+                ..Default::default()
+            },
+        ];
+        let tables = create_acpi_tables(&topology, 1, &ram, &numa, false);
+        assert!(tables.len() as u64 <= ACPI_TABLES_SIZE);
+
+        let rsdp = &tables[..ACPI_RSDP_SIZE];
+        assert_eq!(&rsdp[..8], b"RSD PTR ");
+        assert_eq!(checksum(&rsdp[..20]), 0);
+        assert_eq!(checksum(rsdp), 0);
+        assert_eq!(rsdp[20..24], (ACPI_RSDP_SIZE as u32).to_le_bytes());
+
+        let xsdt = table(&tables, u64_at(rsdp, 24), b"XSDT");
+        assert_eq!(xsdt.len(), ACPI_TABLE_HEADER_SIZE + 4 * 8);
+        let entries = (0..4)
+            .map(|index| u64_at(xsdt, ACPI_TABLE_HEADER_SIZE + index * 8))
+            .collect::<Vec<_>>();
+
+        let fadt = table(&tables, entries[0], b"FACP");
+        assert_eq!(fadt.len(), FADT_SIZE);
+        let dsdt = table(&tables, u64_at(fadt, 140), b"DSDT");
+        assert!(dsdt.len() > ACPI_TABLE_HEADER_SIZE);
+
+        // The local APICs, the IOAPIC, the override and the NMI
+        let madt = table(&tables, entries[1], b"APIC");
+        assert_eq!(
+            madt.len(),
+            ACPI_TABLE_HEADER_SIZE + 8 + 12 * 8 + 12 + 10 + 6
+        );
+        // The first core of the second socket is the CPU 6
+        let entry = &madt[ACPI_TABLE_HEADER_SIZE + 8 + 6 * 8..][..8];
+        assert_eq!(entry[..4], [MADT_LOCAL_APIC, 8, 6, 0b1000]);
+        assert_eq!(entry[4..], 0_u32.to_le_bytes());
+
+        let srat = table(&tables, entries[2], b"SRAT");
+        assert_eq!(srat.len(), ACPI_TABLE_HEADER_SIZE + 12 + 12 * 16 + 2 * 40);
+        let slit = table(&tables, entries[3], b"SLIT");
+        assert_eq!(slit.len(), ACPI_TABLE_HEADER_SIZE + 8 + 4);
+        assert_eq!(slit[ACPI_TABLE_HEADER_SIZE + 8..], [10, 20, 20, 10]);
+    }
+
+    #[test]
+    fn test_pci_host_bridge() {
+        let topology = CpuTopology::default();
+        let contains = |data: &[u8], term: &[u8]| data.windows(term.len()).any(|w| w == term);
+        let dsdt = |pci: bool| {
+            let tables = create_acpi_tables(&topology, 1, &[], &[], pci);
+            let xsdt = table(&tables, u64_at(&tables, 24), b"XSDT");
+            let fadt = table(&tables, u64_at(xsdt, ACPI_TABLE_HEADER_SIZE), b"FACP");
+            table(&tables, u64_at(fadt, 140), b"DSDT").to_vec()
+        };
+
+        let bridge = create_pci_host_bridge();
+        assert!(!contains(&dsdt(false), b"PCI0"));
+        assert!(contains(&dsdt(true), &bridge));
+
+        assert!(contains(
+            &bridge,
+            &aml::name("_HID", &aml::eisa_id("PNP0A08"))
+        ));
+        assert!(contains(
+            &bridge,
+            &aml::name("_CID", &aml::eisa_id("PNP0A03"))
+        ));
+        assert!(contains(
+            &bridge,
+            &[
+                0x88, 13, 0, 0x02, 0x0c, 0, // Bus 0
+                0, 0, 0, 0, 0, 0, 0, 0, 1, 0,
+            ]
+        ));
+        assert!(contains(
+            &bridge,
+            &[0x47, 0x01, 0xf8, 0x0c, 0xf8, 0x0c, 1, 8]
+        ));
+        assert!(contains(
+            &bridge,
+            &[
+                0x87, 23, 0, 0x00, 0x0c, 0x01, // 0xc000_0000 to 0xcfff_ffff
+                0, 0, 0, 0, 0, 0, 0, 0xc0, 0xff, 0xff, 0xff, 0xcf, 0, 0, 0, 0, 0, 0, 0, 0x10,
+            ]
+        ));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_apic_ids() {
+        // The last APIC ID is 0x1ff
+        let topology = CpuTopology {
+            sockets: 2,
+            dies: 1,
+            cores: 256,
+            threads: 1,
+        };
+        create_acpi_tables(&topology, 1, &[], &[], false);
+    }
+}
@@ -0,0 +1,218 @@
+//! Just enough of the ACPI Machine Language to describe the devices in
+//! the DSDT. Each function returns the encoded term.
+
+fn pkg_length(length: usize) -> Vec<u8> {
+    // The encoded length includes the bytes of the encoding itself
+    if length + 1 < 0x40 {
+        return vec![(length + 1) as u8];
+    }
+
+    for count in 1..=3 {
+        let total = length + 1 + count;
+        if total < 1 << (4 + 8 * count) {
+            let mut encoded = vec![(count << 6) as u8 | (total & 0xf) as u8];
+            for index in 0..count {
+                encoded.push((total >> (4 + 8 * index)) as u8);
+            }
+
+            return encoded;
+        }
+    }
+
+    panic!("AML package of {} bytes is too large", length);
+}
+
+fn with_pkg_length(opcode: &[u8], content: &[u8]) -> Vec<u8> {
+    let mut term = opcode.to_vec();
+    term.extend(pkg_length(content.len()));
+    term.extend_from_slice(content);
+
+    term
+}
+
+/// `\_SB_.COM1` and such, the segments shorter than 4 characters are
+/// padded with underscores
+pub fn name_string(path: &str) -> Vec<u8> {
+    let mut encoded = Vec::new();
+    let path = match path.strip_prefix('\\') {
+        Some(path) => {
+            encoded.push(b'\\');
+            path
+        }
+        None => path,
+    };
+
+    let segments: Vec<&str> = path.split('.').filter(|s| !s.is_empty()).collect();
+    match segments.len() {
+        0 => encoded.push(0x00),
+        1 => {}
+        2 => encoded.push(0x2e),
+        count => encoded.extend_from_slice(&[0x2f, count as u8]),
+    }
+
+    for segment in segments {
+        assert!(
+            segment.len() <= 4,
+            "AML name segment {} is too long",
+            segment
+        );
+        let mut name_seg = [b'_'; 4];
+        name_seg[..segment.len()].copy_from_slice(segment.as_bytes());
+        encoded.extend_from_slice(&name_seg);
+    }
+
+    encoded
+}
+
+pub fn integer(value: u64) -> Vec<u8> {
+    match value {
+        0 => vec![0x00],
+        1 => vec![0x01],
+        2..=0xff => vec![0x0a, value as u8],
+        0x100..=0xffff => [&[0x0b][..], &(value as u16).to_le_bytes()].concat(),
+        0x1_0000..=0xffff_ffff => [&[0x0c][..], &(value as u32).to_le_bytes()].concat(),
+        _ => [&[0x0e][..], &value.to_le_bytes()].concat(),
+    }
+}
+
+pub fn string(value: &str) -> Vec<u8> {
+    let mut encoded = vec![0x0d];
+    encoded.extend_from_slice(value.as_bytes());
+    encoded.push(0x00);
+
+    encoded
+}
+
+pub fn package(elements: &[Vec<u8>]) -> Vec<u8> {
+    let mut content = vec![elements.len() as u8];
+    content.extend(elements.concat());
+
+    with_pkg_length(&[0x12], &content)
+}
+
+/// The compressed EISA ID, e.g. `PNP0501`
+pub fn eisa_id(id: &str) -> Vec<u8> {
+    let id = id.as_bytes();
+    assert_eq!(id.len(), 7, "EISA ID must be 7 characters long");
+
+    let vendor = ((id[0] - 0x40) as u16 & 0x1f) << 10
+        | ((id[1] - 0x40) as u16 & 0x1f) << 5
+        | ((id[2] - 0x40) as u16 & 0x1f);
+    let product = u16::from_str_radix(std::str::from_utf8(&id[3..]).unwrap(), 16)
+        .expect("EISA product ID must be hexadecimal");
+
+    let mut encoded = vec![0x0c];
+    encoded.extend_from_slice(&vendor.to_be_bytes());
+    encoded.extend_from_slice(&product.to_be_bytes());
+
+    encoded
+}
+
+pub fn name(name: &str, value: &[u8]) -> Vec<u8> {
+    let mut term = vec![0x08];
+    term.extend(name_string(name));
+    term.extend_from_slice(value);
+
+    term
+}
+
+pub fn scope(path: &str, terms: &[Vec<u8>]) -> Vec<u8> {
+    let mut content = name_string(path);
+    content.extend(terms.concat());
+
+    with_pkg_length(&[0x10], &content)
+}
+
+pub fn device(path: &str, terms: &[Vec<u8>]) -> Vec<u8> {
+    let mut content = name_string(path);
+    content.extend(terms.concat());
+
+    with_pkg_length(&[0x5b, 0x82], &content)
+}
+
+/// Not serialized, the arguments are `Arg0`..`Arg6`
+pub fn method(name: &str, arg_count: u8, terms: &[Vec<u8>]) -> Vec<u8> {
+    assert!(arg_count < 8, "AML method takes at most 7 arguments");
+
+    let mut content = name_string(name);
+    content.push(arg_count);
+    content.extend(terms.concat());
+
+    with_pkg_length(&[0x14], &content)
+}
+
+pub fn notify(path: &str, value: u64) -> Vec<u8> {
+    let mut term = vec![0x86];
+    term.extend(name_string(path));
+    term.extend(integer(value));
+
+    term
+}
+
+/// A buffer with the resource descriptors and the end tag
+pub fn resource_template(descriptors: &[Vec<u8>]) -> Vec<u8> {
+    let mut data = descriptors.concat();
+    data.extend_from_slice(&[0x79, 0x00]);
+
+    let mut content = integer(data.len() as u64);
+    content.extend(data);
+
+    with_pkg_length(&[0x11], &content)
+}
+
+/// I/O port range with 16-bit decoding
+pub fn io_port(base: u16, length: u8) -> Vec<u8> {
+    let mut descriptor = vec![0x47, 0x01];
+    descriptor.extend_from_slice(&base.to_le_bytes());
+    descriptor.extend_from_slice(&base.to_le_bytes());
+    descriptor.extend_from_slice(&[0x01, length]);
+
+    descriptor
+}
+
+/// Edge-triggered, active-high ISA interrupt
+pub fn irq_no_flags(irq: u8) -> Vec<u8> {
+    let mut descriptor = vec![0x22];
+    descriptor.extend_from_slice(&(1_u16 << irq).to_le_bytes());
+
+    descriptor
+}
+
+/// Edge-triggered, active-high, exclusive interrupt consumed by the device,
+/// unlike the ISA one can be any GSI
+pub fn interrupt(gsi: u32) -> Vec<u8> {
+    let mut descriptor = vec![0x89];
+    descriptor.extend_from_slice(&6_u16.to_le_bytes());
+    descriptor.extend_from_slice(&[0x03 /* consumer, edge */, 1 /* count */]);
+    descriptor.extend_from_slice(&gsi.to_le_bytes());
+
+    descriptor
+}
+
+/// Range of bus numbers decoded by a bridge, from `min` to `max` inclusive
+pub fn word_bus_number(min: u16, max: u16) -> Vec<u8> {
+    let mut descriptor = vec![0x88];
+    descriptor.extend_from_slice(&13_u16.to_le_bytes());
+    descriptor.extend_from_slice(&[0x02 /* bus */, 0x0c /* producer, fixed */, 0x00]);
+    for value in [0, min, max, 0, max - min + 1].iter() {
+        descriptor.extend_from_slice(&value.to_le_bytes());
+    }
+
+    descriptor
+}
+
+/// Non-cacheable, read-write MMIO window below 4GiB passed on by a bridge
+pub fn dword_memory(base: u32, size: u32) -> Vec<u8> {
+    let mut descriptor = vec![0x87];
+    descriptor.extend_from_slice(&23_u16.to_le_bytes());
+    descriptor.extend_from_slice(&[
+        0x00, /* memory */
+        0x0c, /* producer, fixed */
+        0x01,
+    ]);
+    for value in [0, base, base + (size - 1), 0, size].iter() {
+        descriptor.extend_from_slice(&value.to_le_bytes());
+    }
+
+    descriptor
+}
@@ -4,6 +4,13 @@
 
 #![allow(dead_code)]
 
+pub const BOOT_PARAMS_GPA: u64 = 0x10000;
+pub const CMD_LINE_GPA: u64 = 0x20000;
+pub const CMD_LINE_MAX_SIZE: usize = 0x1000;
+
+// Offset of `setup_header.cmd_line_ptr` within the boot params
+pub const BOOT_PARAMS_CMD_LINE_PTR_OFFSET: u64 = 0x228;
+
 pub const BOOT_CODE_CS_GDT_INDEX: u16 = 2;
 pub const BOOT_CODE_SS_GDT_INDEX: u16 = 3;
 pub const BOOT_CODE_LDT_GDT_INDEX: u16 = 6;
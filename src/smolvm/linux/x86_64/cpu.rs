@@ -45,6 +45,15 @@ pub const MSR_IA32_CR_PAT_DEFAULT: u64 = 0x0007040600070406;
 pub const MSR_IA32_MISC_ENABLE: u32 = 0x000001a0;
 pub const MSR_IA32_MISC_ENABLE_FAST_STR: u64 = 0x00000001;
 
+/// The paravirtual MSRs point KVM to the guest memory it keeps updating
+pub const MSR_KVM_WALL_CLOCK: u32 = 0x00000011;
+pub const MSR_KVM_SYSTEM_TIME: u32 = 0x00000012;
+pub const MSR_KVM_WALL_CLOCK_NEW: u32 = 0x4b564d00;
+pub const MSR_KVM_SYSTEM_TIME_NEW: u32 = 0x4b564d01;
+pub const MSR_KVM_ASYNC_PF_EN: u32 = 0x4b564d02;
+pub const MSR_KVM_STEAL_TIME: u32 = 0x4b564d03;
+pub const MSR_KVM_PV_EOI_EN: u32 = 0x4b564d04;
+
 /// System-Segment and Gate-Descriptor Types 64-bit mode
 /// See also Intel 3a, Table 3-2 System Segment and Gate-Descriptor Types.
 #[repr(u8)]
@@ -1,5 +1,7 @@
 #![cfg(target_arch = "x86_64")]
 
+mod acpi;
+mod aml;
 mod boot_params;
 mod cpu;
 
@@ -8,19 +10,30 @@ use std::{
     sync::{Arc, Mutex},
 };
 
+pub use acpi::*;
 pub use boot_params::*;
 pub use cpu::*;
 use kvm_bindings::{
-    kvm_cpuid2, kvm_cpuid_entry2, kvm_dtable, kvm_msr_entry, kvm_msrs, kvm_regs, kvm_run,
-    kvm_segment, kvm_sregs, KVMIO, KVM_EXIT_HLT, KVM_EXIT_IO, KVM_EXIT_IO_IN, KVM_EXIT_IO_OUT,
-    KVM_EXIT_MMIO,
+    kvm_cpuid2, kvm_cpuid_entry2, kvm_dtable, kvm_irq_routing_entry,
+    kvm_irq_routing_entry__bindgen_ty_1, kvm_irq_routing_irqchip, kvm_irqchip, kvm_lapic_state,
+    kvm_msr_entry, kvm_msr_filter, kvm_msrs, kvm_pit_config, kvm_pit_state2, kvm_regs, kvm_run,
+    kvm_segment, kvm_sregs, KVMIO, KVM_EXIT_HLT, KVM_EXIT_HYPERCALL, KVM_EXIT_IO, KVM_EXIT_IO_IN,
+    KVM_EXIT_IO_OUT, KVM_EXIT_MMIO, KVM_EXIT_SHUTDOWN, KVM_EXIT_X86_RDMSR, KVM_EXIT_X86_WRMSR,
+    KVM_IRQCHIP_IOAPIC, KVM_IRQCHIP_PIC_MASTER, KVM_IRQCHIP_PIC_SLAVE, KVM_IRQ_ROUTING_IRQCHIP,
+    KVM_MSR_EXIT_REASON_FILTER, KVM_MSR_EXIT_REASON_UNKNOWN, KVM_MSR_FILTER_DEFAULT_ALLOW,
+    KVM_MSR_FILTER_MAX_RANGES, KVM_MSR_FILTER_READ, KVM_MSR_FILTER_WRITE, KVM_PIT_SPEAKER_DUMMY,
 };
-use nix::{ioctl_read, ioctl_readwrite, ioctl_write_ptr};
+use nix::{ioctl_read, ioctl_readwrite, ioctl_write_int_bad, ioctl_write_ptr, request_code_none};
 use raw_cpuid::CpuId;
 use zerocopy::AsBytes;
 
 use super::Memory;
-use crate::smolvm::{CpuExitReason, IoType};
+use crate::smolvm::{
+    bus::VcpuKick,
+    cpuid::{CpuidEntry, CpuidPolicy},
+    topology::CpuTopology,
+    CpuExitReason, Hypercall, IoType, MmIoType, MsrType,
+};
 
 ioctl_read!(kvm_get_regs, KVMIO, 0x81, kvm_regs);
 ioctl_write_ptr!(kvm_set_regs, KVMIO, 0x82, kvm_regs);
@@ -29,6 +42,17 @@ ioctl_write_ptr!(kvm_set_sregs, KVMIO, 0x84, kvm_sregs);
 ioctl_write_ptr!(kvm_set_msrs, KVMIO, 0x89, kvm_msrs);
 ioctl_readwrite!(kvm_get_supported_cpuid, KVMIO, 0x05, kvm_cpuid2);
 ioctl_write_ptr!(kvm_set_cpuid2, KVMIO, 0x90, kvm_cpuid2);
+ioctl_readwrite!(kvm_get_cpuid2, KVMIO, 0x91, kvm_cpuid2);
+ioctl_write_int_bad!(kvm_create_irqchip, request_code_none!(KVMIO, 0x60));
+ioctl_write_ptr!(kvm_create_pit2, KVMIO, 0x77, kvm_pit_config);
+ioctl_readwrite!(kvm_get_irqchip, KVMIO, 0x62, kvm_irqchip);
+// Declared as _IOR in the kernel headers
+ioctl_read!(kvm_set_irqchip, KVMIO, 0x63, kvm_irqchip);
+ioctl_read!(kvm_get_lapic, KVMIO, 0x8e, kvm_lapic_state);
+ioctl_write_ptr!(kvm_set_lapic, KVMIO, 0x8f, kvm_lapic_state);
+ioctl_read!(kvm_get_pit2, KVMIO, 0x9f, kvm_pit_state2);
+ioctl_write_ptr!(kvm_set_pit2, KVMIO, 0xa0, kvm_pit_state2);
+ioctl_write_ptr!(kvm_x86_set_msr_filter, KVMIO, 0xc6, kvm_msr_filter);
 
 #[allow(dead_code)]
 pub enum CpuRegister {
@@ -50,6 +74,206 @@ pub enum CpuRegister {
     R15,
 }
 
+/// Places the NUL-terminated command line into the guest memory and
+/// points the boot params to it.
+pub fn set_command_line(memory: &mut Memory, command_line: &str) {
+    if command_line.len() >= CMD_LINE_MAX_SIZE {
+        panic!(
+            "Command line is {} bytes long, cannot exceed {} bytes",
+            command_line.len(),
+            CMD_LINE_MAX_SIZE - 1
+        );
+    }
+
+    let mut data = command_line.as_bytes().to_vec();
+    data.push(0);
+
+    memory.write(CMD_LINE_GPA, &data);
+    memory.write_obj(
+        BOOT_PARAMS_GPA + BOOT_PARAMS_CMD_LINE_PTR_OFFSET,
+        &(CMD_LINE_GPA as u32),
+    );
+}
+
+/// The MSIs carry the destination in the address
+pub const MSI_ROUTING_FLAGS: u32 = 0;
+
+const IOAPIC_PINS: u32 = 24;
+
+/// Creates the in-kernel PIC, IOAPIC, LAPIC and PIT. There is no separate
+/// device fd on x86_64, the interrupts are raised through the VM fd.
+pub fn create_irqchip(vm_fd: RawFd) -> Result<Vec<RawFd>, std::io::Error> {
+    unsafe { kvm_create_irqchip(vm_fd, 0) }?;
+
+    let pit_config = kvm_pit_config {
+        flags: KVM_PIT_SPEAKER_DUMMY,
+        ..Default::default()
+    };
+    unsafe { kvm_create_pit2(vm_fd, &pit_config as *const _) }?;
+
+    Ok(Vec::new())
+}
+
+/// Nothing to do, the irqchip is ready right after its creation
+pub fn finalize_irqchip(_device_fds: &[RawFd]) -> Result<(), std::io::Error> {
+    Ok(())
+}
+
+/// The PICs, the IOAPIC and the PIT as they were created, to go back to
+/// when the VM is reset
+pub struct IrqChipState {
+    chips: Vec<kvm_irqchip>,
+    pit: kvm_pit_state2,
+}
+
+pub fn save_irqchip(vm_fd: RawFd) -> Result<IrqChipState, std::io::Error> {
+    let mut chips = Vec::new();
+    for chip_id in [
+        KVM_IRQCHIP_PIC_MASTER,
+        KVM_IRQCHIP_PIC_SLAVE,
+        KVM_IRQCHIP_IOAPIC,
+    ] {
+        let mut chip = kvm_irqchip {
+            chip_id,
+            ..Default::default()
+        };
+        unsafe { kvm_get_irqchip(vm_fd, &mut chip) }?;
+        chips.push(chip);
+    }
+
+    let mut pit = kvm_pit_state2::default();
+    unsafe { kvm_get_pit2(vm_fd, &mut pit) }?;
+
+    Ok(IrqChipState { chips, pit })
+}
+
+pub fn restore_irqchip(vm_fd: RawFd, state: &IrqChipState) -> Result<(), std::io::Error> {
+    for chip in &state.chips {
+        let mut chip = *chip;
+        unsafe { kvm_set_irqchip(vm_fd, &mut chip) }?;
+    }
+
+    unsafe { kvm_set_pit2(vm_fd, &state.pit) }?;
+
+    Ok(())
+}
+
+/// The routes KVM sets up when creating the irqchip: GSIs 0..15 go to both
+/// PICs and the IOAPIC, the rest of the IOAPIC pins follow. Setting the
+/// routing table replaces them, so they have to be repeated.
+pub fn irqchip_routes() -> Vec<kvm_irq_routing_entry> {
+    let route = |gsi: u32, irqchip: u32, pin: u32| kvm_irq_routing_entry {
+        gsi,
+        type_: KVM_IRQ_ROUTING_IRQCHIP,
+        flags: 0,
+        pad: 0,
+        u: kvm_irq_routing_entry__bindgen_ty_1 {
+            irqchip: kvm_irq_routing_irqchip { irqchip, pin },
+        },
+    };
+
+    let mut routes = Vec::new();
+    for gsi in 0..IOAPIC_PINS {
+        if gsi < 8 {
+            routes.push(route(gsi, KVM_IRQCHIP_PIC_MASTER, gsi));
+        } else if gsi < 16 {
+            routes.push(route(gsi, KVM_IRQCHIP_PIC_SLAVE, gsi - 8));
+        }
+        routes.push(route(gsi, KVM_IRQCHIP_IOAPIC, gsi));
+    }
+
+    routes
+}
+
+/// Not in the bindings
+const KVM_CAP_EXIT_HYPERCALL: u32 = 201;
+const KVM_HC_MAP_GPA_RANGE: u64 = 12;
+/// The only `vmcall` numbers KVM forwards
+const KVM_EXIT_HYPERCALL_VALID_MASK: u64 = 1 << KVM_HC_MAP_GPA_RANGE;
+/// What the guest gets for the hypercalls nobody answers
+const KVM_ENOSYS: u64 = -1000_i64 as u64;
+
+/// Writing to the port makes the hypercall of the number in RAX with the
+/// arguments in RBX, RCX, RSI and RDI, RAX holds the result afterwards. For
+/// the numbers KVM answers itself on `vmcall`, i.e. all but
+/// `KVM_HC_MAP_GPA_RANGE`. Unused in the PC I/O map.
+pub const HYPERCALL_PORT: u16 = 0x5c0;
+
+/// Whether KVM can exit on the `vmcall` of the number, the others are made
+/// through `HYPERCALL_PORT`
+pub fn is_hypercall_forwarded(nr: u64) -> bool {
+    nr < u64::BITS as u64 && KVM_EXIT_HYPERCALL_VALID_MASK & (1 << nr) != 0
+}
+
+/// Has KVM exit on the hypercalls of the numbers rather than answering them
+/// itself, KVM refuses the numbers it does not forward
+pub fn enable_hypercall_exits(vm_fd: RawFd, numbers: &[u64]) -> Result<(), std::io::Error> {
+    let mut mask = 0_u64;
+    for nr in numbers {
+        if !is_hypercall_forwarded(*nr) {
+            return Err(std::io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        mask |= 1 << nr;
+    }
+
+    super::enable_vm_cap(vm_fd, KVM_CAP_EXIT_HYPERCALL, [mask, 0, 0, 0])
+}
+
+/// MSRs a filter range covers at most, 64 bytes of bitmap
+const MSR_FILTER_RANGE_SIZE: u32 = 512;
+
+/// Has KVM exit on the MSRs it does not know and on the ones the filter
+/// denies rather than injecting a #GP
+pub fn enable_user_space_msr(vm_fd: RawFd) -> Result<(), std::io::Error> {
+    let reasons = KVM_MSR_EXIT_REASON_UNKNOWN | KVM_MSR_EXIT_REASON_FILTER;
+    super::enable_vm_cap(
+        vm_fd,
+        kvm_bindings::KVM_CAP_X86_USER_SPACE_MSR,
+        [reasons as u64, 0, 0, 0],
+    )
+}
+
+/// Has KVM leave the MSRs of the indices to the VMM and handle all the
+/// others, the indices close to each other share a filter range
+pub fn set_msr_filter(vm_fd: RawFd, indices: &[u32]) -> Result<(), std::io::Error> {
+    let mut indices = indices.to_vec();
+    indices.sort_unstable();
+
+    // The base and the allowed MSRs of each range, all but the handled ones
+    let mut ranges: Vec<(u32, Vec<u8>)> = Vec::new();
+    for index in indices {
+        let new_range = match ranges.last() {
+            Some((base, _)) => index - base >= MSR_FILTER_RANGE_SIZE,
+            None => true,
+        };
+        if new_range {
+            if ranges.len() == KVM_MSR_FILTER_MAX_RANGES as usize {
+                return Err(std::io::Error::from_raw_os_error(libc::E2BIG));
+            }
+            ranges.push((index, vec![0xff; MSR_FILTER_RANGE_SIZE as usize / 8]));
+        }
+
+        let (base, bitmap) = ranges.last_mut().unwrap();
+        let bit = index - *base;
+        bitmap[bit as usize / 8] &= !(1 << (bit % 8));
+    }
+
+    let mut filter = kvm_msr_filter {
+        flags: KVM_MSR_FILTER_DEFAULT_ALLOW,
+        ..Default::default()
+    };
+    // KVM copies the bitmaps
+    for (range, (base, bitmap)) in filter.ranges.iter_mut().zip(ranges.iter_mut()) {
+        range.flags = KVM_MSR_FILTER_READ | KVM_MSR_FILTER_WRITE;
+        range.nmsrs = MSR_FILTER_RANGE_SIZE;
+        range.base = *base;
+        range.bitmap = bitmap.as_mut_ptr();
+    }
+    unsafe { kvm_x86_set_msr_filter(vm_fd, &filter) }?;
+
+    Ok(())
+}
+
 // The second entry matters for TSS and LDT only
 fn get_x86_64_dtable_64bit_entry(kvm_entry: &kvm_segment) -> u64 {
     if kvm_entry.s == 0 {
@@ -110,12 +334,77 @@ fn get_x86_64_dtable_128bit_entry(kvm_entry: &kvm_segment) -> [u64; 2] {
     ]
 }
 
+const KVM_CPUID_NENT: usize = 256;
+
+/// `struct kvm_cpuid2` with room for the entries
+#[repr(C)]
+struct KvmCpuid2Array {
+    header: kvm_cpuid2,
+    entries: [kvm_cpuid_entry2; KVM_CPUID_NENT],
+}
+
+impl KvmCpuid2Array {
+    fn new() -> Self {
+        Self {
+            header: kvm_cpuid2 {
+                nent: KVM_CPUID_NENT as u32,
+                ..Default::default()
+            },
+            entries: [kvm_cpuid_entry2::default(); KVM_CPUID_NENT],
+        }
+    }
+
+    fn from_entries(entries: &[CpuidEntry]) -> Result<Self, std::io::Error> {
+        if entries.len() > KVM_CPUID_NENT {
+            return Err(std::io::Error::from_raw_os_error(libc::E2BIG));
+        }
+
+        let mut cpuid = Self::new();
+        cpuid.header.nent = entries.len() as u32;
+        for (kvm_entry, entry) in cpuid.entries.iter_mut().zip(entries) {
+            *kvm_entry = kvm_cpuid_entry2 {
+                function: entry.function,
+                index: entry.index,
+                flags: entry.flags,
+                eax: entry.eax,
+                ebx: entry.ebx,
+                ecx: entry.ecx,
+                edx: entry.edx,
+                ..Default::default()
+            };
+        }
+
+        Ok(cpuid)
+    }
+
+    /// KVM has set the count
+    fn entries(&self) -> Vec<CpuidEntry> {
+        self.entries[..self.header.nent as usize]
+            .iter()
+            .map(|entry| CpuidEntry {
+                function: entry.function,
+                index: entry.index,
+                flags: entry.flags,
+                eax: entry.eax,
+                ebx: entry.ebx,
+                ecx: entry.ecx,
+                edx: entry.edx,
+            })
+            .collect()
+    }
+}
+
 pub struct Cpu {
     kvm_fd: RawFd,
     vcpu_fd: RawFd,
     vcpu_run: *mut kvm_run,
     _vcpu_mmap_size: i32,
     memory: Arc<Mutex<Memory>>,
+    /// `None` without the in-kernel irqchip
+    initial_lapic: Option<kvm_lapic_state>,
+    /// The result of the hypercall made through `HYPERCALL_PORT`, goes to
+    /// RAX before the guest runs again
+    port_hypercall_ret: Option<u64>,
 }
 
 impl Cpu {
@@ -144,51 +433,60 @@ impl Cpu {
             ptr as *mut kvm_run
         };
 
+        let mut lapic = kvm_lapic_state::default();
+        let initial_lapic = unsafe { kvm_get_lapic(vcpu_fd, &mut lapic) }
+            .ok()
+            .map(|_| lapic);
+
         Ok(Self {
             kvm_fd,
             vcpu_fd,
             vcpu_run,
             _vcpu_mmap_size: vcpu_mmap_size,
             memory,
+            initial_lapic,
+            port_hypercall_ret: None,
         })
     }
 
-    fn setup_cpuid(&self) -> Result<(), std::io::Error> {
-        let vcpu_fd = self.vcpu_fd;
-
+    /// Applies the policy to what KVM supports. KVM does not let change
+    /// the CPUID once the vCPU has run.
+    fn setup_cpuid(
+        &self,
+        policy: &CpuidPolicy,
+        topology: &CpuTopology,
+    ) -> Result<(), std::io::Error> {
         let host_cpu_id = CpuId::new();
         log::trace!("Host CPU: {:#x?}", host_cpu_id);
 
-        // Inspect the default CPUID data
-        //let guest_cpu_id = kvm_get_cpuid2(kvm_bindings::KVM_MAX_CPUID_ENTRIES)?.as_slice();
-
-        // TODO For MP, need to set APIC ID properly
-
-        // Set all supported CPUID features, no filtering.
-        // Without that, the kernel would fail to set MSRs, etc as support for that
-        // is communicated through CPUID
-        unsafe {
-            const KVM_CPUID_NENT: u32 = 256;
+        // Without the features, the kernel would fail to set MSRs, etc as
+        // support for that is communicated through CPUID
+        let mut supported = KvmCpuid2Array::new();
+        unsafe { kvm_get_supported_cpuid(self.kvm_fd, &mut supported.header) }?;
+
+        let entries = policy
+            .apply(&supported.entries(), topology, 0 /* vCPU */)
+            .map_err(|missing| {
+                log::error!(
+                    "Host lacks {} of the {:?} CPUID template",
+                    missing.join(", "),
+                    policy.template
+                );
+                std::io::Error::from_raw_os_error(libc::EINVAL)
+            })?;
 
-            #[repr(C)]
-            struct KvmCpuid2Array {
-                header: kvm_cpuid2,
-                entries: [kvm_cpuid_entry2; KVM_CPUID_NENT as usize],
-            }
+        let cpuid = KvmCpuid2Array::from_entries(&entries)?;
+        unsafe { kvm_set_cpuid2(self.vcpu_fd, &cpuid.header) }?;
 
-            let mut cpu_id_entries = KvmCpuid2Array {
-                header: kvm_cpuid2 {
-                    nent: KVM_CPUID_NENT,
-                    ..Default::default()
-                },
-                entries: [kvm_cpuid_entry2::default(); KVM_CPUID_NENT as usize],
-            };
+        Ok(())
+    }
 
-            kvm_get_supported_cpuid(self.kvm_fd, &mut cpu_id_entries.header as *mut _)?;
-            kvm_set_cpuid2(vcpu_fd, &cpu_id_entries.header as *const _)?;
-        }
+    /// What the guest sees, including what KVM updates as the guest runs
+    pub fn cpuid(&self) -> Result<Vec<CpuidEntry>, std::io::Error> {
+        let mut cpuid = KvmCpuid2Array::new();
+        unsafe { kvm_get_cpuid2(self.vcpu_fd, &mut cpuid.header) }?;
 
-        Ok(())
+        Ok(cpuid.entries())
     }
 
     fn get_regs(&self) -> Result<kvm_regs, std::io::Error> {
@@ -345,41 +643,45 @@ impl Cpu {
         Ok(())
     }
 
-    fn setup_msrs(&self) -> Result<(), std::io::Error> {
-        const KVM_MSR_NENT: u32 = 2;
-
-        #[repr(C)]
-        struct KvmMsrs {
-            header: kvm_msrs,
-            entries: [kvm_msr_entry; KVM_MSR_NENT as usize],
-        }
+    fn set_msrs(&self, msrs: &[(u32, u64)]) -> Result<(), std::io::Error> {
+        // struct kvm_msrs is followed by the entries, u64 keeps the buffer
+        // aligned for both
+        let header_size = std::mem::size_of::<kvm_msrs>();
+        let entries_size = msrs.len() * std::mem::size_of::<kvm_msr_entry>();
+        let mut buffer = vec![0_u64; (header_size + entries_size).div_ceil(8)];
+
+        let written = unsafe {
+            let header = buffer.as_mut_ptr() as *mut kvm_msrs;
+            (*header).nmsrs = msrs.len() as u32;
+            for (entry, (index, data)) in (*header)
+                .entries
+                .as_mut_slice(msrs.len())
+                .iter_mut()
+                .zip(msrs)
+            {
+                entry.index = *index;
+                entry.data = *data;
+            }
 
-        let msrs = KvmMsrs {
-            header: kvm_msrs {
-                nmsrs: KVM_MSR_NENT,
-                ..Default::default()
-            },
-            entries: [
-                kvm_msr_entry {
-                    index: MSR_IA32_CR_PAT,
-                    data: MSR_IA32_CR_PAT_DEFAULT,
-                    ..Default::default()
-                },
-                kvm_msr_entry {
-                    index: MSR_IA32_MISC_ENABLE,
-                    data: MSR_IA32_MISC_ENABLE_FAST_STR,
-                    ..Default::default()
-                },
-            ],
+            kvm_set_msrs(self.vcpu_fd, header)?
         };
 
-        unsafe {
-            kvm_set_msrs(self.vcpu_fd, &msrs.header as *const _)?;
+        // KVM stops at the first MSR it cannot set
+        if written as usize != msrs.len() {
+            log::error!("Cannot set MSR {:#x}", msrs[written as usize].0);
+            return Err(std::io::Error::from_raw_os_error(libc::EINVAL));
         }
 
         Ok(())
     }
 
+    fn setup_msrs(&self) -> Result<(), std::io::Error> {
+        self.set_msrs(&[
+            (MSR_IA32_CR_PAT, MSR_IA32_CR_PAT_DEFAULT),
+            (MSR_IA32_MISC_ENABLE, MSR_IA32_MISC_ENABLE_FAST_STR),
+        ])
+    }
+
     fn setup_fpu(&self) -> Result<(), std::io::Error> {
         unsafe {
             super::kvm_set_fpu(
@@ -410,8 +712,12 @@ impl Cpu {
         Ok(())
     }
 
-    pub fn init(&mut self) -> Result<(), std::io::Error> {
-        self.setup_cpuid()?;
+    pub fn init(
+        &mut self,
+        cpuid: &CpuidPolicy,
+        topology: &CpuTopology,
+    ) -> Result<(), std::io::Error> {
+        self.setup_cpuid(cpuid, topology)?;
         self.setup_msrs()?;
         self.setup_fpu()?;
         //self._setup_debug()?;
@@ -420,6 +726,33 @@ impl Cpu {
         Ok(())
     }
 
+    /// Brings the vCPU back to the state `init` has left it in. The CPUID
+    /// stays as KVM does not let change it after the vCPU has run.
+    pub fn reset(&mut self) -> Result<(), std::io::Error> {
+        self.port_hypercall_ret = None;
+
+        if let Some(lapic) = &self.initial_lapic {
+            unsafe { kvm_set_lapic(self.vcpu_fd, lapic) }?;
+        }
+
+        // Otherwise KVM keeps writing to the memory of the previous kernel
+        self.set_msrs(&[
+            (MSR_KVM_WALL_CLOCK, 0),
+            (MSR_KVM_SYSTEM_TIME, 0),
+            (MSR_KVM_WALL_CLOCK_NEW, 0),
+            (MSR_KVM_SYSTEM_TIME_NEW, 0),
+            (MSR_KVM_ASYNC_PF_EN, 0),
+            (MSR_KVM_STEAL_TIME, 0),
+            (MSR_KVM_PV_EOI_EN, 0),
+        ])?;
+
+        self.setup_msrs()?;
+        self.setup_fpu()?;
+        self.setup_long_mode()?;
+
+        Ok(())
+    }
+
     pub fn set_gp_register(&mut self, gpr: CpuRegister, v: u64) -> Result<(), std::io::Error> {
         let mut regs = self.get_regs()?;
         match gpr {
@@ -445,12 +778,38 @@ impl Cpu {
         Ok(())
     }
 
+    /// Lets the other threads interrupt `run` on the calling thread
+    pub fn kick(&self) -> VcpuKick {
+        super::vcpu_kick(self.vcpu_run)
+    }
+
     pub fn run(&mut self) -> Result<CpuExitReason, std::io::Error> {
         let run = &mut unsafe { std::slice::from_raw_parts_mut(self.vcpu_run, 1) }[0];
 
-        unsafe { super::kvm_run(self.vcpu_fd, 0)? };
+        if let Some(ret) = self.port_hypercall_ret.take() {
+            self.set_gp_register(CpuRegister::Rax, ret)?;
+        }
+
+        if !unsafe { super::enter_guest(self.vcpu_fd, self.vcpu_run)? } {
+            return Ok(CpuExitReason::Continue);
+        }
 
         let exit_reason = match run.exit_reason {
+            KVM_EXIT_IO
+                if unsafe { run.__bindgen_anon_1.io.port } == HYPERCALL_PORT
+                    && u32::from(unsafe { run.__bindgen_anon_1.io.direction })
+                        == KVM_EXIT_IO_OUT =>
+            {
+                let regs = self.get_regs()?;
+                let ret = self.port_hypercall_ret.insert(KVM_ENOSYS);
+
+                CpuExitReason::Hypercall(Hypercall {
+                    nr: regs.rax,
+                    args: [regs.rbx, regs.rcx, regs.rsi, regs.rdi, 0, 0],
+                    // Read back when the guest runs again
+                    ret: unsafe { std::slice::from_raw_parts_mut(ret as *mut u64, 1) },
+                })
+            }
             KVM_EXIT_IO => unsafe {
                 // Emulation through setting the ax register makes this code
                 // being VERY slow. Fortunately, the kernel handles that
@@ -458,29 +817,49 @@ impl Cpu {
                 let run_start = run as *mut kvm_run as *mut u8;
                 let io = &run.__bindgen_anon_1.io;
                 let port = io.port;
-                let data_size = io.count as usize * io.size as usize;
+                let count = io.count as usize;
                 let data_ptr = run_start.offset(io.data_offset as isize);
+                let data_size = count * io.size as usize;
 
                 match u32::from(io.direction) {
-                    KVM_EXIT_IO_IN => match data_size {
+                    // `rep ins`, the elements one after another
+                    KVM_EXIT_IO_IN if count > 1 => CpuExitReason::Io(IoType::StringIn(
+                        port,
+                        io.size,
+                        std::slice::from_raw_parts_mut(data_ptr, data_size),
+                    )),
+                    KVM_EXIT_IO_IN => match io.size {
                         1 => CpuExitReason::Io(IoType::ByteIn(
                             port,
-                            &mut std::slice::from_raw_parts_mut(data_ptr as *mut _, data_size)[0],
+                            &mut std::slice::from_raw_parts_mut(data_ptr as *mut _, 1)[0],
                         )),
                         2 => CpuExitReason::Io(IoType::WordIn(
                             port,
-                            &mut std::slice::from_raw_parts_mut(data_ptr as *mut _, data_size)[0],
+                            &mut std::slice::from_raw_parts_mut(data_ptr as *mut _, 1)[0],
+                        )),
+                        4 => CpuExitReason::Io(IoType::DoubleWordIn(
+                            port,
+                            &mut std::slice::from_raw_parts_mut(data_ptr as *mut _, 1)[0],
                         )),
                         _ => CpuExitReason::NotSupported,
                     },
-                    KVM_EXIT_IO_OUT => match data_size {
+                    KVM_EXIT_IO_OUT if count > 1 => CpuExitReason::Io(IoType::StringOut(
+                        port,
+                        io.size,
+                        std::slice::from_raw_parts(data_ptr, data_size),
+                    )),
+                    KVM_EXIT_IO_OUT => match io.size {
                         1 => CpuExitReason::Io(IoType::ByteOut(
                             port,
-                            std::slice::from_raw_parts(data_ptr as *const _, data_size)[0],
+                            std::slice::from_raw_parts(data_ptr as *const _, 1)[0],
                         )),
                         2 => CpuExitReason::Io(IoType::WordOut(
                             port,
-                            std::slice::from_raw_parts(data_ptr as *const _, data_size)[0],
+                            std::slice::from_raw_parts(data_ptr as *const _, 1)[0],
+                        )),
+                        4 => CpuExitReason::Io(IoType::DoubleWordOut(
+                            port,
+                            std::slice::from_raw_parts(data_ptr as *const _, 1)[0],
                         )),
                         _ => CpuExitReason::NotSupported,
                     },
@@ -488,14 +867,90 @@ impl Cpu {
                 }
             },
             KVM_EXIT_HLT => CpuExitReason::Halt,
+            // The numbers `enable_hypercall_exits` has enabled, KVM puts the
+            // value returned into RAX
+            KVM_EXIT_HYPERCALL => unsafe {
+                let hypercall = &mut run.__bindgen_anon_1.hypercall;
+                hypercall.ret = KVM_ENOSYS;
+
+                CpuExitReason::Hypercall(Hypercall {
+                    nr: hypercall.nr,
+                    args: hypercall.args,
+                    ret: std::slice::from_mut(&mut hypercall.ret),
+                })
+            },
+            // The MSRs `set_msr_filter` has denied and the ones KVM does not
+            // know, the guest takes a #GP while the error is set
+            KVM_EXIT_X86_RDMSR => unsafe {
+                let msr = &mut run.__bindgen_anon_1.msr;
+                msr.error = 1;
+                msr.data = 0;
+
+                CpuExitReason::Msr(MsrType::Read(
+                    msr.index,
+                    &mut msr.data,
+                    // Holds 0 or 1, as a bool does
+                    &mut *(&mut msr.error as *mut u8 as *mut bool),
+                ))
+            },
+            KVM_EXIT_X86_WRMSR => unsafe {
+                let msr = &mut run.__bindgen_anon_1.msr;
+                msr.error = 1;
+
+                CpuExitReason::Msr(MsrType::Write(
+                    msr.index,
+                    msr.data,
+                    &mut *(&mut msr.error as *mut u8 as *mut bool),
+                ))
+            },
+            KVM_EXIT_SHUTDOWN => {
+                log::info!("Triple fault");
+                CpuExitReason::Reset
+            }
+            // The instruction has been decoded by KVM, it also extends the
+            // value read to the size of the register
             KVM_EXIT_MMIO => unsafe {
                 let mmio = &mut run.__bindgen_anon_1.mmio;
-                let _addr = mmio.phys_addr;
+                let pa = mmio.phys_addr;
                 let len = mmio.len as usize;
-                let _data_slice = &mut mmio.data[..len];
-                let _is_write = mmio.is_write != 0;
-
-                CpuExitReason::NotSupported
+                let data = &mut mmio.data[..len];
+                let writing_to_memory = mmio.is_write != 0;
+
+                if !writing_to_memory {
+                    match len {
+                        1 => CpuExitReason::MmIo(MmIoType::ByteIn(pa, &mut data[0])),
+                        2 => CpuExitReason::MmIo(MmIoType::WordIn(
+                            pa,
+                            &mut *(&mut data[0] as *mut _ as *mut u16),
+                        )),
+                        4 => CpuExitReason::MmIo(MmIoType::DoubleWordIn(
+                            pa,
+                            &mut *(&mut data[0] as *mut _ as *mut u32),
+                        )),
+                        8 => CpuExitReason::MmIo(MmIoType::QuadWordIn(
+                            pa,
+                            &mut *(&mut data[0] as *mut _ as *mut u64),
+                        )),
+                        _ => CpuExitReason::NotSupported,
+                    }
+                } else {
+                    match len {
+                        1 => CpuExitReason::MmIo(MmIoType::ByteOut(pa, data[0])),
+                        2 => CpuExitReason::MmIo(MmIoType::WordOut(
+                            pa,
+                            *(&data[0] as *const _ as *const u16),
+                        )),
+                        4 => CpuExitReason::MmIo(MmIoType::DoubleWordOut(
+                            pa,
+                            *(&data[0] as *const _ as *const u32),
+                        )),
+                        8 => CpuExitReason::MmIo(MmIoType::QuadWordOut(
+                            pa,
+                            *(&data[0] as *const _ as *const u64),
+                        )),
+                        _ => CpuExitReason::NotSupported,
+                    }
+                }
             },
             _ => CpuExitReason::NotSupported,
         };
@@ -514,6 +969,68 @@ impl Cpu {
         Ok(exit_reason)
     }
 
+    /// Logs the registers, e.g. when the guest has crashed
+    pub fn dump_state(&self) {
+        let (regs, sregs) = match (self.get_regs(), self.get_sregs()) {
+            (Ok(regs), Ok(sregs)) => (regs, sregs),
+            _ => {
+                log::error!("Cannot read the vCPU registers");
+                return;
+            }
+        };
+
+        log::error!(
+            "RIP {:#018x} RSP {:#018x} RFLAGS {:#010x}",
+            regs.rip,
+            regs.rsp,
+            regs.rflags
+        );
+        log::error!(
+            "RAX {:#018x} RBX {:#018x} RCX {:#018x} RDX {:#018x}",
+            regs.rax,
+            regs.rbx,
+            regs.rcx,
+            regs.rdx
+        );
+        log::error!(
+            "RSI {:#018x} RDI {:#018x} RBP {:#018x} R8  {:#018x}",
+            regs.rsi,
+            regs.rdi,
+            regs.rbp,
+            regs.r8
+        );
+        log::error!(
+            "R9  {:#018x} R10 {:#018x} R11 {:#018x} R12 {:#018x}",
+            regs.r9,
+            regs.r10,
+            regs.r11,
+            regs.r12
+        );
+        log::error!(
+            "R13 {:#018x} R14 {:#018x} R15 {:#018x}",
+            regs.r13,
+            regs.r14,
+            regs.r15
+        );
+        log::error!(
+            "CR0 {:#018x} CR2 {:#018x} CR3 {:#018x} CR4 {:#018x} EFER {:#x}",
+            sregs.cr0,
+            sregs.cr2,
+            sregs.cr3,
+            sregs.cr4,
+            sregs.efer
+        );
+        log::error!(
+            "CS {:#06x} SS {:#06x} DS {:#06x} ES {:#06x} FS {:#06x} GS {:#06x}",
+            sregs.cs.selector,
+            sregs.ss.selector,
+            sregs.ds.selector,
+            sregs.es.selector,
+            sregs.fs.selector,
+            sregs.gs.selector
+        );
+    }
+
     pub fn set_instruction_pointer(&mut self, ip: u64) -> Result<(), std::io::Error> {
         let mut regs = self.get_regs()?;
         regs.rip = ip;
@@ -9,7 +9,9 @@ pub use darwin::{Cpu, CpuRegister, HvError, SmolVm};
 #[cfg(target_os = "linux")]
 mod linux;
 use std::{
+    fs::File,
     io::Read,
+    path::PathBuf,
     sync::{Arc, Mutex},
 };
 
@@ -21,19 +23,264 @@ use object::{
     Architecture, Endianness, FileKind, Object, ObjectSection, SectionKind,
 };
 
-use self::{pl011::UartPl011, uart8250::Uart8250};
-use zerocopy::AsBytes;
-
+use self::{
+    bus::{Bus, ExitRequest, ExitRequester, HypercallHandler, MsrHandler, VcpuKick},
+    debug_port::PostCodes,
+    pci::PciRoot,
+    virtio::{SharedVirtioTransport, VirtioMmio, VirtioPci, VIRTIO_MMIO_SIZE},
+};
+use zerocopy::{AsBytes, FromBytes};
+
+#[cfg(target_arch = "x86_64")]
+mod acpi_pm;
+pub mod bus;
+#[cfg(target_arch = "x86_64")]
+mod cmos;
+pub mod cpuid;
+mod debug_exit;
+pub mod debug_port;
+mod fdt;
+#[cfg(target_arch = "x86_64")]
+mod i8042;
+pub mod numa;
+pub mod pci;
 mod pl011;
+#[cfg(target_arch = "aarch64")]
+mod pl031;
+#[cfg(target_arch = "aarch64")]
+mod pl061;
+mod pvpanic;
+#[cfg(target_arch = "aarch64")]
+mod sp805;
+pub mod topology;
 mod uart8250;
+pub mod virtio;
+
+const PL011_BASE: u64 = 0x900_0000;
 
+#[derive(Clone, Copy)]
 pub struct GpaSpan {
     pub start: u64,
     pub size: usize,
 }
 
+/// A host file mapped into the guest as a virtio-pmem device
+pub struct PmemOptions {
+    pub path: PathBuf,
+    pub read_only: bool,
+}
+
+/// Where the pages of a RAM region come from
+#[derive(Clone, Debug, Default)]
+pub enum RamBacking {
+    /// A memfd the vhost-user backends can map
+    #[default]
+    Memfd,
+    /// A memfd sealed against resizing, the processes it is shared with
+    /// cannot be made to fault by truncating it
+    SealedMemfd,
+    /// Private anonymous memory, not shared with the vhost-user backends
+    Anonymous,
+    /// Private anonymous huge pages from the host pool of that size
+    HugePages(HugePageSize),
+    /// A file, on hugetlbfs for huge pages, extended to the size of the
+    /// region if shorter. Resetting the VM punches the RAM out of it.
+    File(PathBuf),
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum HugePageSize {
+    Size2M,
+    Size1G,
+}
+
+impl HugePageSize {
+    pub fn bytes(self) -> u64 {
+        match self {
+            HugePageSize::Size2M => 2 << 20,
+            HugePageSize::Size1G => 1 << 30,
+        }
+    }
+}
+
+/// How a RAM region is backed on the host
+#[derive(Clone, Debug, Default)]
+pub struct RamOptions {
+    pub backing: RamBacking,
+    /// Allocate the pages up front rather than when the guest first touches
+    /// them
+    pub prefault: bool,
+    /// Keep the pages in RAM, allocating them up front
+    pub mlock: bool,
+    /// Let KSM merge the identical pages, of the anonymous backing only
+    pub mergeable: bool,
+}
+
+/// Where the debug console output of the guest goes
+#[derive(Clone, Default)]
+pub enum DebugConOutput {
+    /// Logged line by line
+    #[default]
+    Log,
+    Stdout,
+    Stderr,
+    File(PathBuf),
+}
+
+#[derive(Default)]
+pub struct VmOptions {
+    /// Create the in-kernel interrupt controller. Required by the devices
+    /// that raise interrupts, on x86_64 also makes HLT handled by the kernel
+    /// rather than exiting.
+    pub irqchip: bool,
+    /// Placed above the RAM, require the irqchip
+    pub pmem: Vec<PmemOptions>,
+    /// Put the virtio devices on the PCI bus rather than at the virtio-mmio
+    /// slots, requires the irqchip with MSI support
+    pub pci: bool,
+    /// Let the guest end the run with an exit status, see `debug_exit`
+    pub debug_exit: bool,
+    /// Seconds past 1970-01-01 the real-time clock starts at rather than
+    /// the host time, for reproducible runs
+    pub rtc_epoch: Option<u64>,
+    /// Add the SP805 watchdog on aarch64, its expiry stops the vCPU loop
+    /// with `CpuExitReason::Watchdog`
+    pub watchdog: bool,
+    /// The output of the debug console ports on x86_64, see `debug_port`
+    pub debugcon: DebugConOutput,
+    /// What the CPUID tells the guest on x86_64, see `cpuid`
+    pub cpuid: cpuid::CpuidPolicy,
+    /// The sockets, dies, cores and threads the CPUs are grouped in
+    pub topology: topology::CpuTopology,
+    /// The NUMA nodes the RAM regions and the CPUs are in, none when empty
+    pub numa: Vec<numa::NumaNode>,
+    /// How each RAM region is backed, the ones past the list have the
+    /// default memfd. No more than the regions.
+    pub ram: Vec<RamOptions>,
+}
+
+/// The vCPUs the VM runs, the first ones of the topology
+pub const VCPU_COUNT: u32 = 1;
+
 pub fn create_vm(gpa_map: &[GpaSpan]) -> Result<SmolVm, HvError> {
-    SmolVm::new(gpa_map)
+    create_vm_with_options(gpa_map, &VmOptions::default())
+}
+
+pub fn create_vm_with_options(gpa_map: &[GpaSpan], options: &VmOptions) -> Result<SmolVm, HvError> {
+    SmolVm::new(gpa_map, options)
+}
+
+/// The bus with the console UART of the platform
+fn create_bus(ram: &[GpaSpan], options: &VmOptions) -> Bus {
+    let mut bus = Bus::new();
+
+    if options.debug_exit {
+        use self::debug_exit::DebugExit;
+
+        let debug_exit = Arc::new(Mutex::new(DebugExit::new(bus.exit_requester())));
+
+        #[cfg(target_arch = "x86_64")]
+        bus.add_io_device(
+            debug_exit::DEBUG_EXIT_PORT,
+            debug_exit::DEBUG_EXIT_PORT_COUNT,
+            debug_exit,
+        );
+        #[cfg(target_arch = "aarch64")]
+        bus.add_mmio_device(
+            debug_exit::DEBUG_EXIT_BASE,
+            debug_exit::DEBUG_EXIT_MMIO_SIZE,
+            debug_exit,
+        );
+    }
+
+    {
+        use self::pvpanic::PvPanic;
+
+        let pvpanic = Arc::new(Mutex::new(PvPanic::new(bus.exit_requester())));
+
+        #[cfg(target_arch = "x86_64")]
+        bus.add_io_device(pvpanic::PVPANIC_PORT, pvpanic::PVPANIC_PORT_COUNT, pvpanic);
+        #[cfg(target_arch = "aarch64")]
+        {
+            bus.add_mmio_device(pvpanic::PVPANIC_BASE, pvpanic::PVPANIC_MMIO_SIZE, pvpanic);
+            bus.add_fdt_node(
+                fdt::FdtNode::new(&format!("pvpanic@{:x}", pvpanic::PVPANIC_BASE))
+                    .property_string("compatible", "qemu,pvpanic-mmio")
+                    .reg(pvpanic::PVPANIC_BASE, 2),
+            );
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    {
+        use self::uart8250::{Uart8250, UartBase};
+
+        let uart8250 = Uart8250::new(UartBase::Com1);
+        bus.add_io_device(
+            uart8250.base_addr(),
+            Uart8250::PORT_COUNT,
+            Arc::new(Mutex::new(uart8250)),
+        );
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    {
+        use self::pl011::{UartPl011, PL011_MMIO_SIZE};
+
+        bus.add_fdt_node(options.topology.fdt_cpus(VCPU_COUNT, &options.numa));
+        if !options.numa.is_empty() {
+            for node in numa::fdt_nodes(&options.numa, ram) {
+                bus.add_fdt_node(node);
+            }
+        }
+
+        // For the AMBA devices the `virt` machine does not have
+        bus.add_fdt_node(
+            fdt::FdtNode::new("smolvm-apb-pclk")
+                .property_string("compatible", "fixed-clock")
+                .property_u32("#clock-cells", 0)
+                .property_u32("clock-frequency", fdt::APB_PCLK_FREQUENCY)
+                .property_string("clock-output-names", "clk24mhz")
+                .property_u32("phandle", fdt::APB_PCLK_PHANDLE),
+        );
+
+        bus.add_mmio_device(
+            PL011_BASE,
+            PL011_MMIO_SIZE,
+            Arc::new(Mutex::new(UartPl011::new(PL011_BASE))),
+        );
+    }
+
+    bus
+}
+
+pub trait IrqChip: Send + Sync {
+    /// `irq` is the GSI on x86_64 and the SPI number on aarch64
+    fn set_irq_line(&self, irq: u32, level: bool);
+
+    /// Raises an edge-triggered interrupt
+    fn pulse_irq(&self, irq: u32) {
+        self.set_irq_line(irq, true);
+        self.set_irq_line(irq, false);
+    }
+
+    /// Allocates an interrupt delivered as a message (MSI), `None` if the
+    /// irqchip cannot do that. `requester_id` identifies the device, e.g.
+    /// by its PCI bus/device/function, for the interrupt translation.
+    fn allocate_msi(&self, requester_id: u32) -> Option<u32> {
+        let _ = requester_id;
+        None
+    }
+
+    /// Sets the message the driver has programmed for the interrupt
+    fn set_msi_route(&self, gsi: u32, address: u64, data: u32) {
+        let _ = (gsi, address, data);
+    }
+
+    /// Delivers the message of the allocated interrupt
+    fn signal_msi(&self, gsi: u32) {
+        let _ = gsi;
+    }
 }
 
 fn disassemble_x86_64(bytes: &[u8], ip: u64) {
@@ -99,8 +346,44 @@ pub struct MappedGpa {
     memory: *mut u8,
     gpa: u64,
     size: usize,
+    /// The file mapped at offset 0 if the span is file-backed, lets other
+    /// processes map the same memory
+    file: Option<Arc<File>>,
+    /// The size of the host pages, what is discarded is aligned to it
+    #[cfg(target_os = "linux")]
+    page_size: u64,
+    /// The pages cannot be dropped while locked, discarding zeroes them
+    #[cfg(target_os = "linux")]
+    locked: bool,
+}
+
+impl MappedGpa {
+    pub fn gpa(&self) -> u64 {
+        self.gpa
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn host_address(&self) -> u64 {
+        self.memory as u64
+    }
+
+    pub fn file(&self) -> Option<&File> {
+        self.file.as_deref()
+    }
 }
 
+#[cfg(target_os = "linux")]
+fn host_page_size() -> u64 {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as u64 }
+}
+
+// The mapping lives as long as the VM and is accessed through `Memory`
+// behind a mutex, so it can be handed over to the device threads.
+unsafe impl Send for MappedGpa {}
+
 pub struct Memory {
     spans: Vec<MappedGpa>,
 }
@@ -161,7 +444,7 @@ impl Memory {
         }
     }
 
-    pub fn _read(&self, gpa: u64, size: usize) -> &[u8] {
+    pub fn read(&self, gpa: u64, size: usize) -> &[u8] {
         if let Some(span) = self.find_span(gpa) {
             let span = unsafe {
                 std::slice::from_raw_parts(
@@ -179,16 +462,87 @@ impl Memory {
                 );
             }
 
-            span
+            &span[..size]
         } else {
             panic!("Cannot read as GPA is invalid {:#x}", gpa);
         }
     }
 
+    pub fn read_obj<T: FromBytes + AsBytes + Default>(&self, gpa: u64) -> T {
+        let mut obj = T::default();
+        let size = std::mem::size_of::<T>();
+
+        obj.as_bytes_mut().copy_from_slice(self.read(gpa, size));
+        obj
+    }
+
+    pub fn write_obj<T: AsBytes>(&mut self, gpa: u64, obj: &T) {
+        self.write(gpa, obj.as_bytes());
+    }
+
+    /// Gives the host pages entirely within the range back to the host,
+    /// the guest sees zeroes when it touches them again.
+    #[cfg(target_os = "linux")]
+    pub fn discard(&mut self, gpa: u64, size: usize) -> Result<(), std::io::Error> {
+        let span = match self.find_span(gpa) {
+            Some(span) if gpa + size as u64 <= span.gpa + span.size as u64 => span,
+            _ => return Err(std::io::Error::from_raw_os_error(libc::EFAULT)),
+        };
+
+        let page_size = span.page_size;
+        let start = span.memory as u64 + (gpa - span.gpa);
+        let end = start + size as u64;
+        let start = (start + page_size - 1) & !(page_size - 1);
+        let end = end & !(page_size - 1);
+
+        if start >= end {
+            return Ok(());
+        }
+
+        if span.locked {
+            unsafe { std::ptr::write_bytes(start as *mut u8, 0, (end - start) as usize) };
+            return Ok(());
+        }
+
+        // Dropping the pages of a shared mapping leaves them in the file,
+        // these have to be punched out
+        let advice = if span.file.is_some() {
+            libc::MADV_REMOVE
+        } else {
+            libc::MADV_DONTNEED
+        };
+        let result =
+            unsafe { libc::madvise(start as *mut libc::c_void, (end - start) as usize, advice) };
+        if result != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    /// The host virtual address the guest physical address is mapped at
+    pub fn host_address(&self, gpa: u64) -> Option<u64> {
+        self.find_span(gpa)
+            .map(|span| span.memory as u64 + (gpa - span.gpa))
+    }
+
+    pub fn spans(&self) -> &[MappedGpa] {
+        &self.spans
+    }
+
     pub fn _is_gpa_valid(&self, gpa: u64) -> bool {
         self.find_span(gpa).is_some()
     }
 
+    /// The whole range is within one span, the guest-given ranges have to
+    /// be checked before reading or writing them
+    pub fn contains(&self, gpa: u64, size: usize) -> bool {
+        match self.find_span(gpa) {
+            Some(span) => gpa - span.gpa + size as u64 <= span.size as u64,
+            None => false,
+        }
+    }
+
     pub fn find_span(&self, gpa: u64) -> Option<&MappedGpa> {
         for span in &self.spans {
             if span.gpa <= gpa && gpa < span.gpa + span.size as u64 {
@@ -206,6 +560,20 @@ pub enum IoType<'a> {
     ByteOut(u16 /* port */, u8 /* data */),
     WordIn(u16 /* port */, &'a mut u16 /* data */),
     WordOut(u16 /* port */, u16 /* data */),
+    DoubleWordIn(u16 /* port */, &'a mut u32 /* data */),
+    DoubleWordOut(u16 /* port */, u32 /* data */),
+    /// `rep ins`, the elements of the size are read one by one
+    StringIn(
+        u16,          /* port */
+        u8,           /* size */
+        &'a mut [u8], /* data */
+    ),
+    /// `rep outs`, the elements of the size are written one by one
+    StringOut(
+        u16,      /* port */
+        u8,       /* size */
+        &'a [u8], /* data */
+    ),
 }
 
 #[derive(PartialEq)]
@@ -216,6 +584,39 @@ pub enum MmIoType<'a> {
     WordOut(u64 /* address */, u16 /* data */),
     DoubleWordIn(u64 /* address */, &'a mut u32 /* data */),
     DoubleWordOut(u64 /* address */, u32 /* data */),
+    QuadWordIn(u64 /* address */, &'a mut u64 /* data */),
+    QuadWordOut(u64 /* address */, u64 /* data */),
+}
+
+/// A call the guest makes to the host: `vmcall`/`vmmcall` on x86_64, the
+/// SMCCC `hvc`/`smc` on aarch64
+#[derive(PartialEq)]
+pub struct Hypercall<'a> {
+    /// RAX on x86_64, the SMCCC function ID on aarch64
+    pub nr: u64,
+    /// As KVM has passed them on x86_64, RBX, RCX, RSI and RDI through
+    /// `HYPERCALL_PORT`, X1..X6 on aarch64
+    pub args: [u64; 6],
+    /// What the guest gets back: RAX on x86_64, X0..X3 on aarch64. Holds
+    /// the "not supported" error until a handler answers.
+    pub ret: &'a mut [u64],
+}
+
+/// An `rdmsr`/`wrmsr` KVM leaves to the VMM on x86_64: of an MSR with a
+/// handler or of one KVM does not know. The guest takes a #GP if the error
+/// is left set.
+#[derive(PartialEq)]
+pub enum MsrType<'a> {
+    Read(
+        u32,          /* index */
+        &'a mut u64,  /* data */
+        &'a mut bool, /* error */
+    ),
+    Write(
+        u32,          /* index */
+        u64,          /* data */
+        &'a mut bool, /* error */
+    ),
 }
 
 #[derive(PartialEq)]
@@ -225,11 +626,70 @@ pub enum CpuExitReason<'a> {
     Halt,
     Io(IoType<'a>),
     MmIo(MmIoType<'a>),
+    Hypercall(Hypercall<'a>),
+    Msr(MsrType<'a>),
+    /// The guest has powered the machine off
+    Shutdown,
+    /// The guest has asked for a reboot
+    Reset,
+    /// The guest has asked to end the run with the exit status
+    Exit(i32),
+    /// The guest kernel has reported the pvpanic events
+    Panic(u8),
+    /// The guest has stopped petting the watchdog
+    Watchdog,
+}
+
+impl From<ExitRequest> for CpuExitReason<'_> {
+    fn from(request: ExitRequest) -> Self {
+        match request {
+            ExitRequest::Shutdown => CpuExitReason::Shutdown,
+            ExitRequest::Reset => CpuExitReason::Reset,
+            ExitRequest::Exit(status) => CpuExitReason::Exit(status),
+            ExitRequest::Panic(events) => CpuExitReason::Panic(events),
+            ExitRequest::Watchdog => CpuExitReason::Watchdog,
+        }
+    }
 }
 
+/// Presses the power button of the platform, the guest is expected to shut
+/// itself down
+pub type PowerButton = Arc<dyn Fn() + Send + Sync>;
+
 pub trait SmolVmT {
     fn get_memory(&self) -> Arc<Mutex<Memory>>;
     fn get_cpu(&self) -> Arc<Mutex<Cpu>>;
+    fn get_bus(&self) -> Arc<Mutex<Bus>>;
+    fn get_irq_chip(&self) -> Option<Arc<dyn IrqChip>>;
+    fn get_pci(&self) -> Option<Arc<Mutex<PciRoot>>>;
+    /// `None` if the platform has no power button the guest listens to
+    fn get_power_button(&self) -> Option<PowerButton>;
+    /// `None` if the platform has no POST code port
+    fn get_post_codes(&self) -> Option<PostCodes>;
+
+    /// Has the hypercalls of the number answered by the handler. On x86_64
+    /// KVM exits on `vmcall` only for the numbers `KVM_CAP_EXIT_HYPERCALL`
+    /// allows, i.e. `KVM_HC_MAP_GPA_RANGE`, the guest makes the others by
+    /// writing to `HYPERCALL_PORT`. On aarch64 the number is the SMCCC
+    /// function ID, KVM forwards it only if the handler is added before the
+    /// vCPU first runs.
+    fn add_hypercall_handler(
+        &mut self,
+        nr: u64,
+        handler: Arc<Mutex<dyn HypercallHandler>>,
+    ) -> Result<(), HvError>;
+
+    /// Has the accesses to the MSR emulated by the handler rather than by
+    /// KVM, x86_64 only
+    fn add_msr_handler(
+        &mut self,
+        index: u32,
+        handler: Arc<Mutex<dyn MsrHandler>>,
+    ) -> Result<(), HvError>;
+
+    /// Brings the vCPU, the RAM and the devices back to the power-on state,
+    /// the kernel is to be loaded again
+    fn reset(&mut self) -> Result<(), HvError>;
 
     fn get_native_arch(&self) -> Architecture {
         #[cfg(target_arch = "x86_64")]
@@ -410,6 +870,22 @@ pub trait SmolVmT {
 
         log::info!("Last GPA used: {:#x}", last_gpa_used);
 
+        // On aarch64, the command line comes with the device tree
+        #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+        {
+            let bus = self.get_bus();
+            let bus = bus.lock().unwrap();
+            let command_line = command_line
+                .iter()
+                .map(|s| s.to_string())
+                .chain(bus.cmd_line_extras().iter().cloned())
+                .collect::<Vec<_>>()
+                .join(" ");
+
+            log::info!("Kernel command line: '{}'", command_line);
+            linux::set_command_line(&mut memory, &command_line);
+        }
+
         let cpu = self.get_cpu();
         let mut cpu = cpu.lock().unwrap();
 
@@ -418,6 +894,16 @@ pub trait SmolVmT {
             let mut dtb_data = Vec::new();
             dtb_file.read_to_end(&mut dtb_data).unwrap();
 
+            #[cfg(target_arch = "aarch64")]
+            {
+                let bus = self.get_bus();
+                let bus = bus.lock().unwrap();
+                match fdt::add_nodes(&dtb_data, bus.fdt_nodes()) {
+                    Ok(patched) => dtb_data = patched,
+                    Err(error) => log::warn!("Cannot add the device nodes to the DTB: {}", error),
+                }
+            }
+
             memory.write(last_gpa_used, dtb_data.as_bytes());
             log::info!("Loaded DTB at GPA: {:#x}", last_gpa_used);
 
@@ -458,68 +944,168 @@ pub trait SmolVmT {
         Ok(())
     }
 
+    /// Places the virtio device on the PCI bus if the VM has one, otherwise
+    /// at the next free virtio-mmio slot. Returns the transport to let the
+    /// host poke the device.
+    fn add_virtio_device(&mut self, device: virtio::SharedVirtioDevice) -> SharedVirtioTransport {
+        let irq_chip = self
+            .get_irq_chip()
+            .expect("Virtio devices require the VM to be created with the irqchip");
+
+        if let Some(pci) = self.get_pci() {
+            let mut pci = pci.lock().unwrap();
+            let requester_id = pci.next_requester_id().expect("No free PCI slots");
+
+            log::info!(
+                "Virtio device type {} at PCI {:02x}.{}",
+                device.lock().unwrap().device_type(),
+                requester_id >> 3,
+                requester_id & 7
+            );
+
+            let transport = Arc::new(Mutex::new(VirtioPci::new(
+                device,
+                self.get_memory(),
+                irq_chip,
+                requester_id,
+            )));
+            pci.add_device(transport.clone());
+
+            return transport;
+        }
+
+        let bus = self.get_bus();
+        let mut bus = bus.lock().unwrap();
+
+        let slot = (0..virtio::VIRTIO_MMIO_SLOTS)
+            .find(|slot| {
+                !bus.has_mmio_device(virtio::VIRTIO_MMIO_BASE + *slot as u64 * VIRTIO_MMIO_SIZE)
+            })
+            .expect("No free virtio-mmio slots");
+        let base = virtio::VIRTIO_MMIO_BASE + slot as u64 * VIRTIO_MMIO_SIZE;
+        let irq = virtio::virtio_mmio_irq(slot);
+
+        log::info!(
+            "Virtio device type {} at {:#x}, irq {}",
+            device.lock().unwrap().device_type(),
+            base,
+            irq
+        );
+
+        let transport = Arc::new(Mutex::new(VirtioMmio::new(
+            base,
+            irq,
+            device,
+            self.get_memory(),
+            irq_chip,
+        )));
+        bus.add_mmio_device(base, VIRTIO_MMIO_SIZE, transport.clone());
+
+        // No device tree on x86_64, the guest learns about the device
+        // from the command line (CONFIG_VIRTIO_MMIO_CMDLINE_DEVICES)
+        #[cfg(target_arch = "x86_64")]
+        bus.add_cmd_line_extra(format!(
+            "virtio_mmio.device={:#x}@{:#x}:{}",
+            VIRTIO_MMIO_SIZE, base, irq
+        ));
+
+        transport
+    }
+
     fn run(&mut self) -> Result<CpuExitReason, HvError> {
         let cpu = self.get_cpu();
-        let mut cpu = cpu.lock().unwrap();
-        let mut uart8250 = Uart8250::new(uart8250::UartBase::Com1);
-        let mut pl011 = UartPl011::new(0x9000000);
-
-        loop {
-            let exit_reason = cpu.run()?;
-
-            match exit_reason {
-                CpuExitReason::NotSupported => panic!("Not supported"),
-                CpuExitReason::Continue => continue,
-                CpuExitReason::Halt => {}
-                CpuExitReason::Io(io_type) => match io_type {
-                    IoType::ByteOut(port, byte_out) => {
-                        uart8250.write_byte(port, byte_out);
-                    }
-                    IoType::WordOut(port, word_out) => {
-                        uart8250.write_word(port, word_out);
-                    }
-                    IoType::ByteIn(port, io_byte) => {
-                        if let Some(byte) = uart8250.read_byte(port) {
-                            *io_byte = byte;
-                        }
-                    }
-                    IoType::WordIn(port, io_word) => {
-                        if let Some(word) = uart8250.read_word(port) {
-                            *io_word = word;
-                        }
-                    }
-                },
-                CpuExitReason::MmIo(mmio_type) => match mmio_type {
-                    MmIoType::ByteIn(addr, data) => {
-                        if let Some(value) = pl011.read(addr) {
-                            *data = value as u8;
-                        }
-                    }
-                    MmIoType::ByteOut(addr, data) => {
-                        pl011.write(addr, data as u32);
-                    }
-                    MmIoType::WordIn(addr, data) => {
-                        if let Some(value) = pl011.read(addr) {
-                            *data = value as u16;
-                        }
-                    }
-                    MmIoType::WordOut(addr, data) => {
-                        pl011.write(addr, data as u32);
-                    }
-                    MmIoType::DoubleWordIn(addr, data) => {
-                        if let Some(value) = pl011.read(addr) {
-                            *data = value as u32;
-                        }
-                    }
-                    MmIoType::DoubleWordOut(addr, data) => {
-                        pl011.write(addr, data);
-                    }
-                },
+        let bus = self.get_bus();
+        let exit_requester = bus.lock().unwrap().exit_requester();
+
+        // Only while the vCPU runs on this thread
+        exit_requester.set_vcpu_kick(self.vcpu_kick());
+        let exit_reason = run_vcpu(&cpu, &bus, &exit_requester);
+        exit_requester.set_vcpu_kick(None);
+
+        exit_reason
+    }
+
+    /// Lets the other threads stop the vCPU loop running on this thread,
+    /// `None` if the hypervisor only leaves the guest on its own
+    fn vcpu_kick(&self) -> Option<VcpuKick> {
+        None
+    }
+}
+
+fn run_vcpu(
+    cpu: &Mutex<Cpu>,
+    bus: &Mutex<Bus>,
+    exit_requester: &ExitRequester,
+) -> Result<CpuExitReason<'static>, HvError> {
+    let mut cpu = cpu.lock().unwrap();
+
+    loop {
+        // Also the one posted before the kick has been set
+        if let Some(request) = exit_requester.take() {
+            return Ok(request.into());
+        }
+
+        let exit_reason = cpu.run()?;
+
+        match exit_reason {
+            // Logged by the vCPU along with its registers, the guest cannot
+            // go on past an access nothing emulates
+            CpuExitReason::NotSupported => {
+                #[cfg(target_os = "linux")]
+                return Err(HvError::from_raw_os_error(libc::ENOSYS));
+                #[cfg(target_os = "macos")]
+                return Err(HvError::Unsupported);
             }
+            // Also when kicked by another thread to see the request
+            CpuExitReason::Continue => {}
+            // With the in-kernel irqchip HLT waits for an interrupt inside
+            // KVM, without it nothing can wake the vCPU up.
+            CpuExitReason::Halt => return Ok(CpuExitReason::Halt),
+            CpuExitReason::Io(io_type) => bus.lock().unwrap().handle_io(io_type),
+            CpuExitReason::MmIo(mmio_type) => bus.lock().unwrap().handle_mmio(mmio_type),
+            CpuExitReason::Hypercall(hypercall) => bus.lock().unwrap().handle_hypercall(hypercall),
+            CpuExitReason::Msr(msr_type) => bus.lock().unwrap().handle_msr(msr_type),
+            CpuExitReason::Shutdown => return Ok(CpuExitReason::Shutdown),
+            CpuExitReason::Reset => return Ok(CpuExitReason::Reset),
+            CpuExitReason::Exit(status) => return Ok(CpuExitReason::Exit(status)),
+            CpuExitReason::Panic(events) => return Ok(CpuExitReason::Panic(events)),
+            CpuExitReason::Watchdog => return Ok(CpuExitReason::Watchdog),
         }
     }
 }
 
+/// Anonymous memory for the tests of the devices
+#[cfg(all(test, target_os = "linux"))]
+pub fn test_memory(gpa: u64, size: usize) -> Memory {
+    let addr = unsafe {
+        libc::mmap(
+            std::ptr::null_mut(),
+            size,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+            -1,
+            0,
+        )
+    };
+    assert_ne!(addr, libc::MAP_FAILED);
+
+    Memory::new(vec![MappedGpa {
+        memory: addr as *mut u8,
+        gpa,
+        size,
+        file: None,
+        page_size: host_page_size(),
+        locked: false,
+    }])
+}
+
+/// RAM mapped with the backing of the guest RAM for the tests of the devices
+#[cfg(all(test, target_os = "linux"))]
+pub fn test_ram(gpa: u64, size: usize, options: &RamOptions) -> Result<Memory, std::io::Error> {
+    let span = GpaSpan { start: gpa, size };
+    linux::map_ram(&span, options, None).map(|span| Memory::new(vec![span]))
+}
+
 #[cfg(test)]
 mod tests {
     use super::{GpaSpan, SmolVmT};
@@ -536,6 +1122,461 @@ mod tests {
         vm.run().unwrap();
     }
 
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_acpi_shutdown() {
+        let mut vm = super::create_vm_with_options(
+            &[GpaSpan {
+                start: 0,
+                size: 64 * 1024 * 1024,
+            }],
+            &super::VmOptions {
+                irqchip: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        vm.load_bin(
+            &[
+                0xb0, 0x34, /* mov al, SLP_EN | SLP_TYP(S5) */
+                0x66, 0xba, 0x00, 0x06, /* mov dx, 0x600 */
+                0xee, /* out dx, al */
+                0xf4, /* hlt */
+            ],
+            0x10000,
+        );
+        assert!(vm.run().unwrap() == super::CpuExitReason::Shutdown);
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_debug_exit() {
+        let mut vm = super::create_vm_with_options(
+            &[GpaSpan {
+                start: 0,
+                size: 64 * 1024 * 1024,
+            }],
+            &super::VmOptions {
+                debug_exit: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        vm.load_bin(
+            &[
+                0xb0, 0x21, /* mov al, 0x21 */
+                0xe6, 0xf4, /* out 0xf4, al */
+                0xf4, /* hlt */
+            ],
+            0x10000,
+        );
+        assert!(vm.run().unwrap() == super::CpuExitReason::Exit(0x43));
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_vcpu_kick() {
+        let mut vm = super::create_vm(&[GpaSpan {
+            start: 0,
+            size: 64 * 1024 * 1024,
+        }])
+        .unwrap();
+        vm.load_bin(&[0xeb, 0xfe /* jmp $ */], 0x10000);
+
+        let exit_requester = vm.get_bus().lock().unwrap().exit_requester();
+        std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(100));
+            exit_requester.request(super::ExitRequest::Exit(1));
+        });
+        assert!(vm.run().unwrap() == super::CpuExitReason::Exit(1));
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_pvpanic() {
+        let mut vm = super::create_vm(&[GpaSpan {
+            start: 0,
+            size: 64 * 1024 * 1024,
+        }])
+        .unwrap();
+        vm.load_bin(
+            &[
+                0xb0, 0x01, /* mov al, PANICKED */
+                0x66, 0xba, 0x05, 0x05, /* mov dx, 0x505 */
+                0xee, /* out dx, al */
+                0xf4, /* hlt */
+            ],
+            0x10000,
+        );
+        assert!(vm.run().unwrap() == super::CpuExitReason::Panic(1));
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_reset() {
+        let mut vm = super::create_vm_with_options(
+            &[GpaSpan {
+                start: 0,
+                size: 64 * 1024 * 1024,
+            }],
+            &super::VmOptions {
+                irqchip: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        // No IDT, the exception ends up in a triple fault
+        vm.load_bin(&[0x0f, 0x0b /* ud2 */], 0x10000);
+        assert!(vm.run().unwrap() == super::CpuExitReason::Reset);
+
+        vm.reset().unwrap();
+        vm.load_bin(
+            &[
+                0xb0, 0x34, /* mov al, SLP_EN | SLP_TYP(S5) */
+                0x66, 0xba, 0x00, 0x06, /* mov dx, 0x600 */
+                0xee, /* out dx, al */
+            ],
+            0x10000,
+        );
+        assert!(vm.run().unwrap() == super::CpuExitReason::Shutdown);
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_post_codes() {
+        let mut vm = super::create_vm(&[GpaSpan {
+            start: 0,
+            size: 64 * 1024 * 1024,
+        }])
+        .unwrap();
+        vm.load_bin(
+            &[
+                0xb0, 0x11, /* mov al, 0x11 */
+                0xe6, 0x80, /* out 0x80, al */
+                0xb0, 0x22, /* mov al, 0x22 */
+                0xe6, 0x80, /* out 0x80, al */
+                0xf4, /* hlt */
+            ],
+            0x10000,
+        );
+        assert!(vm.run().unwrap() == super::CpuExitReason::Halt);
+        assert_eq!(vm.get_post_codes().unwrap().last(), [0x11, 0x22]);
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_string_io() {
+        let mut vm = super::create_vm(&[GpaSpan {
+            start: 0,
+            size: 64 * 1024 * 1024,
+        }])
+        .unwrap();
+        vm.load_bin(
+            &[
+                0x48, 0x8d, 0x35, 0x0c, 0x00, 0x00, 0x00, /* lea rsi, [rip + 12] */
+                0xb9, 0x03, 0x00, 0x00, 0x00, /* mov ecx, 3 */
+                0x66, 0xba, 0x80, 0x00, /* mov dx, 0x80 */
+                0xf3, 0x6e, /* rep outsb */
+                0xf4, /* hlt */
+                0x01, 0x02, 0x03,
+            ],
+            0x10000,
+        );
+        assert!(vm.run().unwrap() == super::CpuExitReason::Halt);
+        assert_eq!(vm.get_post_codes().unwrap().last(), [0x01, 0x02, 0x03]);
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_double_word_io() {
+        use super::bus::IoDevice;
+        use std::sync::{Arc, Mutex};
+
+        /// Reads as a constant, keeps what has been written
+        struct Constant(Arc<Mutex<Vec<u8>>>);
+
+        impl IoDevice for Constant {
+            fn io_in(&mut self, _port: u16, data: &mut [u8]) {
+                data.copy_from_slice(&0x1234_5678_u32.to_le_bytes()[..data.len()]);
+            }
+
+            fn io_out(&mut self, _port: u16, data: &[u8]) {
+                *self.0.lock().unwrap() = data.to_vec();
+            }
+        }
+
+        let mut vm = super::create_vm(&[GpaSpan {
+            start: 0,
+            size: 64 * 1024 * 1024,
+        }])
+        .unwrap();
+        let written = Arc::new(Mutex::new(Vec::new()));
+        vm.get_bus().lock().unwrap().add_io_device(
+            0x700,
+            4,
+            Arc::new(Mutex::new(Constant(written.clone()))),
+        );
+
+        vm.load_bin(
+            &[
+                0x66, 0xba, 0x00, 0x07, /* mov dx, 0x700 */
+                0xed, /* in eax, dx */
+                0xf7, 0xd0, /* not eax */
+                0xef, /* out dx, eax */
+                0xf4, /* hlt */
+            ],
+            0x10000,
+        );
+        assert!(vm.run().unwrap() == super::CpuExitReason::Halt);
+        assert_eq!(*written.lock().unwrap(), (!0x1234_5678_u32).to_le_bytes());
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_mmio() {
+        use super::bus::MmIoDevice;
+        use std::sync::{Arc, Mutex};
+
+        /// Reads as all ones, keeps what has been written
+        struct AllOnes(Arc<Mutex<Vec<u8>>>);
+
+        impl MmIoDevice for AllOnes {
+            fn mmio_read(&mut self, _addr: u64, data: &mut [u8]) {
+                data.fill(0xff);
+            }
+
+            fn mmio_write(&mut self, _addr: u64, data: &[u8]) {
+                *self.0.lock().unwrap() = data.to_vec();
+            }
+        }
+
+        let mut vm = super::create_vm(&[GpaSpan {
+            start: 0,
+            size: 64 * 1024 * 1024,
+        }])
+        .unwrap();
+        let written = Arc::new(Mutex::new(Vec::new()));
+        vm.get_bus().lock().unwrap().add_mmio_device(
+            0x3000_0000,
+            0x1000,
+            Arc::new(Mutex::new(AllOnes(written.clone()))),
+        );
+
+        // A triple fault unless the byte is sign-extended to the quad word
+        vm.load_bin(
+            &[
+                0xb9, 0x00, 0x00, 0x00, 0x30, /* mov ecx, 0x30000000 */
+                0x48, 0x8b, 0x01, /* mov rax, [rcx] */
+                0x48, 0x0f, 0xbe, 0x19, /* movsx rbx, byte [rcx] */
+                0x48, 0x39, 0xd8, /* cmp rax, rbx */
+                0x75, 0x04, /* jne ud2 */
+                0x48, 0x89, 0x01, /* mov [rcx], rax */
+                0xf4, /* hlt */
+                0x0f, 0x0b, /* ud2 */
+            ],
+            0x10000,
+        );
+        assert!(vm.run().unwrap() == super::CpuExitReason::Halt);
+        assert_eq!(*written.lock().unwrap(), [0xff; 8]);
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_msr() {
+        use super::bus::MsrHandler;
+        use std::sync::{Arc, Mutex};
+
+        /// Reads as a constant, keeps what has been written
+        struct Constant(Arc<Mutex<Option<u64>>>);
+
+        impl MsrHandler for Constant {
+            fn rdmsr(&mut self, _index: u32) -> Option<u64> {
+                Some(0xaabb_ccdd_1234_5678)
+            }
+
+            fn wrmsr(&mut self, _index: u32, value: u64) -> bool {
+                *self.0.lock().unwrap() = Some(value);
+                true
+            }
+        }
+
+        let mut vm = super::create_vm(&[GpaSpan {
+            start: 0,
+            size: 64 * 1024 * 1024,
+        }])
+        .unwrap();
+        let written = Arc::new(Mutex::new(None));
+        vm.add_msr_handler(0x1234, Arc::new(Mutex::new(Constant(written.clone()))))
+            .unwrap();
+
+        // No IDT, the #GP of the unknown MSR ends up in a triple fault
+        vm.load_bin(
+            &[
+                0xb9, 0x34, 0x12, 0x00, 0x00, /* mov ecx, 0x1234 */
+                0x0f, 0x32, /* rdmsr */
+                0x3d, 0x78, 0x56, 0x34, 0x12, /* cmp eax, 0x12345678 */
+                0x75, 0x09, /* jne hlt */
+                0x0f, 0x30, /* wrmsr */
+                0xb9, 0x35, 0x12, 0x00, 0x00, /* mov ecx, 0x1235 */
+                0x0f, 0x32, /* rdmsr */
+                0xf4, /* hlt */
+            ],
+            0x10000,
+        );
+        assert!(vm.run().unwrap() == super::CpuExitReason::Reset);
+        assert_eq!(*written.lock().unwrap(), Some(0xaabb_ccdd_1234_5678));
+
+        let bus = vm.get_bus();
+        let unknown_msrs = bus.lock().unwrap().unknown_msrs().clone();
+        assert_eq!(unknown_msrs.keys().collect::<Vec<_>>(), [&0x1235]);
+        assert_eq!(unknown_msrs[&0x1235].reads, 1);
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_port_hypercall() {
+        use super::bus::HypercallHandler;
+        use std::sync::{Arc, Mutex};
+
+        /// Answers with the sum of the arguments, keeps them
+        struct Sum(Arc<Mutex<Vec<u64>>>);
+
+        impl HypercallHandler for Sum {
+            fn hypercall(&mut self, _nr: u64, args: &[u64; 6], ret: &mut [u64]) {
+                *self.0.lock().unwrap() = args.to_vec();
+                ret[0] = args.iter().sum();
+            }
+        }
+
+        let mut vm = super::create_vm(&[GpaSpan {
+            start: 0,
+            size: 64 * 1024 * 1024,
+        }])
+        .unwrap();
+        let args = Arc::new(Mutex::new(Vec::new()));
+        vm.add_hypercall_handler(0x1234, Arc::new(Mutex::new(Sum(args.clone()))))
+            .unwrap();
+        // KVM exits on the `vmcall` of this one
+        vm.add_hypercall_handler(12, Arc::new(Mutex::new(Sum(args.clone()))))
+            .unwrap();
+
+        // No IDT, the ud2 ends up in a triple fault
+        vm.load_bin(
+            &[
+                0xb8, 0x34, 0x12, 0x00, 0x00, /* mov eax, 0x1234 */
+                0xbb, 0x01, 0x00, 0x00, 0x00, /* mov ebx, 1 */
+                0xb9, 0x02, 0x00, 0x00, 0x00, /* mov ecx, 2 */
+                0xbe, 0x03, 0x00, 0x00, 0x00, /* mov esi, 3 */
+                0xbf, 0x04, 0x00, 0x00, 0x00, /* mov edi, 4 */
+                0x66, 0xba, 0xc0, 0x05, /* mov dx, 0x5c0 */
+                0xee, /* out dx, al */
+                0x3d, 0x0a, 0x00, 0x00, 0x00, /* cmp eax, 10 */
+                0x75, 0x0e, /* jne ud2 */
+                0xb8, 0x35, 0x12, 0x00, 0x00, /* mov eax, 0x1235 */
+                0xee, /* out dx, al */
+                0x3d, 0x18, 0xfc, 0xff, 0xff, /* cmp eax, -KVM_ENOSYS */
+                0x75, 0x01, /* jne ud2 */
+                0xf4, /* hlt */
+                0x0f, 0x0b, /* ud2 */
+            ],
+            0x10000,
+        );
+        assert!(vm.run().unwrap() == super::CpuExitReason::Halt);
+        assert_eq!(*args.lock().unwrap(), [1, 2, 3, 4, 0, 0]);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_ram_backing() {
+        use super::{test_ram, HugePageSize, RamBacking, RamOptions};
+
+        // The locked pages are zeroed in place rather than dropped
+        let mut memory = test_ram(
+            0,
+            0x10_0000,
+            &RamOptions {
+                mlock: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        memory.write(0x1000, &[0xaa; 0x2000]);
+        memory.discard(0x1000, 0x2000).unwrap();
+        assert!(memory.read(0x1000, 0x2000).iter().all(|byte| *byte == 0));
+
+        // Not a multiple of the huge page size
+        let hugepages = RamOptions {
+            backing: RamBacking::HugePages(HugePageSize::Size2M),
+            ..Default::default()
+        };
+        let err = test_ram(0, 0x30_0000, &hugepages).err().unwrap();
+        assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+        let err = test_ram(0x10_0000, 0x20_0000, &hugepages).err().unwrap();
+        assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+
+        // KSM does not merge the shared pages
+        let mergeable = RamOptions {
+            mergeable: true,
+            ..Default::default()
+        };
+        let err = test_ram(0, 0x10_0000, &mergeable).err().unwrap();
+        assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_pmem_errors() {
+        use super::{PmemOptions, VmOptions};
+
+        let ram = [GpaSpan {
+            start: 0,
+            size: 64 * 1024 * 1024,
+        }];
+        let pmem = |irqchip: bool| VmOptions {
+            irqchip,
+            pmem: vec![PmemOptions {
+                path: "/nonexistent/pmem".into(),
+                read_only: true,
+            }],
+            ..Default::default()
+        };
+
+        // The device could not interrupt the guest
+        let err = super::create_vm_with_options(&ram, &pmem(false))
+            .err()
+            .unwrap();
+        assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+
+        let err = super::create_vm_with_options(&ram, &pmem(true))
+            .err()
+            .unwrap();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_i8042_reset() {
+        let mut vm = super::create_vm(&[GpaSpan {
+            start: 0,
+            size: 64 * 1024 * 1024,
+        }])
+        .unwrap();
+        // The reset command is what the self-test answer turns into
+        vm.load_bin(
+            &[
+                0xb0, 0xaa, /* mov al, 0xaa */
+                0xe6, 0x64, /* out 0x64, al */
+                0xe4, 0x60, /* in al, 0x60 */
+                0x04, 0xa9, /* add al, 0xa9 */
+                0xe6, 0x64, /* out 0x64, al */
+                0xf4, /* hlt */
+            ],
+            0x10000,
+        );
+        assert!(vm.run().unwrap() == super::CpuExitReason::Reset);
+    }
+
     #[test]
     #[cfg(target_arch = "aarch64")]
     fn test_halt() {
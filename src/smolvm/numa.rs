@@ -0,0 +1,155 @@
+//! The NUMA nodes of the guest: the RAM regions and the CPUs of each node,
+//! how far apart the nodes are, and the host node the RAM of a node comes
+//! from. The guest finds the nodes in the SRAT and the SLIT on x86_64, in
+//! the `numa-node-id` properties and the `distance-map` on aarch64.
+
+use super::{fdt::FdtNode, GpaSpan};
+
+/// The distance of a node to itself
+pub const LOCAL_DISTANCE: u8 = 10;
+/// The distance of the other nodes when not given
+pub const REMOTE_DISTANCE: u8 = 20;
+/// Means the nodes cannot reach each other in the SLIT
+const UNREACHABLE_DISTANCE: u8 = 0xff;
+
+#[derive(Clone, Debug, Default)]
+pub struct NumaNode {
+    /// The indices of the RAM regions in the GPA map
+    pub memory: Vec<usize>,
+    /// The indices of the CPUs in the topology
+    pub cpus: Vec<u32>,
+    /// The host node the RAM is bound to, any when `None`
+    pub host_node: Option<u32>,
+    /// The distance to each node, `LOCAL_DISTANCE` to itself and
+    /// `REMOTE_DISTANCE` to the others when empty
+    pub distances: Vec<u8>,
+}
+
+/// Every RAM region and every CPU is in exactly one node, the distances are
+/// given for every node
+pub fn is_valid(nodes: &[NumaNode], region_count: usize, cpu_count: u32) -> bool {
+    let regions_once = (0..region_count).all(|region| {
+        nodes
+            .iter()
+            .flat_map(|node| &node.memory)
+            .filter(|memory| **memory == region)
+            .count()
+            == 1
+    });
+    let cpus_once = (0..cpu_count).all(|cpu| {
+        nodes
+            .iter()
+            .flat_map(|node| &node.cpus)
+            .filter(|node_cpu| **node_cpu == cpu)
+            .count()
+            == 1
+    });
+    let nothing_else = nodes.iter().all(|node| {
+        node.memory.iter().all(|memory| *memory < region_count)
+            && node.cpus.iter().all(|cpu| *cpu < cpu_count)
+    });
+    let distances = nodes.iter().enumerate().all(|(index, node)| {
+        node.distances.is_empty()
+            || node.distances.len() == nodes.len()
+                && node.distances.iter().enumerate().all(|(to, distance)| {
+                    if to == index {
+                        *distance == LOCAL_DISTANCE
+                    } else {
+                        *distance > LOCAL_DISTANCE && *distance < UNREACHABLE_DISTANCE
+                    }
+                })
+    });
+
+    regions_once && cpus_once && nothing_else && distances
+}
+
+pub fn distance(nodes: &[NumaNode], from: usize, to: usize) -> u8 {
+    match nodes[from].distances.get(to) {
+        Some(distance) => *distance,
+        None if from == to => LOCAL_DISTANCE,
+        None => REMOTE_DISTANCE,
+    }
+}
+
+pub fn node_of_cpu(nodes: &[NumaNode], cpu: u32) -> Option<u32> {
+    nodes
+        .iter()
+        .position(|node| node.cpus.contains(&cpu))
+        .map(|node| node as u32)
+}
+
+pub fn node_of_memory(nodes: &[NumaNode], region: usize) -> Option<u32> {
+    nodes
+        .iter()
+        .position(|node| node.memory.contains(&region))
+        .map(|node| node as u32)
+}
+
+/// The `memory` nodes with their `numa-node-id`, replacing the ones of the
+/// blob at the same addresses, and the `distance-map`
+pub fn fdt_nodes(nodes: &[NumaNode], ram: &[GpaSpan]) -> Vec<FdtNode> {
+    let mut fdt_nodes = Vec::new();
+    for (region, span) in ram.iter().enumerate() {
+        if let Some(node) = node_of_memory(nodes, region) {
+            fdt_nodes.push(
+                FdtNode::new(&format!("memory@{:x}", span.start))
+                    .replace()
+                    .property_string("device_type", "memory")
+                    .reg(span.start, span.size as u64)
+                    .property_u32("numa-node-id", node),
+            );
+        }
+    }
+
+    let mut matrix = Vec::new();
+    for from in 0..nodes.len() {
+        for to in 0..nodes.len() {
+            matrix.extend_from_slice(&[from as u32, to as u32, distance(nodes, from, to) as u32]);
+        }
+    }
+    fdt_nodes.push(
+        FdtNode::new("distance-map")
+            .replace()
+            .property_string("compatible", "numa-distance-map-v1")
+            .property_cells("distance-matrix", &matrix),
+    );
+
+    fdt_nodes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nodes() {
+        let mut nodes = vec![
+            NumaNode {
+                memory: vec![0],
+                cpus: vec![0, 1],
+                ..Default::default()
+            },
+            NumaNode {
+                memory: vec![1, 2],
+                cpus: vec![2, 3],
+                host_node: Some(1),
+                distances: vec![21, LOCAL_DISTANCE],
+            },
+        ];
+        assert!(is_valid(&nodes, 3, 4));
+        assert_eq!(distance(&nodes, 0, 1), REMOTE_DISTANCE);
+        assert_eq!(distance(&nodes, 1, 0), 21);
+        assert_eq!(node_of_cpu(&nodes, 3), Some(1));
+        assert_eq!(node_of_memory(&nodes, 2), Some(1));
+
+        // A region left out, a CPU in two nodes, past the topology
+        assert!(!is_valid(&nodes, 4, 4));
+        nodes[0].cpus.push(2);
+        assert!(!is_valid(&nodes, 3, 4));
+        nodes[0].cpus.pop();
+        assert!(!is_valid(&nodes, 3, 2));
+
+        nodes[1].distances = vec![21];
+        assert!(!is_valid(&nodes, 3, 4));
+    }
+}
@@ -0,0 +1,631 @@
+//! PCI host bridge with a single bus.
+//!
+//! The guest reaches the configuration space through the 0xcf8/0xcfc
+//! ports on x86_64 and through the ECAM window on aarch64. The latter
+//! matches the `pcie@10000000` node of the `virt` machine of `qemu`
+//! with `highmem=off`.
+//!
+//! There is no firmware to assign the BARs so the host bridge does that
+//! when a device is added, the guest is free to move them within the
+//! MMIO window later.
+
+use std::sync::{Arc, Mutex};
+
+use super::bus::{Bus, IoDevice, MmIoDevice};
+
+mod msix;
+
+pub use msix::MsixTable;
+
+pub const PCI_VENDOR_ID: usize = 0x00;
+pub const PCI_DEVICE_ID: usize = 0x02;
+pub const PCI_COMMAND: usize = 0x04;
+pub const PCI_STATUS: usize = 0x06;
+pub const PCI_REVISION_ID: usize = 0x08;
+pub const PCI_CLASS_PROG: usize = 0x09;
+pub const PCI_HEADER_TYPE: usize = 0x0e;
+pub const PCI_BASE_ADDRESS_0: usize = 0x10;
+pub const PCI_SUBSYSTEM_VENDOR_ID: usize = 0x2c;
+pub const PCI_SUBSYSTEM_ID: usize = 0x2e;
+pub const PCI_CAPABILITY_LIST: usize = 0x34;
+pub const PCI_INTERRUPT_LINE: usize = 0x3c;
+
+pub const PCI_COMMAND_IO: u16 = 0x1;
+pub const PCI_COMMAND_MEMORY: u16 = 0x2;
+pub const PCI_COMMAND_MASTER: u16 = 0x4;
+pub const PCI_COMMAND_PARITY: u16 = 0x40;
+pub const PCI_COMMAND_SERR: u16 = 0x100;
+pub const PCI_COMMAND_INTX_DISABLE: u16 = 0x400;
+
+pub const PCI_STATUS_CAP_LIST: u16 = 0x10;
+
+pub const PCI_CAP_ID_VNDR: u8 = 0x09;
+pub const PCI_CAP_ID_MSIX: u8 = 0x11;
+
+pub const PCI_CLASS_BRIDGE_HOST: u32 = 0x06_0000;
+
+const PCI_VENDOR_ID_REDHAT: u16 = 0x1b36;
+const PCI_DEVICE_ID_REDHAT_PCIE_HOST: u16 = 0x0008;
+
+pub const PCI_CONFIG_SIZE: usize = 256;
+pub const PCI_BAR_COUNT: usize = 6;
+
+/// A device, not counting the host bridge at 00.0, per slot of bus 0
+const PCI_SLOTS: usize = 32;
+
+/// Configuration address register of the configuration mechanism #1
+#[cfg(target_arch = "x86_64")]
+pub const PCI_CONFIG_ADDRESS_PORT: u16 = 0xcf8;
+#[cfg(target_arch = "x86_64")]
+pub const PCI_CONFIG_PORT_COUNT: u16 = 8;
+#[cfg(target_arch = "x86_64")]
+const PCI_CONFIG_DATA_PORT: u16 = 0xcfc;
+
+/// Below the virtio-mmio transports and the IOAPIC
+#[cfg(target_arch = "x86_64")]
+pub const PCI_MMIO_BASE: u64 = 0xc000_0000;
+#[cfg(target_arch = "x86_64")]
+pub const PCI_MMIO_SIZE: u64 = 0x1000_0000;
+
+#[cfg(target_arch = "aarch64")]
+pub const PCI_ECAM_BASE: u64 = 0x3f00_0000;
+#[cfg(target_arch = "aarch64")]
+pub const PCI_ECAM_SIZE: u64 = 0x100_0000;
+#[cfg(target_arch = "aarch64")]
+pub const PCI_MMIO_BASE: u64 = 0x1000_0000;
+#[cfg(target_arch = "aarch64")]
+pub const PCI_MMIO_SIZE: u64 = 0x2eff_0000;
+
+/// The configuration space of a function together with the masks of
+/// the bits the driver may change.
+pub struct PciConfig {
+    data: [u8; PCI_CONFIG_SIZE],
+    write_mask: [u8; PCI_CONFIG_SIZE],
+    bar_sizes: [u64; PCI_BAR_COUNT],
+    last_capability: Option<usize>,
+    next_capability: usize,
+}
+
+impl PciConfig {
+    pub fn new(
+        vendor_id: u16,
+        device_id: u16,
+        class_code: u32,
+        revision: u8,
+        subsystem_vendor_id: u16,
+        subsystem_id: u16,
+    ) -> Self {
+        let mut config = Self {
+            data: [0; PCI_CONFIG_SIZE],
+            write_mask: [0; PCI_CONFIG_SIZE],
+            bar_sizes: [0; PCI_BAR_COUNT],
+            last_capability: None,
+            next_capability: 0x40,
+        };
+
+        config.write_u16(PCI_VENDOR_ID, vendor_id);
+        config.write_u16(PCI_DEVICE_ID, device_id);
+        config.write_u8(PCI_REVISION_ID, revision);
+        config.data[PCI_CLASS_PROG..PCI_CLASS_PROG + 3]
+            .copy_from_slice(&class_code.to_le_bytes()[..3]);
+        config.write_u16(PCI_SUBSYSTEM_VENDOR_ID, subsystem_vendor_id);
+        config.write_u16(PCI_SUBSYSTEM_ID, subsystem_id);
+
+        config.set_writable(
+            PCI_COMMAND,
+            &(PCI_COMMAND_IO
+                | PCI_COMMAND_MEMORY
+                | PCI_COMMAND_MASTER
+                | PCI_COMMAND_PARITY
+                | PCI_COMMAND_SERR
+                | PCI_COMMAND_INTX_DISABLE)
+                .to_le_bytes(),
+        );
+        config.set_writable(PCI_INTERRUPT_LINE, &[0xff]);
+
+        config
+    }
+
+    /// Adds a 32-bit memory BAR, the size is a power of two
+    pub fn add_bar(&mut self, index: usize, size: u64) {
+        assert!(size.is_power_of_two() && (16..=1 << 31).contains(&size));
+
+        let offset = PCI_BASE_ADDRESS_0 + index * 4;
+        self.bar_sizes[index] = size;
+        self.set_writable(offset, &(!(size as u32 - 1) & !0xf).to_le_bytes());
+    }
+
+    pub fn bar_size(&self, index: usize) -> u64 {
+        self.bar_sizes[index]
+    }
+
+    pub fn set_bar_address(&mut self, index: usize, addr: u64) {
+        self.write_u32(PCI_BASE_ADDRESS_0 + index * 4, addr as u32 & !0xf);
+    }
+
+    pub fn bar_address(&self, index: usize) -> u64 {
+        (self.read_u32(PCI_BASE_ADDRESS_0 + index * 4) & !0xf) as u64
+    }
+
+    /// Finds the BAR that contains the address, the memory decoding
+    /// has to be enabled by the driver.
+    pub fn find_bar(&self, addr: u64) -> Option<(usize, u64)> {
+        if self.read_u16(PCI_COMMAND) & PCI_COMMAND_MEMORY == 0 {
+            return None;
+        }
+
+        (0..PCI_BAR_COUNT)
+            .filter(|index| self.bar_sizes[*index] != 0)
+            .find(|index| {
+                let start = self.bar_address(*index);
+                start != 0 && start <= addr && addr - start < self.bar_sizes[*index]
+            })
+            .map(|index| (index, addr - self.bar_address(index)))
+    }
+
+    /// Appends the capability to the list, the first two bytes of `data`
+    /// are the capability ID and the pointer to the next one that is filled
+    /// in here. Returns the offset of the capability.
+    pub fn add_capability(&mut self, data: &[u8]) -> usize {
+        let offset = self.next_capability;
+        assert!(offset + data.len() <= PCI_CONFIG_SIZE);
+
+        self.data[offset..offset + data.len()].copy_from_slice(data);
+        self.data[offset + 1] = 0;
+
+        match self.last_capability {
+            Some(last) => self.data[last + 1] = offset as u8,
+            None => {
+                self.write_u8(PCI_CAPABILITY_LIST, offset as u8);
+                self.write_u16(PCI_STATUS, self.read_u16(PCI_STATUS) | PCI_STATUS_CAP_LIST);
+            }
+        }
+
+        self.last_capability = Some(offset);
+        self.next_capability = (offset + data.len() + 3) & !3;
+
+        offset
+    }
+
+    /// Back to the power-on state: the bits the driver may change are
+    /// cleared, i.e. the command, the BAR addresses and the capability
+    /// controls
+    pub fn reset(&mut self) {
+        for (byte, mask) in self.data.iter_mut().zip(&self.write_mask) {
+            *byte &= !mask;
+        }
+    }
+
+    pub fn set_writable(&mut self, offset: usize, mask: &[u8]) {
+        self.write_mask[offset..offset + mask.len()].copy_from_slice(mask);
+    }
+
+    /// The access of the driver, the space past the header and the
+    /// capabilities (e.g. the PCIe extended one) reads as zeroes.
+    pub fn read(&self, offset: usize, data: &mut [u8]) {
+        for (index, byte) in data.iter_mut().enumerate() {
+            *byte = self.data.get(offset + index).copied().unwrap_or(0);
+        }
+    }
+
+    /// The access of the driver, only the writable bits change
+    pub fn write(&mut self, offset: usize, data: &[u8]) {
+        for (index, byte) in data.iter().enumerate() {
+            if let Some(mask) = self.write_mask.get(offset + index) {
+                let old = self.data[offset + index];
+                self.data[offset + index] = (old & !mask) | (byte & mask);
+            }
+        }
+    }
+
+    pub fn read_u8(&self, offset: usize) -> u8 {
+        self.data[offset]
+    }
+
+    pub fn read_u16(&self, offset: usize) -> u16 {
+        u16::from_le_bytes([self.data[offset], self.data[offset + 1]])
+    }
+
+    pub fn read_u32(&self, offset: usize) -> u32 {
+        let mut bytes = [0_u8; 4];
+        bytes.copy_from_slice(&self.data[offset..offset + 4]);
+        u32::from_le_bytes(bytes)
+    }
+
+    pub fn write_u8(&mut self, offset: usize, value: u8) {
+        self.data[offset] = value;
+    }
+
+    pub fn write_u16(&mut self, offset: usize, value: u16) {
+        self.data[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u32(&mut self, offset: usize, value: u32) {
+        self.data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+}
+
+pub trait PciDevice: Send {
+    fn config(&self) -> &PciConfig;
+
+    /// The driver writes to the configuration space
+    fn write_config(&mut self, offset: usize, data: &[u8]);
+
+    fn read_bar(&mut self, index: usize, offset: u64, data: &mut [u8]);
+    fn write_bar(&mut self, index: usize, offset: u64, data: &[u8]);
+
+    /// Back to the power-on state when the VM is reset, with the config
+    /// too. The host bridge assigns the BARs again afterwards.
+    fn reset(&mut self) {}
+}
+
+pub type SharedPciDevice = Arc<Mutex<dyn PciDevice>>;
+
+pub struct PciRoot {
+    host_bridge: PciConfig,
+    devices: Vec<SharedPciDevice>,
+    next_bar_addr: u64,
+    config_address: u32,
+}
+
+impl PciRoot {
+    fn new() -> Self {
+        Self {
+            host_bridge: PciConfig::new(
+                PCI_VENDOR_ID_REDHAT,
+                PCI_DEVICE_ID_REDHAT_PCIE_HOST,
+                PCI_CLASS_BRIDGE_HOST,
+                0,
+                0,
+                0,
+            ),
+            devices: Vec::new(),
+            next_bar_addr: PCI_MMIO_BASE,
+            config_address: 0,
+        }
+    }
+
+    /// Creates the host bridge and registers it on the bus
+    pub fn attach(bus: &mut Bus) -> Arc<Mutex<Self>> {
+        let root = Arc::new(Mutex::new(Self::new()));
+
+        #[cfg(target_arch = "x86_64")]
+        bus.add_io_device(PCI_CONFIG_ADDRESS_PORT, PCI_CONFIG_PORT_COUNT, root.clone());
+        #[cfg(target_arch = "aarch64")]
+        bus.add_mmio_device(PCI_ECAM_BASE, PCI_ECAM_SIZE, root.clone());
+
+        bus.add_mmio_device(PCI_MMIO_BASE, PCI_MMIO_SIZE, root.clone());
+
+        root
+    }
+
+    /// The bus/device/function the next device gets, `None` if the bus is full
+    pub fn next_requester_id(&self) -> Option<u32> {
+        let slot = self.devices.len() + 1;
+
+        if slot < PCI_SLOTS {
+            Some((slot as u32) << 3)
+        } else {
+            None
+        }
+    }
+
+    /// Places the device at the next slot and assigns its BARs
+    pub fn add_device(&mut self, device: SharedPciDevice) {
+        if self.next_requester_id().is_none() {
+            panic!("No free PCI slots");
+        }
+
+        self.assign_bars(self.devices.len() + 1, &mut *device.lock().unwrap());
+        self.devices.push(device);
+    }
+
+    fn assign_bars(&mut self, slot: usize, device: &mut dyn PciDevice) {
+        for index in 0..PCI_BAR_COUNT {
+            let size = device.config().bar_size(index);
+            if size == 0 {
+                continue;
+            }
+
+            let addr = (self.next_bar_addr + size - 1) & !(size - 1);
+            if addr + size > PCI_MMIO_BASE + PCI_MMIO_SIZE {
+                panic!("PCI MMIO window is exhausted");
+            }
+
+            let offset = PCI_BASE_ADDRESS_0 + index * 4;
+            device.write_config(offset, &(addr as u32).to_le_bytes());
+            self.next_bar_addr = addr + size;
+
+            log::info!(
+                "PCI 00:{:02x}.0 BAR {} at {:#x}, {:#x} bytes",
+                slot,
+                index,
+                addr,
+                size
+            );
+        }
+    }
+
+    /// The BARs get the addresses they had at first, whatever the guest has
+    /// moved them to
+    fn reset_devices(&mut self) {
+        self.config_address = 0;
+        self.host_bridge.reset();
+        self.next_bar_addr = PCI_MMIO_BASE;
+
+        for (index, device) in self.devices.clone().iter().enumerate() {
+            let mut device = device.lock().unwrap();
+            device.reset();
+            self.assign_bars(index + 1, &mut *device);
+        }
+    }
+
+    fn find_device(&self, bus: u8, device: u8, function: u8) -> Option<SharedPciDevice> {
+        if bus != 0 || function != 0 || device == 0 {
+            return None;
+        }
+
+        self.devices.get(device as usize - 1).cloned()
+    }
+
+    fn read_config(&self, bus: u8, device: u8, function: u8, offset: usize, data: &mut [u8]) {
+        if bus == 0 && device == 0 && function == 0 {
+            self.host_bridge.read(offset, data);
+        } else if let Some(device) = self.find_device(bus, device, function) {
+            device.lock().unwrap().config().read(offset, data);
+        } else {
+            // No function there
+            data.fill(0xff);
+        }
+    }
+
+    fn write_config(&mut self, bus: u8, device: u8, function: u8, offset: usize, data: &[u8]) {
+        if bus == 0 && device == 0 && function == 0 {
+            self.host_bridge.write(offset, data);
+        } else if let Some(device) = self.find_device(bus, device, function) {
+            device.lock().unwrap().write_config(offset, data);
+        }
+    }
+
+    fn find_bar(&self, addr: u64) -> Option<(SharedPciDevice, usize, u64)> {
+        self.devices.iter().find_map(|device| {
+            let bar = device.lock().unwrap().config().find_bar(addr);
+            bar.map(|(index, offset)| (device.clone(), index, offset))
+        })
+    }
+
+    fn read_bar(&self, addr: u64, data: &mut [u8]) {
+        if let Some((device, index, offset)) = self.find_bar(addr) {
+            device.lock().unwrap().read_bar(index, offset, data);
+        } else {
+            log::warn!("Reading from {:#x}, no PCI BAR there", addr);
+            data.fill(0xff);
+        }
+    }
+
+    fn write_bar(&self, addr: u64, data: &[u8]) {
+        if let Some((device, index, offset)) = self.find_bar(addr) {
+            device.lock().unwrap().write_bar(index, offset, data);
+        } else {
+            log::warn!("Writing {:x?} to {:#x}, no PCI BAR there", data, addr);
+        }
+    }
+
+    /// Bus, device, function and the register from `CONFIG_ADDRESS`
+    #[cfg(target_arch = "x86_64")]
+    fn decode_config_address(&self, port: u16) -> Option<(u8, u8, u8, usize)> {
+        if self.config_address & 0x8000_0000 == 0 {
+            return None;
+        }
+
+        Some((
+            (self.config_address >> 16) as u8,
+            ((self.config_address >> 11) & 0x1f) as u8,
+            ((self.config_address >> 8) & 0x7) as u8,
+            (self.config_address & 0xfc) as usize + (port - PCI_CONFIG_DATA_PORT) as usize,
+        ))
+    }
+
+    /// Bus, device, function and the register from the offset in the ECAM window
+    #[cfg(target_arch = "aarch64")]
+    fn decode_ecam_address(offset: u64) -> (u8, u8, u8, usize) {
+        (
+            (offset >> 20) as u8,
+            ((offset >> 15) & 0x1f) as u8,
+            ((offset >> 12) & 0x7) as u8,
+            (offset & 0xfff) as usize,
+        )
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+impl IoDevice for PciRoot {
+    fn io_in(&mut self, port: u16, data: &mut [u8]) {
+        if port == PCI_CONFIG_ADDRESS_PORT && data.len() == 4 {
+            data.copy_from_slice(&self.config_address.to_le_bytes());
+        } else if port >= PCI_CONFIG_DATA_PORT {
+            match self.decode_config_address(port) {
+                Some((bus, device, function, offset)) => {
+                    self.read_config(bus, device, function, offset, data)
+                }
+                None => data.fill(0xff),
+            }
+        } else {
+            data.fill(0xff);
+        }
+    }
+
+    fn io_out(&mut self, port: u16, data: &[u8]) {
+        if port == PCI_CONFIG_ADDRESS_PORT && data.len() == 4 {
+            self.config_address = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+        } else if port >= PCI_CONFIG_DATA_PORT {
+            if let Some((bus, device, function, offset)) = self.decode_config_address(port) {
+                self.write_config(bus, device, function, offset, data);
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        self.reset_devices();
+    }
+}
+
+impl MmIoDevice for PciRoot {
+    fn mmio_read(&mut self, addr: u64, data: &mut [u8]) {
+        #[cfg(target_arch = "aarch64")]
+        if (PCI_ECAM_BASE..PCI_ECAM_BASE + PCI_ECAM_SIZE).contains(&addr) {
+            let (bus, device, function, offset) = Self::decode_ecam_address(addr - PCI_ECAM_BASE);
+            self.read_config(bus, device, function, offset, data);
+            return;
+        }
+
+        self.read_bar(addr, data);
+    }
+
+    fn mmio_write(&mut self, addr: u64, data: &[u8]) {
+        #[cfg(target_arch = "aarch64")]
+        if (PCI_ECAM_BASE..PCI_ECAM_BASE + PCI_ECAM_SIZE).contains(&addr) {
+            let (bus, device, function, offset) = Self::decode_ecam_address(addr - PCI_ECAM_BASE);
+            self.write_config(bus, device, function, offset, data);
+            return;
+        }
+
+        self.write_bar(addr, data);
+    }
+
+    fn reset(&mut self) {
+        self.reset_devices();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TestDevice {
+        config: PciConfig,
+    }
+
+    impl TestDevice {
+        fn new() -> Self {
+            let mut config = PciConfig::new(0x1af4, 0x1041, 0xff_0000, 1, 0x1af4, 0x41);
+            config.add_bar(0, 0x4000);
+            config.add_bar(2, 0x10_0000);
+
+            Self { config }
+        }
+    }
+
+    impl PciDevice for TestDevice {
+        fn config(&self) -> &PciConfig {
+            &self.config
+        }
+
+        fn write_config(&mut self, offset: usize, data: &[u8]) {
+            self.config.write(offset, data);
+        }
+
+        fn read_bar(&mut self, _index: usize, _offset: u64, data: &mut [u8]) {
+            data.fill(0);
+        }
+
+        fn write_bar(&mut self, _index: usize, _offset: u64, _data: &[u8]) {}
+
+        fn reset(&mut self) {
+            self.config.reset();
+        }
+    }
+
+    fn read_u32(root: &PciRoot, device: u8, offset: usize) -> u32 {
+        let mut data = [0_u8; 4];
+        root.read_config(0, device, 0, offset, &mut data);
+        u32::from_le_bytes(data)
+    }
+
+    fn write_u32(root: &mut PciRoot, device: u8, offset: usize, value: u32) {
+        root.write_config(0, device, 0, offset, &value.to_le_bytes());
+    }
+
+    #[test]
+    fn test_bars() {
+        let mut root = PciRoot::new();
+        root.add_device(Arc::new(Mutex::new(TestDevice::new())));
+
+        // Aligned to their sizes one after another
+        let bar0 = PCI_BASE_ADDRESS_0;
+        let bar2 = PCI_BASE_ADDRESS_0 + 2 * 4;
+        assert_eq!(read_u32(&root, 1, bar0) as u64, PCI_MMIO_BASE);
+        assert_eq!(read_u32(&root, 1, bar2) as u64, PCI_MMIO_BASE + 0x10_0000);
+
+        // Sizing: all ones read back as the mask of the size
+        write_u32(&mut root, 1, bar0, 0xffff_ffff);
+        assert_eq!(read_u32(&root, 1, bar0), 0xffff_c000);
+        write_u32(&mut root, 1, bar2, 0xffff_ffff);
+        assert_eq!(read_u32(&root, 1, bar2), 0xfff0_0000);
+        // Not implemented
+        write_u32(&mut root, 1, bar0 + 4, 0xffff_ffff);
+        assert_eq!(read_u32(&root, 1, bar0 + 4), 0);
+
+        // Moved by the guest, decoded only with the memory space enabled
+        let addr = PCI_MMIO_BASE as u32 + 0x20_0000;
+        write_u32(&mut root, 1, bar0, addr);
+        assert!(root.find_bar(addr as u64).is_none());
+        write_u32(&mut root, 1, PCI_COMMAND, PCI_COMMAND_MEMORY as u32);
+        let (_, index, offset) = root.find_bar(addr as u64 + 0x10).unwrap();
+        assert_eq!((index, offset), (0, 0x10));
+
+        // Back to the first addresses with the decoding disabled
+        root.reset_devices();
+        assert_eq!(read_u32(&root, 1, PCI_COMMAND) & 0xffff, 0);
+        assert_eq!(read_u32(&root, 1, bar0) as u64, PCI_MMIO_BASE);
+        assert_eq!(read_u32(&root, 1, bar2) as u64, PCI_MMIO_BASE + 0x10_0000);
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_config_address() {
+        let mut root = PciRoot::new();
+        root.add_device(Arc::new(Mutex::new(TestDevice::new())));
+
+        let config_address =
+            |device: u32, offset: u32| (0x8000_0000 | device << 11 | offset).to_le_bytes();
+        let mut data = [0_u8; 4];
+
+        // The vendor and the device IDs of the host bridge and the device
+        root.io_out(PCI_CONFIG_ADDRESS_PORT, &config_address(0, 0));
+        root.io_in(PCI_CONFIG_DATA_PORT, &mut data);
+        assert_eq!(
+            u32::from_le_bytes(data),
+            (PCI_DEVICE_ID_REDHAT_PCIE_HOST as u32) << 16 | PCI_VENDOR_ID_REDHAT as u32
+        );
+        root.io_out(PCI_CONFIG_ADDRESS_PORT, &config_address(1, 0));
+        root.io_in(PCI_CONFIG_DATA_PORT, &mut data);
+        assert_eq!(u32::from_le_bytes(data), 0x1041_1af4);
+
+        // The narrower accesses at the offset of the data port
+        let mut word = [0_u8; 2];
+        root.io_in(PCI_CONFIG_DATA_PORT + 2, &mut word);
+        assert_eq!(u16::from_le_bytes(word), 0x1041);
+        root.io_out(PCI_CONFIG_ADDRESS_PORT, &config_address(1, 8));
+        let mut byte = [0_u8; 1];
+        root.io_in(PCI_CONFIG_DATA_PORT + 3, &mut byte);
+        assert_eq!(byte, [0xff]);
+
+        // The address reads back
+        root.io_in(PCI_CONFIG_ADDRESS_PORT, &mut data);
+        assert_eq!(data, config_address(1, 8));
+
+        // No function there, the enable bit clear
+        root.io_out(PCI_CONFIG_ADDRESS_PORT, &config_address(2, 0));
+        root.io_in(PCI_CONFIG_DATA_PORT, &mut data);
+        assert_eq!(data, [0xff; 4]);
+        root.io_out(PCI_CONFIG_ADDRESS_PORT, &(1_u32 << 11).to_le_bytes());
+        root.io_in(PCI_CONFIG_DATA_PORT, &mut data);
+        assert_eq!(data, [0xff; 4]);
+
+        // The register is dword aligned, the low bits are ignored
+        root.io_out(PCI_CONFIG_ADDRESS_PORT, &config_address(1, 2));
+        root.io_in(PCI_CONFIG_DATA_PORT, &mut data);
+        assert_eq!(u32::from_le_bytes(data), 0x1041_1af4);
+    }
+}
@@ -0,0 +1,315 @@
+//! MSI-X capability with the vector table and the pending bit array.
+//!
+//! Each vector has an MSI allocated from the irqchip up front, the message
+//! the driver programs into the table becomes the route of that MSI.
+
+use std::sync::Arc;
+
+use super::{PciConfig, PCI_CAP_ID_MSIX};
+use crate::smolvm::IrqChip;
+
+pub const MSIX_ENTRY_SIZE: u64 = 16;
+
+// Message Control
+const MSIX_FLAGS_ENABLE: u16 = 1 << 15;
+const MSIX_FLAGS_MASKALL: u16 = 1 << 14;
+
+// Vector Control
+const MSIX_ENTRY_CTRL_MASKBIT: u32 = 1;
+
+const MSIX_ENTRY_ADDR_LO: usize = 0;
+const MSIX_ENTRY_ADDR_HI: usize = 1;
+const MSIX_ENTRY_DATA: usize = 2;
+const MSIX_ENTRY_VECTOR_CTRL: usize = 3;
+
+#[derive(Clone, Copy)]
+struct MsixEntry {
+    words: [u32; 4],
+}
+
+impl MsixEntry {
+    fn address(&self) -> u64 {
+        self.words[MSIX_ENTRY_ADDR_LO] as u64 | (self.words[MSIX_ENTRY_ADDR_HI] as u64) << 32
+    }
+
+    fn data(&self) -> u32 {
+        self.words[MSIX_ENTRY_DATA]
+    }
+
+    fn is_masked(&self) -> bool {
+        self.words[MSIX_ENTRY_VECTOR_CTRL] & MSIX_ENTRY_CTRL_MASKBIT != 0
+    }
+}
+
+pub struct MsixTable {
+    irq_chip: Arc<dyn IrqChip>,
+    entries: Vec<MsixEntry>,
+    pending: Vec<bool>,
+    gsis: Vec<Option<u32>>,
+    cap_offset: usize,
+}
+
+impl MsixTable {
+    /// Adds the capability to `config`, the table and the PBA are at the
+    /// given offsets of the BAR
+    pub fn new(
+        vectors: u16,
+        requester_id: u32,
+        irq_chip: Arc<dyn IrqChip>,
+        config: &mut PciConfig,
+        bar: u8,
+        table_offset: u32,
+        pba_offset: u32,
+    ) -> Self {
+        let mut cap = [0_u8; 12];
+        cap[0] = PCI_CAP_ID_MSIX;
+        cap[2..4].copy_from_slice(&(vectors - 1).to_le_bytes());
+        cap[4..8].copy_from_slice(&(table_offset | bar as u32).to_le_bytes());
+        cap[8..12].copy_from_slice(&(pba_offset | bar as u32).to_le_bytes());
+
+        let cap_offset = config.add_capability(&cap);
+        config.set_writable(
+            cap_offset + 2,
+            &(MSIX_FLAGS_ENABLE | MSIX_FLAGS_MASKALL).to_le_bytes(),
+        );
+
+        let gsis = (0..vectors)
+            .map(|_| irq_chip.allocate_msi(requester_id))
+            .collect();
+
+        Self {
+            irq_chip,
+            entries: vec![
+                MsixEntry {
+                    words: [0, 0, 0, MSIX_ENTRY_CTRL_MASKBIT]
+                };
+                vectors as usize
+            ],
+            pending: vec![false; vectors as usize],
+            gsis,
+            cap_offset,
+        }
+    }
+
+    /// Every vector masked with no message, none pending. The capability
+    /// is reset with the config.
+    pub fn reset(&mut self) {
+        for entry in &mut self.entries {
+            entry.words = [0, 0, 0, MSIX_ENTRY_CTRL_MASKBIT];
+        }
+        self.pending.fill(false);
+    }
+
+    pub fn vectors(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn message_control(&self, config: &PciConfig) -> u16 {
+        config.read_u16(self.cap_offset + 2)
+    }
+
+    pub fn is_enabled(&self, config: &PciConfig) -> bool {
+        self.message_control(config) & MSIX_FLAGS_ENABLE != 0
+    }
+
+    fn is_masked(&self, config: &PciConfig, vector: usize) -> bool {
+        self.message_control(config) & MSIX_FLAGS_MASKALL != 0 || self.entries[vector].is_masked()
+    }
+
+    /// Sends the message of the vector or makes it pending if masked
+    pub fn notify(&mut self, config: &PciConfig, vector: u16) {
+        let vector = vector as usize;
+        if vector >= self.entries.len() || !self.is_enabled(config) {
+            return;
+        }
+
+        if self.is_masked(config, vector) {
+            self.pending[vector] = true;
+        } else if let Some(gsi) = self.gsis[vector] {
+            self.irq_chip.signal_msi(gsi);
+        }
+    }
+
+    /// Delivers the pending messages of the vectors that are no longer masked,
+    /// to be called after the driver has changed the capability or the table
+    pub fn deliver_pending(&mut self, config: &PciConfig) {
+        if !self.is_enabled(config) {
+            return;
+        }
+
+        for vector in 0..self.entries.len() {
+            if self.pending[vector] && !self.is_masked(config, vector) {
+                self.pending[vector] = false;
+                if let Some(gsi) = self.gsis[vector] {
+                    self.irq_chip.signal_msi(gsi);
+                }
+            }
+        }
+    }
+
+    pub fn read_table(&self, offset: u64, data: &mut [u8]) {
+        let vector = (offset / MSIX_ENTRY_SIZE) as usize;
+        let word = ((offset % MSIX_ENTRY_SIZE) / 4) as usize;
+
+        match (self.entries.get(vector), data.len()) {
+            (Some(entry), 4) => data.copy_from_slice(&entry.words[word].to_le_bytes()),
+            (Some(entry), 8) if word & 1 == 0 => {
+                data[..4].copy_from_slice(&entry.words[word].to_le_bytes());
+                data[4..].copy_from_slice(&entry.words[word + 1].to_le_bytes());
+            }
+            _ => {
+                log::warn!(
+                    "Unsupported {}-byte MSI-X table read at {:#x}",
+                    data.len(),
+                    offset
+                );
+                data.fill(0);
+            }
+        }
+    }
+
+    pub fn write_table(&mut self, config: &PciConfig, offset: u64, data: &[u8]) {
+        let vector = (offset / MSIX_ENTRY_SIZE) as usize;
+        let word = ((offset % MSIX_ENTRY_SIZE) / 4) as usize;
+
+        let entry = match self.entries.get_mut(vector) {
+            Some(entry) if data.len() == 4 || (data.len() == 8 && word & 1 == 0) => entry,
+            _ => {
+                log::warn!(
+                    "Unsupported {}-byte MSI-X table write at {:#x}",
+                    data.len(),
+                    offset
+                );
+                return;
+            }
+        };
+
+        for (index, value) in data.chunks_exact(4).enumerate() {
+            entry.words[word + index] =
+                u32::from_le_bytes([value[0], value[1], value[2], value[3]]);
+        }
+
+        let entry = *entry;
+        if word < MSIX_ENTRY_VECTOR_CTRL || data.len() == 8 {
+            if let Some(gsi) = self.gsis[vector] {
+                self.irq_chip
+                    .set_msi_route(gsi, entry.address(), entry.data());
+            }
+        }
+
+        self.deliver_pending(config);
+    }
+
+    pub fn read_pba(&self, offset: u64, data: &mut [u8]) {
+        for (index, byte) in data.iter_mut().enumerate() {
+            let first_vector = (offset as usize + index) * 8;
+            *byte = (0..8)
+                .filter(|bit| self.pending.get(first_vector + bit) == Some(&true))
+                .fold(0, |byte, bit| byte | 1 << bit);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::smolvm::pci::PCI_CAPABILITY_LIST;
+
+    /// A GSI per vector from 100 up, keeps the messages sent
+    #[derive(Default)]
+    struct TestIrqChip {
+        next_gsi: Mutex<u32>,
+        routes: Mutex<Vec<(u32, u64, u32)>>,
+        signaled: Mutex<Vec<u32>>,
+    }
+
+    impl IrqChip for TestIrqChip {
+        fn set_irq_line(&self, _irq: u32, _level: bool) {}
+
+        fn allocate_msi(&self, _requester_id: u32) -> Option<u32> {
+            let mut next_gsi = self.next_gsi.lock().unwrap();
+            *next_gsi += 1;
+            Some(99 + *next_gsi)
+        }
+
+        fn set_msi_route(&self, gsi: u32, address: u64, data: u32) {
+            self.routes.lock().unwrap().push((gsi, address, data));
+        }
+
+        fn signal_msi(&self, gsi: u32) {
+            self.signaled.lock().unwrap().push(gsi);
+        }
+    }
+
+    #[test]
+    fn test_table() {
+        let irq_chip = Arc::new(TestIrqChip::default());
+        let mut config = PciConfig::new(0x1af4, 0x1041, 0, 1, 0x1af4, 0x41);
+        config.add_bar(1, 0x4000);
+        let mut msix = MsixTable::new(3, 8, irq_chip.clone(), &mut config, 1, 0x2000, 0x3000);
+
+        // The table and the PBA in BAR 1
+        let cap_offset = config.read_u8(PCI_CAPABILITY_LIST) as usize;
+        assert_eq!(config.read_u8(cap_offset), PCI_CAP_ID_MSIX);
+        assert_eq!(config.read_u16(cap_offset + 2), 2);
+        assert_eq!(config.read_u32(cap_offset + 4), 0x2001);
+        assert_eq!(config.read_u32(cap_offset + 8), 0x3001);
+
+        // Masked at first
+        let mut data = [0_u8; 4];
+        msix.read_table(MSIX_ENTRY_SIZE + 12, &mut data);
+        assert_eq!(u32::from_le_bytes(data), MSIX_ENTRY_CTRL_MASKBIT);
+
+        // The address in one access, the data in another
+        msix.write_table(&config, MSIX_ENTRY_SIZE, &0xfee0_1000_u64.to_le_bytes());
+        msix.write_table(&config, MSIX_ENTRY_SIZE + 8, &0x41_u32.to_le_bytes());
+        assert_eq!(
+            irq_chip.routes.lock().unwrap().last(),
+            Some(&(101, 0xfee0_1000, 0x41))
+        );
+        let mut data = [0_u8; 8];
+        msix.read_table(MSIX_ENTRY_SIZE, &mut data);
+        assert_eq!(u64::from_le_bytes(data), 0xfee0_1000);
+
+        // Nothing sent before the driver enables MSI-X
+        msix.notify(&config, 1);
+        config.write(cap_offset + 2, &MSIX_FLAGS_ENABLE.to_le_bytes());
+        msix.deliver_pending(&config);
+        assert!(irq_chip.signaled.lock().unwrap().is_empty());
+
+        // Pending while masked, sent once unmasked
+        msix.notify(&config, 1);
+        let mut pba = [0_u8; 1];
+        msix.read_pba(0, &mut pba);
+        assert_eq!(pba, [0b10]);
+        msix.write_table(&config, MSIX_ENTRY_SIZE + 12, &0_u32.to_le_bytes());
+        msix.read_pba(0, &mut pba);
+        assert_eq!(pba, [0]);
+        assert_eq!(*irq_chip.signaled.lock().unwrap(), [101]);
+
+        // Function mask
+        config.write(
+            cap_offset + 2,
+            &(MSIX_FLAGS_ENABLE | MSIX_FLAGS_MASKALL).to_le_bytes(),
+        );
+        msix.notify(&config, 1);
+        msix.read_pba(0, &mut pba);
+        assert_eq!(pba, [0b10]);
+        config.write(cap_offset + 2, &MSIX_FLAGS_ENABLE.to_le_bytes());
+        msix.deliver_pending(&config);
+        assert_eq!(*irq_chip.signaled.lock().unwrap(), [101, 101]);
+
+        // Power-on state
+        msix.notify(&config, 1);
+        config.reset();
+        msix.reset();
+        assert!(!msix.is_enabled(&config));
+        let mut data = [0_u8; 4];
+        msix.read_table(MSIX_ENTRY_SIZE + 12, &mut data);
+        assert_eq!(u32::from_le_bytes(data), MSIX_ENTRY_CTRL_MASKBIT);
+        msix.read_pba(0, &mut pba);
+        assert_eq!(pba, [0]);
+    }
+}
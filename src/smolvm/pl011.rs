@@ -31,6 +31,8 @@
 
 use zerocopy::AsBytes;
 
+use super::bus::MmIoDevice;
+
 const UART_DR: usize = 0x000;
 const UART_RSR: usize = 0x004;
 const UART_FR: usize = 0x018;
@@ -74,6 +76,8 @@ enum Pl011Access {
     Write,
 }
 
+pub const PL011_MMIO_SIZE: u64 = 0x1000;
+
 pub struct UartPl011 {
     base_addr: u64,
     registers: Vec<u32>,
@@ -220,3 +224,25 @@ impl UartPl011 {
         }
     }
 }
+
+impl MmIoDevice for UartPl011 {
+    fn mmio_read(&mut self, addr: u64, data: &mut [u8]) {
+        if let Some(value) = self.read(addr) {
+            let len = data.len().min(4);
+            data[..len].copy_from_slice(&value.to_le_bytes()[..len]);
+        }
+    }
+
+    fn mmio_write(&mut self, addr: u64, data: &[u8]) {
+        let mut value = [0_u8; 4];
+        let len = data.len().min(4);
+        value[..len].copy_from_slice(&data[..len]);
+
+        self.write(addr, u32::from_le_bytes(value));
+    }
+
+    // The output that has not made it to the host yet stays
+    fn reset(&mut self) {
+        self.registers = Self::new(self.base_addr).registers;
+    }
+}
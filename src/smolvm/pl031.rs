@@ -0,0 +1,245 @@
+/*
+    PL031 Registers:
+
+    Offset  Name              Type Reset        Bits    Description
+    ----------------------------------------------------------------------
+    0x000   RTCDR             RO   0x00000000   32      Data Register
+    0x004   RTCMR             RW   0x00000000   32      Match Register
+    0x008   RTCLR             RW   0x00000000   32      Load Register
+    0x00C   RTCCR             RW   0x0          1       Control Register
+    0x010   RTCIMSC           RW   0x0          1       Interrupt Mask Set/Clear Register
+    0x014   RTCRIS            RO   0x0          1       Raw Interrupt Status Register
+    0x018   RTCMIS            RO   0x0          1       Masked Interrupt Status Register
+    0x01C   RTCICR            WO   -            1       Interrupt Clear Register
+    0xFE0   RTCPeriphID0      RO   0x31         8       RTCPeriphID0 Register
+    0xFE4   RTCPeriphID1      RO   0x10         8       RTCPeriphID1 Register
+    0xFE8   RTCPeriphID2      RO   0x_4         8       RTCPeriphID2 Register
+    0xFEC   RTCPeriphID3      RO   0x00         8       RTCPeriphID3 Register
+    0xFF0   RTCPCellID0       RO   0x0D         8       RTCPCellID0 Register
+    0xFF4   RTCPCellID1       RO   0xF0         8       RTCPCellID1 Register
+    0xFF8   RTCPCellID2       RO   0x05         8       RTCPCellID2 Register
+    0xFFC   RTCPCellID3       RO   0xB1         8       RTCPCellID3 Register
+
+    The data register counts the seconds since the epoch, from the host
+    clock or from the pinned time. The match interrupt is raised when the
+    counter reaches the match register, a timer thread waits for that.
+*/
+
+use std::{
+    sync::{Arc, Condvar, Mutex, Weak},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use super::{bus::MmIoDevice, IrqChip};
+
+const RTC_DR: usize = 0x000;
+const RTC_MR: usize = 0x004;
+const RTC_LR: usize = 0x008;
+const RTC_CR: usize = 0x00C;
+const RTC_IMSC: usize = 0x010;
+const RTC_RIS: usize = 0x014;
+const RTC_MIS: usize = 0x018;
+const RTC_ICR: usize = 0x01C;
+
+const RTC_PERIPH_ID0: usize = 0xFE0;
+const RTC_PCELL_ID3: usize = 0xFFC;
+
+const RTC_CR_START: u32 = 1;
+const RTC_INTERRUPT: u32 = 1;
+
+/// Checks on the VM going away at least that often
+const TIMER_MAX_WAIT: Duration = Duration::from_secs(1);
+
+/// Where the `virt` machine of `qemu` has it
+pub const PL031_BASE: u64 = 0x0901_0000;
+pub const PL031_MMIO_SIZE: u64 = 0x1000;
+pub const PL031_SPI: u32 = 2;
+
+struct RtcState {
+    /// The counter at `load_instant`
+    load: u32,
+    load_instant: Instant,
+    match_value: u32,
+    /// When the counter reaches the match value, `None` once it has
+    match_at: Option<Instant>,
+    mask: u32,
+    raw: u32,
+}
+
+impl RtcState {
+    fn counter(&self) -> u32 {
+        self.load
+            .wrapping_add(self.load_instant.elapsed().as_secs() as u32)
+    }
+
+    /// Called when the counter or the match value change, a value behind
+    /// the counter matches once the counter wraps
+    fn arm_match(&mut self) {
+        let elapsed = self.load_instant.elapsed().as_secs();
+        let ahead = self.match_value.wrapping_sub(self.counter()) as u64;
+        self.match_at = Some(self.load_instant + Duration::from_secs(elapsed + ahead));
+    }
+}
+
+struct RtcShared {
+    state: Mutex<RtcState>,
+    /// Wakes the timer thread up when the guest changes the match value
+    changed: Condvar,
+    irq_chip: Option<Arc<dyn IrqChip>>,
+}
+
+impl RtcShared {
+    fn update_irq_line(&self, state: &RtcState) {
+        if let Some(irq_chip) = &self.irq_chip {
+            irq_chip.set_irq_line(PL031_SPI, state.raw & state.mask != 0);
+        }
+    }
+}
+
+pub struct RtcPl031 {
+    base_addr: u64,
+    id: [u8; 8],
+    shared: Arc<RtcShared>,
+}
+
+impl RtcPl031 {
+    /// The counter starts at `epoch` if given, at the host time otherwise.
+    /// Without the irqchip there is no match interrupt.
+    pub fn new(base_addr: u64, epoch: Option<u64>, irq_chip: Option<Arc<dyn IrqChip>>) -> Self {
+        let load = match epoch {
+            Some(epoch) => epoch as u32,
+            None => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs() as u32,
+        };
+
+        let id = [0x31, 0x10, 0x14, 0x00, 0x0D, 0xF0, 0x05, 0xB1];
+
+        let shared = Arc::new(RtcShared {
+            state: Mutex::new(RtcState {
+                load,
+                load_instant: Instant::now(),
+                match_value: 0,
+                match_at: None,
+                mask: 0,
+                raw: 0,
+            }),
+            changed: Condvar::new(),
+            irq_chip,
+        });
+
+        if shared.irq_chip.is_some() {
+            let shared = Arc::downgrade(&shared);
+            std::thread::spawn(move || Self::run_timer(shared));
+        }
+
+        Self {
+            base_addr,
+            id,
+            shared,
+        }
+    }
+
+    fn run_timer(shared: Weak<RtcShared>) {
+        while let Some(shared) = shared.upgrade() {
+            let state = shared.state.lock().unwrap();
+            let wait = state.match_at.map_or(TIMER_MAX_WAIT, |at| {
+                at.saturating_duration_since(Instant::now())
+                    .min(TIMER_MAX_WAIT)
+            });
+
+            let (mut state, _) = shared.changed.wait_timeout(state, wait).unwrap();
+            if let Some(at) = state.match_at {
+                if Instant::now() >= at {
+                    state.match_at = None;
+                    state.raw |= RTC_INTERRUPT;
+                    shared.update_irq_line(&state);
+                }
+            }
+        }
+    }
+
+    pub fn read(&mut self, addr: u64) -> Option<u32> {
+        let offset = addr.checked_sub(self.base_addr)? as usize;
+        let state = self.shared.state.lock().unwrap();
+
+        match offset {
+            RTC_DR => Some(state.counter()),
+            RTC_MR => Some(state.match_value),
+            RTC_LR => Some(state.load),
+            // Always running
+            RTC_CR => Some(RTC_CR_START),
+            RTC_IMSC => Some(state.mask),
+            RTC_RIS => Some(state.raw),
+            RTC_MIS => Some(state.raw & state.mask),
+            RTC_PERIPH_ID0..=RTC_PCELL_ID3 if offset & 3 == 0 => {
+                Some(self.id[(offset - RTC_PERIPH_ID0) >> 2] as u32)
+            }
+            _ => {
+                log::warn!("Unsupported MMIO read from 0x{:x}", addr);
+                None
+            }
+        }
+    }
+
+    pub fn write(&mut self, addr: u64, value: u32) {
+        let offset = match addr.checked_sub(self.base_addr) {
+            Some(offset) => offset as usize,
+            None => return,
+        };
+        let mut state = self.shared.state.lock().unwrap();
+
+        match offset {
+            RTC_MR => {
+                state.match_value = value;
+                state.arm_match();
+            }
+            RTC_LR => {
+                log::info!("Guest has set the RTC to {} seconds past the epoch", value);
+                state.load = value;
+                state.load_instant = Instant::now();
+                state.arm_match();
+            }
+            RTC_CR => {}
+            RTC_IMSC => state.mask = value & RTC_INTERRUPT,
+            RTC_ICR => state.raw &= !(value & RTC_INTERRUPT),
+            _ => {
+                log::warn!("Unsupported MMIO write to 0x{:x}", addr);
+                return;
+            }
+        }
+
+        self.shared.update_irq_line(&state);
+        self.shared.changed.notify_one();
+    }
+}
+
+impl MmIoDevice for RtcPl031 {
+    fn mmio_read(&mut self, addr: u64, data: &mut [u8]) {
+        if let Some(value) = self.read(addr) {
+            let len = data.len().min(4);
+            data[..len].copy_from_slice(&value.to_le_bytes()[..len]);
+        }
+    }
+
+    fn mmio_write(&mut self, addr: u64, data: &[u8]) {
+        let mut value = [0_u8; 4];
+        let len = data.len().min(4);
+        value[..len].copy_from_slice(&data[..len]);
+
+        self.write(addr, u32::from_le_bytes(value));
+    }
+
+    // The counter keeps running as if battery-backed
+    fn reset(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.match_value = 0;
+        state.match_at = None;
+        state.mask = 0;
+        state.raw = 0;
+
+        self.shared.update_irq_line(&state);
+        self.shared.changed.notify_one();
+    }
+}
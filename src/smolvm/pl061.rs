@@ -0,0 +1,210 @@
+/*
+    PL061 Registers:
+
+    Offset  Name              Type Reset        Bits    Description
+    ----------------------------------------------------------------------
+    0x000   GPIODATA          RW   0x00         8       Data Register, bits [9:2] of
+                                                        the address mask the access
+    0x400   GPIODIR           RW   0x00         8       Data Direction Register
+    0x404   GPIOIS            RW   0x00         8       Interrupt Sense Register
+    0x408   GPIOIBE           RW   0x00         8       Interrupt Both Edges Register
+    0x40C   GPIOIEV           RW   0x00         8       Interrupt Event Register
+    0x410   GPIOIE            RW   0x00         8       Interrupt Mask Register
+    0x414   GPIORIS           RO   0x00         8       Raw Interrupt Status Register
+    0x418   GPIOMIS           RO   0x00         8       Masked Interrupt Status Register
+    0x41C   GPIOIC            WO   -            8       Interrupt Clear Register
+    0x420   GPIOAFSEL         RW   0x00         8       Mode Control Select Register
+    0xFE0   GPIOPeriphID0     RO   0x61         8       GPIOPeriphID0 Register
+    0xFE4   GPIOPeriphID1     RO   0x10         8       GPIOPeriphID1 Register
+    0xFE8   GPIOPeriphID2     RO   0x_4         8       GPIOPeriphID2 Register
+    0xFEC   GPIOPeriphID3     RO   0x00         8       GPIOPeriphID3 Register
+    0xFF0   GPIOPCellID0      RO   0x0D         8       GPIOPCellID0 Register
+    0xFF4   GPIOPCellID1      RO   0xF0         8       GPIOPCellID1 Register
+    0xFF8   GPIOPCellID2      RO   0x05         8       GPIOPCellID2 Register
+    0xFFC   GPIOPCellID3      RO   0xB1         8       GPIOPCellID3 Register
+
+    The host drives the input lines, e.g. the power button of `gpio-keys`
+    is the line 3 as on the `virt` machine of `qemu`.
+*/
+
+use std::{sync::Arc, time::Duration};
+
+use super::{bus::MmIoDevice, IrqChip};
+
+const GPIO_DATA: usize = 0x000;
+const GPIO_DATA_END: usize = 0x3FC;
+const GPIO_DIR: usize = 0x400;
+const GPIO_IS: usize = 0x404;
+const GPIO_IBE: usize = 0x408;
+const GPIO_IEV: usize = 0x40C;
+const GPIO_IE: usize = 0x410;
+const GPIO_RIS: usize = 0x414;
+const GPIO_MIS: usize = 0x418;
+const GPIO_IC: usize = 0x41C;
+const GPIO_AFSEL: usize = 0x420;
+
+const GPIO_PERIPH_ID0: usize = 0xFE0;
+const GPIO_PCELL_ID3: usize = 0xFFC;
+
+/// Where the `virt` machine of `qemu` has it
+pub const PL061_BASE: u64 = 0x0903_0000;
+pub const PL061_MMIO_SIZE: u64 = 0x1000;
+pub const PL061_SPI: u32 = 7;
+
+pub const PL061_POWER_BUTTON_LINE: u8 = 3;
+/// KEY_POWER of the Linux input events
+pub const KEY_POWER: u32 = 116;
+/// How long the host holds a button down, `gpio-keys` debounces for 5ms
+pub const PL061_KEY_PRESS: Duration = Duration::from_millis(100);
+
+pub struct GpioPl061 {
+    base_addr: u64,
+    id: [u8; 8],
+    data: u8,
+    dir: u8,
+    is: u8,
+    ibe: u8,
+    iev: u8,
+    ie: u8,
+    ris: u8,
+    afsel: u8,
+    /// The levels the host drives the lines to
+    inputs: u8,
+    irq_chip: Option<Arc<dyn IrqChip>>,
+}
+
+impl GpioPl061 {
+    /// Without the irqchip the guest can only poll the lines
+    pub fn new(base_addr: u64, irq_chip: Option<Arc<dyn IrqChip>>) -> Self {
+        Self {
+            base_addr,
+            id: [0x61, 0x10, 0x04, 0x00, 0x0D, 0xF0, 0x05, 0xB1],
+            data: 0,
+            dir: 0,
+            is: 0,
+            ibe: 0,
+            iev: 0,
+            ie: 0,
+            ris: 0,
+            afsel: 0,
+            inputs: 0,
+            irq_chip,
+        }
+    }
+
+    /// The outputs the guest drives and the inputs the host does
+    fn levels(&self) -> u8 {
+        (self.data & self.dir) | (self.inputs & !self.dir)
+    }
+
+    /// Drives the input line, raises the interrupt if the guest has set it up
+    pub fn set_input(&mut self, line: u8, level: bool) {
+        let old_levels = self.levels();
+        if level {
+            self.inputs |= 1 << line;
+        } else {
+            self.inputs &= !(1 << line);
+        }
+
+        self.update_interrupts(old_levels);
+    }
+
+    fn update_interrupts(&mut self, old_levels: u8) {
+        let levels = self.levels();
+
+        // Edges: either one or the one the event register selects
+        let changed = (old_levels ^ levels) & !self.is;
+        let rising = changed & levels;
+        let falling = changed & !levels;
+        self.ris |= changed & self.ibe;
+        self.ris |= (rising & self.iev | falling & !self.iev) & !self.ibe;
+
+        // Levels: high or low as the event register selects
+        let active = !(levels ^ self.iev);
+        self.ris = (self.ris & !self.is) | (active & self.is);
+
+        if let Some(irq_chip) = &self.irq_chip {
+            irq_chip.set_irq_line(PL061_SPI, self.ris & self.ie != 0);
+        }
+    }
+
+    pub fn read(&mut self, addr: u64) -> Option<u32> {
+        let offset = addr.checked_sub(self.base_addr)? as usize;
+
+        let value = match offset {
+            GPIO_DATA..=GPIO_DATA_END => self.levels() & (offset >> 2) as u8,
+            GPIO_DIR => self.dir,
+            GPIO_IS => self.is,
+            GPIO_IBE => self.ibe,
+            GPIO_IEV => self.iev,
+            GPIO_IE => self.ie,
+            GPIO_RIS => self.ris,
+            GPIO_MIS => self.ris & self.ie,
+            GPIO_AFSEL => self.afsel,
+            GPIO_PERIPH_ID0..=GPIO_PCELL_ID3 if offset & 3 == 0 => {
+                self.id[(offset - GPIO_PERIPH_ID0) >> 2]
+            }
+            _ => {
+                log::warn!("Unsupported MMIO read from 0x{:x}", addr);
+                return None;
+            }
+        };
+
+        Some(value as u32)
+    }
+
+    pub fn write(&mut self, addr: u64, value: u32) {
+        let offset = match addr.checked_sub(self.base_addr) {
+            Some(offset) => offset as usize,
+            None => return,
+        };
+        let value = value as u8;
+        let old_levels = self.levels();
+
+        match offset {
+            GPIO_DATA..=GPIO_DATA_END => {
+                let mask = (offset >> 2) as u8 & self.dir;
+                self.data = (self.data & !mask) | (value & mask);
+            }
+            GPIO_DIR => self.dir = value,
+            GPIO_IS => self.is = value,
+            GPIO_IBE => self.ibe = value,
+            GPIO_IEV => self.iev = value,
+            GPIO_IE => self.ie = value,
+            GPIO_IC => self.ris &= !value,
+            GPIO_AFSEL => self.afsel = value,
+            _ => {
+                log::warn!("Unsupported MMIO write to 0x{:x}", addr);
+                return;
+            }
+        }
+
+        self.update_interrupts(old_levels);
+    }
+}
+
+impl MmIoDevice for GpioPl061 {
+    fn mmio_read(&mut self, addr: u64, data: &mut [u8]) {
+        if let Some(value) = self.read(addr) {
+            let len = data.len().min(4);
+            data[..len].copy_from_slice(&value.to_le_bytes()[..len]);
+        }
+    }
+
+    fn mmio_write(&mut self, addr: u64, data: &[u8]) {
+        let mut value = [0_u8; 4];
+        let len = data.len().min(4);
+        value[..len].copy_from_slice(&data[..len]);
+
+        self.write(addr, u32::from_le_bytes(value));
+    }
+
+    // The host keeps driving the inputs
+    fn reset(&mut self) {
+        let inputs = self.inputs;
+        *self = Self::new(self.base_addr, self.irq_chip.take());
+        self.inputs = inputs;
+
+        self.update_interrupts(self.levels());
+    }
+}
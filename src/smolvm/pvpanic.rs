@@ -0,0 +1,75 @@
+//! The pvpanic device of `qemu` the guest kernel reports its panics to
+//! (CONFIG_PVPANIC). Reading the register gives the events the device
+//! supports, the guest writes the event that has happened:
+//!
+//!   bit 0  PANICKED      the kernel has panicked
+//!   bit 1  CRASH_LOADED  the kernel has panicked and runs the crash kernel
+//!
+//! The device is the ISA port 0x505 described in the DSDT (QEMU0001) on
+//! x86_64 and an MMIO register with the `qemu,pvpanic-mmio` device tree node
+//! on aarch64. Either event stops the vCPU loop.
+
+use super::bus::{ExitRequest, ExitRequester, IoDevice, MmIoDevice};
+
+pub const PVPANIC_PANICKED: u8 = 1 << 0;
+pub const PVPANIC_CRASH_LOADED: u8 = 1 << 1;
+
+const PVPANIC_EVENTS: u8 = PVPANIC_PANICKED | PVPANIC_CRASH_LOADED;
+
+#[cfg(target_arch = "x86_64")]
+pub const PVPANIC_PORT: u16 = 0x505;
+#[cfg(target_arch = "x86_64")]
+pub const PVPANIC_PORT_COUNT: u16 = 1;
+
+/// Next to the debug exit device
+#[cfg(target_arch = "aarch64")]
+pub const PVPANIC_BASE: u64 = 0x0911_0000;
+#[cfg(target_arch = "aarch64")]
+pub const PVPANIC_MMIO_SIZE: u64 = 0x1000;
+
+pub struct PvPanic {
+    exit_requester: ExitRequester,
+}
+
+impl PvPanic {
+    pub fn new(exit_requester: ExitRequester) -> Self {
+        Self { exit_requester }
+    }
+
+    fn read(&self, data: &mut [u8]) {
+        data.fill(0);
+        if let Some(byte) = data.first_mut() {
+            *byte = PVPANIC_EVENTS;
+        }
+    }
+
+    fn write(&mut self, data: &[u8]) {
+        let events = data.first().copied().unwrap_or_default() & PVPANIC_EVENTS;
+        if events == 0 {
+            return;
+        }
+
+        log::error!("Guest kernel has panicked, events {:#x}", events);
+        self.exit_requester.request(ExitRequest::Panic(events));
+    }
+}
+
+impl IoDevice for PvPanic {
+    fn io_in(&mut self, _port: u16, data: &mut [u8]) {
+        self.read(data);
+    }
+
+    fn io_out(&mut self, _port: u16, data: &[u8]) {
+        self.write(data);
+    }
+}
+
+impl MmIoDevice for PvPanic {
+    fn mmio_read(&mut self, _addr: u64, data: &mut [u8]) {
+        self.read(data);
+    }
+
+    fn mmio_write(&mut self, _addr: u64, data: &[u8]) {
+        self.write(data);
+    }
+}
@@ -0,0 +1,296 @@
+/*
+    SP805 Registers:
+
+    Offset  Name              Type Reset        Bits    Description
+    ----------------------------------------------------------------------
+    0x000   WdogLoad          RW   0xFFFFFFFF   32      Load Register
+    0x004   WdogValue         RO   0xFFFFFFFF   32      Value Register
+    0x008   WdogControl       RW   0x0          2       Control Register
+    0x00C   WdogIntClr        WO   -            -       Interrupt Clear Register
+    0x010   WdogRIS           RO   0x0          1       Raw Interrupt Status Register
+    0x014   WdogMIS           RO   0x0          1       Masked Interrupt Status Register
+    0xC00   WdogLock          RW   0x0          32      Lock Register
+    0xFE0   WdogPeriphID0     RO   0x05         8       WdogPeriphID0 Register
+    0xFE4   WdogPeriphID1     RO   0x18         8       WdogPeriphID1 Register
+    0xFE8   WdogPeriphID2     RO   0x14         8       WdogPeriphID2 Register
+    0xFEC   WdogPeriphID3     RO   0x00         8       WdogPeriphID3 Register
+    0xFF0   WdogPCellID0      RO   0x0D         8       WdogPCellID0 Register
+    0xFF4   WdogPCellID1      RO   0xF0         8       WdogPCellID1 Register
+    0xFF8   WdogPCellID2      RO   0x05         8       WdogPCellID2 Register
+    0xFFC   WdogPCellID3      RO   0xB1         8       WdogPCellID3 Register
+
+    The counter runs down from the load value at the APB clock rate while
+    the interrupt is enabled. Reaching zero raises the interrupt and reloads
+    the counter, reaching zero again with the interrupt still raised resets
+    the machine if enabled, the VM posts `ExitRequest::Watchdog` for that.
+    A timer thread waits for the counter to reach zero.
+*/
+
+use std::{
+    sync::{Arc, Condvar, Mutex, Weak},
+    time::{Duration, Instant},
+};
+
+use super::{
+    bus::{ExitRequest, ExitRequester, MmIoDevice},
+    fdt::APB_PCLK_FREQUENCY,
+    IrqChip,
+};
+
+const WDOG_LOAD: usize = 0x000;
+const WDOG_VALUE: usize = 0x004;
+const WDOG_CONTROL: usize = 0x008;
+const WDOG_INT_CLR: usize = 0x00C;
+const WDOG_RIS: usize = 0x010;
+const WDOG_MIS: usize = 0x014;
+const WDOG_LOCK: usize = 0xC00;
+
+const WDOG_PERIPH_ID0: usize = 0xFE0;
+const WDOG_PCELL_ID3: usize = 0xFFC;
+
+const WDOG_CONTROL_INTEN: u32 = 1 << 0;
+const WDOG_CONTROL_RESEN: u32 = 1 << 1;
+const WDOG_INTERRUPT: u32 = 1;
+/// Writing it enables the writes to the other registers, any other value
+/// disables them
+const WDOG_UNLOCK_KEY: u32 = 0x1ACC_E551;
+
+const WDOG_LOAD_RESET: u32 = 0xFFFF_FFFF;
+
+/// Checks on the VM going away at least that often
+const TIMER_MAX_WAIT: Duration = Duration::from_secs(1);
+
+/// Next to the pvpanic device, not on the `virt` machine of `qemu`
+pub const SP805_BASE: u64 = 0x0912_0000;
+pub const SP805_MMIO_SIZE: u64 = 0x1000;
+/// Past the ones the `virt` machine of `qemu` uses below the virtio-mmio
+/// devices
+pub const SP805_SPI: u32 = 10;
+
+struct WatchdogState {
+    load: u32,
+    control: u32,
+    raw: u32,
+    locked: bool,
+    /// When the counter has been reloaded, `None` while it is stopped
+    loaded_at: Option<Instant>,
+    /// The counter as it has been stopped at
+    stopped_value: u32,
+}
+
+impl WatchdogState {
+    fn period(&self) -> Duration {
+        Duration::from_nanos(
+            ((self.load as u128 + 1) * 1_000_000_000 / APB_PCLK_FREQUENCY as u128) as u64,
+        )
+    }
+
+    fn counter(&self) -> u32 {
+        match self.loaded_at {
+            Some(loaded_at) => {
+                let ticks =
+                    loaded_at.elapsed().as_nanos() * APB_PCLK_FREQUENCY as u128 / 1_000_000_000;
+                self.load.saturating_sub(ticks.min(u32::MAX as u128) as u32)
+            }
+            None => self.stopped_value,
+        }
+    }
+
+    /// When the counter reaches zero
+    fn expires_at(&self) -> Option<Instant> {
+        self.loaded_at.map(|loaded_at| loaded_at + self.period())
+    }
+
+    fn reload(&mut self) {
+        if self.control & WDOG_CONTROL_INTEN != 0 {
+            self.loaded_at = Some(Instant::now());
+        }
+    }
+}
+
+struct WatchdogShared {
+    state: Mutex<WatchdogState>,
+    /// Wakes the timer thread up when the guest reloads the counter
+    changed: Condvar,
+    irq_chip: Option<Arc<dyn IrqChip>>,
+    exit_requester: ExitRequester,
+}
+
+impl WatchdogShared {
+    fn update_irq_line(&self, state: &WatchdogState) {
+        if let Some(irq_chip) = &self.irq_chip {
+            irq_chip.set_irq_line(
+                SP805_SPI,
+                state.raw & state.control & WDOG_CONTROL_INTEN != 0,
+            );
+        }
+    }
+}
+
+pub struct WatchdogSp805 {
+    base_addr: u64,
+    id: [u8; 8],
+    shared: Arc<WatchdogShared>,
+}
+
+impl WatchdogSp805 {
+    /// Without the irqchip the guest only gets the reset
+    pub fn new(
+        base_addr: u64,
+        exit_requester: ExitRequester,
+        irq_chip: Option<Arc<dyn IrqChip>>,
+    ) -> Self {
+        let id = [0x05, 0x18, 0x14, 0x00, 0x0D, 0xF0, 0x05, 0xB1];
+
+        let shared = Arc::new(WatchdogShared {
+            state: Mutex::new(WatchdogState {
+                load: WDOG_LOAD_RESET,
+                control: 0,
+                raw: 0,
+                locked: false,
+                loaded_at: None,
+                stopped_value: WDOG_LOAD_RESET,
+            }),
+            changed: Condvar::new(),
+            irq_chip,
+            exit_requester,
+        });
+
+        {
+            let shared = Arc::downgrade(&shared);
+            std::thread::spawn(move || Self::run_timer(shared));
+        }
+
+        Self {
+            base_addr,
+            id,
+            shared,
+        }
+    }
+
+    fn run_timer(shared: Weak<WatchdogShared>) {
+        while let Some(shared) = shared.upgrade() {
+            let state = shared.state.lock().unwrap();
+            let wait = state.expires_at().map_or(TIMER_MAX_WAIT, |at| {
+                at.saturating_duration_since(Instant::now())
+                    .min(TIMER_MAX_WAIT)
+            });
+
+            let (mut state, _) = shared.changed.wait_timeout(state, wait).unwrap();
+            let at = match state.expires_at() {
+                Some(at) if Instant::now() >= at => at,
+                _ => continue,
+            };
+
+            // Counting on from zero rather than from now
+            state.loaded_at = Some(at);
+
+            if state.raw == 0 {
+                state.raw = WDOG_INTERRUPT;
+                shared.update_irq_line(&state);
+            } else if state.control & WDOG_CONTROL_RESEN != 0 {
+                log::error!("Guest has not cleared the watchdog interrupt in time");
+                shared.exit_requester.request(ExitRequest::Watchdog);
+            }
+        }
+    }
+
+    pub fn read(&mut self, addr: u64) -> Option<u32> {
+        let offset = addr.checked_sub(self.base_addr)? as usize;
+        let state = self.shared.state.lock().unwrap();
+
+        match offset {
+            WDOG_LOAD => Some(state.load),
+            WDOG_VALUE => Some(state.counter()),
+            WDOG_CONTROL => Some(state.control),
+            WDOG_RIS => Some(state.raw),
+            WDOG_MIS => Some(state.raw & state.control & WDOG_CONTROL_INTEN),
+            WDOG_LOCK => Some(state.locked as u32),
+            WDOG_PERIPH_ID0..=WDOG_PCELL_ID3 if offset & 3 == 0 => {
+                Some(self.id[(offset - WDOG_PERIPH_ID0) >> 2] as u32)
+            }
+            _ => {
+                log::warn!("Unsupported MMIO read from 0x{:x}", addr);
+                None
+            }
+        }
+    }
+
+    pub fn write(&mut self, addr: u64, value: u32) {
+        let offset = match addr.checked_sub(self.base_addr) {
+            Some(offset) => offset as usize,
+            None => return,
+        };
+        let mut state = self.shared.state.lock().unwrap();
+
+        if offset == WDOG_LOCK {
+            state.locked = value != WDOG_UNLOCK_KEY;
+            return;
+        }
+        if state.locked {
+            log::warn!("Guest has written to the locked watchdog at 0x{:x}", addr);
+            return;
+        }
+
+        match offset {
+            WDOG_LOAD => {
+                state.load = value;
+                state.reload();
+            }
+            WDOG_CONTROL => {
+                let enabled = state.control & WDOG_CONTROL_INTEN != 0;
+                state.control = value & (WDOG_CONTROL_INTEN | WDOG_CONTROL_RESEN);
+
+                match (enabled, state.control & WDOG_CONTROL_INTEN != 0) {
+                    (false, true) => state.reload(),
+                    (true, false) => {
+                        state.stopped_value = state.counter();
+                        state.loaded_at = None;
+                    }
+                    _ => {}
+                }
+            }
+            WDOG_INT_CLR => {
+                state.raw = 0;
+                state.reload();
+            }
+            _ => {
+                log::warn!("Unsupported MMIO write to 0x{:x}", addr);
+                return;
+            }
+        }
+
+        self.shared.update_irq_line(&state);
+        self.shared.changed.notify_one();
+    }
+}
+
+impl MmIoDevice for WatchdogSp805 {
+    fn mmio_read(&mut self, addr: u64, data: &mut [u8]) {
+        if let Some(value) = self.read(addr) {
+            let len = data.len().min(4);
+            data[..len].copy_from_slice(&value.to_le_bytes()[..len]);
+        }
+    }
+
+    fn mmio_write(&mut self, addr: u64, data: &[u8]) {
+        let mut value = [0_u8; 4];
+        let len = data.len().min(4);
+        value[..len].copy_from_slice(&data[..len]);
+
+        self.write(addr, u32::from_le_bytes(value));
+    }
+
+    fn reset(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.load = WDOG_LOAD_RESET;
+        state.control = 0;
+        state.raw = 0;
+        state.locked = false;
+        state.loaded_at = None;
+        state.stopped_value = WDOG_LOAD_RESET;
+
+        self.shared.update_irq_line(&state);
+        self.shared.changed.notify_one();
+    }
+}
@@ -0,0 +1,233 @@
+//! How the CPUs are grouped: the threads of a core, the cores of a die,
+//! the dies of a socket. The CPU index counts the threads first, then the
+//! cores, the dies and the sockets.
+//!
+//! On x86_64 each level takes the bits of the APIC ID its count needs,
+//! rounded up to a power of two, the CPUID leaves report the widths:
+//!
+//!   | socket | die | core | thread |
+//!
+//! On aarch64 the levels are the affinity fields of MPIDR_EL1, the thread
+//! is Aff0 only if the cores have several (MT set), a die is a cluster:
+//!
+//!   MT:     Aff3 socket  Aff2 die  Aff1 core  Aff0 thread
+//!   not MT:              Aff2 socket  Aff1 die  Aff0 core
+
+use super::{
+    fdt::{FdtNode, CPU_PHANDLE_BASE},
+    numa::{self, NumaNode},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CpuTopology {
+    pub sockets: u32,
+    pub dies: u32,
+    pub cores: u32,
+    pub threads: u32,
+}
+
+impl Default for CpuTopology {
+    fn default() -> Self {
+        Self {
+            sockets: 1,
+            dies: 1,
+            cores: 1,
+            threads: 1,
+        }
+    }
+}
+
+/// Where a CPU is in the topology, the indices are within the level above
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CpuLocation {
+    pub socket: u32,
+    pub die: u32,
+    pub core: u32,
+    pub thread: u32,
+}
+
+/// The bits of the APIC ID a level of that many takes
+fn width(count: u32) -> u32 {
+    u32::BITS - (count - 1).leading_zeros()
+}
+
+const MPIDR_RES1: u64 = 1 << 31;
+const MPIDR_MT: u64 = 1 << 24;
+/// Aff3 and Aff2..Aff0
+const MPIDR_AFFINITY_MASK: u64 = 0xff_00ff_ffff;
+
+impl CpuTopology {
+    pub fn cpu_count(&self) -> u32 {
+        self.sockets * self.dies * self.cores * self.threads
+    }
+
+    /// Every level has a CPU, the affinity fields fit in their 8 bits and
+    /// on x86_64 the APIC IDs in the MADT entries
+    pub fn is_valid(&self) -> bool {
+        let counts = [self.sockets, self.dies, self.cores, self.threads];
+        if counts.contains(&0) || counts.iter().any(|count| *count > 256) {
+            return false;
+        }
+
+        #[cfg(target_arch = "x86_64")]
+        if self.apic_id(self.cpu_count() - 1) >= 0xff {
+            return false;
+        }
+
+        true
+    }
+
+    pub fn location(&self, cpu: u32) -> CpuLocation {
+        CpuLocation {
+            thread: cpu % self.threads,
+            core: cpu / self.threads % self.cores,
+            die: cpu / (self.threads * self.cores) % self.dies,
+            socket: cpu / (self.threads * self.cores * self.dies),
+        }
+    }
+
+    pub fn thread_width(&self) -> u32 {
+        width(self.threads)
+    }
+
+    /// The threads and the cores
+    pub fn core_width(&self) -> u32 {
+        self.thread_width() + width(self.cores)
+    }
+
+    /// The threads, the cores and the dies: the whole package
+    pub fn die_width(&self) -> u32 {
+        self.core_width() + width(self.dies)
+    }
+
+    pub fn apic_id(&self, cpu: u32) -> u32 {
+        let location = self.location(cpu);
+        (location.socket << self.die_width())
+            | (location.die << self.core_width())
+            | (location.core << self.thread_width())
+            | location.thread
+    }
+
+    pub fn mpidr(&self, cpu: u32) -> u64 {
+        let location = self.location(cpu);
+        if self.threads > 1 {
+            MPIDR_RES1
+                | MPIDR_MT
+                | (location.socket as u64) << 32
+                | (location.die as u64) << 16
+                | (location.core as u64) << 8
+                | location.thread as u64
+        } else {
+            MPIDR_RES1
+                | (location.socket as u64) << 16
+                | (location.die as u64) << 8
+                | location.core as u64
+        }
+    }
+
+    /// `/cpus` with the nodes of the first `cpu_count` CPUs in their NUMA
+    /// nodes and the `cpu-map` of the topology, replacing the one of the blob
+    pub fn fdt_cpus(&self, cpu_count: u32, numa: &[NumaNode]) -> FdtNode {
+        let mut cpus = FdtNode::new("cpus")
+            .replace()
+            .property_u32("#address-cells", 2)
+            .property_u32("#size-cells", 0);
+
+        for cpu in 0..cpu_count {
+            let affinity = self.mpidr(cpu) & MPIDR_AFFINITY_MASK;
+            let mut cpu_node = FdtNode::new(&format!("cpu@{:x}", affinity))
+                .property_string("device_type", "cpu")
+                .property_string("compatible", "arm,arm-v8")
+                .property_cells("reg", &[(affinity >> 32) as u32, affinity as u32])
+                .property_string("enable-method", "psci")
+                .property_u32("phandle", CPU_PHANDLE_BASE + cpu);
+            if let Some(node) = numa::node_of_cpu(numa, cpu) {
+                cpu_node = cpu_node.property_u32("numa-node-id", node);
+            }
+            cpus = cpus.child(cpu_node);
+        }
+
+        // Only the levels with a CPU, Linux gives up on an empty one
+        let mut cpu_map = FdtNode::new("cpu-map");
+        for socket in 0..self.sockets {
+            let mut socket_node = None;
+            for die in 0..self.dies {
+                let mut cluster = None;
+                for core in 0..self.cores {
+                    let first = ((socket * self.dies + die) * self.cores + core) * self.threads;
+                    if first >= cpu_count {
+                        break;
+                    }
+
+                    let mut core_node = FdtNode::new(&format!("core{}", core));
+                    if self.threads == 1 {
+                        core_node = core_node.property_u32("cpu", CPU_PHANDLE_BASE + first);
+                    } else {
+                        for thread in 0..self.threads.min(cpu_count - first) {
+                            core_node = core_node.child(
+                                FdtNode::new(&format!("thread{}", thread))
+                                    .property_u32("cpu", CPU_PHANDLE_BASE + first + thread),
+                            );
+                        }
+                    }
+                    cluster = Some(
+                        cluster
+                            .unwrap_or_else(|| FdtNode::new(&format!("cluster{}", die)))
+                            .child(core_node),
+                    );
+                }
+                if let Some(cluster) = cluster {
+                    socket_node = Some(
+                        socket_node
+                            .unwrap_or_else(|| FdtNode::new(&format!("socket{}", socket)))
+                            .child(cluster),
+                    );
+                }
+            }
+            if let Some(socket_node) = socket_node {
+                cpu_map = cpu_map.child(socket_node);
+            }
+        }
+
+        cpus.child(cpu_map)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ids() {
+        let topology = CpuTopology {
+            sockets: 2,
+            dies: 1,
+            cores: 3,
+            threads: 2,
+        };
+        assert!(topology.is_valid());
+        assert_eq!(topology.cpu_count(), 12);
+        assert_eq!(topology.die_width(), 3);
+
+        // Socket 1, core 2, thread 1: APIC ID 1|10|1
+        assert_eq!(
+            topology.location(11),
+            CpuLocation {
+                socket: 1,
+                die: 0,
+                core: 2,
+                thread: 1,
+            }
+        );
+        assert_eq!(topology.apic_id(11), 0b1101);
+        assert_eq!(topology.mpidr(11), 0x1_8100_0201);
+
+        #[cfg(target_arch = "x86_64")]
+        assert!(!CpuTopology {
+            cores: 128,
+            threads: 4,
+            ..Default::default()
+        }
+        .is_valid());
+    }
+}
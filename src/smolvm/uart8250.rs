@@ -65,6 +65,8 @@
 
 use zerocopy::AsBytes;
 
+use super::bus::IoDevice;
+
 pub enum UartBase {
     Com1,
     Com2,
@@ -96,6 +98,8 @@ pub struct Uart8250 {
 }
 
 impl Uart8250 {
+    pub const PORT_COUNT: u16 = 8;
+
     pub fn new(base: UartBase) -> Self {
         let mut uart = Self {
             base_addr: match base {
@@ -107,14 +111,19 @@ impl Uart8250 {
             buffer: Vec::with_capacity(512),
             ..Default::default()
         };
-
-        uart.registers[LSR_OFFSET as usize] = 0x21; // THRE | RBF
-        uart.registers[LCR_OFFSET as usize] = 0x3; // 8 bits, 1 stop bit, no parity
-        uart.registers[MCR_OFFSET as usize] = 0x3; // DTR | RTS
+        uart.reset_registers();
 
         uart
     }
 
+    fn reset_registers(&mut self) {
+        self.divisor_latch = [0; 2];
+        self.registers = [0; 8];
+        self.registers[LSR_OFFSET as usize] = 0x21; // THRE | RBF
+        self.registers[LCR_OFFSET as usize] = 0x3; // 8 bits, 1 stop bit, no parity
+        self.registers[MCR_OFFSET as usize] = 0x3; // DTR | RTS
+    }
+
     pub fn write_byte(&mut self, address: u16, data: u8) {
         if self.divisor_latch_active() {
             if address == self.base_addr {
@@ -215,6 +224,10 @@ impl Uart8250 {
         self.registers[LCR_OFFSET as usize] & 0x80 != 0
     }
 
+    pub fn base_addr(&self) -> u16 {
+        self.base_addr
+    }
+
     fn register_offset(&self, address: u16) -> Option<usize> {
         if address >= self.base_addr && address < self.base_addr + self.registers.len() as u16 {
             Some((address - self.base_addr) as usize)
@@ -223,3 +236,44 @@ impl Uart8250 {
         }
     }
 }
+
+impl IoDevice for Uart8250 {
+    fn io_in(&mut self, port: u16, data: &mut [u8]) {
+        match data.len() {
+            1 => {
+                if let Some(byte) = self.read_byte(port) {
+                    data[0] = byte;
+                }
+            }
+            2 => {
+                if let Some(word) = self.read_word(port) {
+                    data.copy_from_slice(&word.to_le_bytes());
+                }
+            }
+            _ => log::warn!(
+                "Reading {} bytes from {:#x}, base {:#x}, not implemented",
+                data.len(),
+                port,
+                self.base_addr
+            ),
+        }
+    }
+
+    fn io_out(&mut self, port: u16, data: &[u8]) {
+        match data.len() {
+            1 => self.write_byte(port, data[0]),
+            2 => self.write_word(port, u16::from_le_bytes([data[0], data[1]])),
+            _ => log::warn!(
+                "Writing {} bytes to {:#x}, base {:#x}, not implemented",
+                data.len(),
+                port,
+                self.base_addr
+            ),
+        }
+    }
+
+    // The output that has not made it to the host yet stays
+    fn reset(&mut self) {
+        self.reset_registers();
+    }
+}
@@ -0,0 +1,393 @@
+//! virtio-balloon device: the guest gives its memory back to the host.
+//!
+//! The host sets the target size of the balloon in pages, the guest inflates
+//! the balloon by sending the PFNs of the pages it no longer uses, and these
+//! are released from the host mapping. With the free page reporting the guest
+//! also reports the free pages of its buddy allocator which get released the
+//! same way. The pages come back zeroed on the next access.
+
+use std::sync::{Arc, Mutex};
+
+use super::{read_config_bytes, Queue, SharedVirtioTransport, VirtioDevice, VIRTIO_ID_BALLOON};
+use crate::smolvm::Memory;
+
+const VIRTIO_BALLOON_F_STATS_VQ: u64 = 1 << 1;
+const VIRTIO_BALLOON_F_DEFLATE_ON_OOM: u64 = 1 << 2;
+const VIRTIO_BALLOON_F_REPORTING: u64 = 1 << 5;
+
+/// The balloon always works with 4K pages whatever the page size of the
+/// guest or the host is.
+const VIRTIO_BALLOON_PFN_SHIFT: u64 = 12;
+pub const VIRTIO_BALLOON_PAGE_SIZE: u64 = 1 << VIRTIO_BALLOON_PFN_SHIFT;
+
+const INFLATE_QUEUE: usize = 0;
+const DEFLATE_QUEUE: usize = 1;
+
+const QUEUE_SIZE: u16 = 128;
+
+// struct virtio_balloon_config { le32 num_pages; le32 actual; }
+const CONFIG_NUM_PAGES: u64 = 0;
+const CONFIG_ACTUAL: u64 = 4;
+const CONFIG_SIZE: usize = 8;
+
+// struct virtio_balloon_stat { le16 tag; le64 val; } __attribute__((packed))
+const STAT_SIZE: usize = 10;
+
+const VIRTIO_BALLOON_S_SWAP_IN: u16 = 0;
+const VIRTIO_BALLOON_S_SWAP_OUT: u16 = 1;
+const VIRTIO_BALLOON_S_MAJFLT: u16 = 2;
+const VIRTIO_BALLOON_S_MINFLT: u16 = 3;
+const VIRTIO_BALLOON_S_MEMFREE: u16 = 4;
+const VIRTIO_BALLOON_S_MEMTOT: u16 = 5;
+const VIRTIO_BALLOON_S_AVAIL: u16 = 6;
+const VIRTIO_BALLOON_S_CACHES: u16 = 7;
+const VIRTIO_BALLOON_S_HTLB_PGALLOC: u16 = 8;
+const VIRTIO_BALLOON_S_HTLB_PGFAIL: u16 = 9;
+
+/// Memory statistics of the guest, the driver leaves out what it
+/// does not know. The sizes are in bytes.
+#[derive(Default, Clone, Copy, Debug)]
+pub struct BalloonStats {
+    pub swap_in: Option<u64>,
+    pub swap_out: Option<u64>,
+    pub major_faults: Option<u64>,
+    pub minor_faults: Option<u64>,
+    pub free_memory: Option<u64>,
+    pub total_memory: Option<u64>,
+    pub available_memory: Option<u64>,
+    pub disk_caches: Option<u64>,
+    pub hugetlb_allocations: Option<u64>,
+    pub hugetlb_failures: Option<u64>,
+}
+
+impl BalloonStats {
+    fn parse(data: &[u8]) -> Self {
+        let mut stats = Self::default();
+
+        for stat in data.chunks_exact(STAT_SIZE) {
+            let tag = u16::from_le_bytes([stat[0], stat[1]]);
+            let mut value = [0_u8; 8];
+            value.copy_from_slice(&stat[2..]);
+            let value = Some(u64::from_le_bytes(value));
+
+            match tag {
+                VIRTIO_BALLOON_S_SWAP_IN => stats.swap_in = value,
+                VIRTIO_BALLOON_S_SWAP_OUT => stats.swap_out = value,
+                VIRTIO_BALLOON_S_MAJFLT => stats.major_faults = value,
+                VIRTIO_BALLOON_S_MINFLT => stats.minor_faults = value,
+                VIRTIO_BALLOON_S_MEMFREE => stats.free_memory = value,
+                VIRTIO_BALLOON_S_MEMTOT => stats.total_memory = value,
+                VIRTIO_BALLOON_S_AVAIL => stats.available_memory = value,
+                VIRTIO_BALLOON_S_CACHES => stats.disk_caches = value,
+                VIRTIO_BALLOON_S_HTLB_PGALLOC => stats.hugetlb_allocations = value,
+                VIRTIO_BALLOON_S_HTLB_PGFAIL => stats.hugetlb_failures = value,
+                _ => log::debug!("Unknown balloon statistics tag {}", tag),
+            }
+        }
+
+        stats
+    }
+}
+
+pub struct VirtioBalloon {
+    num_pages: u32,
+    actual: u32,
+    queue_sizes: [u16; 4],
+    // The optional queues follow the inflate and deflate ones only when
+    // their features have been negotiated
+    stats_queue: Option<usize>,
+    reporting_queue: Option<usize>,
+    stats_head: Option<u16>,
+    stats_requested: bool,
+    stats: BalloonStats,
+    released_pages: u64,
+}
+
+impl VirtioBalloon {
+    pub fn new() -> Self {
+        Self {
+            num_pages: 0,
+            actual: 0,
+            queue_sizes: [QUEUE_SIZE; 4],
+            stats_queue: None,
+            reporting_queue: None,
+            stats_head: None,
+            stats_requested: false,
+            stats: BalloonStats::default(),
+            released_pages: 0,
+        }
+    }
+
+    /// Releases the guest ranges from the host mapping merging the adjacent pages
+    fn release_pages(&mut self, memory: &mut Memory, pfns: impl Iterator<Item = u64>) {
+        let mut range: Option<(u64, u64)> = None;
+
+        for pfn in pfns {
+            range = match range {
+                Some((start, count)) if start + count == pfn => Some((start, count + 1)),
+                _ => {
+                    if let Some((start, count)) = range {
+                        self.release_range(memory, start, count);
+                    }
+                    Some((pfn, 1))
+                }
+            };
+        }
+
+        if let Some((start, count)) = range {
+            self.release_range(memory, start, count);
+        }
+    }
+
+    fn release_range(&mut self, memory: &mut Memory, pfn: u64, count: u64) {
+        let gpa = pfn << VIRTIO_BALLOON_PFN_SHIFT;
+        let size = (count << VIRTIO_BALLOON_PFN_SHIFT) as usize;
+
+        match memory.discard(gpa, size) {
+            Ok(()) => self.released_pages += count,
+            Err(e) => log::warn!("Cannot release {:#x} bytes at {:#x}: {}", size, gpa, e),
+        }
+    }
+
+    fn process_inflate(&mut self, queue: &mut Queue, memory: &mut Memory) -> bool {
+        let mut used = false;
+
+        while let Some(chain) = queue.pop(memory) {
+            let data = chain.read_all(memory);
+            let pfns = data
+                .chunks_exact(4)
+                .map(|pfn| u32::from_le_bytes([pfn[0], pfn[1], pfn[2], pfn[3]]) as u64);
+
+            self.release_pages(memory, pfns);
+            queue.add_used(memory, chain.head, 0);
+            used = true;
+        }
+
+        used
+    }
+
+    fn process_deflate(&mut self, queue: &mut Queue, memory: &mut Memory) -> bool {
+        let mut used = false;
+
+        // The pages are faulted back in on the first access
+        while let Some(chain) = queue.pop(memory) {
+            queue.add_used(memory, chain.head, 0);
+            used = true;
+        }
+
+        used
+    }
+
+    fn process_stats(&mut self, queue: &mut Queue, memory: &mut Memory) -> bool {
+        let mut used = false;
+
+        // The driver refills the buffer once it gets it back
+        if self.stats_requested {
+            if let Some(head) = self.stats_head.take() {
+                self.stats_requested = false;
+                queue.add_used(memory, head, 0);
+                used = true;
+            }
+        }
+
+        while let Some(chain) = queue.pop(memory) {
+            if let Some(head) = self.stats_head.replace(chain.head) {
+                queue.add_used(memory, head, 0);
+                used = true;
+            }
+
+            self.stats = BalloonStats::parse(&chain.read_all(memory));
+            log::debug!("Balloon statistics {:?}", self.stats);
+        }
+
+        used
+    }
+
+    fn process_reporting(&mut self, queue: &mut Queue, memory: &mut Memory) -> bool {
+        let mut used = false;
+
+        // The reported free pages are described by the device-writable buffers
+        while let Some(chain) = queue.pop(memory) {
+            for (gpa, len) in &chain.writable {
+                let pfn = gpa >> VIRTIO_BALLOON_PFN_SHIFT;
+                let count = *len as u64 >> VIRTIO_BALLOON_PFN_SHIFT;
+                self.release_range(memory, pfn, count);
+            }
+
+            queue.add_used(memory, chain.head, 0);
+            used = true;
+        }
+
+        used
+    }
+}
+
+impl Default for VirtioBalloon {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VirtioDevice for VirtioBalloon {
+    fn device_type(&self) -> u32 {
+        VIRTIO_ID_BALLOON
+    }
+
+    fn features(&self) -> u64 {
+        VIRTIO_BALLOON_F_STATS_VQ | VIRTIO_BALLOON_F_DEFLATE_ON_OOM | VIRTIO_BALLOON_F_REPORTING
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        &self.queue_sizes
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        let mut config = [0_u8; CONFIG_SIZE];
+        config[CONFIG_NUM_PAGES as usize..][..4].copy_from_slice(&self.num_pages.to_le_bytes());
+        config[CONFIG_ACTUAL as usize..][..4].copy_from_slice(&self.actual.to_le_bytes());
+
+        read_config_bytes(&config, offset, data);
+    }
+
+    fn write_config(&mut self, offset: u64, data: &[u8]) {
+        if offset == CONFIG_ACTUAL && data.len() == 4 {
+            self.actual = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+            log::debug!("Balloon holds {} pages", self.actual);
+        } else {
+            log::warn!(
+                "Writing {:x?} to the balloon config space at {:#x}",
+                data,
+                offset
+            );
+        }
+    }
+
+    fn activate(&mut self, features: u64, _queues: &[Queue], _memory: &Memory) {
+        let mut next_queue = DEFLATE_QUEUE + 1;
+        let mut optional_queue = |feature| {
+            if features & feature != 0 {
+                next_queue += 1;
+                Some(next_queue - 1)
+            } else {
+                None
+            }
+        };
+
+        self.stats_queue = optional_queue(VIRTIO_BALLOON_F_STATS_VQ);
+        self.reporting_queue = optional_queue(VIRTIO_BALLOON_F_REPORTING);
+
+        log::info!("Balloon activated with features {:#x}", features);
+    }
+
+    fn process_queue(&mut self, index: usize, queue: &mut Queue, memory: &mut Memory) -> bool {
+        match index {
+            INFLATE_QUEUE => self.process_inflate(queue, memory),
+            DEFLATE_QUEUE => self.process_deflate(queue, memory),
+            _ if Some(index) == self.stats_queue => self.process_stats(queue, memory),
+            _ if Some(index) == self.reporting_queue => self.process_reporting(queue, memory),
+            _ => {
+                log::warn!("Notification for the unused balloon queue {}", index);
+                false
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        self.actual = 0;
+        self.stats_queue = None;
+        self.reporting_queue = None;
+        self.stats_head = None;
+        self.stats_requested = false;
+    }
+}
+
+/// The host side of the balloon
+#[derive(Clone)]
+pub struct BalloonControl {
+    balloon: Arc<Mutex<VirtioBalloon>>,
+    transport: SharedVirtioTransport,
+}
+
+impl BalloonControl {
+    pub fn new(balloon: Arc<Mutex<VirtioBalloon>>, transport: SharedVirtioTransport) -> Self {
+        Self { balloon, transport }
+    }
+
+    /// Asks the guest to give `size` bytes of its memory back to the host
+    pub fn set_target_size(&self, size: u64) {
+        let num_pages = (size / VIRTIO_BALLOON_PAGE_SIZE).min(u32::MAX as u64) as u32;
+
+        self.balloon.lock().unwrap().num_pages = num_pages;
+        self.transport.lock().unwrap().notify_config_change();
+
+        log::info!("Balloon target set to {} pages", num_pages);
+    }
+
+    /// The size of the memory the guest has put into the balloon
+    pub fn actual_size(&self) -> u64 {
+        self.balloon.lock().unwrap().actual as u64 * VIRTIO_BALLOON_PAGE_SIZE
+    }
+
+    /// The size of the memory released from the host mapping so far
+    pub fn released_size(&self) -> u64 {
+        self.balloon.lock().unwrap().released_pages * VIRTIO_BALLOON_PAGE_SIZE
+    }
+
+    /// Asks the guest for the fresh statistics, they show up in `stats`
+    /// once the driver has responded.
+    pub fn request_stats(&self) {
+        let stats_queue = {
+            let mut balloon = self.balloon.lock().unwrap();
+            balloon.stats_requested = true;
+            balloon.stats_queue
+        };
+
+        if let Some(stats_queue) = stats_queue {
+            self.transport.lock().unwrap().process_queue(stats_queue);
+        }
+    }
+
+    pub fn stats(&self) -> BalloonStats {
+        self.balloon.lock().unwrap().stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::smolvm::{test_ram, RamOptions};
+
+    const DESC_TABLE: u64 = 0x1000;
+    const AVAIL_RING: u64 = 0x2000;
+    const USED_RING: u64 = 0x3000;
+    const PFNS: u64 = 0x4000;
+
+    #[test]
+    fn test_inflate() {
+        // The shared memfd backing, where dropping the pages is not enough
+        let mut memory = test_ram(0, 0x20000, &RamOptions::default()).unwrap();
+        let page = 0x10000;
+        memory.write(page, &[0xaa; 2 * VIRTIO_BALLOON_PAGE_SIZE as usize]);
+
+        // One descriptor with the PFNs of the two pages
+        memory.write_obj(DESC_TABLE, &PFNS);
+        memory.write_obj(DESC_TABLE + 8, &8_u32);
+        memory.write_obj(AVAIL_RING + 2, &1_u16);
+        memory.write_obj(PFNS, &((page >> VIRTIO_BALLOON_PFN_SHIFT) as u32));
+        memory.write_obj(PFNS + 4, &((page >> VIRTIO_BALLOON_PFN_SHIFT) as u32 + 1));
+
+        let mut queue = Queue::new(QUEUE_SIZE);
+        queue.desc_table = DESC_TABLE;
+        queue.avail_ring = AVAIL_RING;
+        queue.used_ring = USED_RING;
+        queue.ready = true;
+
+        let mut balloon = VirtioBalloon::new();
+        assert!(balloon.process_queue(INFLATE_QUEUE, &mut queue, &mut memory));
+        assert_eq!(balloon.released_pages, 2);
+        assert_eq!(memory.read_obj::<u16>(USED_RING + 2), 1);
+
+        let data = memory.read(page, 2 * VIRTIO_BALLOON_PAGE_SIZE as usize);
+        assert!(data.iter().all(|byte| *byte == 0));
+    }
+}
@@ -0,0 +1,246 @@
+//! The virtio-mmio transport, version 2 (no legacy).
+//!
+//!   Offset  Name                     Direction
+//!   -----------------------------------------------
+//!   0x000   MagicValue               R   "virt"
+//!   0x004   Version                  R   2
+//!   0x008   DeviceID                 R
+//!   0x00c   VendorID                 R
+//!   0x010   DeviceFeatures           R
+//!   0x014   DeviceFeaturesSel        W
+//!   0x020   DriverFeatures           W
+//!   0x024   DriverFeaturesSel        W
+//!   0x030   QueueSel                 W
+//!   0x034   QueueNumMax              R
+//!   0x038   QueueNum                 W
+//!   0x044   QueueReady               RW
+//!   0x050   QueueNotify              W
+//!   0x060   InterruptStatus          R
+//!   0x064   InterruptACK             W
+//!   0x070   Status                   RW
+//!   0x080   QueueDescLow/High        W
+//!   0x090   QueueDriverLow/High      W
+//!   0x0a0   QueueDeviceLow/High      W
+//!   0x0fc   ConfigGeneration         R
+//!   0x100+  Config                   RW
+
+use std::sync::{Arc, Mutex};
+
+use super::{transport::TransportState, SharedVirtioDevice, VirtioTransport};
+use crate::smolvm::{bus::MmIoDevice, IrqChip, Memory};
+
+pub const VIRTIO_MMIO_SIZE: u64 = 0x200;
+
+const VIRTIO_MMIO_MAGIC_VALUE: u64 = 0x000;
+const VIRTIO_MMIO_VERSION: u64 = 0x004;
+const VIRTIO_MMIO_DEVICE_ID: u64 = 0x008;
+const VIRTIO_MMIO_VENDOR_ID: u64 = 0x00c;
+const VIRTIO_MMIO_DEVICE_FEATURES: u64 = 0x010;
+const VIRTIO_MMIO_DEVICE_FEATURES_SEL: u64 = 0x014;
+const VIRTIO_MMIO_DRIVER_FEATURES: u64 = 0x020;
+const VIRTIO_MMIO_DRIVER_FEATURES_SEL: u64 = 0x024;
+const VIRTIO_MMIO_QUEUE_SEL: u64 = 0x030;
+const VIRTIO_MMIO_QUEUE_NUM_MAX: u64 = 0x034;
+const VIRTIO_MMIO_QUEUE_NUM: u64 = 0x038;
+const VIRTIO_MMIO_QUEUE_READY: u64 = 0x044;
+const VIRTIO_MMIO_QUEUE_NOTIFY: u64 = 0x050;
+const VIRTIO_MMIO_INTERRUPT_STATUS: u64 = 0x060;
+const VIRTIO_MMIO_INTERRUPT_ACK: u64 = 0x064;
+const VIRTIO_MMIO_STATUS: u64 = 0x070;
+const VIRTIO_MMIO_QUEUE_DESC_LOW: u64 = 0x080;
+const VIRTIO_MMIO_QUEUE_DESC_HIGH: u64 = 0x084;
+const VIRTIO_MMIO_QUEUE_DRIVER_LOW: u64 = 0x090;
+const VIRTIO_MMIO_QUEUE_DRIVER_HIGH: u64 = 0x094;
+const VIRTIO_MMIO_QUEUE_DEVICE_LOW: u64 = 0x0a0;
+const VIRTIO_MMIO_QUEUE_DEVICE_HIGH: u64 = 0x0a4;
+const VIRTIO_MMIO_CONFIG_GENERATION: u64 = 0x0fc;
+const VIRTIO_MMIO_CONFIG: u64 = 0x100;
+
+const VIRTIO_MMIO_MAGIC: u32 = 0x7472_6976; // "virt"
+const VIRTIO_MMIO_VENDOR: u32 = 0x554d_4551; // "QEMU", what Linux is happy with
+
+const VIRTIO_MMIO_INT_VRING: u32 = 1;
+const VIRTIO_MMIO_INT_CONFIG: u32 = 2;
+
+pub struct VirtioMmio {
+    base_addr: u64,
+    irq: u32,
+    irq_chip: Arc<dyn IrqChip>,
+    state: TransportState,
+    interrupt_status: u32,
+}
+
+impl VirtioMmio {
+    pub fn new(
+        base_addr: u64,
+        irq: u32,
+        device: SharedVirtioDevice,
+        memory: Arc<Mutex<Memory>>,
+        irq_chip: Arc<dyn IrqChip>,
+    ) -> Self {
+        Self {
+            base_addr,
+            irq,
+            irq_chip,
+            state: TransportState::new(device, memory),
+            interrupt_status: 0,
+        }
+    }
+
+    pub fn irq(&self) -> u32 {
+        self.irq
+    }
+
+    fn set_status(&mut self, status: u32) {
+        if self.state.set_status(status) {
+            log::info!("Virtio device at {:#x} reset", self.base_addr);
+            self.interrupt_status = 0;
+        }
+    }
+
+    fn read_register(&mut self, offset: u64) -> u32 {
+        match offset {
+            VIRTIO_MMIO_MAGIC_VALUE => VIRTIO_MMIO_MAGIC,
+            VIRTIO_MMIO_VERSION => 2,
+            VIRTIO_MMIO_DEVICE_ID => self.state.device_type(),
+            VIRTIO_MMIO_VENDOR_ID => VIRTIO_MMIO_VENDOR,
+            VIRTIO_MMIO_DEVICE_FEATURES => self.state.device_features_word(),
+            VIRTIO_MMIO_QUEUE_NUM_MAX => {
+                self.state.selected_queue().map_or(0, |q| q.max_size as u32)
+            }
+            VIRTIO_MMIO_QUEUE_READY => self.state.selected_queue().map_or(0, |q| q.ready as u32),
+            VIRTIO_MMIO_INTERRUPT_STATUS => self.interrupt_status,
+            VIRTIO_MMIO_STATUS => self.state.status,
+            VIRTIO_MMIO_CONFIG_GENERATION => self.state.config_generation,
+            _ => {
+                log::warn!(
+                    "Unsupported virtio-mmio read from 0x{:x}",
+                    self.base_addr + offset
+                );
+                0
+            }
+        }
+    }
+
+    fn write_register(&mut self, offset: u64, value: u32) {
+        let set_low = |addr: &mut u64, value: u32| *addr = (*addr & !0xffff_ffff) | value as u64;
+        let set_high =
+            |addr: &mut u64, value: u32| *addr = (*addr & 0xffff_ffff) | ((value as u64) << 32);
+
+        match offset {
+            VIRTIO_MMIO_DEVICE_FEATURES_SEL => self.state.device_features_sel = value,
+            VIRTIO_MMIO_DRIVER_FEATURES_SEL => self.state.driver_features_sel = value,
+            VIRTIO_MMIO_DRIVER_FEATURES => self.state.set_driver_features_word(value),
+            VIRTIO_MMIO_QUEUE_SEL => self.state.queue_sel = value,
+            VIRTIO_MMIO_QUEUE_NUM => {
+                if let Some(queue) = self.state.selected_queue() {
+                    queue.size = value as u16;
+                }
+            }
+            VIRTIO_MMIO_QUEUE_READY => {
+                if let Some(queue) = self.state.selected_queue() {
+                    queue.ready = value == 1;
+                }
+            }
+            VIRTIO_MMIO_QUEUE_NOTIFY => self.process_queue(value as usize),
+            VIRTIO_MMIO_INTERRUPT_ACK => self.interrupt_status &= !value,
+            VIRTIO_MMIO_STATUS => self.set_status(value),
+            VIRTIO_MMIO_QUEUE_DESC_LOW => {
+                if let Some(queue) = self.state.selected_queue() {
+                    set_low(&mut queue.desc_table, value);
+                }
+            }
+            VIRTIO_MMIO_QUEUE_DESC_HIGH => {
+                if let Some(queue) = self.state.selected_queue() {
+                    set_high(&mut queue.desc_table, value);
+                }
+            }
+            VIRTIO_MMIO_QUEUE_DRIVER_LOW => {
+                if let Some(queue) = self.state.selected_queue() {
+                    set_low(&mut queue.avail_ring, value);
+                }
+            }
+            VIRTIO_MMIO_QUEUE_DRIVER_HIGH => {
+                if let Some(queue) = self.state.selected_queue() {
+                    set_high(&mut queue.avail_ring, value);
+                }
+            }
+            VIRTIO_MMIO_QUEUE_DEVICE_LOW => {
+                if let Some(queue) = self.state.selected_queue() {
+                    set_low(&mut queue.used_ring, value);
+                }
+            }
+            VIRTIO_MMIO_QUEUE_DEVICE_HIGH => {
+                if let Some(queue) = self.state.selected_queue() {
+                    set_high(&mut queue.used_ring, value);
+                }
+            }
+            _ => log::warn!(
+                "Unsupported virtio-mmio write of {:#x} to 0x{:x}",
+                value,
+                self.base_addr + offset
+            ),
+        }
+    }
+}
+
+impl VirtioTransport for VirtioMmio {
+    fn notify_config_change(&mut self) {
+        self.state.config_generation = self.state.config_generation.wrapping_add(1);
+        self.interrupt_status |= VIRTIO_MMIO_INT_CONFIG;
+        self.irq_chip.pulse_irq(self.irq);
+    }
+
+    fn process_queue(&mut self, index: usize) {
+        if self.state.process_queue(index) {
+            self.signal_used_queue(index);
+        }
+    }
+
+    fn signal_used_queue(&mut self, _index: usize) {
+        self.interrupt_status |= VIRTIO_MMIO_INT_VRING;
+        self.irq_chip.pulse_irq(self.irq);
+    }
+}
+
+impl MmIoDevice for VirtioMmio {
+    fn mmio_read(&mut self, addr: u64, data: &mut [u8]) {
+        let offset = addr - self.base_addr;
+
+        if offset >= VIRTIO_MMIO_CONFIG {
+            self.state.read_config(offset - VIRTIO_MMIO_CONFIG, data);
+        } else if data.len() == 4 {
+            data.copy_from_slice(&self.read_register(offset).to_le_bytes());
+        } else {
+            log::warn!(
+                "Unsupported {}-byte virtio-mmio read from 0x{:x}",
+                data.len(),
+                addr
+            );
+        }
+    }
+
+    fn mmio_write(&mut self, addr: u64, data: &[u8]) {
+        let offset = addr - self.base_addr;
+
+        if offset >= VIRTIO_MMIO_CONFIG {
+            self.state.write_config(offset - VIRTIO_MMIO_CONFIG, data);
+        } else if data.len() == 4 {
+            self.write_register(
+                offset,
+                u32::from_le_bytes([data[0], data[1], data[2], data[3]]),
+            );
+        } else {
+            log::warn!(
+                "Unsupported {}-byte virtio-mmio write to 0x{:x}",
+                data.len(),
+                addr
+            );
+        }
+    }
+
+    fn reset(&mut self) {
+        self.state.reset();
+        self.interrupt_status = 0;
+    }
+}
@@ -0,0 +1,133 @@
+//! Virtio devices over the virtio-mmio and the virtio-pci transports.
+//! See the "Virtual I/O Device (VIRTIO) Version 1.1" specification for
+//! the gory details.
+
+use std::sync::{Arc, Mutex};
+
+use super::Memory;
+
+#[cfg(target_os = "linux")]
+pub mod balloon;
+mod mmio;
+#[cfg(target_os = "linux")]
+pub mod p9;
+mod pci;
+pub mod pmem;
+mod queue;
+mod transport;
+#[cfg(target_os = "linux")]
+pub mod vhost_user;
+
+pub use mmio::{VirtioMmio, VIRTIO_MMIO_SIZE};
+pub use pci::VirtioPci;
+pub use queue::Queue;
+
+pub const VIRTIO_ID_NET: u32 = 1;
+pub const VIRTIO_ID_BLOCK: u32 = 2;
+pub const VIRTIO_ID_CONSOLE: u32 = 3;
+pub const VIRTIO_ID_RNG: u32 = 4;
+pub const VIRTIO_ID_BALLOON: u32 = 5;
+pub const VIRTIO_ID_9P: u32 = 9;
+pub const VIRTIO_ID_FS: u32 = 26;
+pub const VIRTIO_ID_PMEM: u32 = 27;
+
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+pub const VIRTIO_STATUS_ACKNOWLEDGE: u32 = 1;
+pub const VIRTIO_STATUS_DRIVER: u32 = 2;
+pub const VIRTIO_STATUS_DRIVER_OK: u32 = 4;
+pub const VIRTIO_STATUS_FEATURES_OK: u32 = 8;
+pub const VIRTIO_STATUS_NEEDS_RESET: u32 = 0x40;
+pub const VIRTIO_STATUS_FAILED: u32 = 0x80;
+
+/// Where the virtio-mmio transports live in the guest physical address space.
+/// On aarch64 that matches the `virtio_mmio@a000000` nodes of the `virt` machine
+/// of `qemu` so its device tree can be used as-is.
+#[cfg(target_arch = "x86_64")]
+pub const VIRTIO_MMIO_BASE: u64 = 0xd000_0000;
+#[cfg(target_arch = "x86_64")]
+pub const VIRTIO_MMIO_IRQ_BASE: u32 = 5;
+#[cfg(target_arch = "x86_64")]
+pub const VIRTIO_MMIO_SLOTS: u32 = 8;
+/// The RTC has IRQ 8
+#[cfg(target_arch = "x86_64")]
+const VIRTIO_MMIO_SKIPPED_IRQ: u32 = 8;
+
+#[cfg(target_arch = "aarch64")]
+pub const VIRTIO_MMIO_BASE: u64 = 0x0a00_0000;
+#[cfg(target_arch = "aarch64")]
+pub const VIRTIO_MMIO_IRQ_BASE: u32 = 16;
+#[cfg(target_arch = "aarch64")]
+pub const VIRTIO_MMIO_SLOTS: u32 = 32;
+
+/// The interrupt of the virtio-mmio transport at the slot
+pub fn virtio_mmio_irq(slot: u32) -> u32 {
+    let irq = VIRTIO_MMIO_IRQ_BASE + slot;
+
+    #[cfg(target_arch = "x86_64")]
+    if irq >= VIRTIO_MMIO_SKIPPED_IRQ {
+        return irq + 1;
+    }
+
+    irq
+}
+
+pub trait VirtioDevice: Send {
+    fn device_type(&self) -> u32;
+
+    /// Features offered to the driver, `VIRTIO_F_VERSION_1` is added by the transport
+    fn features(&self) -> u64;
+
+    /// Maximum sizes of the virtqueues, their count is the number of the queues
+    fn queue_max_sizes(&self) -> &[u16];
+
+    fn read_config(&self, offset: u64, data: &mut [u8]);
+
+    fn write_config(&mut self, offset: u64, data: &[u8]) {
+        log::warn!(
+            "Writing {:x?} to the read-only config space at {:#x}",
+            data,
+            offset
+        );
+    }
+
+    /// The driver has accepted the `features`, set up the `queues` and set
+    /// DRIVER_OK
+    fn activate(&mut self, features: u64, queues: &[Queue], memory: &Memory) {
+        log::info!("Virtio device activated with features {:#x}", features);
+    }
+
+    /// Consumes the available buffers of the queue, returns `true` if
+    /// anything has been put to the used ring and the driver has to be
+    /// notified.
+    fn process_queue(&mut self, index: usize, queue: &mut Queue, memory: &mut Memory) -> bool;
+
+    fn reset(&mut self) {}
+}
+
+/// Copies `src` that starts at `offset` of the device configuration space into `data`,
+/// bytes beyond the end of the configuration read as zeroes.
+pub fn read_config_bytes(src: &[u8], offset: u64, data: &mut [u8]) {
+    for (index, byte) in data.iter_mut().enumerate() {
+        *byte = src.get(offset as usize + index).copied().unwrap_or(0);
+    }
+}
+
+pub type SharedVirtioDevice = Arc<Mutex<dyn VirtioDevice>>;
+
+/// The transport side of a device, what the host uses to prompt the driver
+/// or the device outside of the register accesses.
+pub trait VirtioTransport: Send {
+    /// Tells the driver the device configuration has changed
+    fn notify_config_change(&mut self);
+
+    /// Lets the device consume the buffers the driver has made available,
+    /// for the devices that produce data without a queue notification
+    fn process_queue(&mut self, index: usize);
+
+    /// Notifies the driver of the buffers the device has put to the used
+    /// ring on its own
+    fn signal_used_queue(&mut self, index: usize);
+}
+
+pub type SharedVirtioTransport = Arc<Mutex<dyn VirtioTransport>>;
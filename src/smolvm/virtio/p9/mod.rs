@@ -0,0 +1,91 @@
+//! virtio-9p device sharing a host directory with the guest.
+//!
+//! The guest mounts it with
+//! `mount -t 9p -o trans=virtio,version=9p2000.L <tag> <mount point>`.
+
+use std::path::Path;
+
+use super::{read_config_bytes, Queue, VirtioDevice, VIRTIO_ID_9P};
+use crate::smolvm::Memory;
+
+mod protocol;
+mod server;
+
+pub use server::P9Server;
+
+const VIRTIO_9P_MOUNT_TAG: u64 = 1;
+
+const QUEUE_SIZE: u16 = 128;
+
+pub struct Virtio9p {
+    config: Vec<u8>,
+    server: P9Server,
+    queue_sizes: [u16; 1],
+}
+
+impl Virtio9p {
+    pub fn new(tag: &str, root_path: &Path, read_only: bool) -> std::io::Result<Self> {
+        if tag.is_empty() || tag.len() > u16::MAX as usize {
+            return Err(std::io::Error::from_raw_os_error(libc::EINVAL));
+        }
+
+        log::info!(
+            "Sharing {} as '{}'{}",
+            root_path.display(),
+            tag,
+            if read_only { ", read-only" } else { "" }
+        );
+
+        // struct virtio_9p_config { le16 tag_len; u8 tag[]; }
+        let mut config = Vec::with_capacity(2 + tag.len());
+        config.extend_from_slice(&(tag.len() as u16).to_le_bytes());
+        config.extend_from_slice(tag.as_bytes());
+
+        Ok(Self {
+            config,
+            server: P9Server::new(root_path, read_only)?,
+            queue_sizes: [QUEUE_SIZE],
+        })
+    }
+}
+
+impl VirtioDevice for Virtio9p {
+    fn device_type(&self) -> u32 {
+        VIRTIO_ID_9P
+    }
+
+    fn features(&self) -> u64 {
+        VIRTIO_9P_MOUNT_TAG
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        &self.queue_sizes
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        read_config_bytes(&self.config, offset, data);
+    }
+
+    fn process_queue(&mut self, _index: usize, queue: &mut Queue, memory: &mut Memory) -> bool {
+        let mut used = false;
+
+        while let Some(chain) = queue.pop(memory) {
+            let request = chain.read_all(memory);
+            let reply = self.server.handle(&request);
+
+            if reply.len() > chain.writable_len() {
+                log::error!(
+                    "9P reply of {} bytes does not fit into {} bytes",
+                    reply.len(),
+                    chain.writable_len()
+                );
+            }
+
+            let written = chain.write_all(memory, &reply);
+            queue.add_used(memory, chain.head, written as u32);
+            used = true;
+        }
+
+        used
+    }
+}
@@ -0,0 +1,198 @@
+//! 9P2000.L wire format.
+//!
+//! Every message starts with `size[4] type[1] tag[2]`, the integers are
+//! little-endian, the strings are `len[2]` followed by UTF-8 without the
+//! terminating NUL, and a qid is `type[1] version[4] path[8]`.
+
+pub const P9_HEADER_SIZE: usize = 7;
+
+pub const P9_TLERROR: u8 = 6;
+pub const P9_RLERROR: u8 = 7;
+pub const P9_TSTATFS: u8 = 8;
+pub const P9_TLOPEN: u8 = 12;
+pub const P9_TLCREATE: u8 = 14;
+pub const P9_TSYMLINK: u8 = 16;
+pub const P9_TMKNOD: u8 = 18;
+pub const P9_TRENAME: u8 = 20;
+pub const P9_TREADLINK: u8 = 22;
+pub const P9_TGETATTR: u8 = 24;
+pub const P9_TSETATTR: u8 = 26;
+pub const P9_TXATTRWALK: u8 = 30;
+pub const P9_TXATTRCREATE: u8 = 32;
+pub const P9_TREADDIR: u8 = 40;
+pub const P9_TFSYNC: u8 = 50;
+pub const P9_TLOCK: u8 = 52;
+pub const P9_TGETLOCK: u8 = 54;
+pub const P9_TLINK: u8 = 70;
+pub const P9_TMKDIR: u8 = 72;
+pub const P9_TRENAMEAT: u8 = 74;
+pub const P9_TUNLINKAT: u8 = 76;
+pub const P9_TVERSION: u8 = 100;
+pub const P9_TAUTH: u8 = 102;
+pub const P9_TATTACH: u8 = 104;
+pub const P9_TFLUSH: u8 = 108;
+pub const P9_TWALK: u8 = 110;
+pub const P9_TREAD: u8 = 116;
+pub const P9_TWRITE: u8 = 118;
+pub const P9_TCLUNK: u8 = 120;
+pub const P9_TREMOVE: u8 = 122;
+
+pub const P9_QTDIR: u8 = 0x80;
+pub const P9_QTSYMLINK: u8 = 0x02;
+pub const P9_QTFILE: u8 = 0x00;
+
+pub const P9_NOFID: u32 = !0;
+
+// Flags of Tlopen and Tlcreate, these are the x86 Linux values regardless of
+// the architecture.
+pub const P9_DOTL_RDONLY: u32 = 0o0;
+pub const P9_DOTL_WRONLY: u32 = 0o1;
+pub const P9_DOTL_RDWR: u32 = 0o2;
+pub const P9_DOTL_ACCMODE: u32 = 0o3;
+pub const P9_DOTL_CREATE: u32 = 0o100;
+pub const P9_DOTL_EXCL: u32 = 0o200;
+pub const P9_DOTL_TRUNC: u32 = 0o1000;
+pub const P9_DOTL_APPEND: u32 = 0o2000;
+pub const P9_DOTL_NONBLOCK: u32 = 0o4000;
+pub const P9_DOTL_DSYNC: u32 = 0o10000;
+pub const P9_DOTL_DIRECTORY: u32 = 0o200000;
+pub const P9_DOTL_SYNC: u32 = 0o4000000;
+
+pub const P9_DOTL_AT_REMOVEDIR: u32 = 0x200;
+
+// Tgetattr request mask and Rgetattr valid bits
+pub const P9_GETATTR_BASIC: u64 = 0x0000_07ff;
+
+// Tsetattr valid bits
+pub const P9_SETATTR_MODE: u32 = 0x0000_0001;
+pub const P9_SETATTR_UID: u32 = 0x0000_0002;
+pub const P9_SETATTR_GID: u32 = 0x0000_0004;
+pub const P9_SETATTR_SIZE: u32 = 0x0000_0008;
+pub const P9_SETATTR_ATIME: u32 = 0x0000_0010;
+pub const P9_SETATTR_MTIME: u32 = 0x0000_0020;
+pub const P9_SETATTR_CTIME: u32 = 0x0000_0040;
+pub const P9_SETATTR_ATIME_SET: u32 = 0x0000_0080;
+pub const P9_SETATTR_MTIME_SET: u32 = 0x0000_0100;
+
+pub const P9_LOCK_SUCCESS: u8 = 0;
+pub const P9_LOCK_TYPE_UNLCK: u8 = 2;
+
+#[derive(Default, Clone, Copy, Debug)]
+pub struct Qid {
+    pub type_: u8,
+    pub version: u32,
+    pub path: u64,
+}
+
+pub struct Reader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, offset: 0 }
+    }
+
+    fn take(&mut self, size: usize) -> std::io::Result<&'a [u8]> {
+        if self.offset + size > self.data.len() {
+            return Err(std::io::Error::from_raw_os_error(libc::EPROTO));
+        }
+
+        let bytes = &self.data[self.offset..self.offset + size];
+        self.offset += size;
+
+        Ok(bytes)
+    }
+
+    pub fn u8(&mut self) -> std::io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> std::io::Result<u16> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn u32(&mut self) -> std::io::Result<u32> {
+        let mut bytes = [0_u8; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    pub fn u64(&mut self) -> std::io::Result<u64> {
+        let mut bytes = [0_u8; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    pub fn string(&mut self) -> std::io::Result<String> {
+        let len = self.u16()? as usize;
+        String::from_utf8(self.take(len)?.to_vec())
+            .map_err(|_| std::io::Error::from_raw_os_error(libc::EINVAL))
+    }
+
+    pub fn bytes(&mut self, size: usize) -> std::io::Result<&'a [u8]> {
+        self.take(size)
+    }
+}
+
+/// Builds a reply, the size of the message is filled in by `finish`
+pub struct Writer {
+    data: Vec<u8>,
+}
+
+impl Writer {
+    pub fn new(type_: u8, tag: u16) -> Self {
+        let mut writer = Self {
+            data: Vec::with_capacity(64),
+        };
+
+        writer.u32(0);
+        writer.u8(type_);
+        writer.u16(tag);
+
+        writer
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn string(&mut self, value: &str) {
+        self.u16(value.len() as u16);
+        self.data.extend_from_slice(value.as_bytes());
+    }
+
+    pub fn bytes(&mut self, value: &[u8]) {
+        self.data.extend_from_slice(value);
+    }
+
+    pub fn qid(&mut self, qid: &Qid) {
+        self.u8(qid.type_);
+        self.u32(qid.version);
+        self.u64(qid.path);
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+        let size = self.data.len() as u32;
+        self.data[..4].copy_from_slice(&size.to_le_bytes());
+        self.data
+    }
+}
@@ -0,0 +1,1474 @@
+//! 9P2000.L file server exposing a host directory.
+//!
+//! A fid is tracked as the list of the path components relative to the
+//! shared root. The components are resolved one by one with `openat`
+//! and `O_NOFOLLOW` starting from the root directory, and the last one
+//! is operated upon with the `*at` calls without following symlinks,
+//! so neither `..` nor a symlink can lead outside of the shared root.
+//! The guest resolves the symlinks itself through `Treadlink`.
+
+use std::{
+    collections::HashMap,
+    ffi::{CStr, CString},
+    fs::File,
+    io::Error,
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{DirEntryExt, FileExt, FileTypeExt},
+        io::{AsRawFd, FromRawFd},
+    },
+    path::Path,
+};
+
+use super::protocol::*;
+
+const P9_MAX_MSIZE: u32 = 512 * 1024;
+/// Room for the headers and some data at least
+const P9_MIN_MSIZE: u32 = 4096;
+const P9_IOHDR_SIZE: u32 = (P9_HEADER_SIZE + 4) as u32;
+
+struct DirEntry {
+    qid: Qid,
+    type_: u8,
+    name: String,
+}
+
+#[derive(Default)]
+struct Fid {
+    path: Vec<String>,
+    file: Option<File>,
+    dir_entries: Option<Vec<DirEntry>>,
+}
+
+/// The parent directory and the name of a file, "." stands for the
+/// directory itself.
+struct Location {
+    dir: File,
+    name: CString,
+}
+
+pub struct P9Server {
+    root: File,
+    read_only: bool,
+    msize: u32,
+    fids: HashMap<u32, Fid>,
+}
+
+fn errno(code: i32) -> Error {
+    Error::from_raw_os_error(code)
+}
+
+fn check(ret: libc::c_int) -> std::io::Result<libc::c_int> {
+    if ret < 0 {
+        Err(Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+fn to_cstring(name: &str) -> std::io::Result<CString> {
+    CString::new(name).map_err(|_| errno(libc::EINVAL))
+}
+
+fn validate_name(name: &str) -> std::io::Result<()> {
+    if name.is_empty() || name == "." || name == ".." || name.contains('/') || name.contains('\0') {
+        Err(errno(libc::EINVAL))
+    } else {
+        Ok(())
+    }
+}
+
+fn open_at(dir: &File, name: &CStr, flags: i32, mode: u32) -> std::io::Result<File> {
+    let fd = check(unsafe {
+        libc::openat(
+            dir.as_raw_fd(),
+            name.as_ptr(),
+            flags | libc::O_NOFOLLOW | libc::O_CLOEXEC,
+            mode,
+        )
+    })?;
+
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+fn fstat(file: &File) -> std::io::Result<libc::stat> {
+    let mut st: libc::stat = unsafe { std::mem::zeroed() };
+    check(unsafe { libc::fstat(file.as_raw_fd(), &mut st) })?;
+
+    Ok(st)
+}
+
+fn qid_from_stat(st: &libc::stat) -> Qid {
+    let type_ = match st.st_mode & libc::S_IFMT {
+        libc::S_IFDIR => P9_QTDIR,
+        libc::S_IFLNK => P9_QTSYMLINK,
+        _ => P9_QTFILE,
+    };
+
+    Qid {
+        type_,
+        version: st.st_mtime as u32,
+        path: st.st_ino,
+    }
+}
+
+fn dir_entry_type(file_type: std::fs::FileType) -> u8 {
+    if file_type.is_dir() {
+        libc::DT_DIR
+    } else if file_type.is_file() {
+        libc::DT_REG
+    } else if file_type.is_symlink() {
+        libc::DT_LNK
+    } else if file_type.is_block_device() {
+        libc::DT_BLK
+    } else if file_type.is_char_device() {
+        libc::DT_CHR
+    } else if file_type.is_fifo() {
+        libc::DT_FIFO
+    } else if file_type.is_socket() {
+        libc::DT_SOCK
+    } else {
+        libc::DT_UNKNOWN
+    }
+}
+
+/// The flags of Tlopen and Tlcreate are always in the x86 encoding
+fn host_open_flags(flags: u32) -> i32 {
+    let mut host_flags = match flags & P9_DOTL_ACCMODE {
+        P9_DOTL_WRONLY => libc::O_WRONLY,
+        P9_DOTL_RDWR => libc::O_RDWR,
+        _ => libc::O_RDONLY,
+    };
+
+    let translated = [
+        (P9_DOTL_CREATE, libc::O_CREAT),
+        (P9_DOTL_EXCL, libc::O_EXCL),
+        (P9_DOTL_TRUNC, libc::O_TRUNC),
+        (P9_DOTL_APPEND, libc::O_APPEND),
+        (P9_DOTL_NONBLOCK, libc::O_NONBLOCK),
+        (P9_DOTL_DSYNC, libc::O_DSYNC),
+        (P9_DOTL_DIRECTORY, libc::O_DIRECTORY),
+        (P9_DOTL_SYNC, libc::O_SYNC),
+    ];
+    for (p9_flag, host_flag) in translated.iter() {
+        if flags & p9_flag == *p9_flag {
+            host_flags |= host_flag;
+        }
+    }
+
+    host_flags
+}
+
+fn is_writing(flags: u32) -> bool {
+    flags & P9_DOTL_ACCMODE != P9_DOTL_RDONLY
+        || flags & (P9_DOTL_CREATE | P9_DOTL_TRUNC | P9_DOTL_APPEND) != 0
+}
+
+impl Location {
+    fn stat(&self) -> std::io::Result<libc::stat> {
+        let mut st: libc::stat = unsafe { std::mem::zeroed() };
+        check(unsafe {
+            libc::fstatat(
+                self.dir.as_raw_fd(),
+                self.name.as_ptr(),
+                &mut st,
+                libc::AT_SYMLINK_NOFOLLOW,
+            )
+        })?;
+
+        Ok(st)
+    }
+
+    fn qid(&self) -> std::io::Result<Qid> {
+        Ok(qid_from_stat(&self.stat()?))
+    }
+
+    fn open(&self, flags: i32) -> std::io::Result<File> {
+        open_at(&self.dir, &self.name, flags, 0)
+    }
+
+    /// Opens a regular file or a directory. Opening a FIFO would block the
+    /// vCPU until the other end shows up, so the special files are refused.
+    fn open_file(&self, flags: i32) -> std::io::Result<File> {
+        let file = self.open(flags | libc::O_NONBLOCK)?;
+        match fstat(&file)?.st_mode & libc::S_IFMT {
+            libc::S_IFREG | libc::S_IFDIR => (),
+            _ => return Err(errno(libc::EOPNOTSUPP)),
+        }
+
+        if flags & libc::O_NONBLOCK == 0 {
+            let status = check(unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETFL) })?;
+            check(unsafe {
+                libc::fcntl(file.as_raw_fd(), libc::F_SETFL, status & !libc::O_NONBLOCK)
+            })?;
+        }
+
+        Ok(file)
+    }
+
+    fn is_root(&self) -> bool {
+        self.name.as_bytes() == b"."
+    }
+
+    fn chmod(&self, mode: u32) -> std::io::Result<()> {
+        // fchmodat cannot be told not to follow symlinks, go through
+        // the O_PATH descriptor of the file instead.
+        let file = self.open(libc::O_PATH)?;
+        if fstat(&file)?.st_mode & libc::S_IFMT == libc::S_IFLNK {
+            return Err(errno(libc::EOPNOTSUPP));
+        }
+
+        let proc_path = to_cstring(&format!("/proc/self/fd/{}", file.as_raw_fd()))?;
+        check(unsafe { libc::chmod(proc_path.as_ptr(), mode & 0o7777) })?;
+
+        Ok(())
+    }
+
+    fn chown(&self, uid: u32, gid: u32) -> std::io::Result<()> {
+        check(unsafe {
+            libc::fchownat(
+                self.dir.as_raw_fd(),
+                self.name.as_ptr(),
+                uid,
+                gid,
+                libc::AT_SYMLINK_NOFOLLOW,
+            )
+        })?;
+
+        Ok(())
+    }
+
+    fn set_times(&self, times: &[libc::timespec; 2]) -> std::io::Result<()> {
+        check(unsafe {
+            libc::utimensat(
+                self.dir.as_raw_fd(),
+                self.name.as_ptr(),
+                times.as_ptr(),
+                libc::AT_SYMLINK_NOFOLLOW,
+            )
+        })?;
+
+        Ok(())
+    }
+}
+
+impl P9Server {
+    pub fn new(root_path: &Path, read_only: bool) -> std::io::Result<Self> {
+        let path =
+            CString::new(root_path.as_os_str().as_bytes()).map_err(|_| errno(libc::EINVAL))?;
+        let fd = check(unsafe {
+            libc::open(
+                path.as_ptr(),
+                libc::O_PATH | libc::O_DIRECTORY | libc::O_CLOEXEC,
+            )
+        })?;
+
+        Ok(Self {
+            root: unsafe { File::from_raw_fd(fd) },
+            read_only,
+            msize: P9_MAX_MSIZE,
+            fids: HashMap::new(),
+        })
+    }
+
+    /// Handles a T-message, returns the R-message (Rlerror on failures)
+    pub fn handle(&mut self, request: &[u8]) -> Vec<u8> {
+        let mut reader = Reader::new(request);
+
+        let header = (|| -> std::io::Result<(u8, u16)> {
+            let _size = reader.u32()?;
+            Ok((reader.u8()?, reader.u16()?))
+        })();
+        let (type_, tag) = match header {
+            Ok(header) => header,
+            Err(e) => {
+                log::error!("Malformed 9P message {:x?}", request);
+                return Self::error_reply(!0, &e);
+            }
+        };
+
+        log::trace!("9P request type {}, tag {}", type_, tag);
+
+        let result = match type_ {
+            P9_TVERSION => self.version(tag, &mut reader),
+            P9_TATTACH => self.attach(tag, &mut reader),
+            P9_TAUTH => Err(errno(libc::EOPNOTSUPP)),
+            P9_TFLUSH => Ok(Writer::new(type_ + 1, tag)),
+            P9_TWALK => self.walk(tag, &mut reader),
+            P9_TCLUNK => self.clunk(tag, &mut reader),
+            P9_TREMOVE => self.remove(tag, &mut reader),
+            P9_TLOPEN => self.lopen(tag, &mut reader),
+            P9_TLCREATE => self.lcreate(tag, &mut reader),
+            P9_TREAD => self.read(tag, &mut reader),
+            P9_TWRITE => self.write(tag, &mut reader),
+            P9_TGETATTR => self.getattr(tag, &mut reader),
+            P9_TSETATTR => self.setattr(tag, &mut reader),
+            P9_TREADDIR => self.readdir(tag, &mut reader),
+            P9_TSTATFS => self.statfs(tag, &mut reader),
+            P9_TFSYNC => self.fsync(tag, &mut reader),
+            P9_TMKDIR => self.mkdir(tag, &mut reader),
+            P9_TSYMLINK => self.symlink(tag, &mut reader),
+            P9_TMKNOD => self.mknod(tag, &mut reader),
+            P9_TREADLINK => self.readlink(tag, &mut reader),
+            P9_TLINK => self.link(tag, &mut reader),
+            P9_TRENAME => self.rename(tag, &mut reader),
+            P9_TRENAMEAT => self.renameat(tag, &mut reader),
+            P9_TUNLINKAT => self.unlinkat(tag, &mut reader),
+            P9_TLOCK => self.lock(tag, &mut reader),
+            P9_TGETLOCK => self.getlock(tag, &mut reader),
+            P9_TXATTRWALK | P9_TXATTRCREATE => Err(errno(libc::EOPNOTSUPP)),
+            _ => {
+                log::warn!("Unsupported 9P request type {}", type_);
+                Err(errno(libc::EOPNOTSUPP))
+            }
+        };
+
+        match result {
+            Ok(writer) => writer.finish(),
+            Err(e) => {
+                log::debug!("9P request type {} failed: {}", type_, e);
+                Self::error_reply(tag, &e)
+            }
+        }
+    }
+
+    fn error_reply(tag: u16, error: &Error) -> Vec<u8> {
+        let mut writer = Writer::new(P9_RLERROR, tag);
+        writer.u32(error.raw_os_error().unwrap_or(libc::EIO) as u32);
+        writer.finish()
+    }
+
+    fn fid(&mut self, fid: u32) -> std::io::Result<&mut Fid> {
+        self.fids.get_mut(&fid).ok_or_else(|| errno(libc::EBADF))
+    }
+
+    fn check_writable(&self) -> std::io::Result<()> {
+        if self.read_only {
+            Err(errno(libc::EROFS))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Opens the directory the path leads to, every component is checked
+    /// not to be a symlink.
+    fn open_dir(&self, path: &[String]) -> std::io::Result<File> {
+        let mut dir = open_at(
+            &self.root,
+            &to_cstring(".")?,
+            libc::O_PATH | libc::O_DIRECTORY,
+            0,
+        )?;
+
+        for component in path {
+            dir = open_at(
+                &dir,
+                &to_cstring(component)?,
+                libc::O_PATH | libc::O_DIRECTORY,
+                0,
+            )?;
+        }
+
+        Ok(dir)
+    }
+
+    fn locate(&self, path: &[String]) -> std::io::Result<Location> {
+        match path.split_last() {
+            Some((name, parent)) => Ok(Location {
+                dir: self.open_dir(parent)?,
+                name: to_cstring(name)?,
+            }),
+            None => Ok(Location {
+                dir: self.open_dir(&[])?,
+                name: to_cstring(".")?,
+            }),
+        }
+    }
+
+    fn locate_fid(&mut self, fid: u32) -> std::io::Result<Location> {
+        let path = self.fid(fid)?.path.clone();
+        self.locate(&path)
+    }
+
+    fn version(&mut self, tag: u16, reader: &mut Reader) -> std::io::Result<Writer> {
+        let msize = reader.u32()?;
+        let version = reader.string()?;
+
+        log::info!("9P version {}, msize {}", version, msize);
+
+        if msize < P9_MIN_MSIZE {
+            log::error!("9P msize {} is below {}", msize, P9_MIN_MSIZE);
+            return Err(errno(libc::EINVAL));
+        }
+
+        self.fids.clear();
+        self.msize = msize.min(P9_MAX_MSIZE);
+
+        let mut writer = Writer::new(P9_TVERSION + 1, tag);
+        writer.u32(self.msize);
+        writer.string(if version == "9P2000.L" {
+            "9P2000.L"
+        } else {
+            "unknown"
+        });
+
+        Ok(writer)
+    }
+
+    fn attach(&mut self, tag: u16, reader: &mut Reader) -> std::io::Result<Writer> {
+        let fid = reader.u32()?;
+        let _afid = reader.u32()?;
+        let uname = reader.string()?;
+        let aname = reader.string()?;
+
+        log::info!(
+            "9P attach fid {}, uname '{}', aname '{}'",
+            fid,
+            uname,
+            aname
+        );
+
+        let qid = self.locate(&[])?.qid()?;
+        self.fids.insert(fid, Fid::default());
+
+        let mut writer = Writer::new(P9_TATTACH + 1, tag);
+        writer.qid(&qid);
+
+        Ok(writer)
+    }
+
+    fn walk(&mut self, tag: u16, reader: &mut Reader) -> std::io::Result<Writer> {
+        let fid = reader.u32()?;
+        let newfid = reader.u32()?;
+        let nwname = reader.u16()?;
+        let names = (0..nwname)
+            .map(|_| reader.string())
+            .collect::<std::io::Result<Vec<_>>>()?;
+
+        if newfid != fid && self.fids.contains_key(&newfid) {
+            return Err(errno(libc::EBADF));
+        }
+
+        let mut path = self.fid(fid)?.path.clone();
+        let mut qids = Vec::new();
+
+        for (index, name) in names.iter().enumerate() {
+            let mut next = path.clone();
+            match name.as_str() {
+                "." => {}
+                // Cannot go above the shared root
+                ".." => {
+                    next.pop();
+                }
+                _ => {
+                    validate_name(name)?;
+                    next.push(name.clone());
+                }
+            }
+
+            match self.locate(&next).and_then(|location| location.qid()) {
+                Ok(qid) => {
+                    qids.push(qid);
+                    path = next;
+                }
+                Err(e) if index == 0 => return Err(e),
+                Err(_) => break,
+            }
+        }
+
+        if qids.len() == names.len() {
+            self.fids.insert(
+                newfid,
+                Fid {
+                    path,
+                    ..Default::default()
+                },
+            );
+        }
+
+        let mut writer = Writer::new(P9_TWALK + 1, tag);
+        writer.u16(qids.len() as u16);
+        for qid in &qids {
+            writer.qid(qid);
+        }
+
+        Ok(writer)
+    }
+
+    fn clunk(&mut self, tag: u16, reader: &mut Reader) -> std::io::Result<Writer> {
+        let fid = reader.u32()?;
+        self.fids.remove(&fid).ok_or_else(|| errno(libc::EBADF))?;
+
+        Ok(Writer::new(P9_TCLUNK + 1, tag))
+    }
+
+    fn remove(&mut self, tag: u16, reader: &mut Reader) -> std::io::Result<Writer> {
+        let fid = reader.u32()?;
+        let location = self.locate_fid(fid);
+
+        // The fid is clunked even if the removal fails
+        self.fids.remove(&fid);
+
+        self.check_writable()?;
+        let location = location?;
+        if location.is_root() {
+            return Err(errno(libc::EBUSY));
+        }
+
+        let flags = if location.stat()?.st_mode & libc::S_IFMT == libc::S_IFDIR {
+            libc::AT_REMOVEDIR
+        } else {
+            0
+        };
+        check(unsafe { libc::unlinkat(location.dir.as_raw_fd(), location.name.as_ptr(), flags) })?;
+
+        Ok(Writer::new(P9_TREMOVE + 1, tag))
+    }
+
+    fn lopen(&mut self, tag: u16, reader: &mut Reader) -> std::io::Result<Writer> {
+        let fid = reader.u32()?;
+        let flags = reader.u32()?;
+
+        if is_writing(flags) {
+            self.check_writable()?;
+        }
+
+        let location = self.locate_fid(fid)?;
+        let file = location.open_file(host_open_flags(flags) & !(libc::O_CREAT | libc::O_EXCL))?;
+        let qid = qid_from_stat(&fstat(&file)?);
+
+        let fid = self.fid(fid)?;
+        fid.file = Some(file);
+        fid.dir_entries = None;
+
+        let mut writer = Writer::new(P9_TLOPEN + 1, tag);
+        writer.qid(&qid);
+        writer.u32(0);
+
+        Ok(writer)
+    }
+
+    fn lcreate(&mut self, tag: u16, reader: &mut Reader) -> std::io::Result<Writer> {
+        let fid = reader.u32()?;
+        let name = reader.string()?;
+        let flags = reader.u32()?;
+        let mode = reader.u32()?;
+        let _gid = reader.u32()?;
+
+        self.check_writable()?;
+        validate_name(&name)?;
+
+        let mut path = self.fid(fid)?.path.clone();
+        let dir = self.open_dir(&path)?;
+        let file = open_at(
+            &dir,
+            &to_cstring(&name)?,
+            host_open_flags(flags) | libc::O_CREAT,
+            mode & 0o7777,
+        )?;
+        let qid = qid_from_stat(&fstat(&file)?);
+
+        // The fid now stands for the newly created file
+        path.push(name);
+        let fid = self.fid(fid)?;
+        fid.path = path;
+        fid.file = Some(file);
+        fid.dir_entries = None;
+
+        let mut writer = Writer::new(P9_TLCREATE + 1, tag);
+        writer.qid(&qid);
+        writer.u32(0);
+
+        Ok(writer)
+    }
+
+    fn read(&mut self, tag: u16, reader: &mut Reader) -> std::io::Result<Writer> {
+        let fid = reader.u32()?;
+        let offset = reader.u64()?;
+        let count = reader.u32()?.min(self.msize - P9_IOHDR_SIZE);
+
+        let file = self
+            .fid(fid)?
+            .file
+            .as_ref()
+            .ok_or_else(|| errno(libc::EBADF))?;
+        let mut data = vec![0_u8; count as usize];
+        let read = file.read_at(&mut data, offset)?;
+
+        let mut writer = Writer::new(P9_TREAD + 1, tag);
+        writer.u32(read as u32);
+        writer.bytes(&data[..read]);
+
+        Ok(writer)
+    }
+
+    fn write(&mut self, tag: u16, reader: &mut Reader) -> std::io::Result<Writer> {
+        let fid = reader.u32()?;
+        let offset = reader.u64()?;
+        let count = reader.u32()?;
+        let data = reader.bytes(count as usize)?;
+
+        self.check_writable()?;
+
+        let file = self
+            .fid(fid)?
+            .file
+            .as_ref()
+            .ok_or_else(|| errno(libc::EBADF))?;
+        let written = file.write_at(data, offset)?;
+
+        let mut writer = Writer::new(P9_TWRITE + 1, tag);
+        writer.u32(written as u32);
+
+        Ok(writer)
+    }
+
+    fn getattr(&mut self, tag: u16, reader: &mut Reader) -> std::io::Result<Writer> {
+        let fid = reader.u32()?;
+        let _request_mask = reader.u64()?;
+
+        let st = self.locate_fid(fid)?.stat()?;
+
+        let mut writer = Writer::new(P9_TGETATTR + 1, tag);
+        writer.u64(P9_GETATTR_BASIC);
+        writer.qid(&qid_from_stat(&st));
+        writer.u32(st.st_mode);
+        writer.u32(st.st_uid);
+        writer.u32(st.st_gid);
+        // u32 on aarch64
+        #[allow(clippy::useless_conversion)]
+        writer.u64(st.st_nlink.into());
+        writer.u64(st.st_rdev);
+        writer.u64(st.st_size as u64);
+        writer.u64(st.st_blksize as u64);
+        writer.u64(st.st_blocks as u64);
+        writer.u64(st.st_atime as u64);
+        writer.u64(st.st_atime_nsec as u64);
+        writer.u64(st.st_mtime as u64);
+        writer.u64(st.st_mtime_nsec as u64);
+        writer.u64(st.st_ctime as u64);
+        writer.u64(st.st_ctime_nsec as u64);
+        // btime, gen, data_version are not reported
+        writer.u64(0);
+        writer.u64(0);
+        writer.u64(0);
+        writer.u64(0);
+
+        Ok(writer)
+    }
+
+    fn setattr(&mut self, tag: u16, reader: &mut Reader) -> std::io::Result<Writer> {
+        let fid = reader.u32()?;
+        let valid = reader.u32()?;
+        let mode = reader.u32()?;
+        let uid = reader.u32()?;
+        let gid = reader.u32()?;
+        let size = reader.u64()?;
+        let atime_sec = reader.u64()?;
+        let atime_nsec = reader.u64()?;
+        let mtime_sec = reader.u64()?;
+        let mtime_nsec = reader.u64()?;
+
+        self.check_writable()?;
+
+        let location = self.locate_fid(fid)?;
+
+        if valid & P9_SETATTR_MODE != 0 {
+            location.chmod(mode)?;
+        }
+
+        if valid & (P9_SETATTR_UID | P9_SETATTR_GID) != 0 {
+            location.chown(
+                if valid & P9_SETATTR_UID != 0 { uid } else { !0 },
+                if valid & P9_SETATTR_GID != 0 { gid } else { !0 },
+            )?;
+        }
+
+        if valid & P9_SETATTR_SIZE != 0 {
+            location.open_file(libc::O_WRONLY)?.set_len(size)?;
+        }
+
+        if valid & (P9_SETATTR_ATIME | P9_SETATTR_MTIME) != 0 {
+            let time = |requested: u32, explicit: u32, sec: u64, nsec: u64| {
+                if valid & requested == 0 {
+                    libc::timespec {
+                        tv_sec: 0,
+                        tv_nsec: libc::UTIME_OMIT,
+                    }
+                } else if valid & explicit == 0 {
+                    libc::timespec {
+                        tv_sec: 0,
+                        tv_nsec: libc::UTIME_NOW,
+                    }
+                } else {
+                    libc::timespec {
+                        tv_sec: sec as libc::time_t,
+                        tv_nsec: nsec as libc::c_long,
+                    }
+                }
+            };
+
+            location.set_times(&[
+                time(
+                    P9_SETATTR_ATIME,
+                    P9_SETATTR_ATIME_SET,
+                    atime_sec,
+                    atime_nsec,
+                ),
+                time(
+                    P9_SETATTR_MTIME,
+                    P9_SETATTR_MTIME_SET,
+                    mtime_sec,
+                    mtime_nsec,
+                ),
+            ])?;
+        }
+
+        Ok(Writer::new(P9_TSETATTR + 1, tag))
+    }
+
+    fn read_dir_entries(&self, fid: &Fid) -> std::io::Result<Vec<DirEntry>> {
+        let file = fid.file.as_ref().ok_or_else(|| errno(libc::EBADF))?;
+        let dir_qid = qid_from_stat(&fstat(file)?);
+
+        let parent_qid = match fid.path.split_last() {
+            Some((_, parent)) => self.locate(parent)?.qid()?,
+            None => dir_qid,
+        };
+
+        let mut entries = vec![
+            DirEntry {
+                qid: dir_qid,
+                type_: libc::DT_DIR,
+                name: ".".to_string(),
+            },
+            DirEntry {
+                qid: parent_qid,
+                type_: libc::DT_DIR,
+                name: "..".to_string(),
+            },
+        ];
+
+        // The descriptor has been opened without following symlinks, reading through
+        // the procfs link stays within the same directory.
+        for entry in std::fs::read_dir(format!("/proc/self/fd/{}", file.as_raw_fd()))? {
+            let entry = entry?;
+            let file_type = entry.file_type()?;
+            let type_ = dir_entry_type(file_type);
+
+            entries.push(DirEntry {
+                qid: Qid {
+                    type_: match type_ {
+                        libc::DT_DIR => P9_QTDIR,
+                        libc::DT_LNK => P9_QTSYMLINK,
+                        _ => P9_QTFILE,
+                    },
+                    version: 0,
+                    path: entry.ino(),
+                },
+                type_,
+                name: entry.file_name().to_string_lossy().into_owned(),
+            });
+        }
+
+        Ok(entries)
+    }
+
+    fn readdir(&mut self, tag: u16, reader: &mut Reader) -> std::io::Result<Writer> {
+        let fid_index = reader.u32()?;
+        let offset = reader.u64()?;
+        let count = reader.u32()?.min(self.msize - P9_IOHDR_SIZE) as usize;
+
+        // Take a snapshot of the directory when starting over
+        if offset == 0 || self.fid(fid_index)?.dir_entries.is_none() {
+            let entries = self.read_dir_entries(self.fids.get(&fid_index).unwrap())?;
+            self.fid(fid_index)?.dir_entries = Some(entries);
+        }
+
+        let fid = self.fid(fid_index)?;
+        let entries = fid.dir_entries.as_ref().unwrap();
+
+        let mut data = Writer::new(0, 0);
+        let header_len = data.len();
+        for (index, entry) in entries.iter().enumerate().skip(offset as usize) {
+            let entry_len = 13 + 8 + 1 + 2 + entry.name.len();
+            if data.len() - header_len + entry_len > count {
+                break;
+            }
+
+            data.qid(&entry.qid);
+            data.u64(index as u64 + 1);
+            data.u8(entry.type_);
+            data.string(&entry.name);
+        }
+
+        let data = data.finish();
+        let data = &data[header_len..];
+
+        let mut writer = Writer::new(P9_TREADDIR + 1, tag);
+        writer.u32(data.len() as u32);
+        writer.bytes(data);
+
+        Ok(writer)
+    }
+
+    fn statfs(&mut self, tag: u16, reader: &mut Reader) -> std::io::Result<Writer> {
+        let _fid = reader.u32()?;
+
+        let mut st: libc::statfs = unsafe { std::mem::zeroed() };
+        check(unsafe { libc::fstatfs(self.root.as_raw_fd(), &mut st) })?;
+
+        let mut writer = Writer::new(P9_TSTATFS + 1, tag);
+        writer.u32(st.f_type as u32);
+        writer.u32(st.f_bsize as u32);
+        writer.u64(st.f_blocks as u64);
+        writer.u64(st.f_bfree as u64);
+        writer.u64(st.f_bavail as u64);
+        writer.u64(st.f_files as u64);
+        writer.u64(st.f_ffree as u64);
+        writer.u64(0);
+        writer.u32(st.f_namelen as u32);
+
+        Ok(writer)
+    }
+
+    fn fsync(&mut self, tag: u16, reader: &mut Reader) -> std::io::Result<Writer> {
+        let fid = reader.u32()?;
+        let datasync = reader.u32()?;
+
+        let file = self
+            .fid(fid)?
+            .file
+            .as_ref()
+            .ok_or_else(|| errno(libc::EBADF))?;
+        if datasync != 0 {
+            file.sync_data()?;
+        } else {
+            file.sync_all()?;
+        }
+
+        Ok(Writer::new(P9_TFSYNC + 1, tag))
+    }
+
+    fn mkdir(&mut self, tag: u16, reader: &mut Reader) -> std::io::Result<Writer> {
+        let dfid = reader.u32()?;
+        let name = reader.string()?;
+        let mode = reader.u32()?;
+        let _gid = reader.u32()?;
+
+        self.check_writable()?;
+        validate_name(&name)?;
+
+        let path = self.fid(dfid)?.path.clone();
+        let location = Location {
+            dir: self.open_dir(&path)?,
+            name: to_cstring(&name)?,
+        };
+        check(unsafe {
+            libc::mkdirat(
+                location.dir.as_raw_fd(),
+                location.name.as_ptr(),
+                mode & 0o7777,
+            )
+        })?;
+
+        let mut writer = Writer::new(P9_TMKDIR + 1, tag);
+        writer.qid(&location.qid()?);
+
+        Ok(writer)
+    }
+
+    fn symlink(&mut self, tag: u16, reader: &mut Reader) -> std::io::Result<Writer> {
+        let fid = reader.u32()?;
+        let name = reader.string()?;
+        let target = reader.string()?;
+        let _gid = reader.u32()?;
+
+        self.check_writable()?;
+        validate_name(&name)?;
+
+        let path = self.fid(fid)?.path.clone();
+        let location = Location {
+            dir: self.open_dir(&path)?,
+            name: to_cstring(&name)?,
+        };
+
+        // The target is never followed on the host
+        let target = to_cstring(&target)?;
+        check(unsafe {
+            libc::symlinkat(
+                target.as_ptr(),
+                location.dir.as_raw_fd(),
+                location.name.as_ptr(),
+            )
+        })?;
+
+        let mut writer = Writer::new(P9_TSYMLINK + 1, tag);
+        writer.qid(&location.qid()?);
+
+        Ok(writer)
+    }
+
+    fn mknod(&mut self, tag: u16, reader: &mut Reader) -> std::io::Result<Writer> {
+        let dfid = reader.u32()?;
+        let name = reader.string()?;
+        let mode = reader.u32()?;
+        let _major = reader.u32()?;
+        let _minor = reader.u32()?;
+        let _gid = reader.u32()?;
+
+        self.check_writable()?;
+        validate_name(&name)?;
+
+        // The device nodes would give the guest the host devices
+        let file_type = mode & libc::S_IFMT;
+        if ![libc::S_IFIFO, libc::S_IFSOCK, libc::S_IFREG].contains(&file_type) {
+            return Err(errno(libc::EPERM));
+        }
+
+        let path = self.fid(dfid)?.path.clone();
+        let location = Location {
+            dir: self.open_dir(&path)?,
+            name: to_cstring(&name)?,
+        };
+        check(unsafe {
+            libc::mknodat(
+                location.dir.as_raw_fd(),
+                location.name.as_ptr(),
+                file_type | mode & 0o7777,
+                0,
+            )
+        })?;
+
+        let mut writer = Writer::new(P9_TMKNOD + 1, tag);
+        writer.qid(&location.qid()?);
+
+        Ok(writer)
+    }
+
+    fn readlink(&mut self, tag: u16, reader: &mut Reader) -> std::io::Result<Writer> {
+        let fid = reader.u32()?;
+
+        let location = self.locate_fid(fid)?;
+        let mut target = vec![0_u8; libc::PATH_MAX as usize];
+        let len = unsafe {
+            libc::readlinkat(
+                location.dir.as_raw_fd(),
+                location.name.as_ptr(),
+                target.as_mut_ptr() as *mut libc::c_char,
+                target.len(),
+            )
+        };
+        if len < 0 {
+            return Err(Error::last_os_error());
+        }
+
+        let mut writer = Writer::new(P9_TREADLINK + 1, tag);
+        writer.string(&String::from_utf8_lossy(&target[..len as usize]));
+
+        Ok(writer)
+    }
+
+    fn link(&mut self, tag: u16, reader: &mut Reader) -> std::io::Result<Writer> {
+        let dfid = reader.u32()?;
+        let fid = reader.u32()?;
+        let name = reader.string()?;
+
+        self.check_writable()?;
+        validate_name(&name)?;
+
+        let source = self.locate_fid(fid)?;
+        let path = self.fid(dfid)?.path.clone();
+        let dir = self.open_dir(&path)?;
+        let name = to_cstring(&name)?;
+
+        check(unsafe {
+            libc::linkat(
+                source.dir.as_raw_fd(),
+                source.name.as_ptr(),
+                dir.as_raw_fd(),
+                name.as_ptr(),
+                0,
+            )
+        })?;
+
+        Ok(Writer::new(P9_TLINK + 1, tag))
+    }
+
+    fn rename(&mut self, tag: u16, reader: &mut Reader) -> std::io::Result<Writer> {
+        let fid = reader.u32()?;
+        let dfid = reader.u32()?;
+        let name = reader.string()?;
+
+        self.check_writable()?;
+        validate_name(&name)?;
+
+        let source = self.locate_fid(fid)?;
+        if source.is_root() {
+            return Err(errno(libc::EBUSY));
+        }
+
+        let mut path = self.fid(dfid)?.path.clone();
+        let dir = self.open_dir(&path)?;
+        let new_name = to_cstring(&name)?;
+
+        check(unsafe {
+            libc::renameat(
+                source.dir.as_raw_fd(),
+                source.name.as_ptr(),
+                dir.as_raw_fd(),
+                new_name.as_ptr(),
+            )
+        })?;
+
+        path.push(name);
+        self.fid(fid)?.path = path;
+
+        Ok(Writer::new(P9_TRENAME + 1, tag))
+    }
+
+    fn renameat(&mut self, tag: u16, reader: &mut Reader) -> std::io::Result<Writer> {
+        let old_dfid = reader.u32()?;
+        let old_name = reader.string()?;
+        let new_dfid = reader.u32()?;
+        let new_name = reader.string()?;
+
+        self.check_writable()?;
+        validate_name(&old_name)?;
+        validate_name(&new_name)?;
+
+        let old_path = self.fid(old_dfid)?.path.clone();
+        let new_path = self.fid(new_dfid)?.path.clone();
+        let old_dir = self.open_dir(&old_path)?;
+        let new_dir = self.open_dir(&new_path)?;
+        let old_name = to_cstring(&old_name)?;
+        let new_name = to_cstring(&new_name)?;
+
+        check(unsafe {
+            libc::renameat(
+                old_dir.as_raw_fd(),
+                old_name.as_ptr(),
+                new_dir.as_raw_fd(),
+                new_name.as_ptr(),
+            )
+        })?;
+
+        Ok(Writer::new(P9_TRENAMEAT + 1, tag))
+    }
+
+    fn unlinkat(&mut self, tag: u16, reader: &mut Reader) -> std::io::Result<Writer> {
+        let dfid = reader.u32()?;
+        let name = reader.string()?;
+        let flags = reader.u32()?;
+
+        self.check_writable()?;
+        validate_name(&name)?;
+
+        let path = self.fid(dfid)?.path.clone();
+        let dir = self.open_dir(&path)?;
+        let name = to_cstring(&name)?;
+        let flags = if flags & P9_DOTL_AT_REMOVEDIR != 0 {
+            libc::AT_REMOVEDIR
+        } else {
+            0
+        };
+
+        check(unsafe { libc::unlinkat(dir.as_raw_fd(), name.as_ptr(), flags) })?;
+
+        Ok(Writer::new(P9_TUNLINKAT + 1, tag))
+    }
+
+    fn lock(&mut self, tag: u16, reader: &mut Reader) -> std::io::Result<Writer> {
+        let fid = reader.u32()?;
+        self.fid(fid)?;
+
+        // Locks are advisory and there is a single client, always granted
+        let mut writer = Writer::new(P9_TLOCK + 1, tag);
+        writer.u8(P9_LOCK_SUCCESS);
+
+        Ok(writer)
+    }
+
+    fn getlock(&mut self, tag: u16, reader: &mut Reader) -> std::io::Result<Writer> {
+        let fid = reader.u32()?;
+        let _type = reader.u8()?;
+        let start = reader.u64()?;
+        let length = reader.u64()?;
+        let proc_id = reader.u32()?;
+        let client_id = reader.string()?;
+        self.fid(fid)?;
+
+        let mut writer = Writer::new(P9_TGETLOCK + 1, tag);
+        writer.u8(P9_LOCK_TYPE_UNLCK);
+        writer.u64(start);
+        writer.u64(length);
+        writer.u32(proc_id);
+        writer.string(&client_id);
+
+        Ok(writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROOT_FID: u32 = 0;
+
+    /// Writes the fields of a request past the header
+    type RequestBody = Box<dyn Fn(&mut Writer)>;
+
+    /// The shared root, with a directory beside it the guest must not reach:
+    ///
+    ///   root/dir/file
+    ///   root/absolute -> /etc
+    ///   root/escape -> ../outside
+    ///   outside/secret
+    struct SharedDir {
+        base: std::path::PathBuf,
+    }
+
+    impl SharedDir {
+        fn new(name: &str) -> Self {
+            let base =
+                std::env::temp_dir().join(format!("smolvm-p9-{}-{}", std::process::id(), name));
+            let _ = std::fs::remove_dir_all(&base);
+            std::fs::create_dir_all(base.join("root/dir")).unwrap();
+            std::fs::create_dir_all(base.join("outside")).unwrap();
+            std::fs::write(base.join("root/dir/file"), b"file").unwrap();
+            std::fs::write(base.join("outside/secret"), b"secret").unwrap();
+            std::os::unix::fs::symlink("/etc", base.join("root/absolute")).unwrap();
+            std::os::unix::fs::symlink("../outside", base.join("root/escape")).unwrap();
+
+            Self { base }
+        }
+
+        fn server(&self, read_only: bool) -> P9Server {
+            let mut server = P9Server::new(&self.base.join("root"), read_only).unwrap();
+            assert_eq!(error(&version(&mut server, P9_MAX_MSIZE)), None);
+            assert_eq!(
+                error(&request(&mut server, P9_TATTACH, |w| {
+                    w.u32(ROOT_FID);
+                    w.u32(P9_NOFID);
+                    w.string("root");
+                    w.string("");
+                })),
+                None
+            );
+
+            server
+        }
+    }
+
+    impl Drop for SharedDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.base);
+        }
+    }
+
+    fn request(server: &mut P9Server, type_: u8, body: impl FnOnce(&mut Writer)) -> Vec<u8> {
+        let mut writer = Writer::new(type_, 1);
+        body(&mut writer);
+        server.handle(&writer.finish())
+    }
+
+    /// The errno of an Rlerror
+    fn error(reply: &[u8]) -> Option<i32> {
+        if reply[4] == P9_RLERROR {
+            Some(u32::from_le_bytes([reply[7], reply[8], reply[9], reply[10]]) as i32)
+        } else {
+            None
+        }
+    }
+
+    fn version(server: &mut P9Server, msize: u32) -> Vec<u8> {
+        request(server, P9_TVERSION, |w| {
+            w.u32(msize);
+            w.string("9P2000.L");
+        })
+    }
+
+    /// The number of the qids walked
+    fn walk(server: &mut P9Server, fid: u32, newfid: u32, names: &[&str]) -> Result<u16, i32> {
+        let reply = request(server, P9_TWALK, |w| {
+            w.u32(fid);
+            w.u32(newfid);
+            w.u16(names.len() as u16);
+            for name in names {
+                w.string(name);
+            }
+        });
+        match error(&reply) {
+            Some(errno) => Err(errno),
+            None => Ok(u16::from_le_bytes([reply[7], reply[8]])),
+        }
+    }
+
+    fn lopen(server: &mut P9Server, fid: u32, flags: u32) -> Option<i32> {
+        error(&request(server, P9_TLOPEN, |w| {
+            w.u32(fid);
+            w.u32(flags);
+        }))
+    }
+
+    fn clunk(server: &mut P9Server, fid: u32) -> Option<i32> {
+        error(&request(server, P9_TCLUNK, |w| w.u32(fid)))
+    }
+
+    #[test]
+    fn test_confinement() {
+        let shared_dir = SharedDir::new("confinement");
+        let mut server = shared_dir.server(false);
+
+        // ".." stays at the root
+        assert_eq!(walk(&mut server, ROOT_FID, 1, &["..", ".."]), Ok(2));
+        assert_eq!(
+            walk(&mut server, ROOT_FID, 2, &["..", "dir", "file"]),
+            Ok(3)
+        );
+        assert_eq!(
+            walk(&mut server, ROOT_FID, 3, &["..", "outside"]),
+            Ok(1),
+            "Only the first name is walked"
+        );
+        assert_eq!(clunk(&mut server, 3), Some(libc::EBADF));
+
+        // The symlinks are not followed, neither as directories nor opened
+        assert_eq!(
+            walk(&mut server, ROOT_FID, 4, &["absolute", "passwd"]),
+            Ok(1)
+        );
+        assert_eq!(walk(&mut server, ROOT_FID, 5, &["escape", "secret"]), Ok(1));
+        assert_eq!(walk(&mut server, ROOT_FID, 6, &["escape"]), Ok(1));
+        assert_eq!(lopen(&mut server, 6, P9_DOTL_RDONLY), Some(libc::ELOOP));
+
+        // The guest gets the target to resolve itself
+        let reply = request(&mut server, P9_TREADLINK, |w| w.u32(6));
+        assert_eq!(&reply[9..], b"../outside");
+
+        // Names with a slash are rejected
+        assert_eq!(
+            walk(&mut server, ROOT_FID, 7, &["escape/secret"]),
+            Err(libc::EINVAL)
+        );
+        assert_eq!(
+            error(&request(&mut server, P9_TLCREATE, |w| {
+                w.u32(ROOT_FID);
+                w.string("../created");
+                w.u32(P9_DOTL_CREATE | P9_DOTL_WRONLY);
+                w.u32(0o644);
+                w.u32(0);
+            })),
+            Some(libc::EINVAL)
+        );
+        assert!(!shared_dir.base.join("created").exists());
+    }
+
+    #[test]
+    fn test_read_only() {
+        let shared_dir = SharedDir::new("read-only");
+        let mut server = shared_dir.server(true);
+        assert_eq!(walk(&mut server, ROOT_FID, 1, &["dir", "file"]), Ok(2));
+        assert_eq!(walk(&mut server, ROOT_FID, 2, &["dir"]), Ok(1));
+
+        assert_eq!(lopen(&mut server, 1, P9_DOTL_RDWR), Some(libc::EROFS));
+        assert_eq!(lopen(&mut server, 1, P9_DOTL_TRUNC), Some(libc::EROFS));
+        assert_eq!(lopen(&mut server, 1, P9_DOTL_RDONLY), None);
+
+        let mutating: Vec<(u8, RequestBody)> = vec![
+            (
+                P9_TLCREATE,
+                Box::new(|w| {
+                    w.u32(2);
+                    w.string("new");
+                    w.u32(P9_DOTL_CREATE | P9_DOTL_WRONLY);
+                    w.u32(0o644);
+                    w.u32(0);
+                }),
+            ),
+            (
+                P9_TWRITE,
+                Box::new(|w| {
+                    w.u32(1);
+                    w.u64(0);
+                    w.u32(1);
+                    w.u8(b'x');
+                }),
+            ),
+            (
+                P9_TSETATTR,
+                Box::new(|w| {
+                    w.u32(1);
+                    w.u32(P9_SETATTR_SIZE);
+                    w.u32(0);
+                    w.u32(0);
+                    w.u32(0);
+                    w.u64(0);
+                    (0..4).for_each(|_| w.u64(0));
+                }),
+            ),
+            (
+                P9_TMKDIR,
+                Box::new(|w| {
+                    w.u32(2);
+                    w.string("new");
+                    w.u32(0o755);
+                    w.u32(0);
+                }),
+            ),
+            (
+                P9_TSYMLINK,
+                Box::new(|w| {
+                    w.u32(2);
+                    w.string("new");
+                    w.string("file");
+                    w.u32(0);
+                }),
+            ),
+            (
+                P9_TMKNOD,
+                Box::new(|w| {
+                    w.u32(2);
+                    w.string("new");
+                    w.u32(libc::S_IFIFO | 0o644);
+                    w.u32(0);
+                    w.u32(0);
+                    w.u32(0);
+                }),
+            ),
+            (
+                P9_TLINK,
+                Box::new(|w| {
+                    w.u32(2);
+                    w.u32(1);
+                    w.string("new");
+                }),
+            ),
+            (
+                P9_TRENAME,
+                Box::new(|w| {
+                    w.u32(1);
+                    w.u32(2);
+                    w.string("new");
+                }),
+            ),
+            (
+                P9_TRENAMEAT,
+                Box::new(|w| {
+                    w.u32(2);
+                    w.string("file");
+                    w.u32(2);
+                    w.string("new");
+                }),
+            ),
+            (
+                P9_TUNLINKAT,
+                Box::new(|w| {
+                    w.u32(2);
+                    w.string("file");
+                    w.u32(0);
+                }),
+            ),
+            (P9_TREMOVE, Box::new(|w| w.u32(1))),
+        ];
+        for (type_, body) in mutating {
+            let reply = request(&mut server, type_, |w| body(w));
+            assert_eq!(error(&reply), Some(libc::EROFS), "Request type {}", type_);
+        }
+
+        assert_eq!(
+            std::fs::read(shared_dir.base.join("root/dir/file")).unwrap(),
+            b"file"
+        );
+        assert_eq!(
+            std::fs::read_dir(shared_dir.base.join("root/dir"))
+                .unwrap()
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_fids() {
+        let shared_dir = SharedDir::new("fids");
+        let mut server = shared_dir.server(false);
+
+        assert_eq!(walk(&mut server, ROOT_FID, 1, &["dir"]), Ok(1));
+        // The new fid must not be in use
+        assert_eq!(walk(&mut server, ROOT_FID, 1, &["dir"]), Err(libc::EBADF));
+        // Walking a fid onto itself moves it
+        assert_eq!(walk(&mut server, 1, 1, &["file"]), Ok(1));
+        assert_eq!(lopen(&mut server, 1, P9_DOTL_RDONLY), None);
+
+        assert_eq!(clunk(&mut server, 1), None);
+        assert_eq!(clunk(&mut server, 1), Some(libc::EBADF));
+        assert_eq!(lopen(&mut server, 1, P9_DOTL_RDONLY), Some(libc::EBADF));
+        assert_eq!(walk(&mut server, 1, 2, &[]), Err(libc::EBADF));
+
+        // Reused once clunked
+        assert_eq!(walk(&mut server, ROOT_FID, 1, &["dir", "file"]), Ok(2));
+
+        // The version starts a new session without the fids
+        assert_eq!(error(&version(&mut server, P9_MAX_MSIZE)), None);
+        assert_eq!(clunk(&mut server, ROOT_FID), Some(libc::EBADF));
+    }
+
+    #[test]
+    fn test_checks() {
+        let shared_dir = SharedDir::new("checks");
+        let mut server = shared_dir.server(false);
+
+        assert_eq!(error(&version(&mut server, 10)), Some(libc::EINVAL));
+        let reply = version(&mut server, 8 * 1024 * 1024);
+        assert_eq!(&reply[7..11], &P9_MAX_MSIZE.to_le_bytes());
+        assert_eq!(
+            error(&request(&mut server, P9_TATTACH, |w| {
+                w.u32(ROOT_FID);
+                w.u32(P9_NOFID);
+                w.string("root");
+                w.string("");
+            })),
+            None
+        );
+
+        let mknod = |server: &mut P9Server, name: &str, mode: u32| {
+            error(&request(server, P9_TMKNOD, |w| {
+                w.u32(ROOT_FID);
+                w.string(name);
+                w.u32(mode);
+                w.u32(1);
+                w.u32(3);
+                w.u32(0);
+            }))
+        };
+        assert_eq!(
+            mknod(&mut server, "null", libc::S_IFCHR | 0o666),
+            Some(libc::EPERM)
+        );
+        assert_eq!(
+            mknod(&mut server, "disk", libc::S_IFBLK | 0o666),
+            Some(libc::EPERM)
+        );
+        assert_eq!(mknod(&mut server, "fifo", libc::S_IFIFO | 0o600), None);
+
+        let st = std::fs::symlink_metadata(shared_dir.base.join("root/fifo")).unwrap();
+        assert!(st.file_type().is_fifo());
+
+        // Opening the FIFO would wait for a writer
+        assert_eq!(walk(&mut server, ROOT_FID, 1, &["fifo"]), Ok(1));
+        assert_eq!(
+            lopen(&mut server, 1, P9_DOTL_RDONLY),
+            Some(libc::EOPNOTSUPP)
+        );
+        let reply = request(&mut server, P9_TSETATTR, |w| {
+            w.u32(1);
+            w.u32(P9_SETATTR_SIZE);
+            w.u32(0);
+            w.u32(0);
+            w.u32(0);
+            w.u64(0);
+            (0..4).for_each(|_| w.u64(0));
+        });
+        assert!(error(&reply).is_some());
+    }
+}
@@ -0,0 +1,399 @@
+//! The virtio-pci transport, modern (virtio 1.x) interface only.
+//!
+//! All structures live in BAR 0:
+//!
+//!   Offset  Structure
+//!   -----------------------------------------------
+//!   0x0000  Common configuration
+//!   0x1000  ISR status
+//!   0x2000  Device-specific configuration
+//!   0x3000  Notifications, 4 bytes per queue
+//!   0x4000  MSI-X table
+//!   0x6000  MSI-X pending bit array
+//!
+//! The interrupts are delivered with MSI-X only, there is no INTx.
+
+use std::sync::{Arc, Mutex};
+
+use super::{
+    transport::TransportState, SharedVirtioDevice, VirtioTransport, VIRTIO_ID_BLOCK, VIRTIO_ID_NET,
+};
+use crate::smolvm::{
+    pci::{MsixTable, PciConfig, PciDevice, PCI_CAP_ID_VNDR},
+    IrqChip, Memory,
+};
+
+const VIRTIO_PCI_VENDOR_ID: u16 = 0x1af4;
+const VIRTIO_PCI_DEVICE_ID_BASE: u16 = 0x1040;
+const VIRTIO_PCI_SUBSYSTEM_ID: u16 = 0x1100;
+
+const VIRTIO_PCI_CAP_COMMON_CFG: u8 = 1;
+const VIRTIO_PCI_CAP_NOTIFY_CFG: u8 = 2;
+const VIRTIO_PCI_CAP_ISR_CFG: u8 = 3;
+const VIRTIO_PCI_CAP_DEVICE_CFG: u8 = 4;
+
+const VIRTIO_PCI_BAR: usize = 0;
+const VIRTIO_PCI_BAR_SIZE: u64 = 0x8000;
+
+const COMMON_CFG_OFFSET: u64 = 0x0000;
+const COMMON_CFG_SIZE: u64 = 0x38;
+const ISR_CFG_OFFSET: u64 = 0x1000;
+const DEVICE_CFG_OFFSET: u64 = 0x2000;
+const DEVICE_CFG_SIZE: u64 = 0x1000;
+const NOTIFY_CFG_OFFSET: u64 = 0x3000;
+const NOTIFY_OFF_MULTIPLIER: u32 = 4;
+const MSIX_TABLE_OFFSET: u64 = 0x4000;
+const MSIX_PBA_OFFSET: u64 = 0x6000;
+
+// struct virtio_pci_common_cfg
+const COMMON_DEVICE_FEATURE_SELECT: u64 = 0x00;
+const COMMON_DEVICE_FEATURE: u64 = 0x04;
+const COMMON_DRIVER_FEATURE_SELECT: u64 = 0x08;
+const COMMON_DRIVER_FEATURE: u64 = 0x0c;
+const COMMON_MSIX_CONFIG: u64 = 0x10;
+const COMMON_NUM_QUEUES: u64 = 0x12;
+const COMMON_DEVICE_STATUS: u64 = 0x14;
+const COMMON_CONFIG_GENERATION: u64 = 0x15;
+const COMMON_QUEUE_SELECT: u64 = 0x16;
+const COMMON_QUEUE_SIZE: u64 = 0x18;
+const COMMON_QUEUE_MSIX_VECTOR: u64 = 0x1a;
+const COMMON_QUEUE_ENABLE: u64 = 0x1c;
+const COMMON_QUEUE_NOTIFY_OFF: u64 = 0x1e;
+const COMMON_QUEUE_DESC_LO: u64 = 0x20;
+const COMMON_QUEUE_DESC_HI: u64 = 0x24;
+const COMMON_QUEUE_DRIVER_LO: u64 = 0x28;
+const COMMON_QUEUE_DRIVER_HI: u64 = 0x2c;
+const COMMON_QUEUE_DEVICE_LO: u64 = 0x30;
+const COMMON_QUEUE_DEVICE_HI: u64 = 0x34;
+
+const VIRTIO_PCI_ISR_QUEUE: u8 = 1;
+const VIRTIO_PCI_ISR_CONFIG: u8 = 2;
+
+const VIRTIO_MSI_NO_VECTOR: u16 = 0xffff;
+
+pub struct VirtioPci {
+    config: PciConfig,
+    state: TransportState,
+    msix: MsixTable,
+    isr_status: u8,
+    msix_config_vector: u16,
+    queue_vectors: Vec<u16>,
+}
+
+impl VirtioPci {
+    pub fn new(
+        device: SharedVirtioDevice,
+        memory: Arc<Mutex<Memory>>,
+        irq_chip: Arc<dyn IrqChip>,
+        requester_id: u32,
+    ) -> Self {
+        let state = TransportState::new(device, memory);
+        let device_type = state.device_type();
+        let class_code = match device_type {
+            VIRTIO_ID_NET => 0x02_0000,
+            VIRTIO_ID_BLOCK => 0x01_8000,
+            _ => 0xff_0000,
+        };
+
+        let mut config = PciConfig::new(
+            VIRTIO_PCI_VENDOR_ID,
+            VIRTIO_PCI_DEVICE_ID_BASE + device_type as u16,
+            class_code,
+            1,
+            VIRTIO_PCI_VENDOR_ID,
+            VIRTIO_PCI_SUBSYSTEM_ID,
+        );
+        config.add_bar(VIRTIO_PCI_BAR, VIRTIO_PCI_BAR_SIZE);
+
+        let virtio_cap = |cfg_type: u8, offset: u64, length: u64| {
+            // struct virtio_pci_cap
+            let mut cap = vec![0_u8; 16];
+            cap[0] = PCI_CAP_ID_VNDR;
+            cap[2] = 16;
+            cap[3] = cfg_type;
+            cap[4] = VIRTIO_PCI_BAR as u8;
+            cap[8..12].copy_from_slice(&(offset as u32).to_le_bytes());
+            cap[12..16].copy_from_slice(&(length as u32).to_le_bytes());
+            cap
+        };
+
+        config.add_capability(&virtio_cap(
+            VIRTIO_PCI_CAP_COMMON_CFG,
+            COMMON_CFG_OFFSET,
+            COMMON_CFG_SIZE,
+        ));
+        config.add_capability(&virtio_cap(VIRTIO_PCI_CAP_ISR_CFG, ISR_CFG_OFFSET, 1));
+        config.add_capability(&virtio_cap(
+            VIRTIO_PCI_CAP_DEVICE_CFG,
+            DEVICE_CFG_OFFSET,
+            DEVICE_CFG_SIZE,
+        ));
+
+        // struct virtio_pci_notify_cap
+        let mut notify_cap = virtio_cap(
+            VIRTIO_PCI_CAP_NOTIFY_CFG,
+            NOTIFY_CFG_OFFSET,
+            state.queues.len() as u64 * NOTIFY_OFF_MULTIPLIER as u64,
+        );
+        notify_cap[2] = 20;
+        notify_cap.extend_from_slice(&NOTIFY_OFF_MULTIPLIER.to_le_bytes());
+        config.add_capability(&notify_cap);
+
+        // A vector per queue and one for the configuration changes
+        let msix = MsixTable::new(
+            state.queues.len() as u16 + 1,
+            requester_id,
+            irq_chip,
+            &mut config,
+            VIRTIO_PCI_BAR as u8,
+            MSIX_TABLE_OFFSET as u32,
+            MSIX_PBA_OFFSET as u32,
+        );
+
+        let queue_count = state.queues.len();
+
+        Self {
+            config,
+            state,
+            msix,
+            isr_status: 0,
+            msix_config_vector: VIRTIO_MSI_NO_VECTOR,
+            queue_vectors: vec![VIRTIO_MSI_NO_VECTOR; queue_count],
+        }
+    }
+
+    fn reset_interrupts(&mut self) {
+        self.isr_status = 0;
+        self.msix_config_vector = VIRTIO_MSI_NO_VECTOR;
+        self.queue_vectors.fill(VIRTIO_MSI_NO_VECTOR);
+    }
+
+    /// The vector if the table has it, `VIRTIO_MSI_NO_VECTOR` tells the
+    /// driver the vector cannot be used
+    fn checked_vector(&self, vector: u16) -> u16 {
+        if (vector as usize) < self.msix.vectors() {
+            vector
+        } else {
+            VIRTIO_MSI_NO_VECTOR
+        }
+    }
+
+    fn read_common(&mut self, offset: u64) -> u32 {
+        let queue_sel = self.state.queue_sel as usize;
+
+        match offset {
+            COMMON_DEVICE_FEATURE_SELECT => self.state.device_features_sel,
+            COMMON_DEVICE_FEATURE => self.state.device_features_word(),
+            COMMON_DRIVER_FEATURE_SELECT => self.state.driver_features_sel,
+            COMMON_DRIVER_FEATURE => match self.state.driver_features_sel {
+                0 => self.state.driver_features as u32,
+                1 => (self.state.driver_features >> 32) as u32,
+                _ => 0,
+            },
+            COMMON_MSIX_CONFIG => self.msix_config_vector as u32,
+            COMMON_NUM_QUEUES => self.state.queues.len() as u32,
+            COMMON_DEVICE_STATUS => self.state.status,
+            COMMON_CONFIG_GENERATION => self.state.config_generation & 0xff,
+            COMMON_QUEUE_SELECT => self.state.queue_sel,
+            COMMON_QUEUE_SIZE => self.state.selected_queue().map_or(0, |q| q.size as u32),
+            COMMON_QUEUE_MSIX_VECTOR => {
+                self.queue_vectors
+                    .get(queue_sel)
+                    .map_or(VIRTIO_MSI_NO_VECTOR, |vector| *vector) as u32
+            }
+            COMMON_QUEUE_ENABLE => self.state.selected_queue().map_or(0, |q| q.ready as u32),
+            COMMON_QUEUE_NOTIFY_OFF => queue_sel as u32,
+            COMMON_QUEUE_DESC_LO => self
+                .state
+                .selected_queue()
+                .map_or(0, |q| q.desc_table as u32),
+            COMMON_QUEUE_DESC_HI => self
+                .state
+                .selected_queue()
+                .map_or(0, |q| (q.desc_table >> 32) as u32),
+            COMMON_QUEUE_DRIVER_LO => self
+                .state
+                .selected_queue()
+                .map_or(0, |q| q.avail_ring as u32),
+            COMMON_QUEUE_DRIVER_HI => self
+                .state
+                .selected_queue()
+                .map_or(0, |q| (q.avail_ring >> 32) as u32),
+            COMMON_QUEUE_DEVICE_LO => self
+                .state
+                .selected_queue()
+                .map_or(0, |q| q.used_ring as u32),
+            COMMON_QUEUE_DEVICE_HI => self
+                .state
+                .selected_queue()
+                .map_or(0, |q| (q.used_ring >> 32) as u32),
+            _ => {
+                log::warn!("Unsupported virtio-pci common config read at {:#x}", offset);
+                0
+            }
+        }
+    }
+
+    fn write_common(&mut self, offset: u64, value: u32) {
+        let set_low = |addr: &mut u64, value: u32| *addr = (*addr & !0xffff_ffff) | value as u64;
+        let set_high =
+            |addr: &mut u64, value: u32| *addr = (*addr & 0xffff_ffff) | ((value as u64) << 32);
+
+        match offset {
+            COMMON_DEVICE_FEATURE_SELECT => self.state.device_features_sel = value,
+            COMMON_DRIVER_FEATURE_SELECT => self.state.driver_features_sel = value,
+            COMMON_DRIVER_FEATURE => self.state.set_driver_features_word(value),
+            COMMON_MSIX_CONFIG => self.msix_config_vector = self.checked_vector(value as u16),
+            COMMON_DEVICE_STATUS => {
+                if self.state.set_status(value) {
+                    log::info!("Virtio PCI device reset");
+                    self.reset_interrupts();
+                }
+            }
+            COMMON_QUEUE_SELECT => self.state.queue_sel = value,
+            COMMON_QUEUE_SIZE => {
+                if let Some(queue) = self.state.selected_queue() {
+                    queue.size = value as u16;
+                }
+            }
+            COMMON_QUEUE_MSIX_VECTOR => {
+                let vector = self.checked_vector(value as u16);
+                if let Some(queue_vector) =
+                    self.queue_vectors.get_mut(self.state.queue_sel as usize)
+                {
+                    *queue_vector = vector;
+                }
+            }
+            COMMON_QUEUE_ENABLE => {
+                if let Some(queue) = self.state.selected_queue() {
+                    queue.ready = value == 1;
+                }
+            }
+            COMMON_QUEUE_DESC_LO => {
+                if let Some(queue) = self.state.selected_queue() {
+                    set_low(&mut queue.desc_table, value);
+                }
+            }
+            COMMON_QUEUE_DESC_HI => {
+                if let Some(queue) = self.state.selected_queue() {
+                    set_high(&mut queue.desc_table, value);
+                }
+            }
+            COMMON_QUEUE_DRIVER_LO => {
+                if let Some(queue) = self.state.selected_queue() {
+                    set_low(&mut queue.avail_ring, value);
+                }
+            }
+            COMMON_QUEUE_DRIVER_HI => {
+                if let Some(queue) = self.state.selected_queue() {
+                    set_high(&mut queue.avail_ring, value);
+                }
+            }
+            COMMON_QUEUE_DEVICE_LO => {
+                if let Some(queue) = self.state.selected_queue() {
+                    set_low(&mut queue.used_ring, value);
+                }
+            }
+            COMMON_QUEUE_DEVICE_HI => {
+                if let Some(queue) = self.state.selected_queue() {
+                    set_high(&mut queue.used_ring, value);
+                }
+            }
+            _ => log::warn!(
+                "Unsupported virtio-pci common config write of {:#x} at {:#x}",
+                value,
+                offset
+            ),
+        }
+    }
+}
+
+impl VirtioTransport for VirtioPci {
+    fn notify_config_change(&mut self) {
+        self.state.config_generation = self.state.config_generation.wrapping_add(1);
+        self.isr_status |= VIRTIO_PCI_ISR_CONFIG;
+        self.msix.notify(&self.config, self.msix_config_vector);
+    }
+
+    fn process_queue(&mut self, index: usize) {
+        if self.state.process_queue(index) {
+            self.signal_used_queue(index);
+        }
+    }
+
+    fn signal_used_queue(&mut self, index: usize) {
+        self.isr_status |= VIRTIO_PCI_ISR_QUEUE;
+        if let Some(vector) = self.queue_vectors.get(index) {
+            self.msix.notify(&self.config, *vector);
+        }
+    }
+}
+
+impl PciDevice for VirtioPci {
+    fn config(&self) -> &PciConfig {
+        &self.config
+    }
+
+    fn write_config(&mut self, offset: usize, data: &[u8]) {
+        self.config.write(offset, data);
+        self.msix.deliver_pending(&self.config);
+    }
+
+    fn read_bar(&mut self, _index: usize, offset: u64, data: &mut [u8]) {
+        match offset {
+            COMMON_CFG_OFFSET..=0x0fff if offset < COMMON_CFG_SIZE => {
+                // The fields are naturally aligned, narrower accesses read
+                // the low bytes of a wider field
+                let value = self.read_common(offset).to_le_bytes();
+                let len = data.len().min(4);
+                data[..len].copy_from_slice(&value[..len]);
+            }
+            ISR_CFG_OFFSET => {
+                // Reading acknowledges the interrupt
+                data.fill(0);
+                data[0] = self.isr_status;
+                self.isr_status = 0;
+            }
+            DEVICE_CFG_OFFSET..=0x2fff => self.state.read_config(offset - DEVICE_CFG_OFFSET, data),
+            MSIX_TABLE_OFFSET..=0x5fff => self.msix.read_table(offset - MSIX_TABLE_OFFSET, data),
+            MSIX_PBA_OFFSET..=0x7fff => self.msix.read_pba(offset - MSIX_PBA_OFFSET, data),
+            _ => {
+                log::warn!("Unsupported virtio-pci read at {:#x}", offset);
+                data.fill(0);
+            }
+        }
+    }
+
+    fn write_bar(&mut self, _index: usize, offset: u64, data: &[u8]) {
+        let mut value = [0_u8; 4];
+        let len = data.len().min(4);
+        value[..len].copy_from_slice(&data[..len]);
+        let value = u32::from_le_bytes(value);
+
+        match offset {
+            COMMON_CFG_OFFSET..=0x0fff if offset < COMMON_CFG_SIZE => {
+                self.write_common(offset, value)
+            }
+            DEVICE_CFG_OFFSET..=0x2fff => self.state.write_config(offset - DEVICE_CFG_OFFSET, data),
+            NOTIFY_CFG_OFFSET..=0x3fff => self.process_queue(
+                ((offset - NOTIFY_CFG_OFFSET) / NOTIFY_OFF_MULTIPLIER as u64) as usize,
+            ),
+            MSIX_TABLE_OFFSET..=0x5fff => {
+                self.msix
+                    .write_table(&self.config, offset - MSIX_TABLE_OFFSET, data)
+            }
+            _ => log::warn!(
+                "Unsupported virtio-pci write of {:x?} at {:#x}",
+                data,
+                offset
+            ),
+        }
+    }
+
+    fn reset(&mut self) {
+        self.state.reset();
+        self.reset_interrupts();
+        self.config.reset();
+        self.msix.reset();
+    }
+}
@@ -0,0 +1,103 @@
+//! virtio-pmem device: a host file mapped into the guest physical address
+//! space. The guest accesses the file directly (`mount -o dax`), so there is
+//! no second copy of the data in the guest page cache, and asks the device
+//! to flush the host page cache to the storage.
+
+use std::{fs::File, sync::Arc};
+
+use super::{read_config_bytes, Queue, VirtioDevice, VIRTIO_ID_PMEM};
+use crate::smolvm::Memory;
+
+const VIRTIO_PMEM_REQ_TYPE_FLUSH: u32 = 0;
+
+const VIRTIO_PMEM_RESP_OK: u32 = 0;
+const VIRTIO_PMEM_RESP_EIO: u32 = 1;
+
+const QUEUE_SIZE: u16 = 256;
+
+/// The guest physical address and the size of the region have to be aligned
+/// at this boundary for the guest to map it as persistent memory.
+pub const PMEM_ALIGNMENT: u64 = 2 * 1024 * 1024;
+
+pub struct VirtioPmem {
+    file: Arc<File>,
+    gpa: u64,
+    size: u64,
+    queue_sizes: [u16; 1],
+}
+
+impl VirtioPmem {
+    /// `file` is mapped at `gpa` and takes `size` bytes of the address space
+    pub fn new(file: Arc<File>, gpa: u64, size: u64) -> Self {
+        Self {
+            file,
+            gpa,
+            size,
+            queue_sizes: [QUEUE_SIZE],
+        }
+    }
+
+    fn flush(&self) -> u32 {
+        match self.file.sync_all() {
+            Ok(()) => VIRTIO_PMEM_RESP_OK,
+            Err(e) => {
+                log::error!(
+                    "Cannot flush the persistent memory at {:#x}: {}",
+                    self.gpa,
+                    e
+                );
+                VIRTIO_PMEM_RESP_EIO
+            }
+        }
+    }
+}
+
+impl VirtioDevice for VirtioPmem {
+    fn device_type(&self) -> u32 {
+        VIRTIO_ID_PMEM
+    }
+
+    fn features(&self) -> u64 {
+        0
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        &self.queue_sizes
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        // struct virtio_pmem_config { le64 start; le64 size; }
+        let mut config = [0_u8; 16];
+        config[..8].copy_from_slice(&self.gpa.to_le_bytes());
+        config[8..].copy_from_slice(&self.size.to_le_bytes());
+
+        read_config_bytes(&config, offset, data);
+    }
+
+    fn process_queue(&mut self, _index: usize, queue: &mut Queue, memory: &mut Memory) -> bool {
+        let mut used = false;
+
+        while let Some(chain) = queue.pop(memory) {
+            // struct virtio_pmem_req { le32 type; } and struct virtio_pmem_resp { le32 ret; }
+            let request = chain.read_all(memory);
+            let response = if request.len() >= 4 {
+                match u32::from_le_bytes([request[0], request[1], request[2], request[3]]) {
+                    VIRTIO_PMEM_REQ_TYPE_FLUSH => self.flush(),
+                    request_type => {
+                        log::warn!("Unknown virtio-pmem request {}", request_type);
+                        VIRTIO_PMEM_RESP_EIO
+                    }
+                }
+            } else {
+                log::warn!("Truncated virtio-pmem request");
+                VIRTIO_PMEM_RESP_EIO
+            };
+
+            let written = chain.write_all(memory, &response.to_le_bytes());
+            queue.add_used(memory, chain.head, written as u32);
+            used = true;
+        }
+
+        used
+    }
+}
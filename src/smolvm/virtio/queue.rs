@@ -0,0 +1,389 @@
+//! The split virtqueue.
+//!
+//!   Descriptor table, 16 bytes per entry:
+//!     +0  addr   u64   guest physical address of the buffer
+//!     +8  len    u32
+//!     +12 flags  u16   NEXT, WRITE (device writes), INDIRECT
+//!     +14 next   u16
+//!
+//!   Available ring (driver area):    Used ring (device area):
+//!     +0  flags      u16               +0  flags      u16
+//!     +2  idx        u16               +2  idx        u16
+//!     +4  ring[size] u16               +4  ring[size] { id u32, len u32 }
+//!         used_event u16                   avail_event u16
+
+use std::sync::atomic::{fence, Ordering};
+
+use zerocopy::{AsBytes, FromBytes};
+
+use crate::smolvm::Memory;
+
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+const VIRTQ_DESC_F_INDIRECT: u16 = 4;
+
+#[repr(C)]
+#[derive(Default, Clone, Copy, AsBytes, FromBytes)]
+struct VirtqDesc {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+static_assertions::const_assert_eq!(std::mem::size_of::<VirtqDesc>(), 16);
+
+#[repr(C)]
+#[derive(Default, Clone, Copy, AsBytes, FromBytes)]
+struct VirtqUsedElem {
+    id: u32,
+    len: u32,
+}
+
+/// A chain of descriptors made available by the driver. The device-readable
+/// buffers always precede the device-writable ones.
+pub struct DescriptorChain {
+    pub head: u16,
+    pub readable: Vec<(u64 /* gpa */, u32 /* len */)>,
+    pub writable: Vec<(u64 /* gpa */, u32 /* len */)>,
+}
+
+impl DescriptorChain {
+    pub fn readable_len(&self) -> usize {
+        self.readable.iter().map(|(_, len)| *len as usize).sum()
+    }
+
+    pub fn writable_len(&self) -> usize {
+        self.writable.iter().map(|(_, len)| *len as usize).sum()
+    }
+
+    /// Gathers the device-readable buffers, up to the first one outside
+    /// the guest memory
+    pub fn read_all(&self, memory: &Memory) -> Vec<u8> {
+        let mut data = Vec::with_capacity(self.readable_len());
+        for (gpa, len) in &self.readable {
+            if !memory.contains(*gpa, *len as usize) {
+                log::error!("Buffer at {:#x}, {} bytes is outside the memory", gpa, len);
+                break;
+            }
+            data.extend_from_slice(memory.read(*gpa, *len as usize));
+        }
+
+        data
+    }
+
+    /// Scatters `data` over the device-writable buffers, returns the number of the
+    /// bytes written which might be less than the size of `data` if the buffers are
+    /// too small.
+    pub fn write_all(&self, memory: &mut Memory, data: &[u8]) -> usize {
+        let mut written = 0;
+        for (gpa, len) in &self.writable {
+            if written == data.len() {
+                break;
+            }
+
+            let chunk = (*len as usize).min(data.len() - written);
+            if !memory.contains(*gpa, chunk) {
+                log::error!("Buffer at {:#x}, {} bytes is outside the memory", gpa, len);
+                break;
+            }
+            memory.write(*gpa, &data[written..written + chunk]);
+            written += chunk;
+        }
+
+        written
+    }
+}
+
+#[derive(Default)]
+pub struct Queue {
+    pub max_size: u16,
+    pub size: u16,
+    pub ready: bool,
+    pub desc_table: u64,
+    pub avail_ring: u64,
+    pub used_ring: u64,
+    next_avail: u16,
+    next_used: u16,
+    /// The driver has given a malformed chain or rings outside the memory,
+    /// the queue is not processed until reset
+    needs_reset: bool,
+}
+
+impl Queue {
+    pub fn new(max_size: u16) -> Self {
+        Self {
+            max_size,
+            size: max_size,
+            ..Default::default()
+        }
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::new(self.max_size);
+    }
+
+    /// The index of the next available descriptor chain, where processing
+    /// resumes when the queue is handed over
+    pub fn base(&self) -> u16 {
+        self.next_avail
+    }
+
+    pub fn set_base(&mut self, index: u16) {
+        self.next_avail = index;
+        self.next_used = index;
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.ready
+            && !self.needs_reset
+            && self.size != 0
+            && self.size <= self.max_size
+            && self.size.is_power_of_two()
+            && self.desc_table != 0
+            && self.avail_ring != 0
+            && self.used_ring != 0
+    }
+
+    /// Takes the next available descriptor chain if there is any
+    pub fn pop(&mut self, memory: &Memory) -> Option<DescriptorChain> {
+        if !self.is_valid() {
+            return None;
+        }
+
+        if !memory.contains(self.avail_ring, 4 + 2 * self.size as usize) {
+            log::error!(
+                "Available ring at {:#x} is outside the memory, the queue needs a reset",
+                self.avail_ring
+            );
+            self.needs_reset = true;
+            return None;
+        }
+
+        let avail_idx: u16 = memory.read_obj(self.avail_ring + 2);
+        if avail_idx == self.next_avail {
+            return None;
+        }
+
+        // Read the ring entry only after having seen the index
+        fence(Ordering::Acquire);
+
+        let ring_offset = 4 + 2 * (self.next_avail % self.size) as u64;
+        let head: u16 = memory.read_obj(self.avail_ring + ring_offset);
+        self.next_avail = self.next_avail.wrapping_add(1);
+
+        let mut chain = DescriptorChain {
+            head,
+            readable: Vec::new(),
+            writable: Vec::new(),
+        };
+
+        let mut table = self.desc_table;
+        let mut table_size = self.size;
+        let mut indirect = false;
+        let mut index = head;
+        // Counts across the indirect table too, a chain cannot loop
+        let mut count = 0_u32;
+        let mut max_count = table_size as u32;
+
+        loop {
+            if index >= table_size || count >= max_count {
+                log::error!(
+                    "Malformed descriptor chain at head {}, index {}, the queue needs a reset",
+                    head,
+                    index
+                );
+                self.needs_reset = true;
+                return None;
+            }
+
+            let desc_gpa = table.wrapping_add(16 * index as u64);
+            if !memory.contains(desc_gpa, 16) {
+                log::error!(
+                    "Descriptor at {:#x} is outside the memory, the queue needs a reset",
+                    desc_gpa
+                );
+                self.needs_reset = true;
+                return None;
+            }
+            let desc: VirtqDesc = memory.read_obj(desc_gpa);
+            count += 1;
+
+            if desc.flags & VIRTQ_DESC_F_INDIRECT != 0 {
+                // The indirect tables do not nest
+                if indirect || desc.len & 15 != 0 || desc.len / 16 > u16::MAX as u32 {
+                    log::error!(
+                        "Malformed indirect descriptor at head {}, index {}, the queue needs a reset",
+                        head, index
+                    );
+                    self.needs_reset = true;
+                    return None;
+                }
+
+                indirect = true;
+                table = desc.addr;
+                table_size = (desc.len / 16) as u16;
+                index = 0;
+                max_count = count + table_size as u32;
+                continue;
+            }
+
+            if !memory.contains(desc.addr, desc.len as usize) {
+                log::error!(
+                    "Buffer at {:#x}, {} bytes is outside the memory, the queue needs a reset",
+                    desc.addr,
+                    desc.len
+                );
+                self.needs_reset = true;
+                return None;
+            }
+
+            if desc.flags & VIRTQ_DESC_F_WRITE != 0 {
+                chain.writable.push((desc.addr, desc.len));
+            } else {
+                chain.readable.push((desc.addr, desc.len));
+            }
+
+            if desc.flags & VIRTQ_DESC_F_NEXT == 0 {
+                break;
+            }
+
+            index = desc.next;
+        }
+
+        Some(chain)
+    }
+
+    /// Returns the descriptor chain to the driver with `len` bytes written into it
+    pub fn add_used(&mut self, memory: &mut Memory, head: u16, len: u32) {
+        if !memory.contains(self.used_ring, 4 + 8 * self.size as usize) {
+            log::error!(
+                "Used ring at {:#x} is outside the memory, the queue needs a reset",
+                self.used_ring
+            );
+            self.needs_reset = true;
+            return;
+        }
+
+        let ring_offset = 4 + 8 * (self.next_used % self.size) as u64;
+        memory.write_obj(
+            self.used_ring + ring_offset,
+            &VirtqUsedElem {
+                id: head as u32,
+                len,
+            },
+        );
+
+        self.next_used = self.next_used.wrapping_add(1);
+
+        // Publish the index only after the ring entry
+        fence(Ordering::Release);
+        memory.write_obj(self.used_ring + 2, &self.next_used);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::smolvm::test_memory;
+
+    const DESC_TABLE: u64 = 0x1000;
+    const AVAIL_RING: u64 = 0x2000;
+    const USED_RING: u64 = 0x3000;
+
+    fn queue_with_head(memory: &mut Memory, head: u16) -> Queue {
+        let mut queue = Queue::new(16);
+        queue.desc_table = DESC_TABLE;
+        queue.avail_ring = AVAIL_RING;
+        queue.used_ring = USED_RING;
+        queue.ready = true;
+
+        memory.write_obj(AVAIL_RING + 4, &head);
+        memory.write_obj(AVAIL_RING + 2, &1_u16);
+
+        queue
+    }
+
+    fn write_desc(memory: &mut Memory, gpa: u64, addr: u64, len: u32, flags: u16, next: u16) {
+        memory.write_obj(
+            gpa,
+            &VirtqDesc {
+                addr,
+                len,
+                flags,
+                next,
+            },
+        );
+    }
+
+    #[test]
+    fn test_chain() {
+        let mut memory = test_memory(0, 0x10000);
+        let mut queue = queue_with_head(&mut memory, 1);
+        write_desc(
+            &mut memory,
+            DESC_TABLE + 16,
+            0x4000,
+            8,
+            VIRTQ_DESC_F_NEXT,
+            2,
+        );
+        write_desc(
+            &mut memory,
+            DESC_TABLE + 32,
+            0x5000,
+            0x100,
+            VIRTQ_DESC_F_WRITE,
+            0,
+        );
+
+        let chain = queue.pop(&memory).unwrap();
+        assert_eq!(chain.readable, vec![(0x4000, 8)]);
+        assert_eq!(chain.writable, vec![(0x5000, 0x100)]);
+        assert!(queue.pop(&memory).is_none());
+
+        queue.add_used(&mut memory, chain.head, 0x100);
+        assert_eq!(memory.read_obj::<u16>(USED_RING + 2), 1);
+    }
+
+    #[test]
+    fn test_malformed() {
+        // The indirect table points at itself
+        let mut memory = test_memory(0, 0x10000);
+        let mut queue = queue_with_head(&mut memory, 0);
+        write_desc(
+            &mut memory,
+            DESC_TABLE,
+            0x4000,
+            16,
+            VIRTQ_DESC_F_INDIRECT,
+            0,
+        );
+        write_desc(&mut memory, 0x4000, 0x4000, 16, VIRTQ_DESC_F_INDIRECT, 0);
+        assert!(queue.pop(&memory).is_none());
+        assert!(!queue.is_valid());
+
+        // A loop within the indirect table
+        let mut queue = queue_with_head(&mut memory, 0);
+        write_desc(&mut memory, 0x4000, 0x5000, 8, VIRTQ_DESC_F_NEXT, 0);
+        assert!(queue.pop(&memory).is_none());
+        assert!(!queue.is_valid());
+
+        // A buffer past the memory
+        let mut queue = queue_with_head(&mut memory, 0);
+        write_desc(&mut memory, DESC_TABLE, 0xf000, 0x2000, 0, 0);
+        assert!(queue.pop(&memory).is_none());
+        assert!(!queue.is_valid());
+
+        // The used ring past the memory
+        let mut queue = queue_with_head(&mut memory, 0);
+        write_desc(&mut memory, DESC_TABLE, 0x4000, 8, 0, 0);
+        queue.used_ring = 0x1_0000;
+        let chain = queue.pop(&memory).unwrap();
+        queue.add_used(&mut memory, chain.head, 0);
+        assert!(!queue.is_valid());
+
+        queue.reset();
+        assert!(!queue.needs_reset);
+    }
+}
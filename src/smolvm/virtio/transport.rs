@@ -0,0 +1,146 @@
+//! The part of the transports that does not depend on how the driver
+//! reaches the device: the feature negotiation, the device status and
+//! the virtqueues.
+
+use std::sync::{Arc, Mutex};
+
+use super::{
+    Queue, SharedVirtioDevice, VIRTIO_F_VERSION_1, VIRTIO_STATUS_DRIVER_OK,
+    VIRTIO_STATUS_FEATURES_OK,
+};
+use crate::smolvm::Memory;
+
+pub struct TransportState {
+    pub device: SharedVirtioDevice,
+    pub memory: Arc<Mutex<Memory>>,
+    pub queues: Vec<Queue>,
+    pub status: u32,
+    pub device_features_sel: u32,
+    pub driver_features_sel: u32,
+    pub driver_features: u64,
+    pub queue_sel: u32,
+    pub config_generation: u32,
+}
+
+impl TransportState {
+    pub fn new(device: SharedVirtioDevice, memory: Arc<Mutex<Memory>>) -> Self {
+        let queues = device
+            .lock()
+            .unwrap()
+            .queue_max_sizes()
+            .iter()
+            .map(|max_size| Queue::new(*max_size))
+            .collect();
+
+        Self {
+            device,
+            memory,
+            queues,
+            status: 0,
+            device_features_sel: 0,
+            driver_features_sel: 0,
+            driver_features: 0,
+            queue_sel: 0,
+            config_generation: 0,
+        }
+    }
+
+    pub fn device_type(&self) -> u32 {
+        self.device.lock().unwrap().device_type()
+    }
+
+    pub fn device_features(&self) -> u64 {
+        self.device.lock().unwrap().features() | VIRTIO_F_VERSION_1
+    }
+
+    /// The 32 bits of the device features selected by the driver
+    pub fn device_features_word(&self) -> u32 {
+        match self.device_features_sel {
+            0 => self.device_features() as u32,
+            1 => (self.device_features() >> 32) as u32,
+            _ => 0,
+        }
+    }
+
+    /// Sets the 32 bits of the driver features selected by the driver
+    pub fn set_driver_features_word(&mut self, value: u32) {
+        match self.driver_features_sel {
+            0 => self.driver_features = (self.driver_features & !0xffff_ffff) | value as u64,
+            1 => {
+                self.driver_features = (self.driver_features & 0xffff_ffff) | ((value as u64) << 32)
+            }
+            _ => {}
+        }
+    }
+
+    pub fn selected_queue(&mut self) -> Option<&mut Queue> {
+        let queue = self.queues.get_mut(self.queue_sel as usize);
+        if queue.is_none() {
+            log::warn!("Invalid virtio queue {} selected", self.queue_sel);
+        }
+
+        queue
+    }
+
+    pub fn is_driver_ok(&self) -> bool {
+        self.status & VIRTIO_STATUS_DRIVER_OK != 0
+    }
+
+    pub fn reset(&mut self) {
+        self.status = 0;
+        self.driver_features = 0;
+        self.driver_features_sel = 0;
+        self.device_features_sel = 0;
+        self.queue_sel = 0;
+        self.queues.iter_mut().for_each(Queue::reset);
+        self.device.lock().unwrap().reset();
+    }
+
+    /// Returns `true` if the driver has reset the device
+    pub fn set_status(&mut self, status: u32) -> bool {
+        if status == 0 {
+            self.reset();
+            return true;
+        }
+
+        if status & VIRTIO_STATUS_FEATURES_OK != 0
+            && self.status & VIRTIO_STATUS_FEATURES_OK == 0
+            && self.driver_features & VIRTIO_F_VERSION_1 == 0
+        {
+            log::warn!("Driver does not support VIRTIO_F_VERSION_1, features not OK");
+            self.status = status & !VIRTIO_STATUS_FEATURES_OK;
+            return false;
+        }
+
+        if status & VIRTIO_STATUS_DRIVER_OK != 0 && self.status & VIRTIO_STATUS_DRIVER_OK == 0 {
+            let memory = self.memory.lock().unwrap();
+            self.device
+                .lock()
+                .unwrap()
+                .activate(self.driver_features, &self.queues, &memory);
+        }
+
+        self.status = status;
+
+        false
+    }
+
+    /// Returns `true` if the device has put anything to the used ring
+    pub fn process_queue(&mut self, index: usize) -> bool {
+        if !self.is_driver_ok() || index >= self.queues.len() {
+            return false;
+        }
+
+        let mut memory = self.memory.lock().unwrap();
+        let mut device = self.device.lock().unwrap();
+        device.process_queue(index, &mut self.queues[index], &mut memory)
+    }
+
+    pub fn read_config(&self, offset: u64, data: &mut [u8]) {
+        self.device.lock().unwrap().read_config(offset, data);
+    }
+
+    pub fn write_config(&mut self, offset: u64, data: &[u8]) {
+        self.device.lock().unwrap().write_config(offset, data);
+    }
+}
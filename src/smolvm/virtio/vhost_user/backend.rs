@@ -0,0 +1,421 @@
+//! The reference vhost-user backend: runs an in-tree device against the
+//! guest memory and the rings the frontend shares.
+
+use std::{
+    fs::File,
+    os::unix::{
+        net::{UnixListener, UnixStream},
+        prelude::AsRawFd,
+    },
+    path::Path,
+    sync::Arc,
+};
+
+use zerocopy::AsBytes;
+
+use super::{io_error, poll_readable, protocol::*, EventFd};
+use crate::smolvm::{
+    host_page_size,
+    virtio::{Queue, SharedVirtioDevice, VIRTIO_F_VERSION_1},
+    MappedGpa, Memory,
+};
+
+const BACKEND_PROTOCOL_FEATURES: u64 =
+    VHOST_USER_PROTOCOL_F_MQ | VHOST_USER_PROTOCOL_F_REPLY_ACK | VHOST_USER_PROTOCOL_F_CONFIG;
+
+pub struct VhostUserBackend {
+    connection: Connection,
+    device: SharedVirtioDevice,
+    features: u64,
+    protocol_features: u64,
+    regions: Vec<VhostUserMemoryRegion>,
+    memory: Memory,
+    queues: Vec<Queue>,
+    kick: Vec<Option<EventFd>>,
+    call: Vec<Option<EventFd>>,
+    enabled: Vec<bool>,
+    activated: bool,
+}
+
+impl VhostUserBackend {
+    pub fn new(stream: UnixStream, device: SharedVirtioDevice) -> Self {
+        let queues: Vec<Queue> = device
+            .lock()
+            .unwrap()
+            .queue_max_sizes()
+            .iter()
+            .map(|max_size| Queue::new(*max_size))
+            .collect();
+        let queue_count = queues.len();
+
+        Self {
+            connection: Connection::new(stream),
+            device,
+            features: 0,
+            protocol_features: 0,
+            regions: Vec::new(),
+            memory: Memory::new(Vec::new()),
+            queues,
+            kick: (0..queue_count).map(|_| None).collect(),
+            call: (0..queue_count).map(|_| None).collect(),
+            enabled: vec![false; queue_count],
+            activated: false,
+        }
+    }
+
+    /// Serves the frontends connecting to the socket at `path` one after
+    /// another
+    pub fn serve(path: &Path, device: SharedVirtioDevice) -> Result<(), std::io::Error> {
+        let listener = UnixListener::bind(path)?;
+        log::info!("vhost-user backend listening at {}", path.display());
+
+        loop {
+            let (stream, _) = listener.accept()?;
+            log::info!("vhost-user frontend connected");
+
+            if let Err(e) = Self::new(stream, device.clone()).run() {
+                log::error!("vhost-user session has failed: {}", e);
+            }
+            device.lock().unwrap().reset();
+        }
+    }
+
+    /// Handles the messages and the kicks until the frontend disconnects
+    pub fn run(mut self) -> Result<(), std::io::Error> {
+        loop {
+            let mut fds = vec![self.connection.as_raw_fd()];
+            let kicked: Vec<usize> = (0..self.kick.len())
+                .filter(|index| self.kick[*index].is_some())
+                .collect();
+            fds.extend(
+                kicked
+                    .iter()
+                    .map(|index| self.kick[*index].as_ref().unwrap().as_raw_fd()),
+            );
+
+            let readable = poll_readable(&fds)?;
+
+            for (index, _) in kicked
+                .iter()
+                .zip(&readable[1..])
+                .filter(|(_, readable)| **readable)
+            {
+                self.process_queue(*index);
+            }
+
+            if readable[0] {
+                let message = match self.connection.recv() {
+                    Ok(message) => message,
+                    Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                        log::info!("vhost-user frontend disconnected");
+                        self.unmap_memory();
+                        return Ok(());
+                    }
+                    Err(e) => return Err(e),
+                };
+
+                self.handle_message(message)?;
+            }
+        }
+    }
+
+    fn process_queue(&mut self, index: usize) {
+        if let Some(kick) = &self.kick[index] {
+            kick.read();
+        }
+        if !self.enabled[index] {
+            return;
+        }
+
+        let used = self.device.lock().unwrap().process_queue(
+            index,
+            &mut self.queues[index],
+            &mut self.memory,
+        );
+        if used {
+            if let Some(call) = &self.call[index] {
+                call.write(1);
+            }
+        }
+    }
+
+    fn handle_message(&mut self, mut message: Message) -> Result<(), std::io::Error> {
+        let reply = match message.request {
+            VHOST_USER_GET_FEATURES => {
+                let features = self.device.lock().unwrap().features()
+                    | VIRTIO_F_VERSION_1
+                    | VHOST_USER_F_PROTOCOL_FEATURES;
+                Some(features.as_bytes().to_vec())
+            }
+            VHOST_USER_GET_PROTOCOL_FEATURES => Some(BACKEND_PROTOCOL_FEATURES.as_bytes().to_vec()),
+            VHOST_USER_GET_QUEUE_NUM => Some((self.queues.len() as u64).as_bytes().to_vec()),
+            VHOST_USER_GET_VRING_BASE => Some(self.stop_queue(&message)?),
+            VHOST_USER_GET_CONFIG => Some(self.read_config(&message)?),
+            _ => None,
+        };
+
+        if let Some(reply) = reply {
+            return self.connection.reply(&message, &reply);
+        }
+
+        let status = match self.handle_request(&mut message) {
+            Ok(()) => 0_u64,
+            Err(e) => {
+                log::error!("vhost-user request {} has failed: {}", message.request, e);
+                1
+            }
+        };
+
+        if message.need_reply() {
+            self.connection.reply(&message, status.as_bytes())?;
+        }
+
+        Ok(())
+    }
+
+    fn handle_request(&mut self, message: &mut Message) -> Result<(), std::io::Error> {
+        match message.request {
+            VHOST_USER_SET_OWNER => {}
+            VHOST_USER_RESET_OWNER => self.reset(),
+            VHOST_USER_SET_FEATURES => self.features = message.u64()?,
+            VHOST_USER_SET_PROTOCOL_FEATURES => {
+                self.protocol_features = message.u64()? & BACKEND_PROTOCOL_FEATURES
+            }
+            VHOST_USER_SET_MEM_TABLE => self.set_mem_table(message)?,
+            VHOST_USER_SET_VRING_NUM => {
+                let state = message.obj::<VhostUserVringState>()?;
+                let queue = self.queue(state.index)?;
+                if state.num == 0 || state.num > queue.max_size as u32 {
+                    return Err(io_error(libc::EINVAL));
+                }
+                queue.size = state.num as u16;
+            }
+            VHOST_USER_SET_VRING_BASE => {
+                let state = message.obj::<VhostUserVringState>()?;
+                self.queue(state.index)?.set_base(state.num as u16);
+            }
+            VHOST_USER_SET_VRING_ADDR => {
+                let addr = message.obj::<VhostUserVringAddr>()?;
+                let desc_table = self.guest_address(addr.desc_user_addr)?;
+                let avail_ring = self.guest_address(addr.avail_user_addr)?;
+                let used_ring = self.guest_address(addr.used_user_addr)?;
+
+                let queue = self.queue(addr.index)?;
+                queue.desc_table = desc_table;
+                queue.avail_ring = avail_ring;
+                queue.used_ring = used_ring;
+            }
+            VHOST_USER_SET_VRING_KICK => {
+                let index = self.vring_index(message)?;
+                self.kick[index] = message.take_file().map(EventFd::from_file);
+                self.start_queue(index);
+            }
+            VHOST_USER_SET_VRING_CALL => {
+                let index = self.vring_index(message)?;
+                self.call[index] = message.take_file().map(EventFd::from_file);
+            }
+            VHOST_USER_SET_VRING_ERR => {
+                self.vring_index(message)?;
+            }
+            VHOST_USER_SET_VRING_ENABLE => {
+                let state = message.obj::<VhostUserVringState>()?;
+                self.queue(state.index)?;
+                self.enabled[state.index as usize] = state.num == 1;
+                if state.num == 1 && self.kick[state.index as usize].is_some() {
+                    self.process_queue(state.index as usize);
+                }
+            }
+            VHOST_USER_SET_CONFIG => {
+                let header = message.obj::<VhostUserConfigHeader>()?;
+                let data = message
+                    .payload
+                    .get(std::mem::size_of::<VhostUserConfigHeader>()..)
+                    .ok_or_else(|| io_error(libc::EINVAL))?;
+                self.device
+                    .lock()
+                    .unwrap()
+                    .write_config(header.offset as u64, data);
+            }
+            request => {
+                log::warn!("Unsupported vhost-user request {}", request);
+                return Err(io_error(libc::EOPNOTSUPP));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn queue(&mut self, index: u32) -> Result<&mut Queue, std::io::Error> {
+        self.queues
+            .get_mut(index as usize)
+            .ok_or_else(|| io_error(libc::EINVAL))
+    }
+
+    /// The ring index of the kick/call/err message, the file descriptor is
+    /// left in the message if there is one
+    fn vring_index(&mut self, message: &Message) -> Result<usize, std::io::Error> {
+        let payload = message.u64()?;
+        let index = (payload & VHOST_USER_VRING_IDX_MASK) as u32;
+        self.queue(index)?;
+
+        if payload & VHOST_USER_VRING_NOFD_MASK != 0 {
+            log::warn!(
+                "vhost-user queue {} without an eventfd is not supported",
+                index
+            );
+        }
+
+        Ok(index as usize)
+    }
+
+    /// Translates the address of the frontend to the guest physical one
+    fn guest_address(&self, user_addr: u64) -> Result<u64, std::io::Error> {
+        self.regions
+            .iter()
+            .find(|region| {
+                region.userspace_addr <= user_addr
+                    && user_addr < region.userspace_addr + region.memory_size
+            })
+            .map(|region| region.guest_phys_addr + (user_addr - region.userspace_addr))
+            .ok_or_else(|| io_error(libc::EFAULT))
+    }
+
+    fn start_queue(&mut self, index: usize) {
+        // Without the protocol features the ring is enabled once started
+        if self.features & VHOST_USER_F_PROTOCOL_FEATURES == 0 {
+            self.enabled[index] = true;
+        }
+        self.queues[index].ready = true;
+
+        if !self.activated {
+            self.activated = true;
+            self.device
+                .lock()
+                .unwrap()
+                .activate(self.features, &self.queues, &self.memory);
+        }
+
+        // Catch up with what the driver has made available before the kick
+        self.process_queue(index);
+    }
+
+    fn stop_queue(&mut self, message: &Message) -> Result<Vec<u8>, std::io::Error> {
+        let state = message.obj::<VhostUserVringState>()?;
+        let index = state.index as usize;
+        let base = self.queue(state.index)?.base();
+
+        self.kick[index] = None;
+        self.enabled[index] = false;
+        self.queues[index].ready = false;
+
+        if self.queues.iter().all(|queue| !queue.ready) {
+            self.activated = false;
+            self.device.lock().unwrap().reset();
+        }
+
+        Ok(VhostUserVringState {
+            index: state.index,
+            num: base as u32,
+        }
+        .as_bytes()
+        .to_vec())
+    }
+
+    fn read_config(&self, message: &Message) -> Result<Vec<u8>, std::io::Error> {
+        let header = message.obj::<VhostUserConfigHeader>()?;
+        let mut reply = header.as_bytes().to_vec();
+        let mut data = vec![0_u8; header.size as usize];
+
+        self.device
+            .lock()
+            .unwrap()
+            .read_config(header.offset as u64, &mut data);
+        reply.extend_from_slice(&data);
+
+        Ok(reply)
+    }
+
+    fn set_mem_table(&mut self, message: &mut Message) -> Result<(), std::io::Error> {
+        let header = message.obj::<VhostUserMemoryHeader>()?;
+        let count = header.nregions as usize;
+        let regions_offset = std::mem::size_of::<VhostUserMemoryHeader>();
+        let region_size = std::mem::size_of::<VhostUserMemoryRegion>();
+
+        if message.files.len() != count
+            || message.payload.len() < regions_offset + count * region_size
+        {
+            return Err(io_error(libc::EINVAL));
+        }
+
+        let mut regions = Vec::new();
+        let mut spans = Vec::new();
+        for (index, file) in message.files.drain(..).enumerate() {
+            let mut region = VhostUserMemoryRegion::default();
+            let offset = regions_offset + index * region_size;
+            region
+                .as_bytes_mut()
+                .copy_from_slice(&message.payload[offset..offset + region_size]);
+
+            spans.push(map_region(&region, file)?);
+            regions.push(region);
+        }
+
+        self.unmap_memory();
+        self.memory = Memory::new(spans);
+        self.regions = regions;
+
+        Ok(())
+    }
+
+    fn unmap_memory(&mut self) {
+        for span in self.memory.spans() {
+            unsafe { libc::munmap(span.host_address() as *mut libc::c_void, span.size()) };
+        }
+
+        self.memory = Memory::new(Vec::new());
+        self.regions.clear();
+    }
+
+    fn reset(&mut self) {
+        for queue in &mut self.queues {
+            queue.reset();
+        }
+        self.kick.iter_mut().for_each(|kick| *kick = None);
+        self.call.iter_mut().for_each(|call| *call = None);
+        self.enabled.fill(false);
+        self.activated = false;
+        self.features = 0;
+        self.device.lock().unwrap().reset();
+    }
+}
+
+fn map_region(region: &VhostUserMemoryRegion, file: File) -> Result<MappedGpa, std::io::Error> {
+    let addr = unsafe {
+        libc::mmap(
+            std::ptr::null_mut(),
+            region.memory_size as usize,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_SHARED | libc::MAP_NORESERVE,
+            file.as_raw_fd(),
+            region.mmap_offset as libc::off_t,
+        )
+    };
+    if addr == libc::MAP_FAILED {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    log::debug!(
+        "vhost-user memory region at {:#x}, {:#x} bytes",
+        region.guest_phys_addr,
+        region.memory_size
+    );
+
+    Ok(MappedGpa {
+        memory: addr as *mut u8,
+        gpa: region.guest_phys_addr,
+        size: region.memory_size as usize,
+        file: Some(Arc::new(file)),
+        page_size: host_page_size(),
+        locked: false,
+    })
+}
@@ -0,0 +1,626 @@
+//! vhost-user frontend: the virtqueues of the device are processed by a
+//! backend in another process (virtiofsd, vhost-user-blk, ...) talking
+//! over a Unix socket.
+//!
+//! The frontend shares the guest memory with the backend as the memfds
+//! the RAM is backed by and hands over the rings of each queue the driver
+//! has set up. The driver notifications are forwarded to the backend via
+//! the kick eventfds, the backend signals the used buffers via the call
+//! eventfds, and a thread turns these into interrupts of the transport.
+//!
+//! `VhostUserBackend` is the other side, it serves any of the in-tree
+//! devices over a socket.
+
+use std::{
+    fs::File,
+    io::{Read, Write},
+    os::unix::{
+        net::UnixStream,
+        prelude::{AsRawFd, FromRawFd, RawFd},
+    },
+    path::Path,
+    sync::Arc,
+};
+
+use zerocopy::AsBytes;
+
+use self::protocol::*;
+use super::{read_config_bytes, Queue, SharedVirtioTransport, VirtioDevice};
+use crate::smolvm::Memory;
+
+mod backend;
+mod protocol;
+
+pub use backend::VhostUserBackend;
+
+/// The protocol features the frontend knows how to use
+const FRONTEND_PROTOCOL_FEATURES: u64 =
+    VHOST_USER_PROTOCOL_F_MQ | VHOST_USER_PROTOCOL_F_REPLY_ACK | VHOST_USER_PROTOCOL_F_CONFIG;
+
+const QUEUE_SIZE: u16 = 256;
+
+fn io_error(errno: i32) -> std::io::Error {
+    std::io::Error::from_raw_os_error(errno)
+}
+
+pub struct EventFd {
+    file: File,
+}
+
+impl EventFd {
+    pub fn new() -> Result<Self, std::io::Error> {
+        let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        Ok(Self::from_file(unsafe { File::from_raw_fd(fd) }))
+    }
+
+    pub fn from_file(file: File) -> Self {
+        Self { file }
+    }
+
+    pub fn write(&self, value: u64) {
+        if let Err(e) = (&self.file).write_all(&value.to_ne_bytes()) {
+            log::error!("Cannot signal the eventfd: {}", e);
+        }
+    }
+
+    /// Takes the counter, `None` if the eventfd has not been signalled
+    pub fn read(&self) -> Option<u64> {
+        let mut value = [0_u8; 8];
+        match (&self.file).read_exact(&mut value) {
+            Ok(()) => Some(u64::from_ne_bytes(value)),
+            Err(_) => None,
+        }
+    }
+}
+
+impl AsRawFd for EventFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+/// Waits until any of the `fds` is readable, returns their readiness
+pub(crate) fn poll_readable(fds: &[RawFd]) -> Result<Vec<bool>, std::io::Error> {
+    let mut pollfds: Vec<libc::pollfd> = fds
+        .iter()
+        .map(|fd| libc::pollfd {
+            fd: *fd,
+            events: libc::POLLIN,
+            revents: 0,
+        })
+        .collect();
+
+    loop {
+        let result = unsafe { libc::poll(pollfds.as_mut_ptr(), pollfds.len() as _, -1) };
+        if result >= 0 {
+            break;
+        }
+
+        let error = std::io::Error::last_os_error();
+        if error.kind() != std::io::ErrorKind::Interrupted {
+            return Err(error);
+        }
+    }
+
+    Ok(pollfds
+        .iter()
+        .map(|pollfd| pollfd.revents & (libc::POLLIN | libc::POLLHUP | libc::POLLERR) != 0)
+        .collect())
+}
+
+pub struct VhostUserDevice {
+    connection: Connection,
+    device_type: u32,
+    /// Offered by the backend, `VHOST_USER_F_PROTOCOL_FEATURES` included
+    backend_features: u64,
+    protocol_features: u64,
+    queue_sizes: Vec<u16>,
+    kick: Vec<Arc<EventFd>>,
+    call: Vec<Arc<EventFd>>,
+    started: Vec<bool>,
+    /// Used when the backend does not provide the configuration space
+    config: Vec<u8>,
+}
+
+impl VhostUserDevice {
+    /// Connects to the backend listening at `path`
+    pub fn connect(
+        path: &Path,
+        device_type: u32,
+        queue_count: usize,
+        config: Vec<u8>,
+    ) -> Result<Self, std::io::Error> {
+        let stream = UnixStream::connect(path).map_err(|e| {
+            log::error!("Cannot connect to {}: {}", path.display(), e);
+            e
+        })?;
+
+        Self::new(stream, device_type, queue_count, config)
+    }
+
+    /// Sets up the session with the backend, the backend has to support at
+    /// least `queue_count` queues of the device
+    pub fn new(
+        stream: UnixStream,
+        device_type: u32,
+        queue_count: usize,
+        config: Vec<u8>,
+    ) -> Result<Self, std::io::Error> {
+        let mut device = Self {
+            connection: Connection::new(stream),
+            device_type,
+            backend_features: 0,
+            protocol_features: 0,
+            queue_sizes: vec![QUEUE_SIZE; queue_count],
+            kick: Vec::new(),
+            call: Vec::new(),
+            started: vec![false; queue_count],
+            config,
+        };
+
+        device.request(VHOST_USER_SET_OWNER, &[], &[])?;
+        device.backend_features = device.request_reply(VHOST_USER_GET_FEATURES, &[])?.u64()?;
+
+        if device.backend_features & VHOST_USER_F_PROTOCOL_FEATURES != 0 {
+            let protocol_features = device
+                .request_reply(VHOST_USER_GET_PROTOCOL_FEATURES, &[])?
+                .u64()?
+                & FRONTEND_PROTOCOL_FEATURES;
+            device.request(
+                VHOST_USER_SET_PROTOCOL_FEATURES,
+                protocol_features.as_bytes(),
+                &[],
+            )?;
+            device.protocol_features = protocol_features;
+        }
+
+        if device.protocol_features & VHOST_USER_PROTOCOL_F_MQ != 0 {
+            let backend_queues = device.request_reply(VHOST_USER_GET_QUEUE_NUM, &[])?.u64()?;
+            if (backend_queues as usize) < queue_count {
+                log::error!(
+                    "vhost-user backend supports {} queues, {} required",
+                    backend_queues,
+                    queue_count
+                );
+                return Err(io_error(libc::EINVAL));
+            }
+        }
+
+        for _ in 0..queue_count {
+            device.kick.push(Arc::new(EventFd::new()?));
+            device.call.push(Arc::new(EventFd::new()?));
+        }
+
+        log::info!(
+            "vhost-user backend features {:#x}, protocol features {:#x}",
+            device.backend_features,
+            device.protocol_features
+        );
+
+        Ok(device)
+    }
+
+    fn has_protocol_feature(&self, feature: u64) -> bool {
+        self.protocol_features & feature != 0
+    }
+
+    /// Sends a request that has no reply, waits for the acknowledgement if
+    /// the backend supports that
+    fn request(&self, request: u32, payload: &[u8], fds: &[RawFd]) -> Result<(), std::io::Error> {
+        if !self.has_protocol_feature(VHOST_USER_PROTOCOL_F_REPLY_ACK) {
+            return self.connection.send(request, 0, payload, fds);
+        }
+
+        self.connection
+            .send(request, VHOST_USER_NEED_REPLY_MASK, payload, fds)?;
+        match self.recv_reply(request)?.u64()? {
+            0 => Ok(()),
+            status => {
+                log::error!(
+                    "vhost-user backend has failed request {} with {}",
+                    request,
+                    status
+                );
+                Err(io_error(libc::EIO))
+            }
+        }
+    }
+
+    fn request_reply(&self, request: u32, payload: &[u8]) -> Result<Message, std::io::Error> {
+        self.connection.send(request, 0, payload, &[])?;
+        self.recv_reply(request)
+    }
+
+    fn recv_reply(&self, request: u32) -> Result<Message, std::io::Error> {
+        let reply = self.connection.recv()?;
+        if reply.request != request || reply.flags & VHOST_USER_REPLY_MASK == 0 {
+            log::error!(
+                "Expected the reply to vhost-user request {}, got {}",
+                request,
+                reply.request
+            );
+            return Err(io_error(libc::EPROTO));
+        }
+
+        Ok(reply)
+    }
+
+    fn set_mem_table(&self, memory: &Memory) -> Result<(), std::io::Error> {
+        let mut regions = Vec::new();
+        let mut fds = Vec::new();
+
+        for span in memory.spans() {
+            // The backend would not see the guest buffers there
+            let file = match span.file() {
+                Some(file) => file,
+                None => {
+                    log::error!(
+                        "Cannot share the memory at {:#x} with no file with the vhost-user backend",
+                        span.gpa()
+                    );
+                    return Err(io_error(libc::EINVAL));
+                }
+            };
+
+            regions.push(VhostUserMemoryRegion {
+                guest_phys_addr: span.gpa(),
+                memory_size: span.size() as u64,
+                userspace_addr: span.host_address(),
+                mmap_offset: 0,
+            });
+            fds.push(file.as_raw_fd());
+        }
+
+        if regions.len() > VHOST_USER_MAX_FDS {
+            log::error!(
+                "Cannot share {} memory regions, at most {} are supported",
+                regions.len(),
+                VHOST_USER_MAX_FDS
+            );
+            return Err(io_error(libc::E2BIG));
+        }
+
+        let mut payload = VhostUserMemoryHeader {
+            nregions: regions.len() as u32,
+            padding: 0,
+        }
+        .as_bytes()
+        .to_vec();
+        for region in &regions {
+            payload.extend_from_slice(region.as_bytes());
+        }
+
+        self.request(VHOST_USER_SET_MEM_TABLE, &payload, &fds)
+    }
+
+    fn start_queue(
+        &mut self,
+        index: usize,
+        queue: &Queue,
+        memory: &Memory,
+    ) -> Result<(), std::io::Error> {
+        let state = |num: u32| VhostUserVringState {
+            index: index as u32,
+            num,
+        };
+        let host_address = |gpa: u64| {
+            memory
+                .host_address(gpa)
+                .ok_or_else(|| io_error(libc::EFAULT))
+        };
+
+        self.request(
+            VHOST_USER_SET_VRING_NUM,
+            state(queue.size as u32).as_bytes(),
+            &[],
+        )?;
+        self.request(
+            VHOST_USER_SET_VRING_BASE,
+            state(queue.base() as u32).as_bytes(),
+            &[],
+        )?;
+        self.request(
+            VHOST_USER_SET_VRING_ADDR,
+            VhostUserVringAddr {
+                index: index as u32,
+                flags: 0,
+                desc_user_addr: host_address(queue.desc_table)?,
+                used_user_addr: host_address(queue.used_ring)?,
+                avail_user_addr: host_address(queue.avail_ring)?,
+                log_guest_addr: 0,
+            }
+            .as_bytes(),
+            &[],
+        )?;
+        self.request(
+            VHOST_USER_SET_VRING_CALL,
+            (index as u64).as_bytes(),
+            &[self.call[index].as_raw_fd()],
+        )?;
+        self.request(
+            VHOST_USER_SET_VRING_KICK,
+            (index as u64).as_bytes(),
+            &[self.kick[index].as_raw_fd()],
+        )?;
+
+        if self.backend_features & VHOST_USER_F_PROTOCOL_FEATURES != 0 {
+            self.request(VHOST_USER_SET_VRING_ENABLE, state(1).as_bytes(), &[])?;
+        }
+
+        self.started[index] = true;
+
+        Ok(())
+    }
+
+    fn start(
+        &mut self,
+        features: u64,
+        queues: &[Queue],
+        memory: &Memory,
+    ) -> Result<(), std::io::Error> {
+        let features = features | (self.backend_features & VHOST_USER_F_PROTOCOL_FEATURES);
+
+        self.request(VHOST_USER_SET_FEATURES, features.as_bytes(), &[])?;
+        self.set_mem_table(memory)?;
+
+        for (index, queue) in queues.iter().enumerate() {
+            if queue.is_valid() {
+                self.start_queue(index, queue, memory)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn stop(&mut self) -> Result<(), std::io::Error> {
+        for index in 0..self.started.len() {
+            if !self.started[index] {
+                continue;
+            }
+
+            // Getting the base stops the ring
+            let state = self
+                .request_reply(
+                    VHOST_USER_GET_VRING_BASE,
+                    VhostUserVringState {
+                        index: index as u32,
+                        num: 0,
+                    }
+                    .as_bytes(),
+                )?
+                .obj::<VhostUserVringState>()?;
+            log::debug!("vhost-user queue {} stopped at {}", index, state.num);
+
+            self.started[index] = false;
+        }
+
+        Ok(())
+    }
+
+    /// Raises the interrupts of the transport when the backend signals
+    /// the used buffers
+    pub fn forward_interrupts(&self, transport: SharedVirtioTransport) {
+        let call = self.call.clone();
+
+        std::thread::Builder::new()
+            .name("vhost-user-call".to_string())
+            .spawn(move || {
+                let fds: Vec<RawFd> = call.iter().map(|eventfd| eventfd.as_raw_fd()).collect();
+
+                loop {
+                    let readable = match poll_readable(&fds) {
+                        Ok(readable) => readable,
+                        Err(e) => {
+                            log::error!("Cannot wait for the vhost-user interrupts: {}", e);
+                            return;
+                        }
+                    };
+
+                    for (index, readable) in readable.into_iter().enumerate() {
+                        if readable && call[index].read().is_some() {
+                            transport.lock().unwrap().signal_used_queue(index);
+                        }
+                    }
+                }
+            })
+            .expect("Cannot start the vhost-user interrupt thread");
+    }
+}
+
+impl VirtioDevice for VhostUserDevice {
+    fn device_type(&self) -> u32 {
+        self.device_type
+    }
+
+    fn features(&self) -> u64 {
+        self.backend_features & !VHOST_USER_F_PROTOCOL_FEATURES
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        &self.queue_sizes
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        if !self.has_protocol_feature(VHOST_USER_PROTOCOL_F_CONFIG) {
+            read_config_bytes(&self.config, offset, data);
+            return;
+        }
+
+        let mut payload = VhostUserConfigHeader {
+            offset: offset as u32,
+            size: data.len() as u32,
+            flags: 0,
+        }
+        .as_bytes()
+        .to_vec();
+        payload.resize(payload.len() + data.len(), 0);
+
+        match self.request_reply(VHOST_USER_GET_CONFIG, &payload) {
+            Ok(reply) if reply.payload.len() == payload.len() => {
+                data.copy_from_slice(&reply.payload[payload.len() - data.len()..])
+            }
+            Ok(_) => {
+                log::error!("Truncated vhost-user configuration space at {:#x}", offset);
+                data.fill(0);
+            }
+            Err(e) => {
+                log::error!("Cannot read the vhost-user configuration space: {}", e);
+                data.fill(0);
+            }
+        }
+    }
+
+    fn write_config(&mut self, offset: u64, data: &[u8]) {
+        if !self.has_protocol_feature(VHOST_USER_PROTOCOL_F_CONFIG) {
+            log::warn!(
+                "Writing {:x?} to the read-only config space at {:#x}",
+                data,
+                offset
+            );
+            return;
+        }
+
+        let mut payload = VhostUserConfigHeader {
+            offset: offset as u32,
+            size: data.len() as u32,
+            flags: 0,
+        }
+        .as_bytes()
+        .to_vec();
+        payload.extend_from_slice(data);
+
+        if let Err(e) = self.request(VHOST_USER_SET_CONFIG, &payload, &[]) {
+            log::error!("Cannot write the vhost-user configuration space: {}", e);
+        }
+    }
+
+    fn activate(&mut self, features: u64, queues: &[Queue], memory: &Memory) {
+        log::info!("vhost-user device activated with features {:#x}", features);
+
+        if let Err(e) = self.start(features, queues, memory) {
+            log::error!(
+                "Cannot hand the queues over to the vhost-user backend: {}",
+                e
+            );
+        }
+    }
+
+    fn process_queue(&mut self, index: usize, _queue: &mut Queue, _memory: &mut Memory) -> bool {
+        if self.started[index] {
+            self.kick[index].write(1);
+        }
+
+        // The backend signals the used buffers on its own
+        false
+    }
+
+    fn reset(&mut self) {
+        if let Err(e) = self.stop() {
+            log::error!("Cannot stop the vhost-user backend: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        ffi::CStr,
+        fs::File,
+        os::unix::{
+            net::UnixStream,
+            prelude::{AsRawFd, FromRawFd},
+        },
+        sync::{Arc, Mutex},
+    };
+
+    use nix::sys::memfd::{memfd_create, MemFdCreateFlag};
+
+    use super::{poll_readable, VhostUserBackend, VhostUserDevice};
+    use crate::smolvm::{
+        host_page_size,
+        virtio::{pmem::VirtioPmem, Queue, VirtioDevice, VIRTIO_F_VERSION_1, VIRTIO_ID_PMEM},
+        MappedGpa, Memory,
+    };
+
+    #[test]
+    fn test_vhost_user_pmem_flush() {
+        const DESC_TABLE: u64 = 0x1000;
+        const AVAIL_RING: u64 = 0x2000;
+        const USED_RING: u64 = 0x3000;
+        const REQUEST: u64 = 0x4000;
+        const RESPONSE: u64 = 0x5000;
+
+        let size = 0x10000;
+        let fd = memfd_create(
+            CStr::from_bytes_with_nul(b"test\0").unwrap(),
+            MemFdCreateFlag::MFD_CLOEXEC,
+        )
+        .unwrap();
+        let file = unsafe { File::from_raw_fd(fd) };
+        file.set_len(size as u64).unwrap();
+        let host = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                fd,
+                0,
+            )
+        };
+        assert_ne!(host, libc::MAP_FAILED);
+        let mut memory = Memory::new(vec![MappedGpa {
+            memory: host as *mut u8,
+            gpa: 0,
+            size,
+            file: Some(Arc::new(file)),
+            page_size: host_page_size(),
+            locked: false,
+        }]);
+
+        let (frontend, backend) = UnixStream::pair().unwrap();
+        let pmem_file = Arc::new(File::open("/proc/self/exe").unwrap());
+        let pmem = Arc::new(Mutex::new(VirtioPmem::new(
+            pmem_file,
+            0x1_0000_0000,
+            0x20_0000,
+        )));
+        std::thread::spawn(move || VhostUserBackend::new(backend, pmem).run());
+
+        let mut device = VhostUserDevice::new(frontend, VIRTIO_ID_PMEM, 1, Vec::new()).unwrap();
+        assert_ne!(device.features() & VIRTIO_F_VERSION_1, 0);
+
+        // A flush request followed by the response buffer
+        memory.write(DESC_TABLE, &REQUEST.to_le_bytes());
+        memory.write(DESC_TABLE + 8, &4_u32.to_le_bytes());
+        memory.write(DESC_TABLE + 12, &1_u16.to_le_bytes());
+        memory.write(DESC_TABLE + 14, &1_u16.to_le_bytes());
+        memory.write(DESC_TABLE + 16, &RESPONSE.to_le_bytes());
+        memory.write(DESC_TABLE + 24, &4_u32.to_le_bytes());
+        memory.write(DESC_TABLE + 28, &2_u16.to_le_bytes());
+        memory.write(RESPONSE, &0xffff_ffff_u32.to_le_bytes());
+        memory.write(AVAIL_RING + 2, &1_u16.to_le_bytes());
+
+        let mut queue = Queue::new(16);
+        queue.size = 16;
+        queue.ready = true;
+        queue.desc_table = DESC_TABLE;
+        queue.avail_ring = AVAIL_RING;
+        queue.used_ring = USED_RING;
+
+        device.activate(VIRTIO_F_VERSION_1, std::slice::from_ref(&queue), &memory);
+        device.process_queue(0, &mut queue, &mut memory);
+
+        poll_readable(&[device.call[0].as_raw_fd()]).unwrap();
+        assert!(device.call[0].read().is_some());
+        assert_eq!(memory.read_obj::<u16>(USED_RING + 2), 1);
+        assert_eq!(memory.read_obj::<u32>(USED_RING + 8), 4);
+        assert_ne!(memory.read_obj::<u32>(RESPONSE), 0xffff_ffff);
+
+        device.reset();
+    }
+}
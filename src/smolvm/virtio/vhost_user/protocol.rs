@@ -0,0 +1,261 @@
+//! The vhost-user messages and the socket they travel over. See
+//! "Vhost-user Protocol" in the QEMU documentation for the details.
+//!
+//!   Message:
+//!     +0  request  u32
+//!     +4  flags    u32   version (1), REPLY, NEED_REPLY
+//!     +8  size     u32   size of the payload
+//!     +12 payload
+//!
+//! The file descriptors are passed as `SCM_RIGHTS` along with the message.
+
+use std::{
+    fs::File,
+    io::Read,
+    os::unix::{
+        net::UnixStream,
+        prelude::{AsRawFd, FromRawFd, RawFd},
+    },
+};
+
+use nix::sys::{
+    socket::{recvmsg, sendmsg, ControlMessage, ControlMessageOwned, MsgFlags},
+    uio::IoVec,
+};
+use zerocopy::{AsBytes, FromBytes};
+
+pub const VHOST_USER_GET_FEATURES: u32 = 1;
+pub const VHOST_USER_SET_FEATURES: u32 = 2;
+pub const VHOST_USER_SET_OWNER: u32 = 3;
+pub const VHOST_USER_RESET_OWNER: u32 = 4;
+pub const VHOST_USER_SET_MEM_TABLE: u32 = 5;
+pub const VHOST_USER_SET_VRING_NUM: u32 = 8;
+pub const VHOST_USER_SET_VRING_ADDR: u32 = 9;
+pub const VHOST_USER_SET_VRING_BASE: u32 = 10;
+pub const VHOST_USER_GET_VRING_BASE: u32 = 11;
+pub const VHOST_USER_SET_VRING_KICK: u32 = 12;
+pub const VHOST_USER_SET_VRING_CALL: u32 = 13;
+pub const VHOST_USER_SET_VRING_ERR: u32 = 14;
+pub const VHOST_USER_GET_PROTOCOL_FEATURES: u32 = 15;
+pub const VHOST_USER_SET_PROTOCOL_FEATURES: u32 = 16;
+pub const VHOST_USER_GET_QUEUE_NUM: u32 = 17;
+pub const VHOST_USER_SET_VRING_ENABLE: u32 = 18;
+pub const VHOST_USER_GET_CONFIG: u32 = 24;
+pub const VHOST_USER_SET_CONFIG: u32 = 25;
+
+pub const VHOST_USER_VERSION: u32 = 0x1;
+pub const VHOST_USER_REPLY_MASK: u32 = 0x4;
+pub const VHOST_USER_NEED_REPLY_MASK: u32 = 0x8;
+
+/// Virtio feature bit the backend offers to tell it speaks the protocol
+/// features. Once negotiated, the rings start disabled.
+pub const VHOST_USER_F_PROTOCOL_FEATURES: u64 = 1 << 30;
+
+pub const VHOST_USER_PROTOCOL_F_MQ: u64 = 1 << 0;
+pub const VHOST_USER_PROTOCOL_F_REPLY_ACK: u64 = 1 << 3;
+pub const VHOST_USER_PROTOCOL_F_CONFIG: u64 = 1 << 9;
+
+/// The payload of the kick/call/err messages: the ring index and the flag
+/// telling there is no file descriptor
+pub const VHOST_USER_VRING_IDX_MASK: u64 = 0xff;
+pub const VHOST_USER_VRING_NOFD_MASK: u64 = 1 << 8;
+
+/// At most that many file descriptors per message, hence the memory regions
+pub const VHOST_USER_MAX_FDS: usize = 8;
+
+const VHOST_USER_HEADER_SIZE: usize = 12;
+const VHOST_USER_MAX_PAYLOAD: usize = 0x1000;
+
+#[repr(C)]
+#[derive(Default, Clone, Copy, AsBytes, FromBytes)]
+pub struct VhostUserMemoryHeader {
+    pub nregions: u32,
+    pub padding: u32,
+}
+
+#[repr(C)]
+#[derive(Default, Clone, Copy, AsBytes, FromBytes)]
+pub struct VhostUserMemoryRegion {
+    pub guest_phys_addr: u64,
+    pub memory_size: u64,
+    /// Where the frontend has the region mapped, the ring addresses are
+    /// given in this address space
+    pub userspace_addr: u64,
+    pub mmap_offset: u64,
+}
+
+#[repr(C)]
+#[derive(Default, Clone, Copy, AsBytes, FromBytes)]
+pub struct VhostUserVringState {
+    pub index: u32,
+    pub num: u32,
+}
+
+#[repr(C)]
+#[derive(Default, Clone, Copy, AsBytes, FromBytes)]
+pub struct VhostUserVringAddr {
+    pub index: u32,
+    pub flags: u32,
+    pub desc_user_addr: u64,
+    pub used_user_addr: u64,
+    pub avail_user_addr: u64,
+    pub log_guest_addr: u64,
+}
+
+/// Followed by `size` bytes of the configuration space
+#[repr(C)]
+#[derive(Default, Clone, Copy, AsBytes, FromBytes)]
+pub struct VhostUserConfigHeader {
+    pub offset: u32,
+    pub size: u32,
+    pub flags: u32,
+}
+
+static_assertions::const_assert_eq!(std::mem::size_of::<VhostUserMemoryRegion>(), 32);
+static_assertions::const_assert_eq!(std::mem::size_of::<VhostUserVringAddr>(), 40);
+
+fn invalid_data(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+pub struct Message {
+    pub request: u32,
+    pub flags: u32,
+    pub payload: Vec<u8>,
+    pub files: Vec<File>,
+}
+
+impl Message {
+    pub fn need_reply(&self) -> bool {
+        self.flags & VHOST_USER_NEED_REPLY_MASK != 0
+    }
+
+    /// The payload read as `T`, fails if the payload is too short
+    pub fn obj<T: FromBytes + AsBytes + Default>(&self) -> Result<T, std::io::Error> {
+        let mut obj = T::default();
+        let size = std::mem::size_of::<T>();
+
+        if self.payload.len() < size {
+            return Err(invalid_data("Truncated vhost-user message"));
+        }
+
+        obj.as_bytes_mut().copy_from_slice(&self.payload[..size]);
+        Ok(obj)
+    }
+
+    pub fn u64(&self) -> Result<u64, std::io::Error> {
+        self.obj::<u64>()
+    }
+
+    /// Takes the only file descriptor of the message if there is one
+    pub fn take_file(&mut self) -> Option<File> {
+        self.files.pop()
+    }
+}
+
+pub struct Connection {
+    stream: UnixStream,
+}
+
+impl Connection {
+    pub fn new(stream: UnixStream) -> Self {
+        Self { stream }
+    }
+
+    pub fn send(
+        &self,
+        request: u32,
+        flags: u32,
+        payload: &[u8],
+        fds: &[RawFd],
+    ) -> Result<(), std::io::Error> {
+        let mut header = [0_u8; VHOST_USER_HEADER_SIZE];
+        header[0..4].copy_from_slice(&request.to_le_bytes());
+        header[4..8].copy_from_slice(&(flags | VHOST_USER_VERSION).to_le_bytes());
+        header[8..12].copy_from_slice(&(payload.len() as u32).to_le_bytes());
+
+        let iov = [IoVec::from_slice(&header), IoVec::from_slice(payload)];
+        let rights = [ControlMessage::ScmRights(fds)];
+        let cmsgs: &[ControlMessage] = if fds.is_empty() { &[] } else { &rights };
+
+        let sent = sendmsg(
+            self.stream.as_raw_fd(),
+            &iov,
+            cmsgs,
+            MsgFlags::empty(),
+            None,
+        )?;
+        if sent != header.len() + payload.len() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::WriteZero,
+                "Short write to the vhost-user socket",
+            ));
+        }
+
+        Ok(())
+    }
+
+    pub fn reply(&self, message: &Message, payload: &[u8]) -> Result<(), std::io::Error> {
+        self.send(message.request, VHOST_USER_REPLY_MASK, payload, &[])
+    }
+
+    /// Waits for the next message, `UnexpectedEof` when the peer has gone
+    pub fn recv(&self) -> Result<Message, std::io::Error> {
+        let mut header = [0_u8; VHOST_USER_HEADER_SIZE];
+        let mut cmsg_buffer = nix::cmsg_space!([RawFd; VHOST_USER_MAX_FDS]);
+        let mut files = Vec::new();
+
+        let received = {
+            let iov = [IoVec::from_mut_slice(&mut header)];
+            let message = recvmsg(
+                self.stream.as_raw_fd(),
+                &iov,
+                Some(&mut cmsg_buffer),
+                MsgFlags::MSG_CMSG_CLOEXEC,
+            )?;
+
+            for cmsg in message.cmsgs() {
+                if let ControlMessageOwned::ScmRights(fds) = cmsg {
+                    files.extend(fds.iter().map(|fd| unsafe { File::from_raw_fd(*fd) }));
+                }
+            }
+
+            message.bytes
+        };
+
+        if received == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "vhost-user peer has disconnected",
+            ));
+        }
+        (&self.stream).read_exact(&mut header[received..])?;
+
+        let request = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+        let flags = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+        let size = u32::from_le_bytes([header[8], header[9], header[10], header[11]]) as usize;
+
+        if flags & 0x3 != VHOST_USER_VERSION {
+            return Err(invalid_data("Unsupported vhost-user protocol version"));
+        }
+        if size > VHOST_USER_MAX_PAYLOAD {
+            return Err(invalid_data("vhost-user message is too large"));
+        }
+
+        let mut payload = vec![0_u8; size];
+        (&self.stream).read_exact(&mut payload)?;
+
+        Ok(Message {
+            request,
+            flags,
+            payload,
+            files,
+        })
+    }
+}
+
+impl AsRawFd for Connection {
+    fn as_raw_fd(&self) -> RawFd {
+        self.stream.as_raw_fd()
+    }
+}